@@ -38,11 +38,12 @@ fn main() {
         bench(fib_recursive, n);
     ";
     let lexer = Lexer::new(code);
-    let ast = unlox_parse::parse(lexer, &mut stderr());
+    let ast = unlox_parse::parse(lexer).ast;
+    let locals = unlox_resolver::resolve(code, &ast, &mut stderr()).unwrap();
     let mut interpreter = Interpreter::new();
     let mut ctx = Ctx {
         src: code,
         out: SplitOutput::new(stdout(), stderr()),
     };
-    interpreter.interpret(&mut ctx, &ast);
+    interpreter.interpret(&mut ctx, &ast, &locals);
 }
@@ -0,0 +1,37 @@
+use std::io::{stderr, stdout};
+
+use unlox_interpreter::{output::SplitOutput, Ctx, Interpreter};
+use unlox_lexer::Lexer;
+
+fn main() {
+    let code = "
+        var step = 1;
+
+        fun sum(n) {
+            var total = 0;
+            {
+                {
+                    {
+                        for (var i = 0; i < n; i = i + step) {
+                            total = total + step;
+                        }
+                    }
+                }
+            }
+            return total;
+        }
+
+        var start = clock();
+        print sum(1000000);
+        print clock() - start;
+    ";
+    let lexer = Lexer::new(code);
+    let ast = unlox_parse::parse(lexer).ast;
+    let locals = unlox_resolver::resolve(code, &ast, &mut stderr()).unwrap();
+    let mut interpreter = Interpreter::new();
+    let mut ctx = Ctx {
+        src: code,
+        out: SplitOutput::new(stdout(), stderr()),
+    };
+    interpreter.interpret(&mut ctx, &ast, &locals);
+}
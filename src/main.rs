@@ -1,35 +1,93 @@
+use rustyline::error::ReadlineError;
 use std::{
     cell::Cell,
-    cmp::Ordering,
     env, fs,
-    io::{self, stderr, stdout, BufRead, Write},
+    io::{self, stderr, stdout, BufRead, IsTerminal, Write},
     process,
 };
-use unlox_interpreter::{output::SplitOutput, Ctx, Interpreter};
+use unlox_ast::{Ast, PrettyPrinter, Stmt, Token};
+use unlox_interpreter::{
+    output::{JsonOutput, Output, SplitOutput},
+    Ctx, Interpreter, Val,
+};
 use unlox_lexer::Lexer;
+use unlox_opt::Warning;
+use unlox_parse::{Completeness, ParseResult};
+use unlox_resolver::Locals;
 
 thread_local! {
     pub static HAD_ERROR: Cell<bool>  = const { Cell::new(false) };
     pub static HAD_RUNTIME_ERROR: Cell<bool>  = const { Cell::new(false) };
+    /// Whether errors are printed with ANSI colors.
+    pub static COLOR: Cell<bool>  = const { Cell::new(false) };
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+/// Command line flags. `--check`, `--ast` and `--json` are only supported when running a script.
+#[derive(Default)]
+struct Options {
+    /// Only parse and resolve the script, reporting the errors found before running it, along
+    /// with warnings about code that can't run and functions that are never called.
+    check: bool,
+    /// Print the syntax tree of the script as S-expressions.
+    ast: bool,
+    /// Print errors without ANSI colors, even to a terminal.
+    no_color: bool,
+    /// Print errors as lines of JSON, for tools to read.
+    json: bool,
+}
 
-    match args.len().cmp(&2) {
-        Ordering::Greater => {
-            println!("Usage: jlox [script]");
-            process::exit(64);
+fn main() {
+    let mut options = Options::default();
+    let mut scripts = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => options.check = true,
+            "--ast" => options.ast = true,
+            "--no-color" => options.no_color = true,
+            "--json" => options.json = true,
+            _ if arg.starts_with("--") => usage(),
+            _ => scripts.push(arg),
         }
-        Ordering::Equal => run_file(&args[1]).unwrap(),
-        Ordering::Less => run_prompt().unwrap(),
     }
+
+    COLOR.with(|c| c.set(!options.no_color && stderr().is_terminal()));
+
+    match scripts.as_slice() {
+        [] if options.check || options.ast || options.json => usage(),
+        [] => run_prompt().unwrap(),
+        [path] => run_file(path, &options).unwrap(),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    println!("Usage: jlox [--check] [--ast] [--no-color] [--json] [script]");
+    process::exit(64);
 }
 
-fn run_file(path: &str) -> io::Result<()> {
+fn run_file(path: &str, options: &Options) -> io::Result<()> {
     let code = fs::read_to_string(path)?;
+    if options.json {
+        run_json(&code, options);
+        return Ok(());
+    }
+    let Some(mut ast) = parse(&code, 0) else {
+        process::exit(65);
+    };
+    if options.ast {
+        println!("{}", PrettyPrinter::new(&code).print(&ast));
+    }
+    if options.check {
+        if resolve(&code, &ast).is_none() {
+            process::exit(65);
+        }
+        for warning in warnings(&code, &mut ast) {
+            report(&code, Some(&warning.token), &warning.message);
+        }
+        return Ok(());
+    }
     let mut interpreter = Interpreter::new();
-    run(&code, &mut interpreter);
+    execute(&code, &ast, &mut interpreter);
     if HAD_ERROR.with(|e| e.get()) {
         process::exit(65);
     }
@@ -39,17 +97,62 @@ fn run_file(path: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Like [`run_file`], but with the errors written as lines of JSON by [`JsonOutput`], rather than
+/// shown along with the source.
+fn run_json(code: &str, options: &Options) {
+    let mut output = JsonOutput::new(SplitOutput::new(stdout(), stderr()), "parse");
+    let ParseResult { mut ast, errors } = unlox_parse::parse(Lexer::new(code));
+    for error in &errors {
+        writeln!(output.err(), "{error}").unwrap();
+    }
+    if !errors.is_empty() {
+        process::exit(65);
+    }
+    if options.ast {
+        println!("{}", PrettyPrinter::new(code).print(&ast));
+    }
+    output.set_kind("resolve");
+    let Some(locals) = unlox_resolver::resolve(code, &ast, &mut output.err()) else {
+        process::exit(65);
+    };
+    if options.check {
+        output.set_kind("warning");
+        for warning in warnings(code, &mut ast) {
+            writeln!(output.err(), "{warning}").unwrap();
+        }
+        return;
+    }
+    output.set_kind("runtime");
+    let mut ctx = Ctx {
+        src: code,
+        out: output,
+    };
+    Interpreter::new().interpret(&mut ctx, &ast, &locals);
+    if ctx.out.error_count() > 0 {
+        process::exit(70);
+    }
+}
+
+/// Number of lines kept in the history of the prompt.
+const HISTORY_SIZE: usize = 1000;
+
+/// Reads the prompt with line editing and history when it's a terminal, and plain lines otherwise,
+/// e.g. when input is piped.
 fn run_prompt() -> io::Result<()> {
+    let mut repl = Repl::new();
+    if io::stdin().is_terminal() {
+        return run_editor(&mut repl).map_err(io::Error::other);
+    }
     let stdin = io::stdin();
     let mut lines = stdin.lock().lines();
-    let mut interpreter = Interpreter::new();
     loop {
-        print!("> ");
+        print!("{}", repl.prompt());
         io::stdout().flush()?;
         match lines.next() {
             Some(line) => {
-                run(&line?, &mut interpreter);
-                HAD_ERROR.with(|e| e.set(false))
+                if !repl.handle_line(&line?) {
+                    break;
+                }
             }
             None => break,
         }
@@ -57,12 +160,237 @@ fn run_prompt() -> io::Result<()> {
     Ok(())
 }
 
-fn run(code: &str, interpreter: &mut Interpreter) {
-    let lexer = Lexer::new(code);
-    let ast = unlox_parse::parse(lexer, &mut std::io::stderr());
+/// Runs the prompt with `rustyline`, keeping the history in `~/.unlox_history`.
+fn run_editor(repl: &mut Repl) -> rustyline::Result<()> {
+    let config = rustyline::Config::builder()
+        .max_history_size(HISTORY_SIZE)?
+        .auto_add_history(true)
+        .build();
+    let mut editor = rustyline::DefaultEditor::with_config(config)?;
+    let history = env::home_dir().map(|home| home.join(".unlox_history"));
+    if let Some(history) = &history {
+        // The file doesn't exist before the first session.
+        let _ = editor.load_history(history);
+    }
+    loop {
+        match editor.readline(repl.prompt()) {
+            Ok(line) => {
+                if !repl.handle_line(&line) {
+                    break;
+                }
+            }
+            // Ctrl-C discards the input typed so far.
+            Err(ReadlineError::Interrupted) => repl.input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error),
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(error) = editor.save_history(history) {
+            eprintln!("Couldn't save history to {}: {error}", history.display());
+        }
+    }
+    Ok(())
+}
+
+struct Repl {
+    interpreter: Interpreter,
+    /// Lines of an incomplete statement, waiting for the rest of it.
+    input: String,
+    /// Inputs run so far. Functions defined by earlier inputs keep pointing into their code and
+    /// tree, so each input is added to them rather than parsed on its own.
+    code: String,
+    ast: Ast,
+    locals: Locals,
+}
+
+impl Repl {
+    fn new() -> Self {
+        Self {
+            interpreter: Interpreter::new(),
+            input: String::new(),
+            code: String::new(),
+            ast: Ast::new(),
+            locals: Locals::new(),
+        }
+    }
+
+    fn prompt(&self) -> &'static str {
+        if self.input.is_empty() {
+            "> "
+        } else {
+            ".. "
+        }
+    }
+
+    /// Handles a line of input, returns `false` once the user asks to exit.
+    fn handle_line(&mut self, line: &str) -> bool {
+        // Meta-commands start with a dot, which can't start a Lox statement.
+        if self.input.is_empty() && line.trim_start().starts_with('.') {
+            return match line.trim() {
+                ".exit" => false,
+                command => {
+                    run_command(command, &mut self.interpreter);
+                    true
+                }
+            };
+        }
+        self.input.push_str(line);
+        self.input.push('\n');
+        if unlox_parse::is_complete(&self.input) == Completeness::Incomplete {
+            return true;
+        }
+        self.run_input();
+        self.input.clear();
+        HAD_ERROR.with(|e| e.set(false));
+        true
+    }
+
+    /// Runs the complete input, printing the value if it's a bare expression.
+    fn run_input(&mut self) {
+        let start = self.code.len();
+        self.code.push_str(&self.input);
+        let Some(ast) = parse_input(&self.code, start) else {
+            return;
+        };
+        let Some(locals) = resolve(&self.code, &ast) else {
+            return;
+        };
+        let mapping = self.ast.merge(ast);
+        self.locals.extend(
+            locals
+                .into_iter()
+                .map(|(expr, depth)| (mapping.expr(expr), depth)),
+        );
+        let mut ctx = Ctx {
+            src: &self.code,
+            out: SplitOutput::new(stdout(), stderr()),
+        };
+        self.interpreter.start(&self.locals);
+        for root in mapping.root_range() {
+            let stmt = self.ast.roots()[root];
+            if let Err(error) = self.interpreter.try_execute_root(&mut ctx, &self.ast, stmt) {
+                report_runtime_error(&self.code, &error);
+                return;
+            }
+        }
+    }
+}
+
+/// Runs a meta-command of the prompt:
+/// - `.vars` lists the global variables, other than functions, and their values
+/// - `.fns` lists the global functions and their arities
+/// - `.reset` removes the globals defined so far
+///
+/// `.exit` is handled by [`Repl::handle_line`].
+fn run_command(command: &str, interpreter: &mut Interpreter) {
+    let mut globals: Vec<_> = interpreter.global_vars().collect();
+    globals.sort_by_key(|(name, _)| *name);
+    match command {
+        ".vars" => {
+            for (name, val) in globals {
+                if !matches!(val, Val::Callable(_)) {
+                    println!("{name} = {}", val.to_lox_string());
+                }
+            }
+        }
+        ".fns" => {
+            for (name, val) in globals {
+                if let Val::Callable(callable) = val {
+                    println!("{name}/{}", callable.arity());
+                }
+            }
+        }
+        ".reset" => interpreter.reset_globals(),
+        _ => eprintln!("Unknown command '{command}'."),
+    }
+}
+
+/// Parses an input of the prompt starting at byte `start` of `code`. A bare expression is turned
+/// into a statement printing it.
+fn parse_input(code: &str, start: usize) -> Option<Ast> {
+    let mut ast = Ast::new();
+    match unlox_parse::parse_expr(Lexer::starting_at(code, start), &mut ast) {
+        Ok(expr) => {
+            ast.push_root_stmt(Stmt::Print(expr));
+            Some(ast)
+        }
+        Err(_) => parse(code, start),
+    }
+}
+
+/// Parses `code` from byte `start` on, reporting the syntax errors if there are any.
+fn parse(code: &str, start: usize) -> Option<Ast> {
+    let lexer = Lexer::starting_at(code, start);
+    let ParseResult { ast, errors } = unlox_parse::parse(lexer);
+    if errors.is_empty() {
+        return Some(ast);
+    }
+    for error in errors {
+        report(code, Some(&error.token), &error.message);
+    }
+    HAD_ERROR.with(|e| e.set(true));
+    None
+}
+
+/// Resolves the variables of `ast`, reporting the errors if there are any.
+fn resolve(code: &str, ast: &Ast) -> Option<Locals> {
+    match unlox_resolver::try_resolve(code, ast) {
+        Ok(locals) => Some(locals),
+        Err(errors) => {
+            for error in errors {
+                report(code, Some(&error.token), &error.message);
+            }
+            HAD_ERROR.with(|e| e.set(true));
+            None
+        }
+    }
+}
+
+/// Finds the code of a script checked with `--check` that can't run, and the functions that are
+/// never called, in the order they appear. Warnings don't fail the check.
+fn warnings(code: &str, ast: &mut Ast) -> Vec<Warning> {
+    let unused = unlox_analysis::find_unused_functions(code, ast)
+        .into_iter()
+        .map(|name| Warning {
+            message: format!("Function '{}' is never used.", name.source_text(code)),
+            token: name,
+        });
+    let mut warnings: Vec<_> = unused.collect();
+    warnings.extend(unlox_opt::eliminate_dead_code(ast));
+    warnings.sort_by_key(|warning| warning.token.lexeme.start);
+    warnings
+}
+
+fn execute(code: &str, ast: &Ast, interpreter: &mut Interpreter) {
+    let Some(locals) = resolve(code, ast) else {
+        return;
+    };
     let mut ctx = Ctx {
         src: code,
         out: SplitOutput::new(stdout(), stderr()),
     };
-    interpreter.interpret(&mut ctx, &ast);
+    if let Err(error) = interpreter.try_interpret(&mut ctx, ast, &locals) {
+        report_runtime_error(code, &error);
+    }
+}
+
+fn report_runtime_error(code: &str, error: &unlox_interpreter::Error) {
+    // Output printed before the error should show up before it.
+    stdout().flush().unwrap();
+    report(code, error.token(), &error.message());
+    HAD_RUNTIME_ERROR.with(|e| e.set(true));
+}
+
+/// Prints an error or a warning to stderr, along with the line of `code` containing `token`.
+fn report(code: &str, token: Option<&Token>, message: &str) {
+    match token {
+        Some(token) => {
+            let message = format!("[Line {}:{}]: {message}", token.line, token.col);
+            let color = COLOR.with(|c| c.get());
+            let diagnostic = unlox_diagnostic::format_diagnostic(code, token, &message, color);
+            eprint!("{diagnostic}");
+        }
+        None => eprintln!("{message}"),
+    }
 }
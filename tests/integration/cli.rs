@@ -0,0 +1,286 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+struct Script(PathBuf);
+
+impl Script {
+    fn new(name: &str, code: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("unlox-{}-{name}.lox", std::process::id()));
+        fs::write(&path, code).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn run_file(name: &str, code: &str) -> Output {
+    let script = Script::new(name, code);
+    Command::new(env!("CARGO_BIN_EXE_unlox"))
+        .arg(&script.0)
+        .output()
+        .unwrap()
+}
+
+fn run_prompt(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_unlox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn syntax_error_exits_before_execution() {
+    let code = r#"
+        print "before";
+        var a = ;
+    "#;
+    let output = run_file("syntax_error", code);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 3:17]: Expected expression.\n  \
+           |\n\
+         3 |         var a = ;\n  \
+           |                 ^\n"
+    );
+}
+
+#[test]
+fn resolution_error_exits_before_execution() {
+    let code = r#"
+        print "before";
+        { var a = a; }
+    "#;
+    let output = run_file("resolution_error", code);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 3:19]: Can't read local variable in its own initializer.\n  \
+           |\n\
+         3 |         { var a = a; }\n  \
+           |                   ^\n"
+    );
+}
+
+#[test]
+fn prompt_continues_incomplete_input() {
+    let input = "if (true) {\n    print 1;\n}\n";
+    let output = run_prompt(input);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "> .. .. 1\n> ");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn prompt_prints_bare_expressions() {
+    let input = "var x = 2;\nx * 3\nx = 5\nx\n1 +\n2\nprint x;\n";
+    let output = run_prompt(input);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "> > 6\n> 5\n> 5\n> .. 3\n> 5\n> "
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn prompt_runs_every_statement_of_a_line() {
+    let input = "var x = 1; print x; x = x + 1; print x;\nprint x;\n";
+    let output = run_prompt(input);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "> 1\n2\n> 2\n> ");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn prompt_runs_meta_commands() {
+    let input =
+        "var b = \"x\";\nvar a = 1;\nfun f(x, y) {}\n.vars\n.reset\n.vars\nprint a;\n.oops\n";
+    let output = run_prompt(input);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "> > > > a = 1\nb = x\n> > > > > "
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 1:7]: Undefined variable 'a'.\n  \
+           |\n\
+         1 | print a;\n  \
+           |       ^\n\
+         Unknown command '.oops'.\n"
+    );
+
+    let output = run_prompt("fun f(x, y) {}\n.fns\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("\nclock/0\n"), "{stdout}");
+    assert!(stdout.contains("\nf/2\n"), "{stdout}");
+}
+
+#[test]
+fn prompt_calls_functions_of_earlier_lines() {
+    let input = "fun f() { print 1; }\nf();\nfun adder(n) { return fun(x) { return x + n; }; }\nvar add = adder(2);\nadd(3)\n";
+    let output = run_prompt(input);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "> > 1\n> > > 5\n> "
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn prompt_exits_on_command() {
+    let output = run_prompt("print 1;\n.exit\nprint 2;\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "> 1\n> ");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+fn run_file_with(name: &str, code: &str, flags: &[&str]) -> Output {
+    let script = Script::new(name, code);
+    Command::new(env!("CARGO_BIN_EXE_unlox"))
+        .args(flags)
+        .arg(&script.0)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_reports_errors_without_running() {
+    let output = run_file_with("check_ok", "print 1;", &["--check"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+
+    let output = run_file_with("check_error", "print 1;\nprint ;", &["--check"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 2:7]: Expected expression.\n  |\n2 | print ;\n  |       ^\n"
+    );
+
+    let output = run_file_with(
+        "check_resolve_error",
+        "print 1;\n{ var a = a; }",
+        &["--check"],
+    );
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 2:11]: Can't read local variable in its own initializer.\n  |\n2 | { var a = a; }\n  |           ^\n"
+    );
+}
+
+#[test]
+fn check_warns_about_unused_functions() {
+    let code = "fun used() {}\nfun unused() { used(); }\nfun main() {}\nused();";
+    let output = run_file_with("check_unused", code, &["--check"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 2:5]: Function 'unused' is never used.\n  |\n2 | fun unused() { used(); }\n  |     ^^^^^^\n"
+    );
+}
+
+#[test]
+fn check_warns_about_dead_code() {
+    let code = "var f = fun () {\n  return 1;\n  print 2;\n};";
+    let output = run_file_with("check_dead_code", code, &["--check"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 2:3]: Code after 'return' is unreachable.\n  |\n2 |   return 1;\n  |   ^^^^^^\n"
+    );
+}
+
+#[test]
+fn prints_ast() {
+    let code = "var a = 1 + 2;\nprint a;";
+    let output = run_file_with("ast", code, &["--check", "--ast"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(var a (+ 1 2))\n(print a)\n"
+    );
+
+    let output = run_file_with("ast_run", code, &["--ast"]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "(var a (+ 1 2))\n(print a)\n3\n"
+    );
+}
+
+#[test]
+fn rejects_unknown_flags() {
+    let output = run_file_with("unknown_flag", "", &["--frobnicate"]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn runtime_error_shows_source_line() {
+    let code = "print \"before\";\nprint -\"a\";\nprint \"after\";";
+    let output = run_file("runtime_error", code);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "before\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[Line 2:7]: Operand must be a number, got string.\n  |\n2 | print -\"a\";\n  |       ^\n"
+    );
+}
+
+#[test]
+fn no_color_flag_is_accepted() {
+    let output = run_file_with("no_color", "print ;", &["--no-color"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(!String::from_utf8(output.stderr).unwrap().contains('\x1b'));
+}
+
+#[test]
+fn json_flag_prints_errors_as_json() {
+    let output = run_file_with("json_parse", "print 1;\nprint ;", &["--json"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"col\":7,\"kind\":\"parse\",\"line\":2,\"message\":\"Expected expression.\"}\n"
+    );
+
+    let output = run_file_with("json_runtime", "print 1;\nprint -nil;", &["--json"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"col\":7,\"kind\":\"runtime\",\"line\":2,\"message\":\"Operand must be a number, got nil.\"}\n"
+    );
+
+    let output = run_file_with("json_resolve", "{ var a = a; }", &["--json"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"col\":11,\"kind\":\"resolve\",\"line\":1,\"message\":\"Can't read local variable in its own initializer.\"}\n"
+    );
+
+    let code = "fun f() {\n  return;\n  print 1;\n}\nf();";
+    let output = run_file_with("json_warning", code, &["--check", "--json"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"col\":3,\"kind\":\"warning\",\"line\":2,\"message\":\"Code after 'return' is unreachable.\"}\n"
+    );
+}
@@ -1,17 +1,38 @@
-use unlox_interpreter::{output::SplitOutput, Ctx, Interpreter};
+use std::{
+    cell::RefCell,
+    io::{self, BufWriter, Write},
+    rc::Rc,
+};
+use unlox_ast::{Ast, Expr, Lit, PrettyPrinter, Stmt};
+use unlox_interpreter::{output::SplitOutput, Ctx, Interpreter, Val};
 use unlox_lexer::Lexer;
+use unlox_parse::ParseResult;
+
+mod cli;
 
 fn interpret(code: &str) -> (String, String) {
+    interpret_with(&mut Interpreter::new(), code)
+}
+
+fn interpret_with(interpreter: &mut Interpreter, code: &str) -> (String, String) {
     let mut out = Vec::new();
     let mut err = Vec::new();
     let lexer = Lexer::new(code);
-    let ast = unlox_parse::parse(lexer, &mut err);
-    let mut interpreter = Interpreter::new();
+    let ParseResult { ast, errors } = unlox_parse::parse(lexer);
+    if !errors.is_empty() {
+        for error in errors {
+            writeln!(err, "{error}").unwrap();
+        }
+        return (String::new(), String::from_utf8(err).unwrap());
+    }
+    let Some(locals) = unlox_resolver::resolve(code, &ast, &mut err) else {
+        return (String::new(), String::from_utf8(err).unwrap());
+    };
     let mut ctx = Ctx {
         src: code,
         out: SplitOutput::new(&mut out, &mut err),
     };
-    interpreter.interpret(&mut ctx, &ast);
+    interpreter.interpret(&mut ctx, &ast, &locals);
     (
         String::from_utf8(out).unwrap(),
         String::from_utf8(err).unwrap(),
@@ -29,6 +50,106 @@ fn math_expressions() {
     assert_eq!(interpret("print (2 + 2) * 2;").0, "8\n");
 }
 
+#[test]
+fn division_by_zero() {
+    assert_eq!(interpret("print 1 / 4;").0, "0.25\n");
+    assert_eq!(
+        interpret("print 1 / 0;"),
+        (String::new(), "[Line 1:9]: Division by zero.\n".to_owned())
+    );
+    assert_eq!(
+        interpret("var a = 0; print a / -0;"),
+        (String::new(), "[Line 1:20]: Division by zero.\n".to_owned())
+    );
+    assert_eq!(
+        interpret("var a = 2; a /= 0; print a;"),
+        (String::new(), "[Line 1:14]: Division by zero.\n".to_owned())
+    );
+}
+
+#[test]
+fn nan_results() {
+    let code = "var inf = 10 ** 400; print inf; print inf - inf;";
+    assert_eq!(
+        interpret(code),
+        (
+            "Infinity\n".to_owned(),
+            "[Line 1:43]: Result is not a number.\n".to_owned()
+        )
+    );
+    assert_eq!(
+        interpret("print 0 / 0;").1,
+        "[Line 1:9]: Division by zero.\n"
+    );
+    assert_eq!(
+        interpret("print (10 ** 400) % 2;").1,
+        "[Line 1:19]: Result is not a number.\n"
+    );
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_nan_is_error(false);
+    let code = "var inf = 10 ** 400; var nan = inf * 0; print nan; print -nan; print nan == nan;";
+    assert_eq!(
+        interpret_with(&mut interpreter, code).0,
+        "NaN\nNaN\nfalse\n"
+    );
+}
+
+#[test]
+fn radix_literals() {
+    assert_eq!(interpret("print 0xff + 0b11 + 0o10;").0, "266\n");
+    assert_eq!(
+        interpret("print 0b12;").1,
+        "[Line 1:7]: Unexpected character '2'.\n"
+    );
+}
+
+#[test]
+fn modulo() {
+    assert_eq!(interpret("print 7 % 3;").0, "1\n");
+    assert_eq!(interpret("print -7 % 3;").0, "-1\n");
+    assert_eq!(interpret("print 5.5 % 2;").0, "1.5\n");
+    assert_eq!(interpret("print 1 + 8 % 3 * 2;").0, "5\n");
+    assert_eq!(interpret("print 1 % 0;").1, "[Line 1:9]: Modulo by zero.\n");
+}
+
+#[test]
+fn exponentiation() {
+    assert_eq!(interpret("print 2 ** 10;").0, "1024\n");
+    assert_eq!(interpret("print 2 ** 3 ** 2;").0, "512\n");
+    assert_eq!(interpret("print 3 * 2 ** 2;").0, "12\n");
+    assert_eq!(interpret("print 4 ** 0.5;").0, "2\n");
+    assert_eq!(interpret("print -8 ** 2;").0, "64\n");
+    assert_eq!(
+        interpret("print -8 ** 0.5;").1,
+        "[Line 1:10]: Can't raise a negative number to a fractional power.\n"
+    );
+}
+
+#[test]
+fn compound_assignment() {
+    let code = r#"
+        var a = 10;
+        a += 5;
+        print a;
+        a -= 3;
+        print a;
+        a *= 2;
+        print a;
+        a /= 8;
+        print a;
+        var s = "un";
+        s += "lox";
+        print s;
+        print a += 1;
+    "#;
+    assert_eq!(interpret(code).0, "15\n12\n24\n3\nunlox\n4\n");
+    assert_eq!(
+        interpret("var a = 1; (a) += 1;").1,
+        "[Line 1:16]: Invalid assignment target.\n"
+    );
+}
+
 #[test]
 fn boolean_logic() {
     let code = r#"
@@ -38,6 +159,23 @@ fn boolean_logic() {
     assert_eq!(interpret(code).0, "hi\nyes\n");
 }
 
+#[test]
+fn ternary_expressions() {
+    let code = r#"
+        fun loud(x) {
+            print x;
+            return x;
+        }
+        print true ? loud("then") : loud("else");
+        print nil ? loud("then") : loud("else");
+        var n = 0;
+        print n < 0 ? "negative" : n == 0 ? "zero" : "positive";
+        var a = false or true ? 1 : 2;
+        print a;
+    "#;
+    assert_eq!(interpret(code).0, "then\nthen\nelse\nelse\nzero\n1\n");
+}
+
 #[test]
 fn if_statements() {
     let code = r#"
@@ -90,6 +228,221 @@ fn for_statements() {
     );
 }
 
+#[test]
+fn uninitialized_variables() {
+    let code = r#"
+        var a;
+        a = "assigned";
+        print a;
+        var b;
+        print b;
+    "#;
+    assert_eq!(
+        interpret(code),
+        (
+            "assigned\n".to_owned(),
+            "[Line 6:15]: Variable 'b' used before being initialized.\n".to_owned()
+        )
+    );
+    let code = "fun f() { var x; return x; } f();";
+    assert_eq!(
+        interpret(code).1,
+        "[Line 1:25]: Variable 'x' used before being initialized.\n"
+    );
+    assert_eq!(interpret("var n = nil; print n;").0, "nil\n");
+}
+
+#[test]
+fn nested_calls() {
+    let code = r#"
+        fun sub(a, b) {
+            return a - b;
+        }
+        fun f(a, b, c) {
+            return sub(a, sub(b, c)) * 10 + c;
+        }
+        print f(sub(10, 1), f(1, 2, 3), sub(3, 3));
+    "#;
+    assert_eq!(interpret(code).0, "-140\n");
+}
+
+#[test]
+fn variable_resolution() {
+    let code = r#"
+        var a = "global";
+        fun show() {
+            print a;
+        }
+        show();
+        {
+            var a = "block";
+            show();
+            print a;
+        }
+        fun outer() {
+            fun inner() {
+                print a;
+            }
+            inner();
+            var a = "local";
+            inner();
+        }
+        outer();
+    "#;
+    assert_eq!(interpret(code).0, "global\nglobal\nblock\nglobal\nlocal\n");
+
+    let code = r#"
+        fun count(n) {
+            if (n > 0) {
+                var m = n;
+                count(n - 1);
+                m = m * 10;
+                print m;
+            }
+        }
+        count(3);
+    "#;
+    assert_eq!(interpret(code).0, "10\n20\n30\n");
+}
+
+#[test]
+fn identifiers_with_digits() {
+    assert_eq!(interpret("var x1 = 1; var x2 = 2; print x1 + x2;").0, "3\n");
+}
+
+#[test]
+fn typeof_expressions() {
+    let code = r#"
+        class A { m() {} }
+        fun f() {}
+        print typeof 1;
+        print typeof "a";
+        print typeof true;
+        print typeof nil;
+        print typeof f;
+        print typeof clock;
+        print typeof fun() {};
+        print typeof A().m;
+        print typeof A;
+        print typeof A();
+        print typeof typeof 1;
+        print typeof 1 == "number";
+        print typeof -1 + 1;
+    "#;
+    let expected = [
+        "number", "string", "bool", "nil", "function", "function", "function", "function", "class",
+        "instance", "string", "true", "number1",
+    ];
+    assert_eq!(
+        interpret(code).0,
+        expected.map(|line| format!("{line}\n")).concat()
+    );
+}
+
+#[test]
+fn undefined_variables() {
+    let (_, err) = interpret("var foo = 1;\nprint fooBar;");
+    assert_eq!(err, "[Line 2:7]: Undefined variable 'fooBar'.\n");
+
+    let (_, err) = interpret("{ missing = 1; }");
+    assert_eq!(err, "[Line 1:3]: Undefined variable 'missing'.\n");
+}
+
+#[test]
+fn break_statements() {
+    let code = r#"
+        for (var i = 0; i < 10; i = i + 1) {
+            var j = 0;
+            while (true) {
+                if (j == i) break;
+                j = j + 1;
+            }
+            if (i == 2) {
+                var x = "inner";
+                break;
+            }
+            print j;
+        }
+        var x = "outer";
+        print x;
+    "#;
+    assert_eq!(interpret(code).0, "0\n1\nouter\n");
+
+    let code = r#"
+        fun f() {
+            while (true) {
+                return "returned";
+            }
+        }
+        print f();
+    "#;
+    assert_eq!(interpret(code).0, "returned\n");
+
+    let code = r#"
+        while (true) {
+            fun f() {
+                break;
+            }
+        }
+    "#;
+    assert_eq!(
+        interpret(code).1,
+        "[Line 4:17]: Can't use 'break' outside of a loop.\n"
+    );
+}
+
+#[test]
+fn continue_statements() {
+    let code = r#"
+        for (var i = 0; i < 5; i = i + 1) {
+            if (i == 1 or i == 3) {
+                var skipped = i;
+                continue;
+            }
+            print i;
+        }
+        var skipped = "none";
+        print skipped;
+    "#;
+    assert_eq!(interpret(code).0, "0\n2\n4\nnone\n");
+
+    let code = r#"
+        var i = 0;
+        while (i < 4) {
+            i = i + 1;
+            if (i == 2) continue;
+            print i;
+        }
+    "#;
+    assert_eq!(interpret(code).0, "1\n3\n4\n");
+
+    let code = r#"
+        fun f() {
+            continue;
+        }
+    "#;
+    assert_eq!(
+        interpret(code).1,
+        "[Line 3:13]: Can't use 'continue' outside of a loop.\n"
+    );
+}
+
+#[test]
+fn do_while_loops() {
+    let code = r#"
+        var i = 0;
+        do {
+            i = i + 1;
+            if (i == 2) continue;
+            if (i == 4) break;
+            print i;
+        } while (i < 5);
+        do { print "once"; } while (false);
+        print i;
+    "#;
+    assert_eq!(interpret(code).0, "1\n3\nonce\n4\n");
+}
+
 #[test]
 fn functions() {
     let code = r#"
@@ -143,5 +496,1092 @@ fn functions() {
         }
         main();
     "#;
-    assert_eq!(interpret(code).1, "[Line 9]: Undefined variable b.\n");
+    assert_eq!(interpret(code).0, "1\n2\n");
+}
+
+#[test]
+fn closures() {
+    let code = r#"
+        fun make_counter() {
+            var i = 0;
+            fun count() {
+                i = i + 1;
+                print i;
+            }
+            return count;
+        }
+
+        var a = make_counter();
+        var b = make_counter();
+        a();
+        a();
+        b();
+        a();
+    "#;
+    assert_eq!(interpret(code).0, "1\n2\n1\n3\n");
+
+    let code = r#"
+        var f;
+        {
+            var captured = "block";
+            fun show() {
+                print captured;
+            }
+            f = show;
+        }
+        {
+            var unrelated = "other";
+            f();
+        }
+    "#;
+    assert_eq!(interpret(code).0, "block\n");
+
+    // Enough iterations for the environments of the loop body to be collected in between.
+    let code = r#"
+        var kept = [];
+        for (var i = 0; i < 2000; i = i + 1) {
+            var j = i;
+            fun get() { return j; }
+            if (j % 500 == 0) push(kept, get);
+        }
+        for (get in kept) print get();
+    "#;
+    assert_eq!(interpret(code).0, "0\n500\n1000\n1500\n");
+}
+
+#[test]
+fn syntax_errors() {
+    let code = r#"
+        print "before";
+        var a = ;
+        print "after";
+    "#;
+    let (out, err) = interpret(code);
+    assert_eq!(out, "");
+    assert_eq!(err, "[Line 3:17]: Expected expression.\n");
+}
+
+#[test]
+fn deep_expressions() {
+    let code = format!("print 0{};", " + 1".repeat(100_000));
+    assert_eq!(interpret(&code).0, "100000\n");
+}
+
+#[test]
+fn deep_recursion() {
+    let code = r#"
+        fun count(n) {
+            if (n == 0) return 0;
+            return count(n - 1) + 1;
+        }
+        print count(100000);
+    "#;
+    assert_eq!(interpret(code).0, "100000\n");
+}
+
+#[test]
+fn call_depth_limit() {
+    let code = r#"
+        fun count(n) {
+            if (n == 0) return 0;
+            return count(n - 1) + 1;
+        }
+        print count(50);
+        print count(100);
+    "#;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_call_depth_limit(100);
+    assert_eq!(
+        interpret_with(&mut interpreter, code),
+        (
+            "50\n".to_owned(),
+            "[Line 4:31]: Stack overflow.\n".to_owned()
+        )
+    );
+    // Frames unwound by the error no longer count.
+    assert_eq!(interpret_with(&mut interpreter, code).0, "50\n");
+
+    let code = r#"
+        fun f() { f(); }
+        f();
+    "#;
+    let (_, err) = interpret(code);
+    assert_eq!(err, "[Line 2:21]: Stack overflow.\n");
+}
+
+#[test]
+fn tail_calls_reuse_frames() {
+    let code = r#"
+        fun loop(n) {
+            if (n == 0) return "done";
+            return loop(n - 1);
+        }
+        fun even(n) { return n == 0 or odd(n - 1); }
+        fun odd(n) { return n != 0 and even(n - 1); }
+        class Counter {
+            down(n) { return n == 0 ? this : this.down(n - 1); }
+        }
+        print loop(1000);
+        print even(1000);
+        print Counter().down(1000);
+    "#;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_call_depth_limit(100);
+    assert_eq!(
+        interpret_with(&mut interpreter, code),
+        ("done\ntrue\nCounter instance\n".to_owned(), String::new())
+    );
+}
+
+#[test]
+fn step_limit() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_step_limit(1000);
+    assert_eq!(
+        interpret_with(&mut interpreter, "print 1; while (true) {}"),
+        (
+            "1\n".to_owned(),
+            "Step limit of 1000 exceeded.\n".to_owned()
+        )
+    );
+    // Steps are counted separately for each run.
+    let code = "for (var i = 0; i < 100; i += 1) {} print \"done\";";
+    assert_eq!(interpret_with(&mut interpreter, code).0, "done\n");
+    assert_eq!(interpret_with(&mut interpreter, code).0, "done\n");
+}
+
+#[test]
+fn annotated_params() {
+    let code = r#"
+        fun add(a: number, b) {
+            return a + b;
+        }
+        print add(1, 2);
+    "#;
+    assert_eq!(interpret(code).0, "3\n");
+}
+
+#[test]
+fn type_checks() {
+    let code = r#"
+        fun greet(name: string, times: number, f: function, x: any) {
+            print name;
+        }
+        greet("Lox", 1, clock, nil);
+        greet("Lox", "once", clock, nil);
+    "#;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_type_checks(true);
+    assert_eq!(
+        interpret_with(&mut interpreter, code),
+        (
+            "Lox\n".to_owned(),
+            "[Line 6:40]: Expected argument times to be number but got string.\n".to_owned()
+        )
+    );
+
+    let code = r#"
+        fun f(x: integer) {}
+        f(1);
+    "#;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_type_checks(true);
+    assert_eq!(
+        interpret_with(&mut interpreter, code).1,
+        "[Line 2:18]: Unknown type integer.\n"
+    );
+}
+
+#[test]
+fn type_checks_disabled_by_default() {
+    let code = r#"
+        fun f(x: number) {
+            print x;
+        }
+        f("not a number");
+    "#;
+    assert_eq!(interpret(code).0, "not a number\n");
+}
+
+#[test]
+fn compaction_preserves_semantics() {
+    let code = r#"
+        fun f(n) {
+            if (n > 1) return n * f(n - 1);
+            return 1;
+        }
+        if (false) print "dead";
+        print f(5) + 2 * 3;
+    "#;
+    let mut ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let dead = ast.roots()[1];
+    let Stmt::Print(expr) = ast.stmt(*ast.roots().last().unwrap()) else {
+        panic!("Expected print statement");
+    };
+    let Expr::Binary(_, _, product) = ast.expr(*expr) else {
+        panic!("Expected binary expression");
+    };
+    let product = *product;
+
+    let run = |ast: &Ast| {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let locals = unlox_resolver::resolve(code, ast, &mut err).unwrap();
+        let mut ctx = Ctx {
+            src: code,
+            out: SplitOutput::new(&mut out, &mut err),
+        };
+        Interpreter::new().interpret(&mut ctx, ast, &locals);
+        String::from_utf8(out).unwrap()
+    };
+    let before = run(&ast);
+    *ast.expr_mut(product) = Expr::Literal(Lit::Number(6.0));
+    *ast.stmt_mut(dead) = Stmt::Block(Vec::new());
+    let folded = ast.clone();
+    ast.compact();
+    assert!(ast.expr_count() < folded.expr_count());
+    assert!(ast.stmt_count() < folded.stmt_count());
+    assert_eq!(run(&ast), before);
+    assert_eq!(run(&folded), before);
+    assert_eq!(before, "126\n");
+}
+
+#[test]
+fn pretty_printing() {
+    let code = r#"
+        var a = 1;
+        a += -2 * (3 + 4);
+        fun f(x: number, y) {
+            if (x < y and !false) return x; else return "y";
+        }
+        for (var i = 0; i < 3; i = i + 1) {
+            continue;
+        }
+        class B extends A {
+            m() {
+                this.x = super.m(nil);
+                return fun(z) {
+                    print z ? z.w : nil;
+                };
+            }
+        }
+        xs[0] = [1, xs[i]];
+    "#;
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let expected = [
+        "(var a 1)",
+        "(; (= a (+ a (* (- 2) (group (+ 3 4))))))",
+        r#"(fun f (x:number y) (if (and (< x y) (! false)) (return x) (return "y")))"#,
+        "(block (var i 0) (while (< i 3) (block (continue)) (= i (+ i 1))))",
+        "(class B (extends A) (fun m () (; (= (. this x) (call (super m) nil))) \
+            (return (fun (z) (print (?: z (. z w) nil))))))",
+        "(; (= ([] xs 0) (array 1 ([] xs i))))",
+    ];
+    assert_eq!(PrettyPrinter::new(code).print(&ast), expected.join("\n"));
+}
+
+#[test]
+fn ast_json_round_trip() {
+    let code = r#"
+        class A extends B {
+            m(x: number) {
+                this.y = x > 1 ? -x : super.m(x);
+                return fun() { print "a" + x; };
+            }
+        }
+        for (var i = 0; i < 2; i += 1) { if (i == 1 or nil) break; }
+        var xs = [1, [2]];
+        xs[0] = xs[1][0];
+    "#;
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let json = ast.to_json();
+    let serde_json::Value::Array(roots) = serde_json::from_str(&json).unwrap() else {
+        panic!("Expected an array of root statements");
+    };
+    assert_eq!(roots.len(), 4);
+    assert!(roots[0]["Class"]["methods"][0]["Function"]["body"].is_array());
+
+    let deserialized = Ast::from_json(&json).unwrap();
+    assert_eq!(
+        PrettyPrinter::new(code).print(&deserialized),
+        PrettyPrinter::new(code).print(&ast)
+    );
+    assert!(Ast::from_json("[{\"Print\": 1}]").is_err());
+}
+
+#[test]
+fn lambdas() {
+    let code = r#"
+        var double = fun(x) { return x * 2; };
+        print double(21);
+        print double;
+
+        fun apply(f, n) {
+            return f(n);
+        }
+        print apply(fun(n) { return n + 1; }, 1);
+
+        fun adder(n) {
+            return fun(x) { return x + n; };
+        }
+        var addThree = adder(3);
+        print addThree(4);
+        print (fun() { return "called"; })();
+    "#;
+    assert_eq!(interpret(code).0, "42\n<fn>\n2\n7\ncalled\n");
+}
+
+#[test]
+fn classes() {
+    let code = r#"
+        class Point {
+            init(x, y) {
+                print "init";
+                return;
+            }
+            len() {}
+        }
+        class Empty {}
+        print Point;
+        var p = Point(1, 2);
+        print p;
+        print p == p;
+        print p == Point(1, 2);
+        print Empty();
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "Point\ninit\nPoint instance\ntrue\ninit\nfalse\nEmpty instance\n"
+    );
+
+    let code = r#"
+        class Point {
+            init(x, y) {}
+        }
+        Point(1);
+    "#;
+    assert_eq!(
+        interpret(code).1,
+        "[Line 5:16]: Expected 2 arguments but got 1.\n"
+    );
+
+    let code = r#"
+        class Point {
+            init(x, y) {
+                this.x = x;
+                this.y = y;
+            }
+            sum() {
+                return this.x + this.y;
+            }
+            moveBy(dx) {
+                this.x = this.x + dx;
+                return this;
+            }
+        }
+        var p = Point(1, 2);
+        print p.sum();
+        p.y = 10;
+        print p.y;
+        print p.moveBy(5).sum();
+        var sum = p.sum;
+        print sum();
+    "#;
+    assert_eq!(interpret(code).0, "3\n10\n16\n16\n");
+
+    let code = r#"
+        class Point {}
+        var p = Point();
+        print p.x;
+    "#;
+    assert_eq!(interpret(code).1, "[Line 4:17]: Undefined property 'x'.\n");
+}
+
+#[test]
+fn property_access() {
+    let code = r#"
+        class Inner {
+            greet() {
+                print "hello";
+            }
+        }
+        class Outer {
+            inner() {
+                return Inner();
+            }
+        }
+        var outer = Outer();
+        print outer.inner;
+        outer.inner().greet();
+    "#;
+    assert_eq!(interpret(code).0, "<fn inner>\nhello\n");
+
+    let (_, err) = interpret("class A {} A().missing;");
+    assert_eq!(err, "[Line 1:16]: Undefined property 'missing'.\n");
+
+    let (_, err) = interpret("var a = 1; a.b;");
+    assert_eq!(err, "[Line 1:14]: Only instances have properties.\n");
+}
+
+#[test]
+fn property_assignment() {
+    let code = r#"
+        class Box {
+            get() {
+                return "method";
+            }
+        }
+        var a = Box();
+        var b = Box();
+        a.value = 1;
+        b.value = a.value + 1;
+        print a.value;
+        print b.value;
+        print a.inner = Box();
+        a.inner.value = "nested";
+        print a.inner.value;
+        a.get = "field";
+        print a.get;
+        print b.get();
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "1\n2\nBox instance\nnested\nfield\nmethod\n"
+    );
+
+    let (_, err) = interpret("var a = 1; a.b = 2;");
+    assert_eq!(err, "[Line 1:14]: Only instances have properties.\n");
+
+    let (_, err) = interpret("class A {} var a = A(); a.b += 2;");
+    assert_eq!(err, "[Line 1:29]: Invalid assignment target.\n");
+}
+
+#[test]
+fn this_in_methods() {
+    let code = r#"
+        class Counter {
+            init(start) {
+                this.count = start;
+            }
+            increment() {
+                this.count = this.count + 1;
+                return this;
+            }
+            adder() {
+                return fun(n) {
+                    this.count = this.count + n;
+                };
+            }
+            show() {
+                print this.count;
+            }
+        }
+        var counter = Counter(10);
+        counter.increment().increment().show();
+        var add = counter.adder();
+        add(5);
+        counter.show();
+        var show = counter.show;
+        counter = nil;
+        show();
+    "#;
+    assert_eq!(interpret(code).0, "12\n17\n17\n");
+
+    let code = r#"
+        class Named {
+            init(name) {
+                this.name = name;
+            }
+            greet(other) {
+                print this.name + " greets " + other.name;
+            }
+        }
+        var a = Named("a");
+        var b = Named("b");
+        a.greet(b);
+        b.greet(a);
+    "#;
+    assert_eq!(interpret(code).0, "a greets b\nb greets a\n");
+}
+
+#[test]
+fn inheritance() {
+    let code = r#"
+        class A {
+            init(name) {
+                this.name = name;
+            }
+            method() {
+                print "A method of " + this.name;
+            }
+            inherited() {
+                print "inherited";
+            }
+        }
+        class B extends A {
+            method() {
+                print "B method";
+            }
+            test() {
+                super.method();
+            }
+        }
+        class C extends B {}
+        var c = C("c");
+        c.method();
+        c.test();
+        c.inherited();
+        var callback = fun() {};
+        class D extends A {
+            init() {
+                super.init("d");
+                callback = fun() {
+                    super.method();
+                };
+            }
+        }
+        D();
+        callback();
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "B method\nA method of c\ninherited\nA method of d\n"
+    );
+
+    let (_, err) = interpret("var A = 1; class B extends A {}");
+    assert_eq!(err, "[Line 1:28]: Superclass must be a class.\n");
+
+    let (_, err) = interpret("class A {} class B extends A { m() { super.m(); } } B().m();");
+    assert_eq!(err, "[Line 1:44]: Undefined property 'm'.\n");
+}
+
+#[test]
+fn resolution_errors() {
+    let code = r#"
+        var a = "global";
+        {
+            var a = a;
+        }
+    "#;
+    assert_eq!(
+        interpret(code),
+        (
+            String::new(),
+            "[Line 4:21]: Can't read local variable in its own initializer.\n".to_owned()
+        )
+    );
+
+    // Globals may refer to their previous definition, if there's one.
+    assert_eq!(interpret("var a = 1; var a = a + 1; print a;").0, "2\n");
+    assert_eq!(
+        interpret("var a = a + 1;").1,
+        "[Line 1:9]: Undefined variable 'a'.\n"
+    );
+}
+
+#[test]
+fn natives() {
+    let code = r#"
+        var start = clock();
+        print clock() >= start;
+        print clock;
+    "#;
+    assert_eq!(interpret(code).0, "true\n<native fn>\n");
+
+    let mut interpreter = Interpreter::new();
+    interpreter.define_native("twice", 1, |_, args| match &args[0] {
+        Val::Number(n) => Ok(Val::Number(n * 2.0)),
+        _ => Ok(Val::Nil),
+    });
+    let code = r#"
+        print twice(21);
+        print twice;
+        twice(1, 2);
+    "#;
+    assert_eq!(
+        interpret_with(&mut interpreter, code),
+        (
+            "42\n<native fn>\n".to_owned(),
+            "[Line 4:19]: Expected 1 arguments but got 2.\n".to_owned()
+        )
+    );
+}
+
+#[test]
+fn math_natives() {
+    let code = r#"
+        print sqrt(16);
+        print floor(-1.5);
+        print ceil(1.2);
+        print abs(-3);
+        print sin(0);
+        print cos(0);
+        print tan(0);
+        print log(exp(2));
+        print log2(8);
+        print pow(2, 10);
+        print floor;
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "4\n-2\n2\n3\n0\n1\n0\n2\n3\n1024\n<native fn>\n"
+    );
+    assert_eq!(
+        interpret("print sqrt(\"4\");").1,
+        "[Line 1:15]: Expected argument x to be number but got string.\n"
+    );
+    assert_eq!(
+        interpret("print pow(2, nil);").1,
+        "[Line 1:17]: Expected argument exp to be number but got nil.\n"
+    );
+    assert_eq!(
+        interpret("print sqrt(-1);").1,
+        "[Line 1:14]: Result is not a number.\n"
+    );
+    assert_eq!(
+        interpret("print log(-1);").1,
+        "[Line 1:13]: Result is not a number.\n"
+    );
+    let mut interpreter = Interpreter::new();
+    interpreter.set_nan_is_error(false);
+    assert_eq!(
+        interpret_with(&mut interpreter, "print sqrt(-1);").0,
+        "NaN\n"
+    );
+}
+
+#[test]
+fn arrays() {
+    let code = r#"
+        var xs = [1, "two", [3]];
+        print xs;
+        print xs[1] + xs[2][0];
+        xs[0] = xs[0] + 10;
+        var alias = xs;
+        push(alias, nil);
+        print xs;
+        print pop(xs);
+        print [];
+        print typeof [] == "array";
+        var matrix = [[1, 2], [3, 4]];
+        matrix[1][0] = 5;
+        print matrix;
+        var cycle = [];
+        push(cycle, cycle);
+        print cycle;
+        print cycle == cycle;
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "[1, two, [3]]\ntwo3\n[11, two, [3], nil]\nnil\n[]\ntrue\n[[1, 2], [5, 4]]\n[[...]]\ntrue\n"
+    );
+    for (code, error) in [
+        (
+            "[1][1];",
+            "[Line 1:6]: Index 1 is out of bounds for an array of length 1.",
+        ),
+        (
+            "[1][-1] = 2;",
+            "[Line 1:7]: Index -1 is out of bounds for an array of length 1.",
+        ),
+        (
+            "[1][0.5];",
+            "[Line 1:8]: Index 0.5 is out of bounds for an array of length 1.",
+        ),
+        (
+            "[1][\"0\"];",
+            "[Line 1:8]: Array index must be a number, got string.",
+        ),
+        (
+            "1[0];",
+            "[Line 1:4]: Only arrays can be indexed, got number.",
+        ),
+        ("pop([]);", "[Line 1:7]: Can't pop from an empty array."),
+        (
+            "push(1, 2);",
+            "[Line 1:10]: Expected argument array to be array but got number.",
+        ),
+    ] {
+        assert_eq!(interpret(code).1, format!("{error}\n"));
+    }
+}
+
+#[test]
+fn for_in_loops() {
+    let code = r#"
+        var xs = [1, 2, 3, 4];
+        for (x in xs) {
+            if (x == 2) continue;
+            if (x == 4) break;
+            print x;
+        }
+        var grown = [1];
+        for (x in grown) if (x < 3) push(grown, x + 1);
+        print grown;
+        var printers = [];
+        for (x in ["a", "b"]) push(printers, fun() { print x; });
+        printers[0]();
+        printers[1]();
+        fun first(pairs) {
+            for (pair in pairs) for (y in pair) return y;
+        }
+        print first([[], [5, 6]]);
+        for (x in []) print "never";
+        var x = "outer";
+        for (x in [1]) {}
+        print x;
+    "#;
+    assert_eq!(interpret(code).0, "1\n3\n[1, 2, 3]\na\nb\n5\nouter\n");
+    assert_eq!(
+        interpret("for (item in [1]) {}\nprint item;").1,
+        "[Line 2:7]: Undefined variable 'item'.\n"
+    );
+    assert_eq!(
+        interpret("for (c in \"abc\") {}").1,
+        "[Line 1:6]: Can only iterate over arrays, got string.\n"
+    );
+}
+
+#[test]
+fn string_conversions() {
+    let code = r#"
+        print str(42) + str(true) + str(nil) + str("s") + str(0.5);
+        print typeof str(clock);
+        print "value: " + 42;
+        print 1.5 + " apples";
+        print 1 + 2 + "3";
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "42truenils0.5\nstring\nvalue: 42\n1.5 apples\n33\n"
+    );
+}
+
+#[test]
+fn string_natives() {
+    let code = r#"
+        print str_len("zażółć");
+        print str_upper("abc");
+        print str_lower("ABC");
+        print "[" + str_trim("  a b ") + "]";
+        print str_substr("hello world", 6, 5);
+        print str_substr("hello", 3, 10);
+        print str_split("a,b,,c", ",");
+        print str_split("abc", "");
+    "#;
+    assert_eq!(
+        interpret(code).0,
+        "6\nABC\nabc\n[a b]\nworld\nlo\n[a, b, , c]\n[a, b, c]\n"
+    );
+    assert_eq!(
+        interpret("str_len(1);").1,
+        "[Line 1:10]: Expected argument s to be string but got number.\n"
+    );
+    assert_eq!(
+        interpret("str_substr(\"abc\", 0.5, 1);").1,
+        "[Line 1:25]: Expected argument start to be a non-negative integer but got number.\n"
+    );
+}
+
+#[test]
+fn natives_with_host_state() {
+    let collected = Rc::new(RefCell::new(Vec::new()));
+    let mut interpreter = Interpreter::new();
+    interpreter.define_native("collect", 1, {
+        let collected = collected.clone();
+        move |_, args| {
+            collected.borrow_mut().push(args[0].clone());
+            Ok(Val::Nil)
+        }
+    });
+    interpreter.define_native("count", 0, {
+        let collected = collected.clone();
+        move |_, _| Ok(Val::Number(collected.borrow().len() as f64))
+    });
+    let code = r#"
+        for (var i = 0; i < 3; i += 1) collect(i * 2);
+        collect("done");
+        print count();
+    "#;
+    assert_eq!(interpret_with(&mut interpreter, code).0, "4\n");
+    assert_eq!(
+        *collected.borrow(),
+        [
+            Val::Number(0.0),
+            Val::Number(2.0),
+            Val::Number(4.0),
+            Val::String("done".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn host_interop() {
+    let code = r#"
+        var greeting = "Hello, " + name;
+        fun on_event(event, times) {
+            print greeting + " " + event;
+            return times * 2;
+        }
+    "#;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_global_var("name", Val::String("host".to_owned()));
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let locals = unlox_resolver::resolve(code, &ast, &mut io::sink()).unwrap();
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    let mut ctx = Ctx {
+        src: code,
+        out: SplitOutput::new(&mut out, &mut err),
+    };
+    interpreter.interpret(&mut ctx, &ast, &locals);
+    assert_eq!(
+        interpreter.get_global_var("greeting"),
+        Some(&Val::String("Hello, host".to_owned()))
+    );
+    let args = vec![Val::String("started".to_owned()), Val::Number(21.0)];
+    let result = interpreter.call_fn(&mut ctx, &ast, &locals, "on_event", args);
+    assert_eq!(result.unwrap(), Val::Number(42.0));
+    assert_eq!(
+        interpreter
+            .call_fn(&mut ctx, &ast, &locals, "on_event", vec![])
+            .unwrap_err()
+            .to_string(),
+        "Function 'on_event' expects 2 arguments but got 0."
+    );
+    assert_eq!(
+        interpreter
+            .call_fn(&mut ctx, &ast, &locals, "greeting", vec![])
+            .unwrap_err()
+            .to_string(),
+        "'greeting' is not a function."
+    );
+    assert_eq!(
+        interpreter
+            .call_fn(&mut ctx, &ast, &locals, "missing", vec![])
+            .unwrap_err()
+            .to_string(),
+        "Undefined function 'missing'."
+    );
+    let error = interpreter
+        .call_fn(&mut ctx, &ast, &locals, "sqrt", vec![Val::Nil])
+        .unwrap_err();
+    assert!(error.token().is_none());
+    assert_eq!(
+        error.to_string(),
+        "Call to 'sqrt' failed: Expected argument x to be number but got nil."
+    );
+    assert_eq!(String::from_utf8(out).unwrap(), "Hello, host started\n");
+    assert!(err.is_empty());
+}
+
+#[test]
+fn calls_functions_of_earlier_programs() {
+    let mut interpreter = Interpreter::new();
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    let first = "fun get(x) { var y = x; { return y; } }";
+    let first_ast = unlox_parse::parse(Lexer::new(first)).ast;
+    let first_locals = unlox_resolver::try_resolve(first, &first_ast).unwrap();
+    let mut ctx = Ctx {
+        src: first,
+        out: SplitOutput::new(&mut out, &mut err),
+    };
+    interpreter.interpret(&mut ctx, &first_ast, &first_locals);
+    let second = "{ var a = 1; print a; }";
+    let second_ast = unlox_parse::parse(Lexer::new(second)).ast;
+    let second_locals = unlox_resolver::try_resolve(second, &second_ast).unwrap();
+    ctx.src = second;
+    interpreter.interpret(&mut ctx, &second_ast, &second_locals);
+    ctx.src = first;
+    let result = interpreter.call_fn(
+        &mut ctx,
+        &first_ast,
+        &first_locals,
+        "get",
+        vec![Val::Number(7.0)],
+    );
+    assert_eq!(result.unwrap(), Val::Number(7.0));
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n");
+    assert!(err.is_empty());
+}
+
+/// Appends everything written to it to a log shared with other writers.
+struct Recorder(Rc<RefCell<String>>);
+
+impl io::Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .borrow_mut()
+            .push_str(std::str::from_utf8(buf).unwrap());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_precedes_error() {
+    let code = "print 1; print -nil;";
+    let log = Rc::new(RefCell::new(String::new()));
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let locals = unlox_resolver::resolve(code, &ast, &mut io::sink()).unwrap();
+    let mut ctx = Ctx {
+        src: code,
+        out: SplitOutput::new(BufWriter::new(Recorder(log.clone())), Recorder(log.clone())),
+    };
+    Interpreter::new().interpret(&mut ctx, &ast, &locals);
+    assert_eq!(
+        *log.borrow(),
+        "1\n[Line 1:16]: Operand must be a number, got nil.\n"
+    );
+}
+
+#[test]
+fn operand_errors() {
+    assert_eq!(
+        interpret("print 1 + true;").1,
+        "[Line 1:9]: Right operand must be a number, got bool.\n"
+    );
+    assert_eq!(
+        interpret("print nil - 2;").1,
+        "[Line 1:11]: Left operand must be a number, got nil.\n"
+    );
+    assert_eq!(
+        interpret(r#"print "a" + nil;"#).1,
+        "[Line 1:11]: Right operand must be a string, got nil.\n"
+    );
+    assert_eq!(
+        interpret("print clock + 1;").1,
+        "[Line 1:13]: Left operand must be a number or a string, got function.\n"
+    );
+    assert_eq!(
+        interpret(r#"print -"a";"#).1,
+        "[Line 1:7]: Operand must be a number, got string.\n"
+    );
+}
+
+#[test]
+fn returns_errors_to_host() {
+    let code = "var a = 1;\nprint a + nil;";
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let locals = unlox_resolver::try_resolve(code, &ast).unwrap();
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    let mut ctx = Ctx {
+        src: code,
+        out: SplitOutput::new(&mut out, &mut err),
+    };
+    let error = Interpreter::new()
+        .try_interpret(&mut ctx, &ast, &locals)
+        .unwrap_err();
+    let token = error.token().unwrap();
+    assert_eq!((token.line, token.col), (2, 9));
+    assert_eq!(error.message(), "Right operand must be a number, got nil.");
+    assert!(err.is_empty());
+
+    let code = "{ var a = a; }";
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let errors = unlox_resolver::try_resolve(code, &ast).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!((errors[0].token.line, errors[0].token.col), (1, 11));
+}
+
+#[test]
+fn records_call_stack_of_errors() {
+    let error_frames = |code| {
+        let mut interpreter = Interpreter::new();
+        let (_, err) = interpret_with(&mut interpreter, code);
+        assert_eq!(
+            err,
+            "[Line 2:34]: Left operand must be a number or a string, got nil.\n"
+        );
+        interpreter
+            .error_call_stack()
+            .iter()
+            .map(|frame| (frame.name.clone(), frame.call_site_line))
+            .collect::<Vec<_>>()
+    };
+
+    // The tail call to `inner` takes the frame of `outer`.
+    let code = "
+        fun inner() { return nil + 1; }
+        fun outer() {
+            return inner();
+        }
+        outer();
+    ";
+    assert_eq!(error_frames(code), [("inner".to_owned(), 4)]);
+
+    let code = "
+        fun inner() { return nil + 1; }
+        fun outer() {
+            inner();
+        }
+        outer();
+    ";
+    assert_eq!(
+        error_frames(code),
+        [("outer".to_owned(), 6), ("inner".to_owned(), 4)]
+    );
+}
+
+#[test]
+fn inspects_variables() {
+    let mut interpreter = Interpreter::new();
+    interpret_with(&mut interpreter, "var a = 1; { var b = 2; } var c;");
+    let mut globals: Vec<_> = interpreter
+        .global_vars()
+        .filter(|(_, val)| !matches!(val, Val::Callable(_)))
+        .map(|(name, val)| (name.to_owned(), val.clone()))
+        .collect();
+    globals.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        globals,
+        [
+            ("a".to_owned(), Val::Number(1.0)),
+            ("c".to_owned(), Val::Uninitialized)
+        ]
+    );
+    assert_eq!(
+        interpreter.vars_at_depth(0).unwrap().count(),
+        interpreter.global_vars().count()
+    );
+    assert!(interpreter.vars_at_depth(1).is_none());
+}
+
+#[test]
+fn resets_globals() {
+    let mut interpreter = Interpreter::new();
+    interpreter.define_native("host", 0, |_, _| Ok(Val::Nil));
+    interpret_with(&mut interpreter, "var a = 1; clock = 2; var c = clock;");
+    interpreter.reset_globals();
+    assert_eq!(interpreter.get_global_var("a"), None);
+    assert_eq!(interpreter.get_global_var("c"), None);
+    assert!(matches!(
+        interpreter.get_global_var("clock"),
+        Some(Val::Callable(_))
+    ));
+    assert!(interpreter.get_global_var("host").is_some());
+}
+
+#[test]
+fn executes_roots_one_at_a_time() {
+    let code = "var a = 1; print a; a = a + 1; print a;";
+    let ast = unlox_parse::parse(Lexer::new(code)).ast;
+    let locals = unlox_resolver::try_resolve(code, &ast).unwrap();
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    let mut ctx = Ctx {
+        src: code,
+        out: SplitOutput::new(&mut out, &mut err),
+    };
+    let mut interpreter = Interpreter::new();
+    interpreter.start(&locals);
+    for stmt in &ast.roots()[..3] {
+        interpreter.try_execute_root(&mut ctx, &ast, *stmt).unwrap();
+    }
+    assert_eq!(interpreter.get_global_var("a"), Some(&Val::Number(2.0)));
+    interpreter
+        .try_execute_root(&mut ctx, &ast, ast.roots()[3])
+        .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n2\n");
 }
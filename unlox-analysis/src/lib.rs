@@ -0,0 +1,7 @@
+//! Static analyses of the syntax tree, answering questions about a program without running it.
+
+pub use purity::is_pure;
+pub use unused::find_unused_functions;
+
+mod purity;
+mod unused;
@@ -0,0 +1,76 @@
+use unlox_ast::{Ast, Expr, ExprIdx};
+
+/// Returns `true` if evaluating `expr` has no observable side effects, i.e. it calls no functions,
+/// which might print, and assigns no variables, properties or array elements.
+///
+/// Evaluating a pure expression may still raise a runtime error, e.g. `nil + 1`. Lambdas are pure,
+/// as their body isn't run by evaluating them.
+pub fn is_pure(expr: ExprIdx, ast: &Ast) -> bool {
+    // Operands are checked with an explicit stack, so that deeply nested expressions don't
+    // overflow the native one.
+    let mut pending = vec![expr];
+    while let Some(expr) = pending.pop() {
+        match ast.expr(expr) {
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Super { .. } | Expr::Lambda { .. } => (),
+            Expr::Grouping(operand)
+            | Expr::Unary(_, operand)
+            | Expr::Typeof(operand)
+            | Expr::Get {
+                object: operand, ..
+            } => pending.push(*operand),
+            Expr::Binary(_, left, right)
+            | Expr::Logical(_, left, right)
+            | Expr::Index {
+                object: left,
+                index: right,
+                ..
+            } => pending.extend([*left, *right]),
+            Expr::Ternary {
+                cond,
+                then_expr,
+                else_expr,
+            } => pending.extend([*cond, *then_expr, *else_expr]),
+            Expr::Array(elements) => pending.extend(elements),
+            Expr::Call { .. }
+            | Expr::Assign { .. }
+            | Expr::Set { .. }
+            | Expr::IndexAssign { .. } => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_lexer::Lexer;
+
+    fn pure(src: &str) -> bool {
+        let mut ast = Ast::new();
+        let expr = unlox_parse::parse_expr(Lexer::new(src), &mut ast).unwrap();
+        is_pure(expr, &ast)
+    }
+
+    #[test]
+    fn detects_side_effects() {
+        for src in [
+            "2 + 2",
+            "-x * (y - 1)",
+            "a and b ? [c, d[0]] : typeof e.f",
+            "fun () { print 1; }",
+        ] {
+            assert!(pure(src), "{src}");
+        }
+        for src in [
+            "f()",
+            "x = 1",
+            "1 + (x = 1)",
+            "a.b = 1",
+            "a[0] = 1",
+            "[1, f()]",
+            "c ? 1 : f()",
+        ] {
+            assert!(!pure(src), "{src}");
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+
+use unlox_ast::{Ast, AstVisitor, Param, Stmt, StmtIdx, Token};
+
+/// Functions that are always considered used, as the host may call them.
+const ENTRY_POINTS: &[&str] = &["main"];
+
+/// Returns the names of the functions declared in `ast`, parsed from `src`, that can't be called
+/// from top-level code, in the order they're declared. Functions only calling each other are
+/// unused too, unless one of them is called.
+///
+/// Functions are told apart by name only, so ones shadowing each other count as one. Referencing
+/// a function in any other way than calling it, e.g. passing it as an argument, makes it used.
+/// Methods are assumed to be used, along with the functions they reference.
+pub fn find_unused_functions(src: &str, ast: &Ast) -> Vec<Token> {
+    let mut graph = CallGraph {
+        src,
+        functions: Vec::new(),
+        references: HashMap::new(),
+        current: None,
+    };
+    for stmt in ast.roots() {
+        graph.visit_stmt(ast, *stmt);
+    }
+
+    let mut used: HashSet<&str> = ENTRY_POINTS.iter().copied().collect();
+    let mut pending: Vec<&str> = used.iter().copied().collect();
+    pending.extend(graph.references.get(&None).into_iter().flatten());
+    while let Some(name) = pending.pop() {
+        used.insert(name);
+        if let Some(references) = graph.references.get(&Some(name)) {
+            pending.extend(references.iter().filter(|name| !used.contains(*name)));
+        }
+    }
+    graph
+        .functions
+        .into_iter()
+        .filter(|name| !used.contains(name.source_text(src)))
+        .collect()
+}
+
+struct CallGraph<'a> {
+    src: &'a str,
+    /// Names of the declared functions, other than methods.
+    functions: Vec<Token>,
+    /// Names of variables referenced by each function, or by code running unconditionally, i.e.
+    /// top-level code and methods, under `None`.
+    references: HashMap<Option<&'a str>, HashSet<&'a str>>,
+    /// Function the visited code belongs to.
+    current: Option<&'a str>,
+}
+
+impl CallGraph<'_> {
+    fn visit_body(&mut self, ast: &Ast, body: &[StmtIdx]) {
+        for stmt in body {
+            self.visit_stmt(ast, *stmt);
+        }
+    }
+}
+
+impl AstVisitor for CallGraph<'_> {
+    type Output = ();
+
+    fn visit_function(&mut self, ast: &Ast, name: &Token, _params: &[Param], body: &[StmtIdx]) {
+        self.functions.push(name.clone());
+        let enclosing = self.current.replace(name.source_text(self.src));
+        self.visit_body(ast, body);
+        self.current = enclosing;
+    }
+
+    fn visit_class(
+        &mut self,
+        ast: &Ast,
+        _name: &Token,
+        _superclass: Option<&Token>,
+        methods: &[StmtIdx],
+    ) {
+        let enclosing = self.current.take();
+        for method in methods {
+            if let Stmt::Function { body, .. } = ast.stmt(*method) {
+                self.visit_body(ast, body);
+            }
+        }
+        self.current = enclosing;
+    }
+
+    fn visit_variable(&mut self, _ast: &Ast, name: &Token) {
+        self.references
+            .entry(self.current)
+            .or_default()
+            .insert(name.source_text(self.src));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_lexer::Lexer;
+
+    fn unused(src: &str) -> Vec<&str> {
+        let ast = unlox_parse::parse(Lexer::new(src)).ast;
+        find_unused_functions(src, &ast)
+            .iter()
+            .map(|name| name.source_text(src))
+            .collect()
+    }
+
+    #[test]
+    fn finds_unused_functions() {
+        let src = "
+            fun a() { return b(); }
+            fun b() { return a(); }
+            fun c() { fun nested() {} return d; }
+            fun d() { print clock(); }
+            fun main() { e(); }
+            fun e() {}
+            class K { m() { return f(); } }
+            fun f() {}
+            fun g() {}
+            print c();
+        ";
+        assert_eq!(unused(src), ["a", "b", "nested", "g"]);
+    }
+}
@@ -0,0 +1,98 @@
+//! Serialization of the tree.
+//!
+//! Indices are meaningless outside of the tree they point into, so nodes are serialized with the
+//! nodes they point to nested inside, rather than as the flat vectors they're stored in. The tree
+//! being serialized or deserialized is kept in [`TREE`], where indices look up or push their
+//! nodes.
+
+use std::cell::RefCell;
+
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Ast, Expr, ExprIdx, Stmt, StmtIdx};
+
+thread_local! {
+    static TREE: RefCell<Option<Ast>> = const { RefCell::new(None) };
+}
+
+impl Ast {
+    /// Serializes the tree to JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Tree should always be serializable")
+    }
+
+    /// Deserializes a tree serialized with [`Ast::to_json`].
+    pub fn from_json(s: &str) -> Result<Ast, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Runs `f` with `tree` in [`TREE`], returning the result and the tree left there.
+fn with_tree<T>(tree: Ast, f: impl FnOnce() -> T) -> (T, Ast) {
+    // Restoring the outer tree keeps trees nested in other data working.
+    let outer = TREE.with(|t| t.replace(Some(tree)));
+    let result = f();
+    let tree = TREE.with(|t| t.replace(outer)).unwrap();
+    (result, tree)
+}
+
+impl Serialize for Ast {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        with_tree(self.clone(), || self.roots.serialize(serializer)).0
+    }
+}
+
+impl<'de> Deserialize<'de> for Ast {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (roots, mut ast) = with_tree(Ast::new(), || Vec::<StmtIdx>::deserialize(deserializer));
+        ast.roots = roots?;
+        Ok(ast)
+    }
+}
+
+impl Serialize for StmtIdx {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TREE.with(|tree| match &*tree.borrow() {
+            Some(tree) => tree.stmt(*self).serialize(serializer),
+            None => Err(ser::Error::custom(
+                "Statements can only be serialized within a tree",
+            )),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for StmtIdx {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Children are pushed while deserializing the statement, before the statement itself.
+        let stmt = Stmt::deserialize(deserializer)?;
+        TREE.with(|tree| match &mut *tree.borrow_mut() {
+            Some(tree) => Ok(tree.push_stmt(stmt)),
+            None => Err(de::Error::custom(
+                "Statements can only be deserialized within a tree",
+            )),
+        })
+    }
+}
+
+impl Serialize for ExprIdx {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TREE.with(|tree| match &*tree.borrow() {
+            Some(tree) => tree.expr(*self).serialize(serializer),
+            None => Err(ser::Error::custom(
+                "Expressions can only be serialized within a tree",
+            )),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ExprIdx {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expr = Expr::deserialize(deserializer)?;
+        TREE.with(|tree| match &mut *tree.borrow_mut() {
+            Some(tree) => Ok(tree.push_expr(expr)),
+            None => Err(de::Error::custom(
+                "Expressions can only be deserialized within a tree",
+            )),
+        })
+    }
+}
@@ -1,6 +1,16 @@
-use std::fmt::{self, Display};
+pub use printer::PrettyPrinter;
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+};
 pub use tokens::{Token, TokenKind};
 pub use unlox_tokens as tokens;
+pub use visit::{walk_expr_mut, walk_stmt_mut, AstVisitor, AstVisitorMut};
+
+#[cfg(feature = "serde")]
+mod json;
+mod printer;
+mod visit;
 
 #[derive(Debug, Default, Clone)]
 pub struct Ast {
@@ -30,7 +40,7 @@ impl Ast {
         &self.stmts[idx.0]
     }
 
-    pub fn stmt_mut(&mut self, idx: ExprIdx) -> &mut Stmt {
+    pub fn stmt_mut(&mut self, idx: StmtIdx) -> &mut Stmt {
         &mut self.stmts[idx.0]
     }
 
@@ -51,9 +61,150 @@ impl Ast {
     pub fn roots(&self) -> &[StmtIdx] {
         &self.roots
     }
+
+    /// Iterates over all statements in the order they were pushed, including unreachable ones.
+    pub fn walk_stmts(&self) -> impl Iterator<Item = (StmtIdx, &Stmt)> {
+        self.stmts
+            .iter()
+            .enumerate()
+            .map(|(idx, stmt)| (StmtIdx(idx), stmt))
+    }
+
+    /// Iterates over all expressions in the order they were pushed, including unreachable ones.
+    pub fn walk_exprs(&self) -> impl Iterator<Item = (ExprIdx, &Expr)> {
+        self.exprs
+            .iter()
+            .enumerate()
+            .map(|(idx, expr)| (ExprIdx(idx), expr))
+    }
+
+    pub fn stmt_count(&self) -> usize {
+        self.stmts.len()
+    }
+
+    pub fn expr_count(&self) -> usize {
+        self.exprs.len()
+    }
+
+    /// Returns `true` if any statement of the tree failed to parse.
+    pub fn has_parse_errors(&self) -> bool {
+        self.stmts
+            .iter()
+            .any(|stmt| matches!(stmt, Stmt::ParseErr(..)))
+    }
+
+    /// Removes statements and expressions that aren't reachable from the roots, renumbering
+    /// the remaining ones while preserving their relative order.
+    ///
+    /// Indices obtained before compaction are invalidated.
+    pub fn compact(&mut self) {
+        let mut live_stmts = vec![false; self.stmts.len()];
+        let mut live_exprs = vec![false; self.exprs.len()];
+        let mut stmt_stack = self.roots.clone();
+        let mut expr_stack = vec![];
+        while !stmt_stack.is_empty() || !expr_stack.is_empty() {
+            if let Some(idx) = stmt_stack.pop() {
+                if !std::mem::replace(&mut live_stmts[idx.0], true) {
+                    self.stmts[idx.0].children(|s| stmt_stack.push(s), |e| expr_stack.push(e));
+                }
+            }
+            if let Some(idx) = expr_stack.pop() {
+                if !std::mem::replace(&mut live_exprs[idx.0], true) {
+                    self.exprs[idx.0].children(|s| stmt_stack.push(s), |e| expr_stack.push(e));
+                }
+            }
+        }
+
+        let stmt_map = renumber(&live_stmts);
+        let expr_map = renumber(&live_exprs);
+        let remap_stmt = |idx: &mut StmtIdx| idx.0 = stmt_map[idx.0];
+        let remap_expr = |idx: &mut ExprIdx| idx.0 = expr_map[idx.0];
+        self.stmts = std::mem::take(&mut self.stmts)
+            .into_iter()
+            .zip(&live_stmts)
+            .filter(|(_, live)| **live)
+            .map(|(mut stmt, _)| {
+                stmt.children_mut(remap_stmt, remap_expr);
+                stmt
+            })
+            .collect();
+        self.exprs = std::mem::take(&mut self.exprs)
+            .into_iter()
+            .zip(&live_exprs)
+            .filter(|(_, live)| **live)
+            .map(|(mut expr, _)| {
+                expr.children_mut(remap_stmt, remap_expr);
+                expr
+            })
+            .collect();
+        self.roots.iter_mut().for_each(remap_stmt);
+    }
+
+    /// Appends the nodes of `other` to this tree and its roots after the existing ones.
+    ///
+    /// Indices into `other` can be translated with the returned mapping. Tokens still refer to
+    /// the source `other` was parsed from.
+    pub fn merge(&mut self, other: Ast) -> AstMapping {
+        let mapping = AstMapping {
+            stmt_offset: self.stmts.len(),
+            expr_offset: self.exprs.len(),
+            roots: self.roots.len()..self.roots.len() + other.roots.len(),
+        };
+        let remap_stmt = |idx: &mut StmtIdx| *idx = mapping.stmt(*idx);
+        let remap_expr = |idx: &mut ExprIdx| *idx = mapping.expr(*idx);
+        self.stmts.extend(other.stmts.into_iter().map(|mut stmt| {
+            stmt.children_mut(remap_stmt, remap_expr);
+            stmt
+        }));
+        self.exprs.extend(other.exprs.into_iter().map(|mut expr| {
+            expr.children_mut(remap_stmt, remap_expr);
+            expr
+        }));
+        self.roots
+            .extend(other.roots.into_iter().map(|idx| mapping.stmt(idx)));
+        mapping
+    }
+}
+
+/// Positions of nodes of an [`Ast`] merged into another one with [`Ast::merge`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AstMapping {
+    stmt_offset: usize,
+    expr_offset: usize,
+    roots: Range<usize>,
+}
+
+impl AstMapping {
+    /// Returns the index a statement of the merged tree has in the tree it was merged into.
+    pub fn stmt(&self, idx: StmtIdx) -> StmtIdx {
+        StmtIdx(idx.0 + self.stmt_offset)
+    }
+
+    /// Returns the index an expression of the merged tree has in the tree it was merged into.
+    pub fn expr(&self, idx: ExprIdx) -> ExprIdx {
+        ExprIdx(idx.0 + self.expr_offset)
+    }
+
+    /// Positions of the roots of the merged tree in [`Ast::roots`], e.g. to tell apart the
+    /// statements of different files.
+    pub fn root_range(&self) -> Range<usize> {
+        self.roots.clone()
+    }
+}
+
+/// Maps old positions of live nodes to their positions after removing the dead ones.
+fn renumber(live: &[bool]) -> Vec<usize> {
+    live.iter()
+        .scan(0, |next, live| {
+            let idx = *next;
+            *next += usize::from(*live);
+            Some(idx)
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Stmt {
     If {
         cond: ExprIdx,
@@ -63,9 +214,27 @@ pub enum Stmt {
     While {
         cond: ExprIdx,
         body: StmtIdx,
+        /// Increment of a `for` loop, evaluated after each iteration, including ones cut short by
+        /// `continue`.
+        inc: Option<ExprIdx>,
+    },
+    /// Loop checking the condition after each iteration, `do { ... } while (cond);`.
+    DoWhile {
+        body: StmtIdx,
+        cond: ExprIdx,
+    },
+    /// Loop over the elements of an array, `for (name in iterable) body`. The variable is scoped
+    /// to the body, with a fresh one for each iteration.
+    ForIn {
+        name: Token,
+        iterable: ExprIdx,
+        body: StmtIdx,
     },
     Print(ExprIdx),
+    /// Only appears within function bodies, the parser rejects `return` in top-level code.
     Return(Token, Option<ExprIdx>),
+    Break(Token),
+    Continue(Token),
     VarDecl {
         name: Token,
         init: Option<ExprIdx>,
@@ -74,38 +243,286 @@ pub enum Stmt {
     Block(Vec<StmtIdx>),
     Function {
         name: Token,
-        params: Vec<Token>,
+        params: Vec<Param>,
         body: Vec<StmtIdx>,
     },
+    Class {
+        name: Token,
+        superclass: Option<Token>,
+        /// `Stmt::Function` declarations of the methods.
+        methods: Vec<StmtIdx>,
+    },
     ParseErr(Token, String),
 }
 
+/// Function parameter.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Param {
+    pub name: Token,
+    /// Optional type annotation, not enforced by default.
+    pub ty: Option<Token>,
+}
+
+impl Stmt {
+    /// Calls `on_stmt` and `on_expr` with indices of the direct children of the statement.
+    fn children(&self, mut on_stmt: impl FnMut(StmtIdx), mut on_expr: impl FnMut(ExprIdx)) {
+        match self {
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                on_expr(*cond);
+                on_stmt(*then_branch);
+                else_branch.iter().copied().for_each(on_stmt);
+            }
+            Stmt::While { cond, body, inc } => {
+                on_expr(*cond);
+                on_stmt(*body);
+                inc.iter().copied().for_each(on_expr);
+            }
+            Stmt::DoWhile { body, cond } => {
+                on_stmt(*body);
+                on_expr(*cond);
+            }
+            Stmt::ForIn { iterable, body, .. } => {
+                on_expr(*iterable);
+                on_stmt(*body);
+            }
+            Stmt::Print(expr) | Stmt::Expression(expr) => on_expr(*expr),
+            Stmt::Return(_, expr) | Stmt::VarDecl { init: expr, .. } => {
+                expr.iter().copied().for_each(on_expr)
+            }
+            Stmt::Block(stmts)
+            | Stmt::Function { body: stmts, .. }
+            | Stmt::Class { methods: stmts, .. } => stmts.iter().copied().for_each(on_stmt),
+            Stmt::Break(_) | Stmt::Continue(_) | Stmt::ParseErr(..) => (),
+        }
+    }
+
+    /// Mutable counterpart of [`Stmt::children`].
+    fn children_mut(
+        &mut self,
+        mut on_stmt: impl FnMut(&mut StmtIdx),
+        mut on_expr: impl FnMut(&mut ExprIdx),
+    ) {
+        match self {
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                on_expr(cond);
+                on_stmt(then_branch);
+                else_branch.iter_mut().for_each(on_stmt);
+            }
+            Stmt::While { cond, body, inc } => {
+                on_expr(cond);
+                on_stmt(body);
+                inc.iter_mut().for_each(on_expr);
+            }
+            Stmt::DoWhile { body, cond } => {
+                on_stmt(body);
+                on_expr(cond);
+            }
+            Stmt::ForIn { iterable, body, .. } => {
+                on_expr(iterable);
+                on_stmt(body);
+            }
+            Stmt::Print(expr) | Stmt::Expression(expr) => on_expr(expr),
+            Stmt::Return(_, expr) | Stmt::VarDecl { init: expr, .. } => {
+                expr.iter_mut().for_each(on_expr)
+            }
+            Stmt::Block(stmts)
+            | Stmt::Function { body: stmts, .. }
+            | Stmt::Class { methods: stmts, .. } => stmts.iter_mut().for_each(on_stmt),
+            Stmt::Break(_) | Stmt::Continue(_) | Stmt::ParseErr(..) => (),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct StmtIdx(usize);
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expr {
     Binary(Token, ExprIdx, ExprIdx),
     Grouping(ExprIdx),
     Literal(Lit),
     Unary(Token, ExprIdx),
+    /// Runtime type name of the operand, `typeof value`.
+    Typeof(ExprIdx),
     Variable(Token),
     Assign {
         var: Token,
         value: ExprIdx,
     },
     Logical(Token, ExprIdx, ExprIdx),
+    Ternary {
+        cond: ExprIdx,
+        then_expr: ExprIdx,
+        else_expr: ExprIdx,
+    },
     Call {
         callee: ExprIdx,
         paren: Token,
         args: Vec<ExprIdx>,
     },
+    /// Property access.
+    Get {
+        object: ExprIdx,
+        name: Token,
+    },
+    /// Superclass method access, `super.method`.
+    Super {
+        keyword: Token,
+        method: Token,
+    },
+    /// Property assignment.
+    Set {
+        object: ExprIdx,
+        name: Token,
+        value: ExprIdx,
+    },
+    /// Anonymous function.
+    Lambda {
+        keyword: Token,
+        params: Vec<Param>,
+        body: Vec<StmtIdx>,
+    },
+    /// Array literal, `[a, b]`.
+    Array(Vec<ExprIdx>),
+    /// Element access, `object[index]`.
+    Index {
+        object: ExprIdx,
+        bracket: Token,
+        index: ExprIdx,
+    },
+    /// Element assignment, `object[index] = value`.
+    IndexAssign {
+        object: ExprIdx,
+        bracket: Token,
+        index: ExprIdx,
+        value: ExprIdx,
+    },
+}
+
+impl Expr {
+    /// Calls `on_stmt` and `on_expr` with indices of the direct children of the expression.
+    fn children(&self, on_stmt: impl FnMut(StmtIdx), mut on_expr: impl FnMut(ExprIdx)) {
+        match self {
+            Expr::Binary(_, left, right)
+            | Expr::Logical(_, left, right)
+            | Expr::Set {
+                object: left,
+                value: right,
+                ..
+            }
+            | Expr::Index {
+                object: left,
+                index: right,
+                ..
+            } => {
+                on_expr(*left);
+                on_expr(*right);
+            }
+            Expr::Grouping(expr)
+            | Expr::Unary(_, expr)
+            | Expr::Typeof(expr)
+            | Expr::Assign { value: expr, .. }
+            | Expr::Get { object: expr, .. } => on_expr(*expr),
+            Expr::Ternary {
+                cond: first,
+                then_expr: second,
+                else_expr: third,
+            }
+            | Expr::IndexAssign {
+                object: first,
+                index: second,
+                value: third,
+                ..
+            } => {
+                on_expr(*first);
+                on_expr(*second);
+                on_expr(*third);
+            }
+            Expr::Call { callee, args, .. } => {
+                on_expr(*callee);
+                args.iter().copied().for_each(on_expr);
+            }
+            Expr::Lambda { body, .. } => body.iter().copied().for_each(on_stmt),
+            Expr::Array(elements) => elements.iter().copied().for_each(on_expr),
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Super { .. } => (),
+        }
+    }
+
+    /// Mutable counterpart of [`Expr::children`].
+    fn children_mut(
+        &mut self,
+        on_stmt: impl FnMut(&mut StmtIdx),
+        mut on_expr: impl FnMut(&mut ExprIdx),
+    ) {
+        match self {
+            Expr::Binary(_, left, right)
+            | Expr::Logical(_, left, right)
+            | Expr::Set {
+                object: left,
+                value: right,
+                ..
+            }
+            | Expr::Index {
+                object: left,
+                index: right,
+                ..
+            } => {
+                on_expr(left);
+                on_expr(right);
+            }
+            Expr::Grouping(expr)
+            | Expr::Unary(_, expr)
+            | Expr::Typeof(expr)
+            | Expr::Assign { value: expr, .. }
+            | Expr::Get { object: expr, .. } => on_expr(expr),
+            Expr::Ternary {
+                cond: first,
+                then_expr: second,
+                else_expr: third,
+            }
+            | Expr::IndexAssign {
+                object: first,
+                index: second,
+                value: third,
+                ..
+            } => {
+                on_expr(first);
+                on_expr(second);
+                on_expr(third);
+            }
+            Expr::Call { callee, args, .. } => {
+                on_expr(callee);
+                args.iter_mut().for_each(on_expr);
+            }
+            Expr::Lambda { body, .. } => body.iter_mut().for_each(on_stmt),
+            Expr::Array(elements) => elements.iter_mut().for_each(on_expr),
+            Expr::Literal(_) | Expr::Variable(_) | Expr::Super { .. } => (),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ExprIdx(usize);
 
+impl ExprIdx {
+    /// Position of the expression in the tree, usable for indexing side tables.
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Lit {
     String(String),
     Number(f64),
@@ -123,9 +540,133 @@ impl Display for Lit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Lit::String(s) => write!(f, "{s}"),
-            Lit::Number(n) => write!(f, "{n}"),
+            Lit::Number(n) => write!(f, "{}", display_number(*n)),
             Lit::Bool(b) => write!(f, "{b}"),
             Lit::Nil => write!(f, "nil"),
         }
     }
 }
+
+/// Formats a number the way Lox prints it.
+///
+/// Integral numbers are printed without a fractional part or an exponent, however large they
+/// are. Infinities and NaN are spelled like in the reference implementation.
+pub fn display_number(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_owned()
+    } else if n.is_infinite() {
+        let sign = if n < 0.0 { "-" } else { "" };
+        format!("{sign}Infinity")
+    } else if n.fract() == 0.0 {
+        format!("{n:.0}")
+    } else {
+        format!("{n}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compacts_unreachable_nodes() {
+        // print (1 + 2);
+        let mut ast = Ast::new();
+        let one = ast.push_expr(Expr::Literal(Lit::Number(1.0)));
+        let two = ast.push_expr(Expr::Literal(Lit::Number(2.0)));
+        let plus = Token {
+            kind: TokenKind::Plus,
+            ..Default::default()
+        };
+        let sum = ast.push_expr(Expr::Binary(plus, one, two));
+        let grouping = ast.push_expr(Expr::Grouping(sum));
+        let print = ast.push_root_stmt(Stmt::Print(grouping));
+
+        // Fold the sum, leaving both operands unreachable.
+        *ast.expr_mut(sum) = Expr::Literal(Lit::Number(3.0));
+        ast.compact();
+
+        assert_eq!(ast.stmt_count(), 1);
+        assert_eq!(ast.expr_count(), 2);
+        let Stmt::Print(grouping) = ast.stmt(ast.roots()[0]) else {
+            panic!("Expected print statement");
+        };
+        let Expr::Grouping(sum) = ast.expr(*grouping) else {
+            panic!("Expected grouping");
+        };
+        assert!(matches!(ast.expr(*sum), Expr::Literal(Lit::Number(3.0))));
+        assert_eq!(ast.roots(), [print]);
+    }
+
+    #[test]
+    fn walks_nodes_in_insertion_order() {
+        let mut ast = Ast::new();
+        let one = ast.push_expr(Expr::Literal(Lit::Number(1.0)));
+        let nil = ast.push_expr(Expr::Literal(Lit::Nil));
+        let print = ast.push_root_stmt(Stmt::Print(one));
+        let indices: Vec<_> = ast.walk_exprs().map(|(idx, _)| idx).collect();
+        assert_eq!(indices, [one, nil]);
+        let stmts: Vec<_> = ast.walk_stmts().collect();
+        assert!(matches!(stmts[..], [(idx, Stmt::Print(expr))] if idx == print && *expr == one));
+    }
+
+    #[test]
+    fn merges_trees() {
+        let mut first = Ast::new();
+        let one = first.push_expr(Expr::Literal(Lit::Number(1.0)));
+        first.push_root_stmt(Stmt::Print(one));
+
+        // { print (2); }
+        let mut second = Ast::new();
+        let two = second.push_expr(Expr::Literal(Lit::Number(2.0)));
+        let grouping = second.push_expr(Expr::Grouping(two));
+        let print = second.push_stmt(Stmt::Print(grouping));
+        let block = second.push_root_stmt(Stmt::Block(vec![print]));
+
+        let mapping = first.merge(second);
+        assert_eq!(mapping.root_range(), 1..2);
+        assert_eq!(first.roots()[1], mapping.stmt(block));
+        let Stmt::Block(stmts) = first.stmt(mapping.stmt(block)) else {
+            panic!("Expected block");
+        };
+        assert_eq!(stmts[..], [mapping.stmt(print)]);
+        let Stmt::Print(expr) = first.stmt(stmts[0]) else {
+            panic!("Expected print statement");
+        };
+        assert_eq!(*expr, mapping.expr(grouping));
+        assert!(matches!(first.expr(*expr), Expr::Grouping(inner) if *inner == mapping.expr(two)));
+        assert!(matches!(first.expr(one), Expr::Literal(Lit::Number(1.0))));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_through_json() {
+        let src = "{ print 1 + 2; }";
+        let mut ast = Ast::new();
+        let one = ast.push_expr(Expr::Literal(Lit::Number(1.0)));
+        let two = ast.push_expr(Expr::Literal(Lit::Number(2.0)));
+        let plus = Token {
+            kind: TokenKind::Plus,
+            lexeme: 10..11,
+            line: 1,
+            col: 11,
+        };
+        let sum = ast.push_expr(Expr::Binary(plus, one, two));
+        let print = ast.push_stmt(Stmt::Print(sum));
+        ast.push_root_stmt(Stmt::Block(vec![print]));
+
+        let json = ast.to_json();
+        // Children are nested in their parents.
+        assert_eq!(
+            json,
+            r#"[{"Block":[{"Print":{"Binary":[{"kind":"Plus","lexeme":{"start":10,"end":11},"line":1,"col":11},{"Literal":{"Number":1.0}},{"Literal":{"Number":2.0}}]}}]}]"#
+        );
+        let deserialized = Ast::from_json(&json).unwrap();
+        assert_eq!(
+            PrettyPrinter::new(src).print(&deserialized),
+            PrettyPrinter::new(src).print(&ast)
+        );
+        assert_eq!(deserialized.to_json(), json);
+        assert!(Ast::from_json(r#"[{"Print":0}]"#).is_err());
+    }
+}
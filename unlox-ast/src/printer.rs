@@ -0,0 +1,320 @@
+use crate::{Ast, AstVisitor, ExprIdx, Lit, Param, StmtIdx, Token, TokenKind};
+
+/// Prints the tree as parenthesized S-expressions, one line per root statement.
+pub struct PrettyPrinter<'src> {
+    /// Source the tree was parsed from, holding the lexemes of tokens.
+    src: &'src str,
+}
+
+impl<'src> PrettyPrinter<'src> {
+    pub fn new(src: &'src str) -> Self {
+        Self { src }
+    }
+
+    pub fn print(&mut self, ast: &Ast) -> String {
+        let lines: Vec<_> = ast
+            .roots()
+            .iter()
+            .map(|stmt| self.visit_stmt(ast, *stmt))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn lexeme(&self, token: &Token) -> &'src str {
+        token.source_text(self.src)
+    }
+
+    /// Returns the symbol of a binary operator.
+    fn operator(&self, token: &Token) -> &'src str {
+        // Operators of desugared compound assignments keep the lexeme of the assignment.
+        match token.kind {
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            _ => self.lexeme(token),
+        }
+    }
+
+    fn params(&self, params: &[Param]) -> String {
+        let params: Vec<_> = params
+            .iter()
+            .map(|param| match &param.ty {
+                Some(ty) => format!("{}:{}", self.lexeme(&param.name), self.lexeme(ty)),
+                None => self.lexeme(&param.name).to_owned(),
+            })
+            .collect();
+        format!("({})", params.join(" "))
+    }
+
+    fn stmts(&mut self, ast: &Ast, stmts: &[StmtIdx]) -> Vec<String> {
+        stmts
+            .iter()
+            .map(|stmt| self.visit_stmt(ast, *stmt))
+            .collect()
+    }
+}
+
+fn parenthesize(name: &str, parts: impl IntoIterator<Item = String>) -> String {
+    let mut out = format!("({name}");
+    for part in parts {
+        out.push(' ');
+        out.push_str(&part);
+    }
+    out.push(')');
+    out
+}
+
+impl AstVisitor for PrettyPrinter<'_> {
+    type Output = String;
+
+    fn visit_if(
+        &mut self,
+        ast: &Ast,
+        cond: ExprIdx,
+        then_branch: StmtIdx,
+        else_branch: Option<StmtIdx>,
+    ) -> String {
+        let mut parts = vec![
+            self.visit_expr(ast, cond),
+            self.visit_stmt(ast, then_branch),
+        ];
+        parts.extend(else_branch.map(|stmt| self.visit_stmt(ast, stmt)));
+        parenthesize("if", parts)
+    }
+
+    fn visit_while(
+        &mut self,
+        ast: &Ast,
+        cond: ExprIdx,
+        body: StmtIdx,
+        inc: Option<ExprIdx>,
+    ) -> String {
+        let mut parts = vec![self.visit_expr(ast, cond), self.visit_stmt(ast, body)];
+        parts.extend(inc.map(|expr| self.visit_expr(ast, expr)));
+        parenthesize("while", parts)
+    }
+
+    fn visit_do_while(&mut self, ast: &Ast, body: StmtIdx, cond: ExprIdx) -> String {
+        parenthesize(
+            "do-while",
+            [self.visit_stmt(ast, body), self.visit_expr(ast, cond)],
+        )
+    }
+
+    fn visit_for_in(
+        &mut self,
+        ast: &Ast,
+        name: &Token,
+        iterable: ExprIdx,
+        body: StmtIdx,
+    ) -> String {
+        let parts = [
+            self.lexeme(name).to_owned(),
+            self.visit_expr(ast, iterable),
+            self.visit_stmt(ast, body),
+        ];
+        parenthesize("for-in", parts)
+    }
+
+    fn visit_print(&mut self, ast: &Ast, expr: ExprIdx) -> String {
+        parenthesize("print", [self.visit_expr(ast, expr)])
+    }
+
+    fn visit_return(&mut self, ast: &Ast, _keyword: &Token, value: Option<ExprIdx>) -> String {
+        parenthesize("return", value.map(|expr| self.visit_expr(ast, expr)))
+    }
+
+    fn visit_break(&mut self, _ast: &Ast, _keyword: &Token) -> String {
+        parenthesize("break", [])
+    }
+
+    fn visit_continue(&mut self, _ast: &Ast, _keyword: &Token) -> String {
+        parenthesize("continue", [])
+    }
+
+    fn visit_var_decl(&mut self, ast: &Ast, name: &Token, init: Option<ExprIdx>) -> String {
+        let mut parts = vec![self.lexeme(name).to_owned()];
+        parts.extend(init.map(|expr| self.visit_expr(ast, expr)));
+        parenthesize("var", parts)
+    }
+
+    fn visit_expression(&mut self, ast: &Ast, expr: ExprIdx) -> String {
+        parenthesize(";", [self.visit_expr(ast, expr)])
+    }
+
+    fn visit_block(&mut self, ast: &Ast, stmts: &[StmtIdx]) -> String {
+        parenthesize("block", self.stmts(ast, stmts))
+    }
+
+    fn visit_function(
+        &mut self,
+        ast: &Ast,
+        name: &Token,
+        params: &[Param],
+        body: &[StmtIdx],
+    ) -> String {
+        let mut parts = vec![self.lexeme(name).to_owned(), self.params(params)];
+        parts.extend(self.stmts(ast, body));
+        parenthesize("fun", parts)
+    }
+
+    fn visit_class(
+        &mut self,
+        ast: &Ast,
+        name: &Token,
+        superclass: Option<&Token>,
+        methods: &[StmtIdx],
+    ) -> String {
+        let mut parts = vec![self.lexeme(name).to_owned()];
+        if let Some(superclass) = superclass {
+            parts.push(format!("(extends {})", self.lexeme(superclass)));
+        }
+        parts.extend(self.stmts(ast, methods));
+        parenthesize("class", parts)
+    }
+
+    fn visit_parse_err(&mut self, _ast: &Ast, _token: &Token, message: &str) -> String {
+        parenthesize("error", [format!("{message:?}")])
+    }
+
+    fn visit_binary(
+        &mut self,
+        ast: &Ast,
+        operator: &Token,
+        left: ExprIdx,
+        right: ExprIdx,
+    ) -> String {
+        let parts = [self.visit_expr(ast, left), self.visit_expr(ast, right)];
+        parenthesize(self.operator(operator), parts)
+    }
+
+    fn visit_grouping(&mut self, ast: &Ast, inner: ExprIdx) -> String {
+        parenthesize("group", [self.visit_expr(ast, inner)])
+    }
+
+    fn visit_literal(&mut self, _ast: &Ast, lit: &Lit) -> String {
+        match lit {
+            Lit::String(s) => format!("{s:?}"),
+            lit => lit.to_string(),
+        }
+    }
+
+    fn visit_unary(&mut self, ast: &Ast, operator: &Token, right: ExprIdx) -> String {
+        parenthesize(self.lexeme(operator), [self.visit_expr(ast, right)])
+    }
+
+    fn visit_typeof(&mut self, ast: &Ast, operand: ExprIdx) -> String {
+        parenthesize("typeof", [self.visit_expr(ast, operand)])
+    }
+
+    fn visit_variable(&mut self, _ast: &Ast, name: &Token) -> String {
+        self.lexeme(name).to_owned()
+    }
+
+    fn visit_assign(&mut self, ast: &Ast, var: &Token, value: ExprIdx) -> String {
+        let parts = [self.lexeme(var).to_owned(), self.visit_expr(ast, value)];
+        parenthesize("=", parts)
+    }
+
+    fn visit_logical(
+        &mut self,
+        ast: &Ast,
+        operator: &Token,
+        left: ExprIdx,
+        right: ExprIdx,
+    ) -> String {
+        let parts = [self.visit_expr(ast, left), self.visit_expr(ast, right)];
+        parenthesize(self.lexeme(operator), parts)
+    }
+
+    fn visit_ternary(
+        &mut self,
+        ast: &Ast,
+        cond: ExprIdx,
+        then_expr: ExprIdx,
+        else_expr: ExprIdx,
+    ) -> String {
+        let parts = [
+            self.visit_expr(ast, cond),
+            self.visit_expr(ast, then_expr),
+            self.visit_expr(ast, else_expr),
+        ];
+        parenthesize("?:", parts)
+    }
+
+    fn visit_call(
+        &mut self,
+        ast: &Ast,
+        callee: ExprIdx,
+        _paren: &Token,
+        args: &[ExprIdx],
+    ) -> String {
+        let mut parts = vec![self.visit_expr(ast, callee)];
+        parts.extend(args.iter().map(|arg| self.visit_expr(ast, *arg)));
+        parenthesize("call", parts)
+    }
+
+    fn visit_get(&mut self, ast: &Ast, object: ExprIdx, name: &Token) -> String {
+        let parts = [self.visit_expr(ast, object), self.lexeme(name).to_owned()];
+        parenthesize(".", parts)
+    }
+
+    fn visit_super(&mut self, _ast: &Ast, _keyword: &Token, method: &Token) -> String {
+        parenthesize("super", [self.lexeme(method).to_owned()])
+    }
+
+    fn visit_set(&mut self, ast: &Ast, object: ExprIdx, name: &Token, value: ExprIdx) -> String {
+        let target = parenthesize(
+            ".",
+            [self.visit_expr(ast, object), self.lexeme(name).to_owned()],
+        );
+        parenthesize("=", [target, self.visit_expr(ast, value)])
+    }
+
+    fn visit_lambda(
+        &mut self,
+        ast: &Ast,
+        _keyword: &Token,
+        params: &[Param],
+        body: &[StmtIdx],
+    ) -> String {
+        let mut parts = vec![self.params(params)];
+        parts.extend(self.stmts(ast, body));
+        parenthesize("fun", parts)
+    }
+
+    fn visit_array(&mut self, ast: &Ast, elements: &[ExprIdx]) -> String {
+        let parts: Vec<_> = elements
+            .iter()
+            .map(|element| self.visit_expr(ast, *element))
+            .collect();
+        parenthesize("array", parts)
+    }
+
+    fn visit_index(
+        &mut self,
+        ast: &Ast,
+        object: ExprIdx,
+        _bracket: &Token,
+        index: ExprIdx,
+    ) -> String {
+        let parts = [self.visit_expr(ast, object), self.visit_expr(ast, index)];
+        parenthesize("[]", parts)
+    }
+
+    fn visit_index_assign(
+        &mut self,
+        ast: &Ast,
+        object: ExprIdx,
+        _bracket: &Token,
+        index: ExprIdx,
+        value: ExprIdx,
+    ) -> String {
+        let target = parenthesize(
+            "[]",
+            [self.visit_expr(ast, object), self.visit_expr(ast, index)],
+        );
+        parenthesize("=", [target, self.visit_expr(ast, value)])
+    }
+}
@@ -0,0 +1,651 @@
+use std::cell::RefCell;
+
+use crate::{Ast, Expr, ExprIdx, Lit, Param, Stmt, StmtIdx, Token};
+
+/// Visitor of the tree, with one method per statement and expression variant.
+///
+/// Default implementations visit the children of the node and return `Output::default()`, so
+/// implementors only need to override the nodes they care about.
+pub trait AstVisitor {
+    type Output: Default;
+
+    fn visit_stmt(&mut self, ast: &Ast, stmt: StmtIdx) -> Self::Output {
+        match ast.stmt(stmt) {
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => self.visit_if(ast, *cond, *then_branch, *else_branch),
+            Stmt::While { cond, body, inc } => self.visit_while(ast, *cond, *body, *inc),
+            Stmt::DoWhile { body, cond } => self.visit_do_while(ast, *body, *cond),
+            Stmt::ForIn {
+                name,
+                iterable,
+                body,
+            } => self.visit_for_in(ast, name, *iterable, *body),
+            Stmt::Print(expr) => self.visit_print(ast, *expr),
+            Stmt::Return(keyword, value) => self.visit_return(ast, keyword, *value),
+            Stmt::Break(keyword) => self.visit_break(ast, keyword),
+            Stmt::Continue(keyword) => self.visit_continue(ast, keyword),
+            Stmt::VarDecl { name, init } => self.visit_var_decl(ast, name, *init),
+            Stmt::Expression(expr) => self.visit_expression(ast, *expr),
+            Stmt::Block(stmts) => self.visit_block(ast, stmts),
+            Stmt::Function { name, params, body } => self.visit_function(ast, name, params, body),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.visit_class(ast, name, superclass.as_ref(), methods),
+            Stmt::ParseErr(token, message) => self.visit_parse_err(ast, token, message),
+        }
+    }
+
+    fn visit_expr(&mut self, ast: &Ast, expr: ExprIdx) -> Self::Output {
+        match ast.expr(expr) {
+            Expr::Binary(operator, left, right) => self.visit_binary(ast, operator, *left, *right),
+            Expr::Grouping(inner) => self.visit_grouping(ast, *inner),
+            Expr::Literal(lit) => self.visit_literal(ast, lit),
+            Expr::Unary(operator, right) => self.visit_unary(ast, operator, *right),
+            Expr::Typeof(operand) => self.visit_typeof(ast, *operand),
+            Expr::Variable(name) => self.visit_variable(ast, name),
+            Expr::Assign { var, value } => self.visit_assign(ast, var, *value),
+            Expr::Logical(operator, left, right) => {
+                self.visit_logical(ast, operator, *left, *right)
+            }
+            Expr::Ternary {
+                cond,
+                then_expr,
+                else_expr,
+            } => self.visit_ternary(ast, *cond, *then_expr, *else_expr),
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => self.visit_call(ast, *callee, paren, args),
+            Expr::Get { object, name } => self.visit_get(ast, *object, name),
+            Expr::Super { keyword, method } => self.visit_super(ast, keyword, method),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => self.visit_set(ast, *object, name, *value),
+            Expr::Lambda {
+                keyword,
+                params,
+                body,
+            } => self.visit_lambda(ast, keyword, params, body),
+            Expr::Array(elements) => self.visit_array(ast, elements),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => self.visit_index(ast, *object, bracket, *index),
+            Expr::IndexAssign {
+                object,
+                bracket,
+                index,
+                value,
+            } => self.visit_index_assign(ast, *object, bracket, *index, *value),
+        }
+    }
+
+    fn visit_if(
+        &mut self,
+        ast: &Ast,
+        cond: ExprIdx,
+        then_branch: StmtIdx,
+        else_branch: Option<StmtIdx>,
+    ) -> Self::Output {
+        self.visit_expr(ast, cond);
+        self.visit_stmt(ast, then_branch);
+        if let Some(else_branch) = else_branch {
+            self.visit_stmt(ast, else_branch);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_while(
+        &mut self,
+        ast: &Ast,
+        cond: ExprIdx,
+        body: StmtIdx,
+        inc: Option<ExprIdx>,
+    ) -> Self::Output {
+        self.visit_expr(ast, cond);
+        self.visit_stmt(ast, body);
+        if let Some(inc) = inc {
+            self.visit_expr(ast, inc);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_do_while(&mut self, ast: &Ast, body: StmtIdx, cond: ExprIdx) -> Self::Output {
+        self.visit_stmt(ast, body);
+        self.visit_expr(ast, cond);
+        Self::Output::default()
+    }
+
+    fn visit_for_in(
+        &mut self,
+        ast: &Ast,
+        _name: &Token,
+        iterable: ExprIdx,
+        body: StmtIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, iterable);
+        self.visit_stmt(ast, body);
+        Self::Output::default()
+    }
+
+    fn visit_print(&mut self, ast: &Ast, expr: ExprIdx) -> Self::Output {
+        self.visit_expr(ast, expr);
+        Self::Output::default()
+    }
+
+    fn visit_return(
+        &mut self,
+        ast: &Ast,
+        _keyword: &Token,
+        value: Option<ExprIdx>,
+    ) -> Self::Output {
+        if let Some(value) = value {
+            self.visit_expr(ast, value);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_break(&mut self, _ast: &Ast, _keyword: &Token) -> Self::Output {
+        Self::Output::default()
+    }
+
+    fn visit_continue(&mut self, _ast: &Ast, _keyword: &Token) -> Self::Output {
+        Self::Output::default()
+    }
+
+    fn visit_var_decl(&mut self, ast: &Ast, _name: &Token, init: Option<ExprIdx>) -> Self::Output {
+        if let Some(init) = init {
+            self.visit_expr(ast, init);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_expression(&mut self, ast: &Ast, expr: ExprIdx) -> Self::Output {
+        self.visit_expr(ast, expr);
+        Self::Output::default()
+    }
+
+    fn visit_block(&mut self, ast: &Ast, stmts: &[StmtIdx]) -> Self::Output {
+        for stmt in stmts {
+            self.visit_stmt(ast, *stmt);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_function(
+        &mut self,
+        ast: &Ast,
+        _name: &Token,
+        _params: &[Param],
+        body: &[StmtIdx],
+    ) -> Self::Output {
+        for stmt in body {
+            self.visit_stmt(ast, *stmt);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_class(
+        &mut self,
+        ast: &Ast,
+        _name: &Token,
+        _superclass: Option<&Token>,
+        methods: &[StmtIdx],
+    ) -> Self::Output {
+        for method in methods {
+            self.visit_stmt(ast, *method);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_parse_err(&mut self, _ast: &Ast, _token: &Token, _message: &str) -> Self::Output {
+        Self::Output::default()
+    }
+
+    fn visit_binary(
+        &mut self,
+        ast: &Ast,
+        _operator: &Token,
+        left: ExprIdx,
+        right: ExprIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, left);
+        self.visit_expr(ast, right);
+        Self::Output::default()
+    }
+
+    fn visit_grouping(&mut self, ast: &Ast, inner: ExprIdx) -> Self::Output {
+        self.visit_expr(ast, inner);
+        Self::Output::default()
+    }
+
+    fn visit_literal(&mut self, _ast: &Ast, _lit: &Lit) -> Self::Output {
+        Self::Output::default()
+    }
+
+    fn visit_unary(&mut self, ast: &Ast, _operator: &Token, right: ExprIdx) -> Self::Output {
+        self.visit_expr(ast, right);
+        Self::Output::default()
+    }
+
+    fn visit_typeof(&mut self, ast: &Ast, operand: ExprIdx) -> Self::Output {
+        self.visit_expr(ast, operand);
+        Self::Output::default()
+    }
+
+    fn visit_variable(&mut self, _ast: &Ast, _name: &Token) -> Self::Output {
+        Self::Output::default()
+    }
+
+    fn visit_assign(&mut self, ast: &Ast, _var: &Token, value: ExprIdx) -> Self::Output {
+        self.visit_expr(ast, value);
+        Self::Output::default()
+    }
+
+    fn visit_logical(
+        &mut self,
+        ast: &Ast,
+        _operator: &Token,
+        left: ExprIdx,
+        right: ExprIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, left);
+        self.visit_expr(ast, right);
+        Self::Output::default()
+    }
+
+    fn visit_ternary(
+        &mut self,
+        ast: &Ast,
+        cond: ExprIdx,
+        then_expr: ExprIdx,
+        else_expr: ExprIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, cond);
+        self.visit_expr(ast, then_expr);
+        self.visit_expr(ast, else_expr);
+        Self::Output::default()
+    }
+
+    fn visit_call(
+        &mut self,
+        ast: &Ast,
+        callee: ExprIdx,
+        _paren: &Token,
+        args: &[ExprIdx],
+    ) -> Self::Output {
+        self.visit_expr(ast, callee);
+        for arg in args {
+            self.visit_expr(ast, *arg);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_get(&mut self, ast: &Ast, object: ExprIdx, _name: &Token) -> Self::Output {
+        self.visit_expr(ast, object);
+        Self::Output::default()
+    }
+
+    fn visit_super(&mut self, _ast: &Ast, _keyword: &Token, _method: &Token) -> Self::Output {
+        Self::Output::default()
+    }
+
+    fn visit_set(
+        &mut self,
+        ast: &Ast,
+        object: ExprIdx,
+        _name: &Token,
+        value: ExprIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, object);
+        self.visit_expr(ast, value);
+        Self::Output::default()
+    }
+
+    fn visit_lambda(
+        &mut self,
+        ast: &Ast,
+        _keyword: &Token,
+        _params: &[Param],
+        body: &[StmtIdx],
+    ) -> Self::Output {
+        for stmt in body {
+            self.visit_stmt(ast, *stmt);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_array(&mut self, ast: &Ast, elements: &[ExprIdx]) -> Self::Output {
+        for element in elements {
+            self.visit_expr(ast, *element);
+        }
+        Self::Output::default()
+    }
+
+    fn visit_index(
+        &mut self,
+        ast: &Ast,
+        object: ExprIdx,
+        _bracket: &Token,
+        index: ExprIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, object);
+        self.visit_expr(ast, index);
+        Self::Output::default()
+    }
+
+    fn visit_index_assign(
+        &mut self,
+        ast: &Ast,
+        object: ExprIdx,
+        _bracket: &Token,
+        index: ExprIdx,
+        value: ExprIdx,
+    ) -> Self::Output {
+        self.visit_expr(ast, object);
+        self.visit_expr(ast, index);
+        self.visit_expr(ast, value);
+        Self::Output::default()
+    }
+}
+
+/// Visitor transforming the tree in place, with one method per statement and expression variant.
+///
+/// Methods get the index of the node rather than its fields, as the tree can't be borrowed while
+/// it's being changed. The node can be read and rewritten through [`Ast::stmt_mut`] or
+/// [`Ast::expr_mut`], before or after visiting its children with [`walk_stmt_mut`] or
+/// [`walk_expr_mut`]. Default implementations visit the children in the same order as
+/// [`AstVisitor`], so implementors only need to override the nodes they care about.
+pub trait AstVisitorMut {
+    fn visit_stmt_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        match ast.stmt(stmt) {
+            Stmt::If { .. } => self.visit_if_mut(ast, stmt),
+            Stmt::While { .. } => self.visit_while_mut(ast, stmt),
+            Stmt::DoWhile { .. } => self.visit_do_while_mut(ast, stmt),
+            Stmt::ForIn { .. } => self.visit_for_in_mut(ast, stmt),
+            Stmt::Print(..) => self.visit_print_mut(ast, stmt),
+            Stmt::Return(..) => self.visit_return_mut(ast, stmt),
+            Stmt::Break(..) => self.visit_break_mut(ast, stmt),
+            Stmt::Continue(..) => self.visit_continue_mut(ast, stmt),
+            Stmt::VarDecl { .. } => self.visit_var_decl_mut(ast, stmt),
+            Stmt::Expression(..) => self.visit_expression_mut(ast, stmt),
+            Stmt::Block(..) => self.visit_block_mut(ast, stmt),
+            Stmt::Function { .. } => self.visit_function_mut(ast, stmt),
+            Stmt::Class { .. } => self.visit_class_mut(ast, stmt),
+            Stmt::ParseErr(..) => self.visit_parse_err_mut(ast, stmt),
+        }
+    }
+
+    fn visit_expr_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        match ast.expr(expr) {
+            Expr::Binary(..) => self.visit_binary_mut(ast, expr),
+            Expr::Grouping(..) => self.visit_grouping_mut(ast, expr),
+            Expr::Literal(..) => self.visit_literal_mut(ast, expr),
+            Expr::Unary(..) => self.visit_unary_mut(ast, expr),
+            Expr::Typeof(..) => self.visit_typeof_mut(ast, expr),
+            Expr::Variable(..) => self.visit_variable_mut(ast, expr),
+            Expr::Assign { .. } => self.visit_assign_mut(ast, expr),
+            Expr::Logical(..) => self.visit_logical_mut(ast, expr),
+            Expr::Ternary { .. } => self.visit_ternary_mut(ast, expr),
+            Expr::Call { .. } => self.visit_call_mut(ast, expr),
+            Expr::Get { .. } => self.visit_get_mut(ast, expr),
+            Expr::Super { .. } => self.visit_super_mut(ast, expr),
+            Expr::Set { .. } => self.visit_set_mut(ast, expr),
+            Expr::Lambda { .. } => self.visit_lambda_mut(ast, expr),
+            Expr::Array(..) => self.visit_array_mut(ast, expr),
+            Expr::Index { .. } => self.visit_index_mut(ast, expr),
+            Expr::IndexAssign { .. } => self.visit_index_assign_mut(ast, expr),
+        }
+    }
+
+    fn visit_if_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_while_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_do_while_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_for_in_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_print_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_return_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_break_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_continue_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_var_decl_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_expression_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_block_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_function_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_class_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_parse_err_mut(&mut self, ast: &mut Ast, stmt: StmtIdx) {
+        walk_stmt_mut(self, ast, stmt);
+    }
+
+    fn visit_binary_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_grouping_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_literal_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_unary_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_typeof_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_variable_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_assign_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_logical_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_ternary_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_call_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_get_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_super_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_set_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_lambda_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_array_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_index_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+
+    fn visit_index_assign_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+        walk_expr_mut(self, ast, expr);
+    }
+}
+
+/// Child of a node, in the order they're visited.
+enum Child {
+    Stmt(StmtIdx),
+    Expr(ExprIdx),
+}
+
+/// Visits the direct children of the statement.
+pub fn walk_stmt_mut<V: AstVisitorMut + ?Sized>(visitor: &mut V, ast: &mut Ast, stmt: StmtIdx) {
+    let children = RefCell::new(vec![]);
+    ast.stmt(stmt).children(
+        |stmt| children.borrow_mut().push(Child::Stmt(stmt)),
+        |expr| children.borrow_mut().push(Child::Expr(expr)),
+    );
+    walk_children_mut(visitor, ast, children.into_inner());
+}
+
+/// Visits the direct children of the expression.
+pub fn walk_expr_mut<V: AstVisitorMut + ?Sized>(visitor: &mut V, ast: &mut Ast, expr: ExprIdx) {
+    let children = RefCell::new(vec![]);
+    ast.expr(expr).children(
+        |stmt| children.borrow_mut().push(Child::Stmt(stmt)),
+        |expr| children.borrow_mut().push(Child::Expr(expr)),
+    );
+    walk_children_mut(visitor, ast, children.into_inner());
+}
+
+fn walk_children_mut<V: AstVisitorMut + ?Sized>(
+    visitor: &mut V,
+    ast: &mut Ast,
+    children: Vec<Child>,
+) {
+    for child in children {
+        match child {
+            Child::Stmt(stmt) => visitor.visit_stmt_mut(ast, stmt),
+            Child::Expr(expr) => visitor.visit_expr_mut(ast, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenKind;
+
+    fn number(ast: &mut Ast, n: f64) -> ExprIdx {
+        ast.push_expr(Expr::Literal(Lit::Number(n)))
+    }
+
+    fn sum(ast: &mut Ast, left: ExprIdx, right: ExprIdx) -> ExprIdx {
+        let plus = Token {
+            kind: TokenKind::Plus,
+            ..Default::default()
+        };
+        ast.push_expr(Expr::Binary(plus, left, right))
+    }
+
+    #[test]
+    fn folds_constants() {
+        /// Folds additions of number literals.
+        struct Folder;
+
+        impl AstVisitorMut for Folder {
+            fn visit_binary_mut(&mut self, ast: &mut Ast, expr: ExprIdx) {
+                walk_expr_mut(self, ast, expr);
+                let Expr::Binary(_, left, right) = ast.expr(expr) else {
+                    unreachable!();
+                };
+                if let (Expr::Literal(Lit::Number(l)), Expr::Literal(Lit::Number(r))) =
+                    (ast.expr(*left), ast.expr(*right))
+                {
+                    *ast.expr_mut(expr) = Expr::Literal(Lit::Number(l + r));
+                }
+            }
+        }
+
+        // print 1 + 2 + (3 + 4);
+        let mut ast = Ast::new();
+        let one = number(&mut ast, 1.0);
+        let two = number(&mut ast, 2.0);
+        let left = sum(&mut ast, one, two);
+        let three = number(&mut ast, 3.0);
+        let four = number(&mut ast, 4.0);
+        let inner = sum(&mut ast, three, four);
+        let grouping = ast.push_expr(Expr::Grouping(inner));
+        let outer = sum(&mut ast, left, grouping);
+        let print = ast.push_root_stmt(Stmt::Print(outer));
+
+        Folder.visit_stmt_mut(&mut ast, print);
+        assert!(matches!(ast.expr(left), Expr::Literal(Lit::Number(3.0))));
+        assert!(matches!(ast.expr(inner), Expr::Literal(Lit::Number(7.0))));
+        // The grouping isn't a literal, so the outer sum is left alone.
+        assert!(matches!(ast.expr(outer), Expr::Binary(_, l, r) if *l == left && *r == grouping));
+    }
+
+    #[test]
+    fn visits_children_in_source_order() {
+        /// Records the literals in the order they're visited.
+        struct Recorder(Vec<ExprIdx>);
+
+        impl AstVisitorMut for Recorder {
+            fn visit_literal_mut(&mut self, _ast: &mut Ast, expr: ExprIdx) {
+                self.0.push(expr);
+            }
+        }
+
+        // do { print 1; } while (2);
+        let mut ast = Ast::new();
+        let one = number(&mut ast, 1.0);
+        let body = ast.push_stmt(Stmt::Print(one));
+        let two = number(&mut ast, 2.0);
+        let do_while = ast.push_root_stmt(Stmt::DoWhile { body, cond: two });
+
+        let mut recorder = Recorder(vec![]);
+        recorder.visit_stmt_mut(&mut ast, do_while);
+        assert_eq!(recorder.0, [one, two]);
+    }
+}
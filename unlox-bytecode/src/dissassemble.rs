@@ -5,44 +5,158 @@ use crate::{Chunk, OpCode};
 pub fn dissassemble(chunk: &Chunk, name: &str, out: &mut impl io::Write) -> io::Result<()> {
     writeln!(out, "== {name} ==")?;
 
-    let mut bytecode = chunk.code.iter().cloned().enumerate();
-    while let Some((offset, opcode)) = bytecode.next() {
-        write!(out, "{:04} ", offset)?;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = dissassemble_instruction(chunk, offset, out)?;
+    }
 
-        if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-            write!(out, "   | ")?;
-        } else {
-            write!(out, "{:4} ", chunk.lines[offset])?;
-        }
+    Ok(())
+}
 
-        let opcode = OpCode::parse(opcode).unwrap();
-        match opcode {
-            OpCode::Constant => {
-                let name = "OP_CONSTANT";
-                let (_, arg_idx) = bytecode.next().unwrap();
-                let arg = &chunk.constants[usize::from(arg_idx)];
-                writeln!(out, "{name:<16} {arg_idx:4} '{arg}'")?;
-            }
-            OpCode::Add => writeln!(out, "OP_ADD")?,
-            OpCode::Subtract => writeln!(out, "OP_SUBTRACT")?,
-            OpCode::Multiply => writeln!(out, "OP_MULTIPLY")?,
-            OpCode::Divide => writeln!(out, "OP_DIVIDE")?,
-            OpCode::Negate => writeln!(out, "OP_NEGATE")?,
-            OpCode::Return => writeln!(out, "OP_RETURN")?,
-        }
+/// Writes the instruction at `offset`, returning the offset of the next one.
+pub fn dissassemble_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut impl io::Write,
+) -> io::Result<usize> {
+    write!(out, "{:04} ", offset)?;
+
+    let line = chunk.line_at(offset);
+    if offset > 0 && line == chunk.line_at(offset - 1) {
+        write!(out, "   | ")?;
+    } else {
+        write!(out, "{line:4} ")?;
     }
 
-    Ok(())
+    let Some(opcode) = OpCode::parse(chunk.code[offset]) else {
+        writeln!(out, "Unknown opcode {}", chunk.code[offset])?;
+        return Ok(offset + 1);
+    };
+    match opcode {
+        OpCode::Constant => constant_instruction("OP_CONSTANT", chunk, offset, out),
+        OpCode::Add => simple_instruction("OP_ADD", offset, out),
+        OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset, out),
+        OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset, out),
+        OpCode::Divide => simple_instruction("OP_DIVIDE", offset, out),
+        OpCode::Negate => simple_instruction("OP_NEGATE", offset, out),
+        OpCode::Greater => simple_instruction("OP_GREATER", offset, out),
+        OpCode::Less => simple_instruction("OP_LESS", offset, out),
+        OpCode::Return => simple_instruction("OP_RETURN", offset, out),
+        OpCode::Nil => simple_instruction("OP_NIL", offset, out),
+        OpCode::True => simple_instruction("OP_TRUE", offset, out),
+        OpCode::False => simple_instruction("OP_FALSE", offset, out),
+        OpCode::Not => simple_instruction("OP_NOT", offset, out),
+        OpCode::GetLocal => byte_instruction("OP_GET_LOCAL", chunk, offset, out),
+        OpCode::SetLocal => byte_instruction("OP_SET_LOCAL", chunk, offset, out),
+        OpCode::DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, out),
+        OpCode::GetGlobal => constant_instruction("OP_GET_GLOBAL", chunk, offset, out),
+        OpCode::SetGlobal => constant_instruction("OP_SET_GLOBAL", chunk, offset, out),
+        OpCode::Pop => simple_instruction("OP_POP", offset, out),
+        OpCode::Jump => jump_instruction("OP_JUMP", true, chunk, offset, out),
+        OpCode::JumpIfFalse => jump_instruction("OP_JUMP_IF_FALSE", true, chunk, offset, out),
+        OpCode::Loop => jump_instruction("OP_LOOP", false, chunk, offset, out),
+        OpCode::Call => byte_instruction("OP_CALL", chunk, offset, out),
+        OpCode::Closure => closure_instruction(chunk, offset, out),
+        OpCode::GetUpvalue => byte_instruction("OP_GET_UPVALUE", chunk, offset, out),
+        OpCode::SetUpvalue => byte_instruction("OP_SET_UPVALUE", chunk, offset, out),
+        OpCode::CloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset, out),
+        OpCode::ConstantLong => constant_long_instruction(chunk, offset, out),
+    }
+}
+
+/// Instruction without operands.
+fn simple_instruction(name: &str, offset: usize, out: &mut impl io::Write) -> io::Result<usize> {
+    writeln!(out, "{name}")?;
+    Ok(offset + 1)
+}
+
+/// Instruction followed by a one byte operand, e.g. a stack slot.
+fn byte_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut impl io::Write,
+) -> io::Result<usize> {
+    let operand = chunk.code[offset + 1];
+    writeln!(out, "{name:<16} {operand:4}")?;
+    Ok(offset + 2)
+}
+
+/// Instruction followed by a two byte jump distance, `forward` or backward.
+fn jump_instruction(
+    name: &str,
+    forward: bool,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut impl io::Write,
+) -> io::Result<usize> {
+    let distance = usize::from(chunk.read_u16(offset));
+    let next = offset + 3;
+    let target = if forward {
+        next + distance
+    } else {
+        next.wrapping_sub(distance)
+    };
+    writeln!(out, "{name:<16} {offset:4} -> {target}")?;
+    Ok(next)
+}
+
+/// Instruction followed by a one byte index into the constants.
+fn constant_instruction(
+    name: &str,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut impl io::Write,
+) -> io::Result<usize> {
+    let idx = chunk.code[offset + 1];
+    let constant = &chunk.constants[usize::from(idx)];
+    writeln!(out, "{name:<16} {idx:4} '{constant}'")?;
+    Ok(offset + 2)
+}
+
+/// `OpCode::ConstantLong`, followed by a three byte index into the constants.
+fn constant_long_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut impl io::Write,
+) -> io::Result<usize> {
+    let idx = chunk.read_u24(offset);
+    let constant = &chunk.constants[idx];
+    writeln!(out, "{:<16} {idx:4} '{constant}'", "OP_CONSTANT_LONG")?;
+    Ok(offset + 4)
+}
+
+/// `OpCode::Closure`, followed by a line for each captured variable.
+fn closure_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut impl io::Write,
+) -> io::Result<usize> {
+    let next = constant_instruction("OP_CLOSURE", chunk, offset, out)?;
+    let upvalue_count = usize::from(chunk.code[next]);
+    let mut offset = next + 1;
+    for _ in 0..upvalue_count {
+        let kind = if chunk.code[offset] == 1 {
+            "local"
+        } else {
+            "upvalue"
+        };
+        let idx = chunk.code[offset + 1];
+        writeln!(out, "{offset:04}    |                     {kind} {idx}")?;
+        offset += 2;
+    }
+    Ok(offset)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{Function, Value};
 
     #[test]
     fn test() {
         let mut chunk = Chunk::new();
-        let constant = chunk.add_constant(1.2);
+        let constant = chunk.add_constant(Value::Number(1.2));
         chunk.write(OpCode::Constant as u8, 123);
         chunk.write(constant, 123);
         chunk.write(OpCode::Return as u8, 123);
@@ -59,4 +173,99 @@ mod tests {
         println!("{expected}");
         assert_eq!(out, expected);
     }
+
+    #[test]
+    fn dissassembles_arithmetic() {
+        let mut chunk = Chunk::new();
+        for (opcode, line) in [
+            (OpCode::Add, 1),
+            (OpCode::Subtract, 1),
+            (OpCode::Multiply, 2),
+            (OpCode::Divide, 2),
+            (OpCode::Negate, 3),
+            (OpCode::Nil, 3),
+            (OpCode::True, 3),
+            (OpCode::False, 3),
+            (OpCode::Not, 3),
+        ] {
+            chunk.write(opcode as u8, line);
+        }
+        chunk.write(OpCode::GetLocal as u8, 4);
+        chunk.write(1, 4);
+        chunk.write(OpCode::SetLocal as u8, 4);
+        chunk.write(0, 4);
+        let jump = chunk.write_jump(OpCode::JumpIfFalse, 5);
+        chunk.write(OpCode::Pop as u8, 5);
+        chunk.patch_jump(jump, chunk.code.len());
+        chunk.write_loop(jump, 5);
+        chunk.write(0xff, 5);
+
+        let mut out = Vec::new();
+        dissassemble(&chunk, "arithmetic", &mut out).unwrap();
+        let expected = "\
+            == arithmetic ==\n\
+            0000    1 OP_ADD\n\
+            0001    | OP_SUBTRACT\n\
+            0002    2 OP_MULTIPLY\n\
+            0003    | OP_DIVIDE\n\
+            0004    3 OP_NEGATE\n\
+            0005    | OP_NIL\n\
+            0006    | OP_TRUE\n\
+            0007    | OP_FALSE\n\
+            0008    | OP_NOT\n\
+            0009    4 OP_GET_LOCAL        1\n\
+            0011    | OP_SET_LOCAL        0\n\
+            0013    5 OP_JUMP_IF_FALSE   13 -> 17\n\
+            0016    | OP_POP\n\
+            0017    | OP_LOOP            17 -> 13\n\
+            0020    | Unknown opcode 255\n\
+        ";
+        assert_eq!(std::str::from_utf8(&out).unwrap(), expected);
+    }
+
+    #[test]
+    fn dissassembles_long_constants() {
+        let mut chunk = Chunk::new();
+        for n in 0..257 {
+            chunk.write_constant_instruction(Value::Number(f64::from(n)), 1);
+        }
+        let mut out = Vec::new();
+        dissassemble_instruction(&chunk, 256 * 2, &mut out).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "0512    | OP_CONSTANT_LONG  256 '256'\n"
+        );
+    }
+
+    #[test]
+    fn dissassembles_closures() {
+        let function = Function {
+            name: "f".into(),
+            arity: 0,
+            chunk: Chunk::new(),
+        };
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Function(function.into()));
+        for byte in [OpCode::Closure as u8, constant, 2, 1, 3, 0, 0] {
+            chunk.write(byte, 1);
+        }
+        chunk.write(OpCode::GetUpvalue as u8, 2);
+        chunk.write(1, 2);
+        chunk.write(OpCode::SetUpvalue as u8, 2);
+        chunk.write(0, 2);
+        chunk.write(OpCode::CloseUpvalue as u8, 2);
+
+        let mut out = Vec::new();
+        dissassemble(&chunk, "closures", &mut out).unwrap();
+        let expected = "\
+            == closures ==\n\
+            0000    1 OP_CLOSURE          0 '<fn f>'\n\
+            0003    |                     local 3\n\
+            0005    |                     upvalue 0\n\
+            0007    2 OP_GET_UPVALUE      1\n\
+            0009    | OP_SET_UPVALUE      0\n\
+            0011    | OP_CLOSE_UPVALUE\n\
+        ";
+        assert_eq!(std::str::from_utf8(&out).unwrap(), expected);
+    }
 }
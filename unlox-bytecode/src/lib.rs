@@ -1,9 +1,22 @@
+use std::{cell::RefCell, collections::HashSet, fmt, rc::Rc, sync::Arc};
+
 pub mod dissassemble;
+mod serialize;
+mod verify;
+
+pub use serialize::DeserializeError;
+pub use verify::VerifyError;
+
+/// Number of stack slots a single frame can use, including the called function and its
+/// arguments.
+pub const FRAME_SIZE: usize = 256;
 
+#[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<usize>,
+    /// Source lines of the bytes in `code`, run-length encoded as (count, line) pairs.
+    pub lines: Vec<(usize, usize)>,
 }
 
 impl Chunk {
@@ -17,13 +30,105 @@ impl Chunk {
 
     pub fn write(&mut self, byte: u8, line: usize) {
         self.code.push(byte);
-        self.lines.push(line);
+        match self.lines.last_mut() {
+            Some((count, last)) if *last == line => *count += 1,
+            _ => self.lines.push((1, line)),
+        }
     }
 
+    /// Returns the source line of the byte at `offset`.
+    ///
+    /// # Panics if `offset` is out of bounds
+    pub fn line_at(&self, offset: usize) -> usize {
+        let mut start = 0;
+        for &(count, line) in &self.lines {
+            start += count;
+            if offset < start {
+                return line;
+            }
+        }
+        panic!("Offset {offset} is out of bounds");
+    }
+
+    /// Adds a constant, returning its index as the one byte operand of `OpCode::Constant`.
+    ///
+    /// Use [`Chunk::write_constant_instruction`] unless the chunk is known to stay small, as it
+    /// switches to `OpCode::ConstantLong` once the index no longer fits.
+    ///
+    /// # Panics if the index doesn't fit in 8 bits
     pub fn add_constant(&mut self, v: Value) -> u8 {
+        let idx =
+            u8::try_from(self.constants.len()).expect("Too many constants for a one byte operand");
+        self.constants.push(v);
+        idx
+    }
+
+    /// Like [`Chunk::add_constant`], but for chunks with more than 256 constants, returning the
+    /// index as a 24 bit big-endian operand for `OpCode::ConstantLong`.
+    ///
+    /// # Panics if the index doesn't fit in 24 bits
+    pub fn add_constant_long(&mut self, v: Value) -> [u8; 3] {
         let idx = self.constants.len();
+        assert!(idx < 1 << 24, "Too many constants in one chunk");
         self.constants.push(v);
-        idx as u8
+        let [_, bytes @ ..] = (idx as u32).to_be_bytes();
+        bytes
+    }
+
+    /// Writes an instruction pushing `v`, using `OpCode::ConstantLong` only once the one byte
+    /// operand of `OpCode::Constant` no longer fits.
+    pub fn write_constant_instruction(&mut self, v: Value, line: usize) {
+        if self.constants.len() <= usize::from(u8::MAX) {
+            let idx = self.add_constant(v);
+            self.write(OpCode::Constant as u8, line);
+            self.write(idx, line);
+        } else {
+            let idx = self.add_constant_long(v);
+            self.write(OpCode::ConstantLong as u8, line);
+            for byte in idx {
+                self.write(byte, line);
+            }
+        }
+    }
+
+    /// Writes a jump instruction with a placeholder distance, returning its offset for
+    /// [`Chunk::patch_jump`].
+    pub fn write_jump(&mut self, opcode: OpCode, line: usize) -> usize {
+        let offset = self.code.len();
+        self.write(opcode as u8, line);
+        self.write(0xff, line);
+        self.write(0xff, line);
+        offset
+    }
+
+    /// Makes the forward jump instruction at `offset` jump to `target`.
+    ///
+    /// # Panics if the distance doesn't fit in 16 bits
+    pub fn patch_jump(&mut self, offset: usize, target: usize) {
+        let distance = u16::try_from(target - (offset + 3)).expect("Too much code to jump over");
+        self.code[offset + 1..offset + 3].copy_from_slice(&distance.to_be_bytes());
+    }
+
+    /// Writes a backward jump to `target`.
+    ///
+    /// # Panics if the distance doesn't fit in 16 bits
+    pub fn write_loop(&mut self, target: usize, line: usize) {
+        self.write(OpCode::Loop as u8, line);
+        let distance = u16::try_from(self.code.len() + 2 - target).expect("Loop body too large");
+        for byte in distance.to_be_bytes() {
+            self.write(byte, line);
+        }
+    }
+
+    /// Reads the 16 bit operand of the instruction at `offset`.
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]])
+    }
+
+    /// Reads the 24 bit operand of the instruction at `offset`.
+    pub fn read_u24(&self, offset: usize) -> usize {
+        let [a, b, c] = [1, 2, 3].map(|i| self.code[offset + i]);
+        u32::from_be_bytes([0, a, b, c]) as usize
     }
 }
 
@@ -43,6 +148,53 @@ pub enum OpCode {
     Divide,
     Negate,
     Return,
+    Nil,
+    True,
+    False,
+    Not,
+    /// Pushes the local variable in the slot given by the operand byte, relative to the frame.
+    GetLocal,
+    /// Assigns the top of the stack, leaving it there, to the local variable in the slot given by
+    /// the operand byte.
+    SetLocal,
+    /// Pops a value into a new global variable, named by the constant given by the operand byte.
+    DefineGlobal,
+    /// Pushes the global variable named by the constant given by the operand byte.
+    GetGlobal,
+    /// Assigns the top of the stack, leaving it there, to the existing global variable named by
+    /// the constant given by the operand byte.
+    SetGlobal,
+    /// Discards the top of the stack.
+    Pop,
+    /// Jumps forward by the distance given by the 16 bit operand, counted from the next
+    /// instruction.
+    Jump,
+    /// Like `Jump`, but only if the top of the stack, which is left there, is falsey.
+    JumpIfFalse,
+    /// Jumps backward by the distance given by the 16 bit operand, counted from the next
+    /// instruction.
+    Loop,
+    /// Calls the function below as many arguments as given by the operand byte.
+    Call,
+    /// Pushes a closure of the function in the constant given by the first operand byte. The
+    /// second operand byte is the number of captured variables, each described by two more bytes:
+    /// 1 if it's a local variable of the enclosing function or 0 if it's one of its upvalues,
+    /// followed by the slot or upvalue index.
+    Closure,
+    /// Pushes the upvalue of the current closure given by the operand byte.
+    GetUpvalue,
+    /// Assigns the top of the stack, leaving it there, to the upvalue of the current closure given
+    /// by the operand byte.
+    SetUpvalue,
+    /// Moves the local variable at the top of the stack into the upvalues capturing it and pops
+    /// it.
+    CloseUpvalue,
+    /// Like `Constant`, but with a 24 bit big-endian operand.
+    ConstantLong,
+    /// Pops two numbers and pushes whether the first is greater than the second.
+    Greater,
+    /// Pops two numbers and pushes whether the first is less than the second.
+    Less,
 }
 
 impl OpCode {
@@ -55,9 +207,172 @@ impl OpCode {
             0x04 => Some(OpCode::Divide),
             0x05 => Some(OpCode::Negate),
             0x06 => Some(OpCode::Return),
+            0x07 => Some(OpCode::Nil),
+            0x08 => Some(OpCode::True),
+            0x09 => Some(OpCode::False),
+            0x0a => Some(OpCode::Not),
+            0x0b => Some(OpCode::GetLocal),
+            0x0c => Some(OpCode::SetLocal),
+            0x0d => Some(OpCode::DefineGlobal),
+            0x0e => Some(OpCode::GetGlobal),
+            0x0f => Some(OpCode::SetGlobal),
+            0x10 => Some(OpCode::Pop),
+            0x11 => Some(OpCode::Jump),
+            0x12 => Some(OpCode::JumpIfFalse),
+            0x13 => Some(OpCode::Loop),
+            0x14 => Some(OpCode::Call),
+            0x15 => Some(OpCode::Closure),
+            0x16 => Some(OpCode::GetUpvalue),
+            0x17 => Some(OpCode::SetUpvalue),
+            0x18 => Some(OpCode::CloseUpvalue),
+            0x19 => Some(OpCode::ConstantLong),
+            0x1a => Some(OpCode::Greater),
+            0x1b => Some(OpCode::Less),
             _ => None,
         }
     }
 }
 
-pub type Value = f64;
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+    #[default]
+    Nil,
+    String(Arc<str>),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    /// Object allocated on the VM's garbage collected heap.
+    Object(ObjectId),
+}
+
+/// Index of an object on the VM's heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectId(pub usize);
+
+/// Compiled function.
+#[derive(Debug)]
+pub struct Function {
+    pub name: Arc<str>,
+    pub arity: u8,
+    pub chunk: Chunk,
+}
+
+/// Functions are only equal to themselves.
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Function together with the variables it captured.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// Closures are only equal to themselves.
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Variable captured by a closure.
+#[derive(Debug)]
+pub enum Upvalue {
+    /// The variable is still on the stack, in the given absolute slot.
+    Open(usize),
+    /// The variable went out of scope and now lives in the upvalue.
+    Closed(Value),
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+/// Deduplicates strings, so that equal strings share an allocation. Comparing [`Value::String`]s
+/// made from the same interner is then a pointer comparison.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, s: &str) -> Arc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = s.into();
+        self.strings.insert(interned.clone());
+        interned
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Nil => write!(f, "nil"),
+            Value::String(s) => write!(f, "{s}"),
+            Value::Function(function) => write!(f, "<fn {}>", function.name),
+            Value::Closure(closure) => write!(f, "<fn {}>", closure.function.name),
+            Value::Object(id) => write!(f, "<object {}>", id.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_strings() {
+        let mut interner = Interner::new();
+        let a = interner.intern("hello");
+        let b = interner.intern(&String::from("hello"));
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &interner.intern("world")));
+    }
+
+    #[test]
+    fn encodes_lines() {
+        let mut chunk = Chunk::new();
+        for line in [1, 1, 1, 2, 4, 4] {
+            chunk.write(OpCode::Nil as u8, line);
+        }
+        assert_eq!(chunk.lines, [(3, 1), (1, 2), (2, 4)]);
+        let lines: Vec<_> = (0..6).map(|offset| chunk.line_at(offset)).collect();
+        assert_eq!(lines, [1, 1, 1, 2, 4, 4]);
+    }
+
+    #[test]
+    fn writes_long_constants() {
+        let mut chunk = Chunk::new();
+        for n in 0..300 {
+            chunk.write_constant_instruction(Value::Number(f64::from(n)), 1);
+        }
+        assert_eq!(chunk.code.len(), 256 * 2 + 44 * 4);
+        let offset = 256 * 2 + 43 * 4;
+        assert_eq!(chunk.code[offset], OpCode::ConstantLong as u8);
+        assert_eq!(chunk.read_u24(offset), 299);
+        assert_eq!(chunk.constants[299], Value::Number(299.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many constants")]
+    fn rejects_constants_past_one_byte_operand() {
+        let mut chunk = Chunk::new();
+        for n in 0..=256 {
+            chunk.add_constant(Value::Number(f64::from(n)));
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Binary format of chunks, so that they can be stored without the source code.
+//!
+//! A file starts with [`MAGIC`] and [`VERSION`], followed by the chunk. Chunks are made of their
+//! code, constants and lines, each prefixed with its length. Integers are big-endian and lengths
+//! take 32 bits.
+
+use std::rc::Rc;
+
+use crate::{Chunk, Function, Value};
+
+const MAGIC: &[u8] = b"UNLOX\0";
+const VERSION: u8 = 1;
+
+const TAG_NUMBER: u8 = 0x01;
+const TAG_BOOL: u8 = 0x02;
+const TAG_NIL: u8 = 0x03;
+const TAG_STRING: u8 = 0x04;
+const TAG_FUNCTION: u8 = 0x05;
+
+/// How deeply functions can be nested in the constants of a chunk, so that a malicious file
+/// can't overflow the stack while being read.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum DeserializeError {
+    #[error("Not an unlox bytecode file.")]
+    BadMagic,
+    #[error("Unsupported bytecode version {0}.")]
+    UnsupportedVersion(u8),
+    #[error("Unexpected end of bytecode.")]
+    UnexpectedEnd,
+    #[error("Unknown constant tag {0}.")]
+    UnknownTag(u8),
+    #[error("String constant isn't valid UTF-8.")]
+    InvalidUtf8,
+    #[error("Unexpected bytes after the chunk.")]
+    TrailingBytes,
+    #[error("Functions are nested more than {MAX_DEPTH} levels deep.")]
+    TooDeep,
+    #[error("Lines don't cover the code of the chunk.")]
+    BadLines,
+}
+
+impl Chunk {
+    /// # Panics if a constant is a runtime-only value, e.g. a closure
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_chunk(self, &mut out);
+        out
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, DeserializeError> {
+        let mut reader = Reader { bytes, depth: 0 };
+        if reader.take(MAGIC.len()) != Ok(MAGIC) {
+            return Err(DeserializeError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+        let chunk = reader.chunk()?;
+        if !reader.bytes.is_empty() {
+            return Err(DeserializeError::TrailingBytes);
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_chunk(chunk: &Chunk, out: &mut Vec<u8>) {
+    write_len(chunk.code.len(), out);
+    out.extend_from_slice(&chunk.code);
+    write_len(chunk.constants.len(), out);
+    for constant in &chunk.constants {
+        write_value(constant, out);
+    }
+    write_len(chunk.lines.len(), out);
+    for &(count, line) in &chunk.lines {
+        write_len(count, out);
+        write_len(line, out);
+    }
+}
+
+fn write_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Number(n) => {
+            out.push(TAG_NUMBER);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        Value::Bool(b) => {
+            out.push(TAG_BOOL);
+            out.push(u8::from(*b));
+        }
+        Value::Nil => out.push(TAG_NIL),
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            write_str(s, out);
+        }
+        Value::Function(function) => {
+            out.push(TAG_FUNCTION);
+            write_str(&function.name, out);
+            out.push(function.arity);
+            write_chunk(&function.chunk, out);
+        }
+        Value::Closure(_) | Value::Object(_) => {
+            panic!("Only values known at compile time can be serialized, got {value}")
+        }
+    }
+}
+
+fn write_str(s: &str, out: &mut Vec<u8>) {
+    write_len(s.len(), out);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_len(len: usize, out: &mut Vec<u8>) {
+    let len = u32::try_from(len).expect("Chunk too large to serialize");
+    out.extend_from_slice(&len.to_be_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    /// Number of function constants the chunk being read is nested in.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DeserializeError> {
+        if self.bytes.len() < n {
+            return Err(DeserializeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn len(&mut self) -> Result<usize, DeserializeError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_be_bytes(bytes) as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str, DeserializeError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| DeserializeError::InvalidUtf8)
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, DeserializeError> {
+        let len = self.len()?;
+        let code = self.take(len)?.to_vec();
+        // Lengths aren't trusted for preallocation, as a corrupted one could be huge.
+        let mut constants = Vec::new();
+        for _ in 0..self.len()? {
+            constants.push(self.value()?);
+        }
+        let mut lines = Vec::new();
+        for _ in 0..self.len()? {
+            lines.push((self.len()?, self.len()?));
+        }
+        let covered = lines
+            .iter()
+            .try_fold(0usize, |covered, &(count, _)| covered.checked_add(count));
+        if covered != Some(code.len()) {
+            return Err(DeserializeError::BadLines);
+        }
+        Ok(Chunk {
+            code,
+            constants,
+            lines,
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, DeserializeError> {
+        let value = match self.u8()? {
+            TAG_NUMBER => Value::Number(f64::from_be_bytes(self.take(8)?.try_into().unwrap())),
+            TAG_BOOL => Value::Bool(self.u8()? != 0),
+            TAG_NIL => Value::Nil,
+            TAG_STRING => Value::String(self.str()?.into()),
+            TAG_FUNCTION => {
+                let name = self.str()?.into();
+                let arity = self.u8()?;
+                if self.depth == MAX_DEPTH {
+                    return Err(DeserializeError::TooDeep);
+                }
+                self.depth += 1;
+                let chunk = self.chunk()?;
+                self.depth -= 1;
+                Value::Function(Rc::new(Function { name, arity, chunk }))
+            }
+            tag => return Err(DeserializeError::UnknownTag(tag)),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpCode;
+
+    fn sample() -> Chunk {
+        let mut function = Chunk::new();
+        function.write(OpCode::Nil as u8, 2);
+        function.write(OpCode::Return as u8, 2);
+        let function = Function {
+            name: "f".into(),
+            arity: 1,
+            chunk: function,
+        };
+
+        let mut chunk = Chunk::new();
+        for value in [
+            Value::Number(1.5),
+            Value::Bool(true),
+            Value::Nil,
+            Value::String("héllo".into()),
+            Value::Function(function.into()),
+        ] {
+            chunk.write_constant_instruction(value, 1);
+        }
+        chunk.write(OpCode::Return as u8, 3);
+        chunk
+    }
+
+    #[test]
+    fn round_trips() {
+        let chunk = sample();
+        let bytes = chunk.serialize();
+        assert!(bytes.starts_with(b"UNLOX\0\x01"));
+        let deserialized = Chunk::deserialize(&bytes).unwrap();
+        assert_eq!(deserialized.code, chunk.code);
+        assert_eq!(deserialized.lines, chunk.lines);
+        assert_eq!(deserialized.constants[..4], chunk.constants[..4]);
+        let Value::Function(function) = &deserialized.constants[4] else {
+            panic!("Expected a function constant");
+        };
+        assert_eq!((&*function.name, function.arity), ("f", 1));
+        assert_eq!(
+            function.chunk.code,
+            [OpCode::Nil as u8, OpCode::Return as u8]
+        );
+        assert_eq!(function.chunk.lines, [(2, 2)]);
+    }
+
+    #[test]
+    fn rejects_malformed_bytes() {
+        let bytes = sample().serialize();
+        let error = |bytes: &[u8]| Chunk::deserialize(bytes).unwrap_err();
+        assert_eq!(error(b"LOX"), DeserializeError::BadMagic);
+        assert_eq!(
+            error(b"UNLOX\0\x02"),
+            DeserializeError::UnsupportedVersion(2)
+        );
+        assert_eq!(
+            error(&bytes[..bytes.len() - 1]),
+            DeserializeError::UnexpectedEnd
+        );
+        assert_eq!(
+            error(&[&bytes[..], &[0]].concat()),
+            DeserializeError::TrailingBytes
+        );
+        // Code of a single byte, followed by a constant with an unknown tag.
+        let bytes = b"UNLOX\0\x01\0\0\0\x01\x06\0\0\0\x01\xff";
+        assert_eq!(error(bytes), DeserializeError::UnknownTag(0xff));
+
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.lines.clear();
+        assert_eq!(error(&chunk.serialize()), DeserializeError::BadLines);
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| {
+            let mut chunk = Chunk::new();
+            for _ in 0..depth {
+                let function = Function {
+                    name: "f".into(),
+                    arity: 0,
+                    chunk,
+                };
+                chunk = Chunk::new();
+                chunk.add_constant(Value::Function(function.into()));
+            }
+            chunk.serialize()
+        };
+        assert!(Chunk::deserialize(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Chunk::deserialize(&nested(MAX_DEPTH + 1)).unwrap_err(),
+            DeserializeError::TooDeep
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use crate::{Chunk, OpCode, Value, FRAME_SIZE};
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum VerifyError {
+    #[error("Unknown opcode {byte} at offset {offset}.")]
+    UnknownOpcode { offset: usize, byte: u8 },
+    #[error("Instruction at offset {offset} is missing operands.")]
+    MissingOperands { offset: usize },
+    #[error("Instruction at offset {offset} uses constant {idx}, which doesn't exist.")]
+    InvalidConstant { offset: usize, idx: usize },
+    #[error(
+        "Instruction at offset {offset} names a global with constant {idx}, which isn't a string."
+    )]
+    InvalidGlobalName { offset: usize, idx: usize },
+    #[error("Closure at offset {offset} is made of constant {idx}, which isn't a function.")]
+    InvalidClosure { offset: usize, idx: usize },
+    #[error("Instruction at offset {offset} uses local slot {slot}, which isn't on the stack.")]
+    InvalidLocal { offset: usize, slot: usize },
+    #[error("Instruction at offset {offset} uses upvalue {idx}, which isn't captured.")]
+    InvalidUpvalue { offset: usize, idx: usize },
+    #[error("Jump at offset {offset} lands at {target}, which isn't an instruction.")]
+    InvalidJump { offset: usize, target: usize },
+    #[error("Instruction at offset {offset} pops from an empty stack.")]
+    StackUnderflow { offset: usize },
+    #[error(
+        "Instruction at offset {offset} pushes past the {FRAME_SIZE} values a frame can hold."
+    )]
+    StackOverflow { offset: usize },
+    #[error("Stack depth at offset {offset} depends on how it's reached.")]
+    InconsistentStack { offset: usize },
+    #[error("Chunk doesn't end with OP_RETURN.")]
+    MissingReturn,
+    #[error("Lines cover {covered} bytes of code, but the chunk has {len}.")]
+    InvalidLines { covered: usize, len: usize },
+    #[error("In function '{name}': {source}")]
+    InFunction {
+        name: String,
+        source: Box<VerifyError>,
+    },
+}
+
+impl Chunk {
+    /// Checks that the chunk, and the chunks of functions in its constants, can be executed
+    /// without the VM panicking.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        verify_chunk(self, 0, 0)
+    }
+}
+
+/// `base_depth` is the number of values in the frame before the first instruction runs and
+/// `upvalue_count` the number of upvalues the chunk can access.
+fn verify_chunk(chunk: &Chunk, base_depth: usize, upvalue_count: usize) -> Result<(), VerifyError> {
+    let covered = chunk.lines.iter().map(|(count, _)| count).sum();
+    if covered != chunk.code.len() {
+        let len = chunk.code.len();
+        return Err(VerifyError::InvalidLines { covered, len });
+    }
+
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let instruction = Instruction::decode(chunk, offset, upvalue_count)?;
+        offset += instruction.len;
+        instructions.push(instruction);
+    }
+    match instructions.last() {
+        Some(last) if matches!(last.opcode, OpCode::Return) => {}
+        _ => return Err(VerifyError::MissingReturn),
+    }
+
+    // Functions can only access the upvalues of the closures made of them, and none when they're
+    // called without making one.
+    let mut upvalue_counts = vec![None; chunk.constants.len()];
+    for instruction in &instructions {
+        let (idx, count) = match instruction.opcode {
+            OpCode::Closure => (
+                usize::from(chunk.code[instruction.offset + 1]),
+                usize::from(chunk.code[instruction.offset + 2]),
+            ),
+            OpCode::Constant => (usize::from(chunk.code[instruction.offset + 1]), 0),
+            OpCode::ConstantLong => (chunk.read_u24(instruction.offset), 0),
+            _ => continue,
+        };
+        let known: &mut Option<usize> = &mut upvalue_counts[idx];
+        *known = Some(known.map_or(count, |known| known.min(count)));
+    }
+    for (constant, upvalue_count) in chunk.constants.iter().zip(upvalue_counts) {
+        if let Value::Function(function) = constant {
+            let base_depth = usize::from(function.arity) + 1;
+            verify_chunk(&function.chunk, base_depth, upvalue_count.unwrap_or(0)).map_err(
+                |source| VerifyError::InFunction {
+                    name: function.name.to_string(),
+                    source: Box::new(source),
+                },
+            )?;
+        }
+    }
+
+    // Stack depth before each instruction, found by following every path through the code.
+    let mut depths = vec![None; instructions.len()];
+    let mut pending = vec![(0, base_depth)];
+    while let Some((idx, depth)) = pending.pop() {
+        match depths[idx] {
+            Some(known) if known == depth => continue,
+            Some(_) => {
+                let offset = instructions[idx].offset;
+                return Err(VerifyError::InconsistentStack { offset });
+            }
+            None => depths[idx] = Some(depth),
+        }
+        let instruction = &instructions[idx];
+        let offset = instruction.offset;
+        for slot in instruction.locals(chunk) {
+            if slot >= depth {
+                return Err(VerifyError::InvalidLocal { offset, slot });
+            }
+        }
+        let (pops, pushes) = instruction.stack_effect(chunk);
+        let Some(depth) = depth.checked_sub(pops) else {
+            return Err(VerifyError::StackUnderflow { offset });
+        };
+        let depth = depth + pushes;
+        if depth > FRAME_SIZE {
+            return Err(VerifyError::StackOverflow { offset });
+        }
+        let jump_target = |forward: bool| {
+            let next = instruction.offset + 3;
+            let distance = usize::from(chunk.read_u16(instruction.offset));
+            let target = if forward {
+                next.checked_add(distance)
+            } else {
+                next.checked_sub(distance)
+            };
+            target
+                .and_then(|target| {
+                    instructions
+                        .binary_search_by_key(&target, |i| i.offset)
+                        .ok()
+                })
+                .ok_or(VerifyError::InvalidJump {
+                    offset: instruction.offset,
+                    target: target.unwrap_or(0),
+                })
+        };
+        match instruction.opcode {
+            OpCode::Return => {}
+            OpCode::Jump => pending.push((jump_target(true)?, depth)),
+            OpCode::Loop => pending.push((jump_target(false)?, depth)),
+            OpCode::JumpIfFalse => {
+                pending.push((jump_target(true)?, depth));
+                pending.push((idx + 1, depth));
+            }
+            _ => pending.push((idx + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+struct Instruction {
+    opcode: OpCode,
+    offset: usize,
+    /// Length in bytes, including operands.
+    len: usize,
+}
+
+impl Instruction {
+    /// Decodes the instruction at `offset` of a chunk able to access `upvalue_count` upvalues.
+    fn decode(chunk: &Chunk, offset: usize, upvalue_count: usize) -> Result<Self, VerifyError> {
+        let byte = chunk.code[offset];
+        let Some(opcode) = OpCode::parse(byte) else {
+            return Err(VerifyError::UnknownOpcode { offset, byte });
+        };
+        let operand = |i: usize| {
+            chunk
+                .code
+                .get(offset + i)
+                .copied()
+                .ok_or(VerifyError::MissingOperands { offset })
+        };
+        let check_constant = |idx: usize| match chunk.constants.get(idx) {
+            Some(constant) => Ok(constant),
+            None => Err(VerifyError::InvalidConstant { offset, idx }),
+        };
+        let check_upvalue = |idx: usize| {
+            if idx < upvalue_count {
+                Ok(())
+            } else {
+                Err(VerifyError::InvalidUpvalue { offset, idx })
+            }
+        };
+        let len = match opcode {
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Negate
+            | OpCode::Return
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Not
+            | OpCode::Pop
+            | OpCode::CloseUpvalue => 1,
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::Call => {
+                operand(1)?;
+                2
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                check_upvalue(usize::from(operand(1)?))?;
+                2
+            }
+            OpCode::Constant => {
+                check_constant(usize::from(operand(1)?))?;
+                2
+            }
+            OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal => {
+                let idx = usize::from(operand(1)?);
+                if !matches!(check_constant(idx)?, Value::String(_)) {
+                    return Err(VerifyError::InvalidGlobalName { offset, idx });
+                }
+                2
+            }
+            OpCode::ConstantLong => {
+                operand(3)?;
+                check_constant(chunk.read_u24(offset))?;
+                4
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                operand(2)?;
+                3
+            }
+            OpCode::Closure => {
+                let idx = usize::from(operand(1)?);
+                if !matches!(check_constant(idx)?, Value::Function(_)) {
+                    return Err(VerifyError::InvalidClosure { offset, idx });
+                }
+                let len = 3 + 2 * usize::from(operand(2)?);
+                operand(len - 1)?;
+                // Upvalues captured from the enclosing closure, rather than from its locals.
+                for capture in (3..len).step_by(2) {
+                    if chunk.code[offset + capture] != 1 {
+                        check_upvalue(usize::from(chunk.code[offset + capture + 1]))?;
+                    }
+                }
+                len
+            }
+        };
+        Ok(Self {
+            opcode,
+            offset,
+            len,
+        })
+    }
+
+    /// Returns the stack slots of the frame the instruction accesses.
+    fn locals(&self, chunk: &Chunk) -> Vec<usize> {
+        match self.opcode {
+            OpCode::GetLocal | OpCode::SetLocal => vec![usize::from(chunk.code[self.offset + 1])],
+            // Locals captured as upvalues.
+            OpCode::Closure => (self.offset + 3..self.offset + self.len)
+                .step_by(2)
+                .filter(|&capture| chunk.code[capture] == 1)
+                .map(|capture| usize::from(chunk.code[capture + 1]))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns how many values the instruction pops, and then pushes.
+    fn stack_effect(&self, chunk: &Chunk) -> (usize, usize) {
+        match self.opcode {
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Closure => (0, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Greater
+            | OpCode::Less => (2, 1),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::Return | OpCode::DefineGlobal | OpCode::Pop | OpCode::CloseUpvalue => (1, 0),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            OpCode::Call => (usize::from(chunk.code[self.offset + 1]) + 1, 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Function;
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Number(1.0));
+        for &byte in code {
+            chunk.write(byte, 1);
+        }
+        chunk
+    }
+
+    #[test]
+    fn accepts_valid_chunks() {
+        let mut valid = chunk(&[OpCode::True as u8]);
+        let jump = valid.write_jump(OpCode::JumpIfFalse, 1);
+        valid.write(OpCode::Pop as u8, 1);
+        valid.write_loop(0, 1);
+        valid.patch_jump(jump, valid.code.len());
+        valid.write(OpCode::Return as u8, 1);
+        assert_eq!(valid.verify(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_chunks() {
+        let constant = OpCode::Constant as u8;
+        let ret = OpCode::Return as u8;
+        for (code, error) in [
+            (&[][..], VerifyError::MissingReturn),
+            (&[constant, 0], VerifyError::MissingReturn),
+            (
+                &[0xff, ret],
+                VerifyError::UnknownOpcode {
+                    offset: 0,
+                    byte: 0xff,
+                },
+            ),
+            (&[constant], VerifyError::MissingOperands { offset: 0 }),
+            (
+                &[constant, 1, ret],
+                VerifyError::InvalidConstant { offset: 0, idx: 1 },
+            ),
+            (&[ret], VerifyError::StackUnderflow { offset: 0 }),
+            (
+                &[OpCode::Nil as u8, OpCode::Add as u8, ret],
+                VerifyError::StackUnderflow { offset: 1 },
+            ),
+            (
+                &[OpCode::Jump as u8, 0, 2, OpCode::Nil as u8, ret],
+                VerifyError::InvalidJump {
+                    offset: 0,
+                    target: 5,
+                },
+            ),
+            (
+                &[OpCode::Loop as u8, 0, 9, ret],
+                VerifyError::InvalidJump {
+                    offset: 0,
+                    target: 0,
+                },
+            ),
+            (
+                &[OpCode::Nil as u8, OpCode::GetLocal as u8, 1, ret],
+                VerifyError::InvalidLocal { offset: 1, slot: 1 },
+            ),
+            (
+                &[OpCode::GetUpvalue as u8, 0, ret],
+                VerifyError::InvalidUpvalue { offset: 0, idx: 0 },
+            ),
+            (
+                &[OpCode::Nil as u8, OpCode::DefineGlobal as u8, 0, ret],
+                VerifyError::InvalidGlobalName { offset: 1, idx: 0 },
+            ),
+            (
+                &[OpCode::Closure as u8, 0, 0, ret],
+                VerifyError::InvalidClosure { offset: 0, idx: 0 },
+            ),
+            (
+                &[[OpCode::Nil as u8; FRAME_SIZE + 1].as_slice(), &[ret]].concat(),
+                VerifyError::StackOverflow { offset: FRAME_SIZE },
+            ),
+        ] {
+            assert_eq!(chunk(code).verify(), Err(error), "{code:?}");
+        }
+
+        // Pushes a value only when the condition is false.
+        let mut inconsistent = chunk(&[OpCode::True as u8]);
+        let jump = inconsistent.write_jump(OpCode::JumpIfFalse, 1);
+        inconsistent.write(OpCode::Nil as u8, 1);
+        inconsistent.patch_jump(jump, inconsistent.code.len());
+        inconsistent.write(ret, 1);
+        assert_eq!(
+            inconsistent.verify(),
+            Err(VerifyError::InconsistentStack { offset: 5 })
+        );
+
+        let mut lines = chunk(&[OpCode::Nil as u8, ret]);
+        lines.lines = vec![(1, 1)];
+        assert_eq!(
+            lines.verify(),
+            Err(VerifyError::InvalidLines { covered: 1, len: 2 })
+        );
+    }
+
+    #[test]
+    fn verifies_functions() {
+        let function = Function {
+            name: "f".into(),
+            arity: 0,
+            chunk: chunk(&[OpCode::Pop as u8, OpCode::Return as u8]),
+        };
+        let mut script = Chunk::new();
+        script.write_constant_instruction(Value::Function(function.into()), 1);
+        script.write(OpCode::Return as u8, 1);
+        assert_eq!(
+            script.verify().unwrap_err().to_string(),
+            "In function 'f': Instruction at offset 1 pops from an empty stack."
+        );
+    }
+
+    #[test]
+    fn limits_upvalues_to_the_closures_made() {
+        let function = Value::Function(
+            Function {
+                name: "f".into(),
+                arity: 0,
+                chunk: chunk(&[OpCode::GetUpvalue as u8, 1, OpCode::Return as u8]),
+            }
+            .into(),
+        );
+        // Closures capturing the first local, and then also the second one.
+        let closure = |captures: &[u8]| {
+            let mut script = chunk(&[OpCode::Nil as u8, OpCode::Nil as u8]);
+            let idx = script.add_constant(function.clone());
+            let count = (captures.len() / 2) as u8;
+            for byte in [&[OpCode::Closure as u8, idx, count], captures].concat() {
+                script.write(byte, 1);
+            }
+            script.write(OpCode::Return as u8, 1);
+            script
+        };
+        assert_eq!(closure(&[1, 0, 1, 1]).verify(), Ok(()));
+        assert_eq!(
+            closure(&[1, 0]).verify().unwrap_err().to_string(),
+            "In function 'f': Instruction at offset 0 uses upvalue 1, which isn't captured."
+        );
+        assert_eq!(
+            closure(&[1, 0, 1, 2]).verify(),
+            Err(VerifyError::InvalidLocal { offset: 2, slot: 2 })
+        );
+    }
+}
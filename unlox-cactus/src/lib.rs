@@ -15,7 +15,7 @@ struct Node<T> {
 }
 
 /// Node index.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Index(usize);
 
 impl<T> Default for Cactus<T> {
@@ -75,6 +75,45 @@ impl<T> Cactus<T> {
         Some(node.data)
     }
 
+    /// Pops node out of the active stack frame, leaving it in the cactus.
+    ///
+    /// The node remains accessible by its index and as a parent of its children.
+    pub fn pop_retain(&mut self) -> Option<Index> {
+        self.stack.pop()
+    }
+
+    /// Removes a node that's not on the active stack, e.g. one left by [`Cactus::pop_retain`].
+    ///
+    /// Children of the node are left pointing at it, so they should be removed as well.
+    ///
+    /// ```
+    /// # use unlox_cactus::Cactus;
+    /// let mut cactus = Cactus::new();
+    /// cactus.push("root");
+    /// let child = cactus.push("child");
+    /// cactus.pop_retain();
+    /// assert_eq!(cactus.remove(child), Some("child"));
+    /// assert!(!cactus.contains(child));
+    /// ```
+    pub fn remove(&mut self, idx: Index) -> Option<T> {
+        debug_assert!(
+            !self.stack.contains(&idx),
+            "Node should be off the active stack"
+        );
+        self.nodes.try_remove(idx.as_usize()).map(|node| node.data)
+    }
+
+    /// Returns the indices of the nodes on the active stack, bottom first.
+    pub fn stack(&self) -> &[Index] {
+        &self.stack
+    }
+
+    /// Iterates over all nodes, whether they're on the active stack or not, in no particular
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = (Index, &T)> {
+        self.nodes.iter().map(|(i, node)| (Index(i), &node.data))
+    }
+
     /// Returns index of the parent's node.
     ///
     /// # Panics if node doesn't exist
@@ -91,6 +130,61 @@ impl<T> Cactus<T> {
     pub fn node_data_mut(&mut self, idx: Index) -> Option<&mut T> {
         self.nodes.get_mut(idx.as_usize()).map(|n| &mut n.data)
     }
+
+    /// Iterates over the node at `idx` and its ancestors, up to and including the root.
+    ///
+    /// ```
+    /// # use unlox_cactus::Cactus;
+    /// let mut cactus = Cactus::new();
+    /// cactus.push("root");
+    /// let child = cactus.push("child");
+    /// let data: Vec<_> = cactus.ancestors(child).map(|(_, data)| *data).collect();
+    /// assert_eq!(data, ["child", "root"]);
+    /// ```
+    ///
+    /// # Panics if node doesn't exist
+    pub fn ancestors(&self, idx: Index) -> impl Iterator<Item = (Index, &T)> {
+        std::iter::successors(Some(idx), |idx| self.parent(*idx))
+            .map(|idx| (idx, &self.nodes[idx.as_usize()].data))
+    }
+
+    /// Returns the number of hops from the node at `idx` to the root.
+    ///
+    /// ```
+    /// # use unlox_cactus::Cactus;
+    /// let mut cactus = Cactus::new();
+    /// let root = cactus.push(());
+    /// let child = cactus.push(());
+    /// assert_eq!(cactus.depth_of(root), 0);
+    /// assert_eq!(cactus.depth_of(child), 1);
+    /// ```
+    ///
+    /// # Panics if node doesn't exist
+    pub fn depth_of(&self, idx: Index) -> usize {
+        self.ancestors(idx).count() - 1
+    }
+
+    /// Iterates over the other nodes with the same parent as the node at `idx`, in no particular
+    /// order.
+    ///
+    /// ```
+    /// # use unlox_cactus::Cactus;
+    /// let mut cactus = Cactus::new();
+    /// let root = cactus.push("root");
+    /// let first = cactus.push_at(root, "first");
+    /// cactus.push_at(root, "second");
+    /// let data: Vec<_> = cactus.siblings(first).map(|(_, data)| *data).collect();
+    /// assert_eq!(data, ["second"]);
+    /// ```
+    ///
+    /// # Panics if node doesn't exist
+    pub fn siblings(&self, idx: Index) -> impl Iterator<Item = (Index, &T)> {
+        let parent = self.parent(idx);
+        self.nodes
+            .iter()
+            .filter(move |(i, node)| *i != idx.as_usize() && node.parent == parent)
+            .map(|(i, node)| (Index(i), &node.data))
+    }
 }
 
 impl Index {
@@ -0,0 +1,122 @@
+use unlox_tokens::Token;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// Formats an error `message` reported at `token`, followed by the line of `src` it's on with the
+/// token underlined, e.g.
+///
+/// ```text
+/// [Line 2:9]: Expected expression.
+///   |
+/// 2 | var a = ;
+///   |         ^
+/// ```
+///
+/// Uses ANSI escape codes if `color` is set. The result ends with a newline.
+pub fn format_diagnostic(src: &str, token: &Token, message: &str, color: bool) -> String {
+    let paint = |style: &str, text: &str| {
+        if color {
+            format!("{style}{text}{RESET}")
+        } else {
+            text.to_owned()
+        }
+    };
+
+    // The line is found from the span, as lines may be counted from an input appended to `src`.
+    let start = token.lexeme.start.min(src.len());
+    let line_start = src[..start].rfind('\n').map_or(0, |idx| idx + 1);
+    let line = src[line_start..].lines().next().unwrap_or("");
+    let col_idx = usize::try_from(token.col).unwrap().saturating_sub(1);
+    // Tabs are kept, so that the carets line up with the token whatever their width.
+    let indent: String = line
+        .chars()
+        .take(col_idx)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    // Tokens spanning multiple lines are only underlined up to the end of the first one.
+    let token_len = token
+        .source_text(src)
+        .lines()
+        .next()
+        .unwrap_or("")
+        .chars()
+        .count();
+    let line_len = line.chars().count();
+    let carets = "^".repeat(token_len.min(line_len.saturating_sub(col_idx)).max(1));
+
+    let line_number = token.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    let bar = paint(BLUE, "|");
+    format!(
+        "{}\n{gutter} {bar}\n{} {bar} {line}\n{gutter} {bar} {indent}{}\n",
+        paint(RED, message),
+        paint(BLUE, &line_number),
+        paint(RED, &carets),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_tokens::TokenKind;
+
+    fn token(src: &str, lexeme: &str, line: u32, col: u32) -> Token {
+        let start = src.find(lexeme).unwrap();
+        Token {
+            kind: TokenKind::Identifier,
+            lexeme: start..start + lexeme.len(),
+            line,
+            col,
+        }
+    }
+
+    #[test]
+    fn underlines_token() {
+        let src = "print 1;\n\tprint foo + 1;\n";
+        let token = token(src, "foo", 2, 8);
+        let diagnostic = format_diagnostic(src, &token, "Undefined variable 'foo'.", false);
+        let expected = "\
+            Undefined variable 'foo'.\n  \
+              |\n\
+            2 | \tprint foo + 1;\n  \
+              | \t      ^^^\n\
+        ";
+        assert_eq!(diagnostic, expected);
+    }
+
+    #[test]
+    fn points_past_the_end() {
+        let src = "print";
+        let token = Token {
+            kind: TokenKind::Eof,
+            lexeme: 5..5,
+            line: 1,
+            col: 6,
+        };
+        let diagnostic = format_diagnostic(src, &token, "Expected expression.", false);
+        assert_eq!(
+            diagnostic,
+            "Expected expression.\n  |\n1 | print\n  |      ^\n"
+        );
+    }
+
+    #[test]
+    fn finds_line_from_span() {
+        // Lines are counted from the second input of a prompt.
+        let src = "var a;\nprint b;\n";
+        let token = token(src, "b", 1, 7);
+        let diagnostic = format_diagnostic(src, &token, "Oops.", false);
+        assert_eq!(diagnostic, "Oops.\n  |\n1 | print b;\n  |       ^\n");
+    }
+
+    #[test]
+    fn colors_output() {
+        let src = "x";
+        let token = token(src, "x", 1, 1);
+        let diagnostic = format_diagnostic(src, &token, "Oops.", true);
+        assert!(diagnostic.starts_with("\x1b[1;31mOops.\x1b[0m\n"));
+        assert!(diagnostic.ends_with("\x1b[1;31m^\x1b[0m\n"));
+    }
+}
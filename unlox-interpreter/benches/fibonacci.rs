@@ -0,0 +1,31 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use unlox_interpreter::{output::SplitOutput, Ctx, Interpreter};
+use unlox_lexer::Lexer;
+
+const CODE: &str = r#"
+    fun fib(n) {
+        if (n < 2) return n;
+        return fib(n - 1) + fib(n - 2);
+    }
+    print fib(20);
+"#;
+
+fn fibonacci(c: &mut Criterion) {
+    let ast = unlox_parse::parse(Lexer::new(CODE)).ast;
+    let locals = unlox_resolver::resolve(CODE, &ast, &mut std::io::sink()).unwrap();
+    c.bench_function("fibonacci", |b| {
+        b.iter(|| {
+            let mut out = Vec::new();
+            let mut err = Vec::new();
+            let mut ctx = Ctx {
+                src: CODE,
+                out: SplitOutput::new(&mut out, &mut err),
+            };
+            Interpreter::new().interpret(&mut ctx, &ast, &locals);
+            assert_eq!(out, b"6765\n");
+        })
+    });
+}
+
+criterion_group!(benches, fibonacci);
+criterion_main!(benches);
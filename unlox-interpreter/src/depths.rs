@@ -0,0 +1,26 @@
+use unlox_ast::ExprIdx;
+use unlox_resolver::Locals;
+
+/// Depths of local variables computed by the resolver, keyed by the expression accessing the
+/// variable and stored in a vector for fast lookup.
+#[derive(Default)]
+pub struct Depths {
+    entries: Vec<Option<usize>>,
+}
+
+impl Depths {
+    pub fn new(locals: &Locals) -> Self {
+        let len = locals.keys().map(|expr| expr.as_usize() + 1).max();
+        let mut entries = vec![None; len.unwrap_or(0)];
+        for (expr, depth) in locals {
+            entries[expr.as_usize()] = Some(*depth);
+        }
+        Self { entries }
+    }
+
+    /// Returns how many environments up the variable accessed by `expr` is defined, or `None`
+    /// if it's not a local one.
+    pub fn get(&self, expr: ExprIdx) -> Option<usize> {
+        self.entries.get(expr.as_usize()).copied().flatten()
+    }
+}
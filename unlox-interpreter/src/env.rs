@@ -1,55 +1,219 @@
 use unlox_cactus::Index;
 
-use crate::Val;
-use std::collections::HashMap;
+use crate::{
+    val::{CollectionPtr, Shared},
+    Val,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/// Number of retained environments at which the first collection happens.
+const GC_INITIAL_THRESHOLD: usize = 256;
 
 pub struct EnvCactus {
     cactus: unlox_cactus::Cactus<Env>,
     global: EnvIndex,
+    definitions: u64,
+    /// Environments popped from the active stack but kept for closures.
+    retained: Vec<EnvIndex>,
+    /// Number of retained environments at which they're collected next.
+    next_gc: usize,
 }
 
 #[derive(Default)]
 pub struct Env {
     vars: HashMap<String, Val>,
+    /// Whether a closure captured this environment or one of its descendants.
+    captured: bool,
+    /// Reference shared by the closures that captured this environment.
+    closures: Option<EnvRef>,
 }
 
 pub type EnvIndex = unlox_cactus::Index;
 
+/// Reference to an environment held by the closures that captured it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvRef(Rc<EnvIndex>);
+
+impl EnvRef {
+    pub fn index(&self) -> EnvIndex {
+        *self.0
+    }
+
+    pub(crate) fn as_ptr(&self) -> CollectionPtr {
+        Rc::as_ptr(&self.0).cast()
+    }
+
+    /// Returns the number of closures holding the reference, not counting the one kept by the
+    /// environment itself.
+    pub(crate) fn closure_count(&self) -> usize {
+        Rc::strong_count(&self.0) - 1
+    }
+}
+
 impl EnvCactus {
     /// Creates a new environment cactus stack with the `global` env used for it's root.
     pub fn with_global(global: Env) -> Self {
         let mut cactus = unlox_cactus::Cactus::new();
         let global = cactus.push(global);
-        Self { cactus, global }
+        Self {
+            cactus,
+            global,
+            definitions: 0,
+            retained: Vec::new(),
+            next_gc: GC_INITIAL_THRESHOLD,
+        }
     }
 
     pub fn global(&self) -> EnvIndex {
         self.global
     }
 
-    /// Pushes a new environment onto the current one.
-    pub fn push(&mut self, env: Env) -> EnvIndex {
-        self.cactus.push(env)
-    }
-
     pub fn push_at(&mut self, parent: EnvIndex, env: Env) -> EnvIndex {
         self.cactus.push_at(parent, env)
     }
 
     /// Pops current environemnt.
     ///
-    /// Returns `None` if on attempt to pop the global environment.
-    pub fn pop(&mut self) -> Option<Env> {
-        if self.cactus.len() == 1 {
-            return None;
+    /// Captured environments are only removed from the active stack, so that closures can
+    /// still access them, until [`EnvCactus::collect`] finds them unreachable. Does nothing on
+    /// attempt to pop the global environment.
+    pub fn pop(&mut self) {
+        let current = self.current();
+        if current == self.global {
+            return;
+        }
+        if self.cactus.node_data(current).unwrap().captured {
+            self.cactus.pop_retain();
+            self.retained.push(current);
+            if self.retained.len() >= self.next_gc {
+                self.collect();
+            }
+        } else {
+            self.cactus.pop();
+        }
+    }
+
+    /// Returns a reference to the environment at `idx` for a closure, marking it and all of its
+    /// ancestors as captured.
+    pub fn capture(&mut self, idx: EnvIndex) -> EnvRef {
+        let mut next = Some(idx);
+        while let Some(idx) = next {
+            let env = self.cactus.node_data_mut(idx).unwrap();
+            if env.captured {
+                break;
+            }
+            env.captured = true;
+            next = self.cactus.parent(idx);
         }
-        self.cactus.pop()
+        let env = self.cactus.node_data_mut(idx).unwrap();
+        env.closures
+            .get_or_insert_with(|| EnvRef(Rc::new(idx)))
+            .clone()
+    }
+
+    /// Frees the retained environments that can't be reached anymore.
+    ///
+    /// Closures are often stored in the environments they capture, so the references to an
+    /// environment don't tell if it's still in use. Instead, references to shared values are
+    /// counted within all environments, and the values with more references than that are held
+    /// by something else, e.g. the host or the interpreter's value stack. Those, along with the
+    /// active stack, are what the environments still in use are reached from.
+    pub fn collect(&mut self) {
+        // References held by environments, and in total.
+        let mut counts: HashMap<CollectionPtr, (usize, usize)> = HashMap::new();
+        for (_, env) in self.cactus.iter() {
+            for val in env.vars.values() {
+                val.visit_shared(&mut |shared| {
+                    let count = counts
+                        .entry(shared.ptr())
+                        .or_insert((0, shared.strong_count()));
+                    count.0 += 1;
+                    count.0 == 1
+                });
+            }
+        }
+
+        let mut pending = self.cactus.stack().to_vec();
+        let mut roots = Vec::new();
+        let mut seen = HashSet::new();
+        for (_, env) in self.cactus.iter() {
+            for val in env.vars.values() {
+                val.visit_shared(&mut |shared| {
+                    if !seen.insert(shared.ptr()) {
+                        return false;
+                    }
+                    let (held, total) = counts[&shared.ptr()];
+                    if held < total {
+                        match shared {
+                            Shared::Closure(env) => pending.push(env.index()),
+                            Shared::Array(array) => roots.push(Val::Array(array.clone())),
+                            Shared::Map(map) => roots.push(Val::Map(map.clone())),
+                            Shared::Class(class) => roots.push(Val::Class(class.clone())),
+                            Shared::Instance(instance) => {
+                                roots.push(Val::Instance(instance.clone()))
+                            }
+                        }
+                    }
+                    true
+                });
+            }
+        }
+        // Closures that no environment holds don't show up in the counts.
+        for (idx, env) in self.cactus.iter() {
+            if let Some(closures) = &env.closures {
+                if !counts.contains_key(&closures.as_ptr()) && closures.closure_count() > 0 {
+                    pending.push(idx);
+                }
+            }
+        }
+
+        let mut live = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut mark = |val: &Val, pending: &mut Vec<EnvIndex>| {
+            val.visit_shared(&mut |shared| {
+                if let Shared::Closure(env) = shared {
+                    pending.push(env.index());
+                }
+                seen.insert(shared.ptr())
+            })
+        };
+        for val in &roots {
+            mark(val, &mut pending);
+        }
+        while let Some(idx) = pending.pop() {
+            if !live.insert(idx) {
+                continue;
+            }
+            for val in self.cactus.node_data(idx).unwrap().vars.values() {
+                mark(val, &mut pending);
+            }
+            pending.extend(self.cactus.parent(idx));
+        }
+
+        let (retained, dead): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retained)
+            .into_iter()
+            .partition(|idx| live.contains(idx));
+        self.retained = retained;
+        for idx in dead {
+            self.cactus.remove(idx);
+        }
+        self.next_gc = (self.retained.len() * 2).max(GC_INITIAL_THRESHOLD);
     }
 
     pub fn current(&self) -> Index {
         self.cactus.current().unwrap()
     }
 
+    /// Returns a mutable reference to the environment at `idx`.
+    ///
+    /// # Panics if the environment doesn't exist
+    pub fn env_mut(&mut self, idx: EnvIndex) -> &mut Env {
+        self.cactus.node_data_mut(idx).unwrap()
+    }
+
     pub fn current_env_mut(&mut self) -> &mut Env {
         self.cactus
             .current()
@@ -57,60 +221,93 @@ impl EnvCactus {
             .expect("Should always have at least global env")
     }
 
-    /// Assigns value to variable.
-    pub fn assign_var(&mut self, name: &str, value: Val) -> Option<&Val> {
-        let slot = self.var_mut(name)?;
-        *slot = value;
-        Some(slot)
+    /// Defines a variable in the current environment.
+    pub fn define_var(&mut self, name: String, value: Val) {
+        self.definitions += 1;
+        self.current_env_mut().define_var(name, value);
     }
 
-    /// Returns a reference to the value of a variable from the current environment.
-    pub fn var(&self, name: &str) -> Option<&Val> {
-        let mut env_idx = self.cactus.current().unwrap();
+    /// Defines a variable in the global environment.
+    pub fn define_global_var(&mut self, name: String, value: Val) {
+        self.definitions += 1;
+        let global = self.global;
+        self.env_mut(global).define_var(name, value);
+    }
 
-        loop {
-            let env = self.cactus.node_data(env_idx).unwrap();
-            if let Some(val) = env.vars.get(name) {
-                break Some(val);
-            }
+    /// Returns a reference to the value of a global variable.
+    pub fn global_var(&self, name: &str) -> Option<&Val> {
+        self.cactus.node_data(self.global).unwrap().vars.get(name)
+    }
 
-            if let Some(parent) = self.cactus.parent(env_idx) {
-                env_idx = parent;
-            } else {
-                break None;
-            }
-        }
+    /// Iterates over the variables of the global environment.
+    pub fn global_vars(&self) -> impl Iterator<Item = (&str, &Val)> {
+        self.cactus.node_data(self.global).unwrap().vars()
     }
 
-    /// Returns a mutable reference to the value of a Val from the current environment.
-    pub fn var_mut(&mut self, name: &str) -> Option<&mut Val> {
-        let mut env_idx = self.cactus.current().unwrap();
+    /// Returns the number of variables defined with [`EnvCactus::define_var`] or
+    /// [`EnvCactus::define_global_var`] so far.
+    ///
+    /// Defining a variable may shadow an outer one, so depths returned by
+    /// [`EnvCactus::resolve`] are only known to be valid while this number stays the same.
+    pub fn definitions(&self) -> u64 {
+        self.definitions
+    }
 
-        loop {
-            // Current borrow checker implementation doesn't allow mutable borrows of a variable
-            // in a loop if the function also returns a reference to the variable or it's part.
-            // As a safe workaround, use non-mutable borrow in a loop and then reborrow it mutably.
-            let env = self.cactus.node_data(env_idx).unwrap();
-            if env.vars.contains_key(name) {
-                break;
-            }
+    /// Returns how many environments up from the current one the variable is defined.
+    pub fn resolve(&self, name: &str) -> Option<usize> {
+        self.ancestors(self.current())
+            .position(|(_, env)| env.vars.contains_key(name))
+    }
 
-            if let Some(parent_idx) = self.cactus.parent(env_idx) {
-                env_idx = parent_idx;
-            } else {
-                return None;
-            }
-        }
+    /// Returns a reference to the value of a variable defined `depth` environments up from the
+    /// current one.
+    pub fn var_at(&self, depth: usize, name: &str) -> Option<&Val> {
+        self.debug_assert_resolved(depth);
+        let env_idx = self.ancestor(depth)?;
+        self.cactus.node_data(env_idx).unwrap().vars.get(name)
+    }
+
+    /// Assigns value to a variable defined `depth` environments up from the current one.
+    pub fn assign_var_at(&mut self, depth: usize, name: &str, value: Val) -> Option<&Val> {
+        self.debug_assert_resolved(depth);
+        let env_idx = self.ancestor(depth)?;
+        let slot = self.env_mut(env_idx).vars.get_mut(name)?;
+        *slot = value;
+        Some(slot)
+    }
+
+    /// Iterates over the variables defined `depth` environments up from the current one.
+    pub fn vars_at(&self, depth: usize) -> Option<impl Iterator<Item = (&str, &Val)>> {
+        let env_idx = self.ancestor(depth)?;
+        Some(self.cactus.node_data(env_idx).unwrap().vars())
+    }
 
-        let var = self
-            .cactus
-            .node_data_mut(env_idx)
-            .unwrap()
-            .vars
-            .get_mut(name)
-            .expect("Presence of variable should already be asserted");
+    /// Checks that a depth from the resolver doesn't reach past the global environment, which
+    /// would mean the environments got out of step with the scopes it saw.
+    fn debug_assert_resolved(&self, depth: usize) {
+        debug_assert!(
+            depth <= self.depth_of(self.current()),
+            "Resolved depth {depth} reaches past the global environment"
+        );
+    }
+
+    fn ancestor(&self, depth: usize) -> Option<EnvIndex> {
+        self.ancestors(self.current())
+            .nth(depth)
+            .map(|(idx, _)| idx)
+    }
+
+    /// Iterates over the environment at `start` and its ancestors, up to and including the global
+    /// one.
+    ///
+    /// # Panics if the environment doesn't exist
+    pub fn ancestors(&self, start: EnvIndex) -> impl Iterator<Item = (EnvIndex, &Env)> {
+        self.cactus.ancestors(start)
+    }
 
-        Some(var)
+    /// Returns how many environments up from the one at `idx` the global environment is.
+    pub fn depth_of(&self, idx: EnvIndex) -> usize {
+        self.cactus.depth_of(idx)
     }
 }
 
@@ -124,4 +321,73 @@ impl Env {
     pub fn define_var(&mut self, name: String, value: Val) {
         self.vars.insert(name, value);
     }
+
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &Val)> {
+        self.vars.iter().map(|(name, val)| (name.as_str(), val))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Callable;
+
+    /// Returns a function closing over the environment at `idx`.
+    fn closure(envs: &mut EnvCactus, idx: EnvIndex) -> Val {
+        Val::Callable(Callable::Function {
+            name: String::new(),
+            params: Vec::new(),
+            body: Vec::new(),
+            closure: envs.capture(idx),
+            this: None,
+        })
+    }
+
+    #[test]
+    fn collects_unreachable_environments() {
+        let mut envs = EnvCactus::with_global(Env::new());
+        let global = envs.global();
+        for _ in 0..10 * GC_INITIAL_THRESHOLD {
+            // { fun f() {} }
+            let block = envs.push_at(global, Env::new());
+            let f = closure(&mut envs, block);
+            envs.define_var("f".to_owned(), f);
+            envs.pop();
+        }
+        assert!(envs.cactus.len() <= GC_INITIAL_THRESHOLD);
+    }
+
+    #[test]
+    fn keeps_environments_of_reachable_closures() {
+        let mut envs = EnvCactus::with_global(Env::new());
+        let global = envs.global();
+        let block = envs.push_at(global, Env::new());
+        let held = closure(&mut envs, block);
+        envs.define_var("f".to_owned(), held.clone());
+        envs.pop();
+
+        envs.collect();
+        assert!(envs.cactus.contains(block));
+        let array = Val::Array(Rc::new(std::cell::RefCell::new(vec![held])));
+        envs.define_global_var("fs".to_owned(), array);
+        envs.collect();
+        assert!(envs.cactus.contains(block));
+        envs.define_global_var("fs".to_owned(), Val::Nil);
+        envs.collect();
+        assert!(!envs.cactus.contains(block));
+    }
+
+    #[test]
+    fn walks_ancestors() {
+        let mut envs = EnvCactus::with_global(Env::new());
+        let global = envs.global();
+        let outer = envs.push_at(global, Env::new());
+        envs.define_var("x".to_owned(), Val::Nil);
+        let inner = envs.push_at(outer, Env::new());
+        let indices: Vec<_> = envs.ancestors(inner).map(|(idx, _)| idx).collect();
+        assert_eq!(indices, [inner, outer, global]);
+        assert_eq!(envs.depth_of(inner), 2);
+        assert_eq!(envs.depth_of(global), 0);
+        assert_eq!(envs.resolve("x"), Some(1));
+    }
 }
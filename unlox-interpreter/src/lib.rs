@@ -1,43 +1,350 @@
-use env::{Env, EnvCactus, EnvIndex};
+use depths::Depths;
+use env::{Env, EnvCactus};
 use output::Output;
-use std::{
-    io::Write,
-    ops::ControlFlow,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{cell::RefCell, collections::HashMap, io::Write, rc::Rc};
 use unlox_ast::{Ast, Expr, ExprIdx, Stmt, StmtIdx, Token, TokenKind};
-use val::{Callable, Val};
+use unlox_resolver::Locals;
+pub use val::{Callable, LoxClass, LoxInstance, Native, NativeFn, Val};
+use var_cache::VarCache;
 
+mod depths;
 mod env;
 pub mod output;
+mod stdlib;
 mod val;
+mod var_cache;
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug)]
 pub enum Error {
-    #[error("[Line {}]: Operand must be a number.", operator.line)]
-    ExpectedNumber { operator: Token },
-    #[error("[Line {}]: Operands must be numbers.", operator.line)]
-    ExpectedNumbers { operator: Token },
-    #[error("[Line {}]: Operands must be two numbers or two strings.", operator.line)]
-    ExpectedNumbersOrStrings { operator: Token },
-    #[error("[Line {}]: Undefined variable {}.", token.line, name)]
-    UndefinedVariable { name: String, token: Token },
-    #[error("[Line {}]: Can only call functions and classes.", paren.line)]
-    BadCall { paren: Token },
-    #[error("[Line {}]: Expected {expected} arguments but got {got}.", paren.line)]
+    ExpectedNumber {
+        operator: Token,
+        got: &'static str,
+    },
+    ExpectedNumbers {
+        operator: Token,
+        side: Side,
+        got: &'static str,
+    },
+    ExpectedString {
+        operator: Token,
+        side: Side,
+        got: &'static str,
+    },
+    ExpectedNumbersOrStrings {
+        operator: Token,
+        side: Side,
+        got: &'static str,
+    },
+    FractionalPowerOfNegative {
+        operator: Token,
+    },
+    DivisionByZero {
+        operator: Token,
+    },
+    NanResult {
+        operator: Token,
+    },
+    ModuloByZero {
+        operator: Token,
+    },
+    UndefinedVariable {
+        name: String,
+        token: Token,
+    },
+    UninitializedVariable {
+        name: String,
+        token: Token,
+    },
+    NotAnInstance {
+        token: Token,
+    },
+    UndefinedProperty {
+        name: String,
+        token: Token,
+    },
+    NotIndexable {
+        bracket: Token,
+        got: &'static str,
+    },
+    IndexNotANumber {
+        bracket: Token,
+        got: &'static str,
+    },
+    IndexOutOfBounds {
+        bracket: Token,
+        index: String,
+        len: usize,
+    },
+    NotIterable {
+        name: Token,
+        got: &'static str,
+    },
+    EmptyArray {
+        paren: Token,
+    },
+    SuperclassNotAClass {
+        token: Token,
+    },
+    StackOverflow {
+        paren: Token,
+    },
+    BadCall {
+        paren: Token,
+    },
     WrongNumberOfArgs {
         paren: Token,
         expected: usize,
         got: usize,
     },
-    #[error("[Line {}]: The program terminated due to a syntax error: {err}", token.line)]
-    Parsing { token: Token, err: String },
+    TypeMismatch {
+        paren: Token,
+        param: String,
+        expected: String,
+        got: &'static str,
+    },
+    UnknownType {
+        name: String,
+        token: Token,
+    },
+    UndefinedFunction {
+        name: String,
+    },
+    NotAFunction {
+        name: String,
+    },
+    WrongNumberOfHostArgs {
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+    /// Error raised by a call made by the host rather than the code the function runs, e.g.
+    /// an argument of the wrong type.
+    HostCall {
+        name: String,
+        error: Box<Error>,
+    },
+    StepLimitExceeded {
+        limit: u64,
+    },
+    Parsing {
+        token: Token,
+        err: String,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(token) = self.token() {
+            write!(f, "[Line {}:{}]: ", token.line, token.col)?;
+        }
+        f.write_str(&self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Returns the message of the error, without the location it's reported at.
+    pub fn message(&self) -> String {
+        match self {
+            Error::ExpectedNumber { got, .. } => format!("Operand must be a number, got {got}."),
+            Error::ExpectedNumbers { side, got, .. } => {
+                format!("{side} operand must be a number, got {got}.")
+            }
+            Error::ExpectedString { side, got, .. } => {
+                format!("{side} operand must be a string, got {got}.")
+            }
+            Error::ExpectedNumbersOrStrings { side, got, .. } => {
+                format!("{side} operand must be a number or a string, got {got}.")
+            }
+            Error::FractionalPowerOfNegative { .. } => {
+                "Can't raise a negative number to a fractional power.".to_owned()
+            }
+            Error::DivisionByZero { .. } => "Division by zero.".to_owned(),
+            Error::NanResult { .. } => "Result is not a number.".to_owned(),
+            Error::ModuloByZero { .. } => "Modulo by zero.".to_owned(),
+            Error::UndefinedVariable { name, .. } => format!("Undefined variable '{name}'."),
+            Error::UninitializedVariable { name, .. } => {
+                format!("Variable '{name}' used before being initialized.")
+            }
+            Error::NotAnInstance { .. } => "Only instances have properties.".to_owned(),
+            Error::UndefinedProperty { name, .. } => format!("Undefined property '{name}'."),
+            Error::NotIndexable { got, .. } => format!("Only arrays can be indexed, got {got}."),
+            Error::IndexNotANumber { got, .. } => {
+                format!("Array index must be a number, got {got}.")
+            }
+            Error::IndexOutOfBounds { index, len, .. } => {
+                format!("Index {index} is out of bounds for an array of length {len}.")
+            }
+            Error::NotIterable { got, .. } => format!("Can only iterate over arrays, got {got}."),
+            Error::EmptyArray { .. } => "Can't pop from an empty array.".to_owned(),
+            Error::SuperclassNotAClass { .. } => "Superclass must be a class.".to_owned(),
+            Error::StackOverflow { .. } => "Stack overflow.".to_owned(),
+            Error::BadCall { .. } => "Can only call functions and classes.".to_owned(),
+            Error::WrongNumberOfArgs { expected, got, .. } => {
+                format!("Expected {expected} arguments but got {got}.")
+            }
+            Error::TypeMismatch {
+                param,
+                expected,
+                got,
+                ..
+            } => format!("Expected argument {param} to be {expected} but got {got}."),
+            Error::UnknownType { name, .. } => format!("Unknown type {name}."),
+            Error::UndefinedFunction { name } => format!("Undefined function '{name}'."),
+            Error::NotAFunction { name } => format!("'{name}' is not a function."),
+            Error::WrongNumberOfHostArgs {
+                name,
+                expected,
+                got,
+            } => format!("Function '{name}' expects {expected} arguments but got {got}."),
+            Error::HostCall { name, error } => {
+                format!("Call to '{name}' failed: {}", error.message())
+            }
+            Error::StepLimitExceeded { limit } => format!("Step limit of {limit} exceeded."),
+            Error::Parsing { err, .. } => {
+                format!("The program terminated due to a syntax error: {err}")
+            }
+        }
+    }
+
+    /// Returns the token the error is reported at, if it comes from the program rather than the
+    /// host.
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Error::ExpectedNumber { operator, .. }
+            | Error::ExpectedNumbers { operator, .. }
+            | Error::ExpectedString { operator, .. }
+            | Error::ExpectedNumbersOrStrings { operator, .. }
+            | Error::FractionalPowerOfNegative { operator }
+            | Error::DivisionByZero { operator }
+            | Error::NanResult { operator }
+            | Error::ModuloByZero { operator } => Some(operator),
+            Error::UndefinedVariable { token, .. }
+            | Error::UninitializedVariable { token, .. }
+            | Error::NotAnInstance { token }
+            | Error::UndefinedProperty { token, .. }
+            | Error::SuperclassNotAClass { token }
+            | Error::UnknownType { token, .. }
+            | Error::Parsing { token, .. } => Some(token),
+            Error::NotIterable { name, .. } => Some(name),
+            Error::NotIndexable { bracket, .. }
+            | Error::IndexNotANumber { bracket, .. }
+            | Error::IndexOutOfBounds { bracket, .. } => Some(bracket),
+            Error::EmptyArray { paren }
+            | Error::StackOverflow { paren }
+            | Error::BadCall { paren }
+            | Error::WrongNumberOfArgs { paren, .. }
+            | Error::TypeMismatch { paren, .. } => Some(paren),
+            Error::UndefinedFunction { .. }
+            | Error::NotAFunction { .. }
+            | Error::WrongNumberOfHostArgs { .. }
+            | Error::HostCall { .. }
+            | Error::StepLimitExceeded { .. } => None,
+        }
+    }
+}
+
+/// Operand of a binary operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Left => write!(f, "Left"),
+            Side::Right => write!(f, "Right"),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct Interpreter {
     env_tree: EnvCactus,
+    tasks: Vec<Task>,
+    vals: Vec<Val>,
+    depths: Depths,
+    var_cache: VarCache,
+    type_checks: bool,
+    nan_is_error: bool,
+    /// Arrays iterated by the for-in loops being executed, innermost last, along with the index
+    /// of their next element.
+    iterators: Vec<(Rc<RefCell<Vec<Val>>>, usize)>,
+    /// Lox functions currently being executed, outermost first.
+    call_stack: Vec<CallFrame>,
+    /// Copy of `call_stack` taken when the last runtime error was raised.
+    error_call_stack: Vec<CallFrame>,
+    call_depth_limit: usize,
+    /// Number of statements executed and expressions evaluated by the current `interpret` call.
+    steps: u64,
+    step_limit: u64,
+}
+
+/// Call of a Lox function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    /// Empty for lambdas.
+    pub name: String,
+    pub call_site_line: u32,
+}
+
+/// Maximum number of nested calls, unless set by [`Interpreter::set_call_depth_limit`].
+///
+/// Calls don't consume the native stack, so the limit only guards against runaway recursion
+/// exhausting memory.
+pub const DEFAULT_CALL_DEPTH_LIMIT: usize = 200_000;
+
+/// Pending unit of work.
+///
+/// Instead of recursing on nested statements and expressions, the interpreter schedules them on
+/// an explicit stack of tasks, so nesting depth of a program is bounded by the heap rather than
+/// the native stack. Evaluated expressions leave their result on the value stack, where it is
+/// picked up by the task scheduled to run after them.
+#[derive(Debug, Clone, Copy)]
+enum Task {
+    Execute(StmtIdx),
+    Evaluate(ExprIdx),
+    /// Pops the condition of `Stmt::If` and schedules one of its branches.
+    Branch(StmtIdx),
+    /// Pops the condition of `Stmt::While` or `Stmt::DoWhile` and schedules the body followed by
+    /// the next iteration.
+    Loop(StmtIdx),
+    /// Evaluates the increment and re-evaluates the condition of `Stmt::While` or
+    /// `Stmt::DoWhile`. Marks the point `break` and `continue` unwind to.
+    NextIteration(StmtIdx),
+    /// Pops the array of `Stmt::ForIn` and starts iterating over it.
+    Iterate(StmtIdx),
+    /// Binds the next element of the array iterated by `Stmt::ForIn` and schedules the body, or
+    /// leaves the loop past the last one. Marks the point `break` and `continue` unwind to.
+    NextElement(StmtIdx),
+    /// Unwinds to the nearest `NextIteration` or `NextElement`, leaving the loop.
+    Break,
+    /// Unwinds to the nearest `NextIteration` or `NextElement`, skipping the rest of the loop
+    /// body.
+    Continue,
+    Print,
+    /// Pops the value of an expression statement.
+    Discard,
+    /// Pops the initializer of `Stmt::VarDecl` and defines the variable.
+    Define(StmtIdx),
+    /// Pops the returned value and unwinds to the nearest `CallFrame`.
+    Return,
+    /// Pops the operands of `Expr::Unary`, `Expr::Typeof`, `Expr::Binary`, `Expr::Assign`,
+    /// `Expr::Call`, `Expr::Get`, `Expr::Set`, `Expr::Array`, `Expr::Index` or
+    /// `Expr::IndexAssign` and pushes the result.
+    Apply(ExprIdx),
+    /// Inspects the left operand of `Expr::Logical` and evaluates the right one if needed.
+    ShortCircuit(ExprIdx),
+    /// Pops the condition of `Expr::Ternary` and evaluates one of its branches.
+    Choose(ExprIdx),
+    /// Leaves a block.
+    PopEnv,
+    /// Marks the point a function returns to. Reaching it without a `Return` yields `nil`.
+    CallFrame,
 }
 
 pub struct Ctx<'a, Out> {
@@ -55,161 +362,740 @@ impl Interpreter {
     pub fn new() -> Self {
         Self {
             env_tree: EnvCactus::with_global(new_global_env()),
+            tasks: Vec::new(),
+            vals: Vec::new(),
+            depths: Depths::default(),
+            var_cache: VarCache::default(),
+            type_checks: false,
+            nan_is_error: true,
+            iterators: Vec::new(),
+            call_stack: Vec::new(),
+            error_call_stack: Vec::new(),
+            call_depth_limit: DEFAULT_CALL_DEPTH_LIMIT,
+            steps: 0,
+            step_limit: u64::MAX,
         }
     }
+
+    /// Enables checking arguments against the type annotations of function parameters.
+    ///
+    /// Supported annotations are `number`, `string`, `bool`, `function` and `any`.
+    pub fn set_type_checks(&mut self, enabled: bool) {
+        self.type_checks = enabled;
+    }
+
+    /// Sets whether arithmetic or native functions producing NaN, e.g. `inf - inf` or
+    /// `sqrt(-1)`, are a runtime error. Enabled by default.
+    pub fn set_nan_is_error(&mut self, enabled: bool) {
+        self.nan_is_error = enabled;
+    }
+
+    /// Sets the maximum number of nested function calls, exceeding it is a runtime error.
+    pub fn set_call_depth_limit(&mut self, limit: usize) {
+        self.call_depth_limit = limit;
+    }
+
+    /// Sets the maximum number of statements executed and expressions evaluated by a single
+    /// [`Interpreter::interpret`] call, exceeding it is a runtime error. Unlimited by default.
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    /// Defines a global function implemented in Rust.
+    ///
+    /// The function is called with the closing parenthesis of the call, to report errors at, and
+    /// exactly `arity` arguments, a call with a different number of them is a runtime error. It can
+    /// capture host state, e.g. to collect values from the program.
+    pub fn define_native(
+        &mut self,
+        name: impl Into<String>,
+        arity: usize,
+        f: impl Fn(&Token, &[Val]) -> Result<Val> + 'static,
+    ) {
+        let global = self.env_tree.global();
+        stdlib::define_native(self.env_tree.env_mut(global), name.into(), arity, f);
+    }
+
+    /// Defines a global variable, or overwrites it if it already exists.
+    pub fn set_global_var(&mut self, name: &str, val: Val) {
+        self.env_tree.define_global_var(name.to_owned(), val);
+    }
+
+    /// Iterates over the global variables, including the native functions.
+    pub fn global_vars(&self) -> impl Iterator<Item = (&str, &Val)> {
+        self.env_tree.global_vars()
+    }
+
+    /// Iterates over the variables of the environment `depth` environments up from the current
+    /// one, or returns `None` if there are fewer environments. Between calls to
+    /// [`Interpreter::interpret`] only the global one is left.
+    pub fn vars_at_depth(&self, depth: usize) -> Option<impl Iterator<Item = (&str, &Val)>> {
+        self.env_tree.vars_at(depth)
+    }
+
+    /// Returns the Lox functions that were being executed when the last runtime error was raised,
+    /// outermost first. Functions that returned the result of a call right away aren't included,
+    /// their frame is taken by the function they called.
+    pub fn error_call_stack(&self) -> &[CallFrame] {
+        &self.error_call_stack
+    }
+
+    /// Returns the value of a global variable, e.g. one defined by a program that already ran.
+    pub fn get_global_var(&self, name: &str) -> Option<&Val> {
+        self.env_tree.global_var(name)
+    }
+
+    /// Removes the global variables defined by programs, keeping the standard library and the
+    /// functions defined with [`Interpreter::define_native`]. Built-ins that were overwritten get
+    /// restored.
+    ///
+    /// Must be called between calls to [`Interpreter::interpret`].
+    pub fn reset_globals(&mut self) {
+        let natives: Vec<_> = self
+            .global_vars()
+            .filter(|(name, val)| {
+                matches!(val, Val::Callable(Callable::Native(native)) if native.name == *name)
+            })
+            .map(|(name, val)| (name.to_owned(), val.clone()))
+            .collect();
+        let mut global = new_global_env();
+        for (name, val) in natives {
+            global.define_var(name, val);
+        }
+        self.env_tree = EnvCactus::with_global(global);
+        self.var_cache.clear();
+    }
 }
 
 fn new_global_env() -> Env {
     let mut global = Env::new();
-    global.define_var("clock".to_owned(), Val::Callable(Callable::Clock));
+    stdlib::define(&mut global);
     global
 }
 
 impl Interpreter {
-    pub fn interpret(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast) {
+    /// Executes `ast`, with variables resolved to `locals` by [`unlox_resolver::resolve`].
+    pub fn interpret(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, locals: &Locals) {
+        if let Err(error) = self.try_interpret(ctx, ast, locals) {
+            // Buffered output printed before the error should show up before it.
+            ctx.out.out().flush().unwrap();
+            writeln!(ctx.out.err(), "{error}").unwrap();
+        }
+    }
+
+    /// Like [`Interpreter::interpret`], but returns the runtime error instead of reporting it.
+    pub fn try_interpret(
+        &mut self,
+        ctx: &mut Ctx<impl Output>,
+        ast: &Ast,
+        locals: &Locals,
+    ) -> Result<()> {
+        self.start(locals);
         for stmt in ast.roots() {
-            if let Err(error) = self.execute(ctx, ast, *stmt) {
-                writeln!(ctx.out.err(), "{error}").unwrap();
-                return;
-            }
+            self.try_execute_root(ctx, ast, *stmt)?;
         }
+        Ok(())
     }
 
-    fn execute(
+    /// Prepares for executing the top-level statements of an `ast` one at a time, with
+    /// [`Interpreter::try_execute_root`]. The step limit applies to all of them together.
+    pub fn start(&mut self, locals: &Locals) {
+        self.depths = Depths::new(locals);
+        self.var_cache.clear();
+        self.steps = 0;
+    }
+
+    /// Executes `stmt`, one of the roots of the `ast` passed to the last
+    /// [`Interpreter::start`].
+    pub fn try_execute_root(
         &mut self,
         ctx: &mut Ctx<impl Output>,
         ast: &Ast,
         stmt: StmtIdx,
-    ) -> Result<ControlFlow<Val>> {
-        match ast.stmt(stmt) {
-            Stmt::If {
-                cond,
-                then_branch,
-                else_branch,
-            } => {
-                if self.evaluate(ctx, ast, *cond)?.is_truthy() {
-                    self.execute(ctx, ast, *then_branch)
+    ) -> Result<()> {
+        self.run(ctx, ast, Task::Execute(stmt))
+    }
+
+    /// Calls the global function `name` with `args`, returning its result.
+    ///
+    /// The function must have been defined by a program interpreted from `ast`, with the
+    /// `locals` it was resolved to. Like [`Interpreter::start`], this makes it the current program.
+    pub fn call_fn(
+        &mut self,
+        ctx: &mut Ctx<impl Output>,
+        ast: &Ast,
+        locals: &Locals,
+        name: &str,
+        args: Vec<Val>,
+    ) -> Result<Val> {
+        let callable = match self.get_global_var(name) {
+            Some(Val::Callable(callable)) => callable.clone(),
+            Some(_) => {
+                return Err(Error::NotAFunction {
+                    name: name.to_owned(),
+                })
+            }
+            None => {
+                return Err(Error::UndefinedFunction {
+                    name: name.to_owned(),
+                })
+            }
+        };
+        if callable.arity() != args.len() {
+            return Err(Error::WrongNumberOfHostArgs {
+                name: name.to_owned(),
+                expected: callable.arity(),
+                got: args.len(),
+            });
+        }
+        self.start(locals);
+        let tasks_base = self.tasks.len();
+        let vals_base = self.vals.len();
+        self.vals.extend(args);
+        // There's no call expression to point the errors of the call itself at.
+        if let Err(error) = self.call(ctx, &Token::default(), callable, vals_base) {
+            self.vals.truncate(vals_base);
+            return Err(Error::HostCall {
+                name: name.to_owned(),
+                error: Box::new(error),
+            });
+        }
+        self.run_pending(ctx, ast, tasks_base, vals_base)?;
+        Ok(self.pop_val())
+    }
+
+    /// Runs `task` along with everything it schedules.
+    ///
+    /// On error, the pending work is discarded and the environments are restored.
+    fn run(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, task: Task) -> Result<()> {
+        let tasks_base = self.tasks.len();
+        let vals_base = self.vals.len();
+        self.tasks.push(task);
+        self.run_pending(ctx, ast, tasks_base, vals_base)
+    }
+
+    /// Runs the tasks scheduled above `tasks_base`, restoring both stacks to the given bases on
+    /// error.
+    fn run_pending(
+        &mut self,
+        ctx: &mut Ctx<impl Output>,
+        ast: &Ast,
+        tasks_base: usize,
+        vals_base: usize,
+    ) -> Result<()> {
+        while self.tasks.len() > tasks_base {
+            let task = self.tasks.pop().unwrap();
+            if let Err(error) = self.step(ctx, ast, task) {
+                self.error_call_stack.clone_from(&self.call_stack);
+                while self.tasks.len() > tasks_base {
+                    self.unwind_task();
+                }
+                self.vals.truncate(vals_base);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the top task, leaving the block it belongs to if necessary.
+    fn unwind_task(&mut self) -> Option<Task> {
+        let task = self.tasks.pop()?;
+        match task {
+            Task::PopEnv => {
+                self.env_tree.pop();
+            }
+            Task::CallFrame => {
+                self.call_stack.pop();
+            }
+            Task::NextElement(_) => {
+                self.iterators.pop();
+            }
+            _ => (),
+        }
+        Some(task)
+    }
+
+    fn step(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, task: Task) -> Result<()> {
+        if let Task::Execute(_) | Task::Evaluate(_) = task {
+            self.steps += 1;
+            if self.steps > self.step_limit {
+                return Err(Error::StepLimitExceeded {
+                    limit: self.step_limit,
+                });
+            }
+        }
+        match task {
+            Task::Execute(stmt) => self.execute(ctx, ast, stmt)?,
+            Task::Evaluate(expr) => self.evaluate(ctx, ast, expr)?,
+            Task::Branch(stmt) => {
+                let Stmt::If {
+                    then_branch,
+                    else_branch,
+                    ..
+                } = ast.stmt(stmt)
+                else {
+                    unreachable!()
+                };
+                if self.pop_val().is_truthy() {
+                    self.tasks.push(Task::Execute(*then_branch));
                 } else if let Some(else_branch) = else_branch {
-                    self.execute(ctx, ast, *else_branch)
-                } else {
-                    Ok(ControlFlow::Continue(()))
+                    self.tasks.push(Task::Execute(*else_branch));
                 }
             }
-            Stmt::While { cond, body } => {
-                while self.evaluate(ctx, ast, *cond)?.is_truthy() {
-                    let control_flow = self.execute(ctx, ast, *body)?;
-                    if control_flow.is_break() {
-                        return Ok(control_flow);
+            Task::Loop(stmt) => {
+                let (Stmt::While { body, .. } | Stmt::DoWhile { body, .. }) = ast.stmt(stmt) else {
+                    unreachable!()
+                };
+                if self.pop_val().is_truthy() {
+                    self.tasks.push(Task::NextIteration(stmt));
+                    self.tasks.push(Task::Execute(*body));
+                }
+            }
+            Task::NextIteration(stmt) => {
+                let (cond, inc) = match ast.stmt(stmt) {
+                    Stmt::While { cond, inc, .. } => (cond, inc),
+                    Stmt::DoWhile { cond, .. } => (cond, &None),
+                    _ => unreachable!(),
+                };
+                self.tasks.push(Task::Loop(stmt));
+                self.tasks.push(Task::Evaluate(*cond));
+                if let Some(inc) = inc {
+                    self.tasks.push(Task::Discard);
+                    self.tasks.push(Task::Evaluate(*inc));
+                }
+            }
+            Task::Iterate(stmt) => {
+                let Stmt::ForIn { name, .. } = ast.stmt(stmt) else {
+                    unreachable!()
+                };
+                let array = match self.pop_val() {
+                    Val::Array(array) => array,
+                    val => {
+                        return Err(Error::NotIterable {
+                            name: name.clone(),
+                            got: val.type_name(),
+                        })
+                    }
+                };
+                self.iterators.push((array, 0));
+                self.tasks.push(Task::NextElement(stmt));
+            }
+            Task::NextElement(stmt) => {
+                let Stmt::ForIn { name, body, .. } = ast.stmt(stmt) else {
+                    unreachable!()
+                };
+                let (array, index) = self.iterators.last_mut().unwrap();
+                // The length is checked on each iteration, as the body may modify the array.
+                let Some(element) = array.borrow().get(*index).cloned() else {
+                    self.iterators.pop();
+                    return Ok(());
+                };
+                *index += 1;
+                self.tasks.push(Task::NextElement(stmt));
+                self.env_tree.push_at(self.env_tree.current(), Env::new());
+                self.env_tree
+                    .define_var(name.source_text(ctx.src).to_owned(), element);
+                self.tasks.push(Task::PopEnv);
+                self.tasks.push(Task::Execute(*body));
+            }
+            Task::Break => {
+                while let Some(task) = self.unwind_task() {
+                    if let Task::NextIteration(_) | Task::NextElement(_) = task {
+                        break;
                     }
                 }
-                Ok(ControlFlow::Continue(()))
             }
-            Stmt::Print(expr) => {
-                let val = self.evaluate(ctx, ast, *expr)?;
+            Task::Continue => {
+                while !matches!(
+                    self.tasks.last(),
+                    None | Some(Task::NextIteration(_) | Task::NextElement(_))
+                ) {
+                    self.unwind_task();
+                }
+            }
+            Task::Print => {
+                let val = self.pop_val();
                 writeln!(ctx.out.out(), "{val}").unwrap();
-                Ok(ControlFlow::Continue(()))
             }
-            Stmt::Return(_, expr) => {
-                let val = expr
-                    .map(|e| self.evaluate(ctx, ast, e))
-                    .transpose()?
-                    .unwrap_or_default();
-                Ok(ControlFlow::Break(val))
-            }
-            Stmt::VarDecl { name, init } => {
-                let init = match init {
-                    Some(init) => self.evaluate(ctx, ast, *init)?,
-                    None => Val::Nil,
+            Task::Discard => {
+                self.pop_val();
+            }
+            Task::Define(stmt) => {
+                let Stmt::VarDecl { name, .. } = ast.stmt(stmt) else {
+                    unreachable!()
                 };
+                let init = self.pop_val();
                 self.env_tree
-                    .current_env_mut()
-                    .define_var(ctx.src[name.lexeme.clone()].to_owned(), init);
-                Ok(ControlFlow::Continue(()))
+                    .define_var(name.source_text(ctx.src).to_owned(), init);
+            }
+            Task::Return => loop {
+                match self.unwind_task() {
+                    Some(Task::CallFrame) => break,
+                    Some(_) => (),
+                    None => unreachable!("the resolver rejects a return outside of any function"),
+                }
+            },
+            Task::Apply(expr) => self.apply(ctx, ast, expr)?,
+            Task::ShortCircuit(expr) => {
+                let Expr::Logical(operator, _, right) = ast.expr(expr) else {
+                    unreachable!()
+                };
+                let left = self.vals.last().unwrap();
+                let is_short_circuit = match operator.kind {
+                    TokenKind::Or => left.is_truthy(),
+                    _ => !left.is_truthy(),
+                };
+                if !is_short_circuit {
+                    self.pop_val();
+                    self.tasks.push(Task::Evaluate(*right));
+                }
+            }
+            Task::Choose(expr) => {
+                let Expr::Ternary {
+                    then_expr,
+                    else_expr,
+                    ..
+                } = ast.expr(expr)
+                else {
+                    unreachable!()
+                };
+                let branch = if self.pop_val().is_truthy() {
+                    then_expr
+                } else {
+                    else_expr
+                };
+                self.tasks.push(Task::Evaluate(*branch));
+            }
+            Task::PopEnv => {
+                self.env_tree.pop();
+            }
+            Task::CallFrame => {
+                self.call_stack.pop();
+                self.vals.push(Val::Nil);
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, stmt: StmtIdx) -> Result<()> {
+        match ast.stmt(stmt) {
+            Stmt::If { cond, .. } => {
+                self.tasks.push(Task::Branch(stmt));
+                self.tasks.push(Task::Evaluate(*cond));
+            }
+            Stmt::While { cond, .. } => {
+                self.tasks.push(Task::Loop(stmt));
+                self.tasks.push(Task::Evaluate(*cond));
+            }
+            Stmt::DoWhile { body, .. } => {
+                self.tasks.push(Task::NextIteration(stmt));
+                self.tasks.push(Task::Execute(*body));
+            }
+            Stmt::ForIn { iterable, .. } => {
+                self.tasks.push(Task::Iterate(stmt));
+                self.tasks.push(Task::Evaluate(*iterable));
+            }
+            Stmt::Print(expr) => {
+                self.tasks.push(Task::Print);
+                self.tasks.push(Task::Evaluate(*expr));
+            }
+            Stmt::Return(_, expr) => {
+                self.tasks.push(Task::Return);
+                match expr {
+                    Some(expr) => self.tasks.push(Task::Evaluate(*expr)),
+                    None => self.vals.push(Val::Nil),
+                }
+            }
+            Stmt::Break(_) => self.tasks.push(Task::Break),
+            Stmt::Continue(_) => self.tasks.push(Task::Continue),
+            Stmt::VarDecl { init, .. } => {
+                self.tasks.push(Task::Define(stmt));
+                match init {
+                    Some(init) => self.tasks.push(Task::Evaluate(*init)),
+                    None => self.vals.push(Val::Uninitialized),
+                }
             }
             Stmt::Expression(expr) => {
-                self.evaluate(ctx, ast, *expr)?;
-                Ok(ControlFlow::Continue(()))
+                self.tasks.push(Task::Discard);
+                self.tasks.push(Task::Evaluate(*expr));
             }
             Stmt::Block(stmts) => {
-                self.execute_block(ctx, ast, stmts, Env::new(), self.env_tree.current())
+                self.env_tree.push_at(self.env_tree.current(), Env::new());
+                self.tasks.push(Task::PopEnv);
+                self.schedule_block(stmts);
             }
             Stmt::Function { name, params, body } => {
+                let closure = self.env_tree.capture(self.env_tree.current());
                 let callable = Callable::Function {
-                    name: ctx.src[name.lexeme.clone()].to_owned(),
+                    name: name.source_text(ctx.src).to_owned(),
                     params: params.clone(),
                     body: body.clone(),
+                    closure,
+                    this: None,
                 };
-                self.env_tree.current_env_mut().define_var(
-                    ctx.src[name.lexeme.clone()].to_owned(),
+                self.env_tree.define_var(
+                    name.source_text(ctx.src).to_owned(),
                     Val::Callable(callable),
                 );
-                Ok(ControlFlow::Continue(()))
             }
-            Stmt::ParseErr(token, err) => Err(Error::Parsing {
-                token: token.clone(),
-                err: err.clone(),
-            }),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let superclass = superclass
+                    .as_ref()
+                    .map(|token| self.superclass(ctx, token))
+                    .transpose()?;
+                let closure = match &superclass {
+                    Some(superclass) => {
+                        // Methods of a subclass see `super` in an environment between the class
+                        // declaration and `this`.
+                        let mut env = Env::new();
+                        env.define_var("super".to_owned(), Val::Class(superclass.clone()));
+                        let env = self.env_tree.push_at(self.env_tree.current(), env);
+                        let closure = self.env_tree.capture(env);
+                        self.env_tree.pop();
+                        closure
+                    }
+                    None => self.env_tree.capture(self.env_tree.current()),
+                };
+                let methods = methods
+                    .iter()
+                    .map(|method| {
+                        let Stmt::Function { name, params, body } = ast.stmt(*method) else {
+                            unreachable!()
+                        };
+                        let name = name.source_text(ctx.src).to_owned();
+                        let method = Callable::Function {
+                            name: name.clone(),
+                            params: params.clone(),
+                            body: body.clone(),
+                            closure: closure.clone(),
+                            this: None,
+                        };
+                        (name, method)
+                    })
+                    .collect();
+                let name = name.source_text(ctx.src).to_owned();
+                let class = LoxClass {
+                    name: name.clone(),
+                    superclass,
+                    methods,
+                };
+                self.env_tree.define_var(name, Val::Class(Rc::new(class)));
+            }
+            Stmt::ParseErr(token, err) => {
+                return Err(Error::Parsing {
+                    token: token.clone(),
+                    err: err.clone(),
+                })
+            }
         }
+        Ok(())
     }
 
-    fn execute_block(
-        &mut self,
-        ctx: &mut Ctx<impl Output>,
-        ast: &Ast,
-        stmts: &[StmtIdx],
-        env: Env,
-        env_parent: EnvIndex,
-    ) -> Result<ControlFlow<Val>> {
-        self.env_tree.push_at(env_parent, env);
-        let result = (|| {
-            for stmt in stmts {
-                let control_flow = self.execute(ctx, ast, *stmt)?;
-                if control_flow.is_break() {
-                    return Ok(control_flow);
+    /// Schedules statements of a block to be executed in order.
+    fn schedule_block(&mut self, stmts: &[StmtIdx]) {
+        self.tasks
+            .extend(stmts.iter().rev().map(|stmt| Task::Execute(*stmt)));
+    }
+
+    fn evaluate(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, expr: ExprIdx) -> Result<()> {
+        match ast.expr(expr) {
+            Expr::Literal(value) => self.vals.push(value.clone().into()),
+            Expr::Grouping(inner) => self.tasks.push(Task::Evaluate(*inner)),
+            Expr::Unary(_, right) | Expr::Typeof(right) => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*right));
+            }
+            Expr::Binary(_, left, right) => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*right));
+                self.tasks.push(Task::Evaluate(*left));
+            }
+            Expr::Variable(var) => {
+                let name = var.source_text(ctx.src);
+                let val = self
+                    .depth(expr, name)
+                    .and_then(|depth| self.env_tree.var_at(depth, name))
+                    .ok_or_else(|| Error::UndefinedVariable {
+                        name: name.to_owned(),
+                        token: var.clone(),
+                    })?;
+                if let Val::Uninitialized = val {
+                    return Err(Error::UninitializedVariable {
+                        name: name.to_owned(),
+                        token: var.clone(),
+                    });
                 }
+                self.vals.push(val.clone());
+            }
+            Expr::Assign { value, .. } => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*value));
+            }
+            Expr::Logical(_, left, _) => {
+                self.tasks.push(Task::ShortCircuit(expr));
+                self.tasks.push(Task::Evaluate(*left));
+            }
+            Expr::Get { object, .. } => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*object));
+            }
+            Expr::Super { method, .. } => {
+                let depth = self.depths.get(expr);
+                let superclass = depth.and_then(|depth| self.env_tree.var_at(depth, "super"));
+                let (Some(depth), Some(Val::Class(superclass))) = (depth, superclass) else {
+                    unreachable!("Parser only allows 'super' in methods of subclasses")
+                };
+                // `this` is defined in the environment right below `super`.
+                let Some(Val::Instance(instance)) = self.env_tree.var_at(depth - 1, "this") else {
+                    unreachable!("Methods are always bound to an instance")
+                };
+                let name = method.source_text(ctx.src);
+                let method =
+                    superclass
+                        .find_method(name)
+                        .ok_or_else(|| Error::UndefinedProperty {
+                            name: name.to_owned(),
+                            token: method.clone(),
+                        })?;
+                self.vals.push(Val::Callable(method.bind(instance.clone())));
+            }
+            Expr::Set { object, value, .. } => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*value));
+                self.tasks.push(Task::Evaluate(*object));
+            }
+            Expr::Lambda { params, body, .. } => {
+                let closure = self.env_tree.capture(self.env_tree.current());
+                self.vals.push(Val::Callable(Callable::Function {
+                    name: String::new(),
+                    params: params.clone(),
+                    body: body.clone(),
+                    closure,
+                    this: None,
+                }));
             }
-            Ok(ControlFlow::Continue(()))
-        })();
-        self.env_tree.pop();
-        result
+            Expr::Ternary { cond, .. } => {
+                self.tasks.push(Task::Choose(expr));
+                self.tasks.push(Task::Evaluate(*cond));
+            }
+            Expr::Call { callee, args, .. } => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks
+                    .extend(args.iter().rev().map(|arg| Task::Evaluate(*arg)));
+                self.tasks.push(Task::Evaluate(*callee));
+            }
+            Expr::Array(elements) => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.extend(
+                    elements
+                        .iter()
+                        .rev()
+                        .map(|element| Task::Evaluate(*element)),
+                );
+            }
+            Expr::Index { object, index, .. } => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*index));
+                self.tasks.push(Task::Evaluate(*object));
+            }
+            Expr::IndexAssign {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.tasks.push(Task::Apply(expr));
+                self.tasks.push(Task::Evaluate(*value));
+                self.tasks.push(Task::Evaluate(*index));
+                self.tasks.push(Task::Evaluate(*object));
+            }
+        }
+        Ok(())
     }
 
-    fn evaluate(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, expr: ExprIdx) -> Result<Val> {
-        let lit = match ast.expr(expr) {
-            Expr::Literal(value) => value.clone().into(),
-            Expr::Grouping(expr) => self.evaluate(ctx, ast, *expr)?,
-            Expr::Unary(operator, right) => {
-                let right = self.evaluate(ctx, ast, *right)?;
+    /// Combines already evaluated operands of `expr`.
+    fn apply(&mut self, ctx: &mut Ctx<impl Output>, ast: &Ast, expr: ExprIdx) -> Result<()> {
+        let val = match ast.expr(expr) {
+            Expr::Unary(operator, _) => {
+                let right = self.pop_val();
                 match (&operator.kind, right) {
                     (TokenKind::Bang, right) => Val::Bool(!right.is_truthy()),
-                    (TokenKind::Minus, Val::Number(n)) => Val::Number(-n),
-                    (TokenKind::Minus, _) => {
+                    (TokenKind::Minus, Val::Number(n)) => self.check_nan(operator, -n)?,
+                    (TokenKind::Minus, right) => {
                         return Err(Error::ExpectedNumber {
                             operator: operator.clone(),
+                            got: right.type_name(),
                         });
                     }
                     _ => unreachable!(),
                 }
             }
-            Expr::Binary(operator, left, right) => {
-                let left = self.evaluate(ctx, ast, *left)?;
-                let right = self.evaluate(ctx, ast, *right)?;
-
-                match (&operator.kind, left, right) {
+            Expr::Typeof(_) => Val::String(self.pop_val().type_name().to_owned()),
+            Expr::Binary(operator, _, _) => {
+                let right = self.pop_val();
+                let left = self.pop_val();
+                let val = match (&operator.kind, left, right) {
                     (TokenKind::Minus, Val::Number(l), Val::Number(r)) => Val::Number(l - r),
+                    (TokenKind::Slash, Val::Number(_), Val::Number(0.0)) => {
+                        return Err(Error::DivisionByZero {
+                            operator: operator.clone(),
+                        });
+                    }
                     (TokenKind::Slash, Val::Number(l), Val::Number(r)) => Val::Number(l / r),
                     (TokenKind::Star, Val::Number(l), Val::Number(r)) => Val::Number(l * r),
+                    (TokenKind::Percent, Val::Number(_), Val::Number(0.0)) => {
+                        return Err(Error::ModuloByZero {
+                            operator: operator.clone(),
+                        });
+                    }
+                    (TokenKind::Percent, Val::Number(l), Val::Number(r)) => Val::Number(l % r),
+                    (TokenKind::StarStar, Val::Number(l), Val::Number(r)) => {
+                        let val = l.powf(r);
+                        if val.is_nan() && !l.is_nan() && !r.is_nan() {
+                            return Err(Error::FractionalPowerOfNegative {
+                                operator: operator.clone(),
+                            });
+                        }
+                        Val::Number(val)
+                    }
                     (TokenKind::Plus, Val::Number(l), Val::Number(r)) => Val::Number(l + r),
                     (TokenKind::Plus, Val::String(l), Val::String(r)) => Val::String(l + &r),
+                    // Numbers are converted when concatenated with strings.
+                    (TokenKind::Plus, Val::String(l), r @ Val::Number(_)) => {
+                        Val::String(l + &r.to_lox_string())
+                    }
+                    (TokenKind::Plus, l @ Val::Number(_), Val::String(r)) => {
+                        Val::String(l.to_lox_string() + &r)
+                    }
                     (TokenKind::Greater, Val::Number(l), Val::Number(r)) => Val::Bool(l > r),
                     (TokenKind::GreaterEqual, Val::Number(l), Val::Number(r)) => Val::Bool(l >= r),
                     (TokenKind::Less, Val::Number(l), Val::Number(r)) => Val::Bool(l < r),
                     (TokenKind::LessEqual, Val::Number(l), Val::Number(r)) => Val::Bool(l <= r),
                     (TokenKind::BangEqual, l, r) => Val::Bool(l != r),
                     (TokenKind::EqualEqual, l, r) => Val::Bool(l == r),
-                    (TokenKind::Plus, _, _) => {
+                    (TokenKind::Plus, Val::Number(_), r) => {
+                        return Err(Error::ExpectedNumbers {
+                            operator: operator.clone(),
+                            side: Side::Right,
+                            got: r.type_name(),
+                        });
+                    }
+                    (TokenKind::Plus, Val::String(_), r) => {
+                        return Err(Error::ExpectedString {
+                            operator: operator.clone(),
+                            side: Side::Right,
+                            got: r.type_name(),
+                        });
+                    }
+                    (TokenKind::Plus, l, _) => {
                         return Err(Error::ExpectedNumbersOrStrings {
                             operator: operator.clone(),
+                            side: Side::Left,
+                            got: l.type_name(),
                         });
                     }
                     (
@@ -218,104 +1104,339 @@ impl Interpreter {
                         | TokenKind::Less
                         | TokenKind::LessEqual
                         | TokenKind::Minus
+                        | TokenKind::Percent
+                        | TokenKind::StarStar
                         | TokenKind::Slash
                         | TokenKind::Star,
-                        _,
-                        _,
+                        l,
+                        r,
                     ) => {
+                        let (side, got) = match l {
+                            Val::Number(_) => (Side::Right, r),
+                            _ => (Side::Left, l),
+                        };
                         return Err(Error::ExpectedNumbers {
                             operator: operator.clone(),
+                            side,
+                            got: got.type_name(),
                         });
                     }
                     _ => unreachable!(),
+                };
+                match val {
+                    Val::Number(n) => self.check_nan(operator, n)?,
+                    val => val,
                 }
             }
-            Expr::Variable(var) => {
-                let name = &ctx.src[var.lexeme.clone()];
-                self.env_tree
-                    .var(name)
-                    .ok_or_else(|| Error::UndefinedVariable {
-                        name: name.to_owned(),
-                        token: var.clone(),
-                    })?
-                    .clone()
-            }
-            Expr::Assign { var, value } => {
-                let value = self.evaluate(ctx, ast, *value)?;
-                let name = &ctx.src[var.lexeme.clone()];
-                self.env_tree
-                    .assign_var(name, value)
+            Expr::Assign { var, .. } => {
+                let value = self.pop_val();
+                let name = var.source_text(ctx.src);
+                self.depth(expr, name)
+                    .and_then(|depth| self.env_tree.assign_var_at(depth, name, value))
                     .ok_or_else(|| Error::UndefinedVariable {
                         name: name.to_owned(),
                         token: var.clone(),
                     })?
                     .clone()
             }
-            Expr::Logical(operator, left, right) => {
-                let left = self.evaluate(ctx, ast, *left)?;
-                match (&operator.kind, left.is_truthy()) {
-                    (TokenKind::Or, true) => left,
-                    (TokenKind::Or, false) => self.evaluate(ctx, ast, *right)?,
-                    (_, false) => left,
-                    _ => self.evaluate(ctx, ast, *right)?,
+            Expr::Get { name, .. } => {
+                let Val::Instance(instance) = self.pop_val() else {
+                    return Err(Error::NotAnInstance {
+                        token: name.clone(),
+                    });
+                };
+                let name_str = name.source_text(ctx.src);
+                let field = instance.borrow().fields.get(name_str).cloned();
+                match field {
+                    Some(field) => field,
+                    None => instance
+                        .borrow()
+                        .class
+                        .find_method(name_str)
+                        .map(|method| Val::Callable(method.bind(instance.clone())))
+                        .ok_or_else(|| Error::UndefinedProperty {
+                            name: name_str.to_owned(),
+                            token: name.clone(),
+                        })?,
                 }
             }
-            Expr::Call {
-                callee,
-                paren,
-                args,
-            } => {
-                let callee = self.evaluate(ctx, ast, *callee)?;
-                let Val::Callable(callable) = callee else {
-                    return Err(Error::BadCall {
-                        paren: paren.clone(),
+            Expr::Set { name, .. } => {
+                let value = self.pop_val();
+                let Val::Instance(instance) = self.pop_val() else {
+                    return Err(Error::NotAnInstance {
+                        token: name.clone(),
                     });
                 };
-                let args: Result<Vec<_>> = args
-                    .iter()
-                    .map(|arg| self.evaluate(ctx, ast, *arg))
-                    .collect();
-                let args = args?;
-                if args.len() != callable.arity() {
-                    return Err(Error::WrongNumberOfArgs {
+                // Fields shadow methods of the same name.
+                instance
+                    .borrow_mut()
+                    .fields
+                    .insert(name.source_text(ctx.src).to_owned(), value.clone());
+                value
+            }
+            Expr::Array(elements) => {
+                let elements = self.vals.split_off(self.vals.len() - elements.len());
+                Val::Array(Rc::new(RefCell::new(elements)))
+            }
+            Expr::Index { bracket, .. } => {
+                let index = self.pop_val();
+                let array = self.pop_val();
+                let (array, index) = array_slot(bracket, &array, &index)?;
+                let val = array.borrow()[index].clone();
+                val
+            }
+            Expr::IndexAssign { bracket, .. } => {
+                let value = self.pop_val();
+                let index = self.pop_val();
+                let array = self.pop_val();
+                let (array, index) = array_slot(bracket, &array, &index)?;
+                array.borrow_mut()[index] = value.clone();
+                value
+            }
+            Expr::Call { paren, args, .. } => {
+                // Arguments stay on the value stack, so calls don't allocate a buffer for them.
+                let args_start = self.vals.len() - args.len() - 1;
+                return match self.vals.remove(args_start) {
+                    Val::Callable(callable) => {
+                        check_arity(paren, callable.arity(), args.len())?;
+                        if self.is_tail_call() {
+                            // The caller has nothing left to do, so the callee takes its frame
+                            // and tail recursion doesn't count towards the call depth limit.
+                            while !matches!(self.unwind_task(), Some(Task::CallFrame)) {}
+                        }
+                        self.call(ctx, paren, callable, args_start)
+                    }
+                    Val::Class(class) => {
+                        check_arity(paren, class.arity(), args.len())?;
+                        self.construct(ctx, paren, class, args_start)
+                    }
+                    _ => Err(Error::BadCall {
                         paren: paren.clone(),
-                        expected: callable.arity(),
-                        got: args.len(),
-                    });
-                }
-                self.call(ctx, ast, callable, args)?
+                    }),
+                };
             }
+            _ => unreachable!(),
         };
-        Ok(lit)
+        self.vals.push(val);
+        Ok(())
     }
 
+    /// Calls `callable` with the arguments on top of the value stack, starting at `args_start`.
+    ///
+    /// The arguments are popped. Natives push their result right away, while functions schedule
+    /// their body and push the result once it returns.
     fn call(
         &mut self,
         ctx: &mut Ctx<impl Output>,
-        ast: &Ast,
+        paren: &Token,
         callable: Callable,
-        args: Vec<Val>,
-    ) -> Result<Val> {
+        args_start: usize,
+    ) -> Result<()> {
         match callable {
-            Callable::Clock => Ok(Val::Number(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs_f64(),
-            )),
-            Callable::Function { params, body, .. } => {
+            Callable::Native(native) => {
+                let val = match (native.f)(paren, &self.vals[args_start..])? {
+                    // Natives are held to the same rule as operators, e.g. `sqrt(-1)`.
+                    Val::Number(n) => self.check_nan(paren, n)?,
+                    val => val,
+                };
+                self.vals.truncate(args_start);
+                self.vals.push(val);
+            }
+            Callable::Function {
+                name,
+                params,
+                body,
+                closure,
+                this,
+            } => {
+                if self.call_stack.len() >= self.call_depth_limit {
+                    return Err(Error::StackOverflow {
+                        paren: paren.clone(),
+                    });
+                }
                 let mut env = Env::new();
-                for (param, arg) in params.iter().zip(args) {
-                    let name = &ctx.src[param.lexeme.clone()];
+                for (param, arg) in params.iter().zip(self.vals.drain(args_start..)) {
+                    let name = param.name.source_text(ctx.src);
+                    if let (true, Some(ty)) = (self.type_checks, &param.ty) {
+                        check_type(ctx, paren, name, ty, &arg)?;
+                    }
                     env.define_var(name.to_owned(), arg);
                 }
-                let control_flow =
-                    self.execute_block(ctx, ast, &body, env, self.env_tree.global())?;
-                match control_flow {
-                    ControlFlow::Continue(()) => Ok(Val::Nil),
-                    ControlFlow::Break(val) => Ok(val),
-                }
+                self.call_stack.push(CallFrame {
+                    name,
+                    call_site_line: paren.line,
+                });
+                self.tasks.push(Task::CallFrame);
+                let closure = match this {
+                    Some(instance) => {
+                        // Methods see `this` in an environment between their closure and
+                        // parameters.
+                        let mut this_env = Env::new();
+                        this_env.define_var("this".to_owned(), Val::Instance(instance));
+                        self.tasks.push(Task::PopEnv);
+                        self.env_tree.push_at(closure.index(), this_env)
+                    }
+                    None => closure.index(),
+                };
+                self.env_tree.push_at(closure, env);
+                self.tasks.push(Task::PopEnv);
+                self.schedule_block(&body);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the value of the call being applied is returned by the function making
+    /// it right away, e.g. `return f(n - 1);`.
+    fn is_tail_call(&self) -> bool {
+        matches!(self.tasks.last(), Some(Task::Return))
+    }
+
+    /// Creates an instance of `class`, initializing it with the arguments on top of the value
+    /// stack, starting at `args_start`.
+    fn construct(
+        &mut self,
+        ctx: &mut Ctx<impl Output>,
+        paren: &Token,
+        class: Rc<LoxClass>,
+        args_start: usize,
+    ) -> Result<()> {
+        let instance = Rc::new(RefCell::new(LoxInstance {
+            class,
+            fields: HashMap::new(),
+        }));
+        let init = instance.borrow().class.find_method("init").cloned();
+        match init {
+            Some(init) => {
+                // The instance stays below the arguments, ending up on top once the value
+                // returned by `init` is discarded.
+                self.vals
+                    .insert(args_start, Val::Instance(instance.clone()));
+                self.tasks.push(Task::Discard);
+                self.call(ctx, paren, init.bind(instance), args_start + 1)
             }
+            None => {
+                self.vals.push(Val::Instance(instance));
+                Ok(())
+            }
+        }
+    }
+
+    /// Wraps the result of an arithmetic `operator`, rejecting NaN unless allowed.
+    fn check_nan(&self, operator: &Token, n: f64) -> Result<Val> {
+        if n.is_nan() && self.nan_is_error {
+            return Err(Error::NanResult {
+                operator: operator.clone(),
+            });
         }
+        Ok(Val::Number(n))
+    }
+
+    /// Looks up the superclass named by `token` in the current environment.
+    fn superclass(&self, ctx: &Ctx<impl Output>, token: &Token) -> Result<Rc<LoxClass>> {
+        let name = token.source_text(ctx.src);
+        let superclass = self
+            .env_tree
+            .resolve(name)
+            .and_then(|depth| self.env_tree.var_at(depth, name))
+            .ok_or_else(|| Error::UndefinedVariable {
+                name: name.to_owned(),
+                token: token.clone(),
+            })?;
+        match superclass {
+            Val::Class(class) => Ok(class.clone()),
+            _ => Err(Error::SuperclassNotAClass {
+                token: token.clone(),
+            }),
+        }
+    }
+
+    /// Returns how many environments up the variable accessed by `expr` is defined.
+    ///
+    /// Variables the resolver didn't find in local scopes are looked up by scanning the
+    /// environments, caching the result.
+    fn depth(&mut self, expr: ExprIdx, name: &str) -> Option<usize> {
+        if let Some(depth) = self.depths.get(expr) {
+            return Some(depth);
+        }
+        let definitions = self.env_tree.definitions();
+        if let Some(depth) = self.var_cache.get(expr, definitions) {
+            return Some(depth);
+        }
+        let depth = self.env_tree.resolve(name)?;
+        self.var_cache.insert(expr, depth, definitions);
+        Some(depth)
+    }
+
+    fn pop_val(&mut self) -> Val {
+        self.vals
+            .pop()
+            .expect("Scheduled tasks should leave their operands on the value stack")
+    }
+}
+
+fn check_arity(paren: &Token, expected: usize, got: usize) -> Result<()> {
+    if expected != got {
+        return Err(Error::WrongNumberOfArgs {
+            paren: paren.clone(),
+            expected,
+            got,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `array` is an array and `index` a valid position in it.
+fn array_slot<'a>(
+    bracket: &Token,
+    array: &'a Val,
+    index: &Val,
+) -> Result<(&'a Rc<RefCell<Vec<Val>>>, usize)> {
+    let Val::Array(array) = array else {
+        return Err(Error::NotIndexable {
+            bracket: bracket.clone(),
+            got: array.type_name(),
+        });
+    };
+    let Val::Number(index) = *index else {
+        return Err(Error::IndexNotANumber {
+            bracket: bracket.clone(),
+            got: index.type_name(),
+        });
+    };
+    let len = array.borrow().len();
+    if index < 0.0 || index.fract() != 0.0 || index >= len as f64 {
+        return Err(Error::IndexOutOfBounds {
+            bracket: bracket.clone(),
+            index: unlox_ast::display_number(index),
+            len,
+        });
+    }
+    Ok((array, index as usize))
+}
+
+/// Checks `arg` passed for parameter `param` against the type annotation `ty`.
+fn check_type(
+    ctx: &Ctx<impl Output>,
+    paren: &Token,
+    param: &str,
+    ty: &Token,
+    arg: &Val,
+) -> Result<()> {
+    let expected = ty.source_text(ctx.src);
+    if !matches!(expected, "number" | "string" | "bool" | "function" | "any") {
+        return Err(Error::UnknownType {
+            name: expected.to_owned(),
+            token: ty.clone(),
+        });
+    }
+    if expected != "any" && expected != arg.type_name() {
+        return Err(Error::TypeMismatch {
+            paren: paren.clone(),
+            param: param.to_owned(),
+            expected: expected.to_owned(),
+            got: arg.type_name(),
+        });
     }
+    Ok(())
 }
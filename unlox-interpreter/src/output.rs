@@ -47,3 +47,142 @@ where
         &mut self.1
     }
 }
+
+/// Wraps an output, writing each line written to [`Output::err`] as a JSON object on a line of its
+/// own, e.g. `{"col":7,"kind":"runtime","line":2,"message":"Operand must be a number, got nil."}`.
+/// Writes to [`Output::out`] are passed through unchanged.
+///
+/// The `[Line l:c]: ` location errors start with is moved to the `line` and `col` fields, which are
+/// 0 for lines without one.
+pub struct JsonOutput<Inner> {
+    inner: Inner,
+    kind: &'static str,
+    error_count: usize,
+}
+
+impl<Inner> JsonOutput<Inner> {
+    pub fn new(inner: Inner, kind: &'static str) -> Self {
+        Self {
+            inner,
+            kind,
+            error_count: 0,
+        }
+    }
+
+    /// Sets the `kind` field of the errors written from now on, e.g. `"parse"` or `"runtime"`.
+    pub fn set_kind(&mut self, kind: &'static str) {
+        self.kind = kind;
+    }
+
+    /// Returns how many errors were written so far.
+    pub fn error_count(&self) -> usize {
+        self.error_count
+    }
+}
+
+impl<Inner> Output for JsonOutput<Inner>
+where
+    Inner: Output,
+{
+    fn out(&mut self) -> impl io::Write {
+        self.inner.out()
+    }
+
+    fn err(&mut self) -> impl io::Write {
+        JsonLines {
+            inner: self.inner.err(),
+            kind: self.kind,
+            error_count: &mut self.error_count,
+            line: Vec::new(),
+        }
+    }
+}
+
+/// Writer returned by [`JsonOutput::err`], holding back the current line until it's complete.
+struct JsonLines<'a, W: io::Write> {
+    inner: W,
+    kind: &'static str,
+    error_count: &'a mut usize,
+    line: Vec<u8>,
+}
+
+impl<W: io::Write> JsonLines<'_, W> {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = String::from_utf8_lossy(line);
+        let (line, col, message) = split_location(&line).unwrap_or((0, 0, &line));
+        let json = serde_json::json!({
+            "kind": self.kind,
+            "line": line,
+            "col": col,
+            "message": message,
+        });
+        writeln!(self.inner, "{json}")?;
+        *self.error_count += 1;
+        Ok(())
+    }
+}
+
+impl<W: io::Write> io::Write for JsonLines<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+            let rest = self.line.split_off(end + 1);
+            let line = std::mem::replace(&mut self.line, rest);
+            self.write_line(&line[..end])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: io::Write> Drop for JsonLines<'_, W> {
+    fn drop(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        if !line.is_empty() {
+            // Errors can't be returned from `drop`.
+            let _ = self.write_line(&line);
+        }
+    }
+}
+
+/// Splits `[Line l:c]: message` into its parts.
+fn split_location(line: &str) -> Option<(u32, u32, &str)> {
+    let (location, message) = line.strip_prefix("[Line ")?.split_once("]: ")?;
+    let (line, col) = location.split_once(':')?;
+    Some((line.parse().ok()?, col.parse().ok()?, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn writes_errors_as_json() {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let mut output = JsonOutput::new(SplitOutput::new(&mut out, &mut err), "parse");
+        writeln!(output.out(), "[Line 1:1]: Not an error.").unwrap();
+        let mut writer = output.err();
+        write!(writer, "[Line 2:7]: Expected ").unwrap();
+        writeln!(writer, "expression.").unwrap();
+        drop(writer);
+        output.set_kind("runtime");
+        write!(output.err(), "Oops.\nNo newline").unwrap();
+        assert_eq!(output.error_count(), 3);
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[Line 1:1]: Not an error.\n"
+        );
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            "{\"col\":7,\"kind\":\"parse\",\"line\":2,\"message\":\"Expected expression.\"}\n\
+             {\"col\":0,\"kind\":\"runtime\",\"line\":0,\"message\":\"Oops.\"}\n\
+             {\"col\":0,\"kind\":\"runtime\",\"line\":0,\"message\":\"No newline\"}\n"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Native functions defined in the global environment.
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use unlox_ast::Token;
+
+use crate::{env::Env, Callable, Error, Native, Result, Val};
+
+/// Defines the standard library in the global environment.
+pub fn define(global: &mut Env) {
+    define_native(global, "clock".to_owned(), 0, |_, _| {
+        Ok(Val::Number(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+        ))
+    });
+    define_native(global, "str".to_owned(), 1, |_, args| {
+        Ok(Val::String(args[0].to_lox_string()))
+    });
+
+    let math: [(_, fn(_) -> _); 10] = [
+        ("sqrt", f64::sqrt),
+        ("floor", f64::floor),
+        ("ceil", f64::ceil),
+        ("abs", f64::abs),
+        ("sin", f64::sin),
+        ("cos", f64::cos),
+        ("tan", f64::tan),
+        ("log", f64::ln),
+        ("log2", f64::log2),
+        ("exp", f64::exp),
+    ];
+    for (name, f) in math {
+        define_native(global, name.to_owned(), 1, move |paren, args| {
+            Ok(Val::Number(f(number(paren, args, 0, "x")?)))
+        });
+    }
+    define_native(global, "pow".to_owned(), 2, |paren, args| {
+        let base = number(paren, args, 0, "base")?;
+        let exp = number(paren, args, 1, "exp")?;
+        Ok(Val::Number(base.powf(exp)))
+    });
+
+    // Strings are indexed by characters rather than bytes.
+    define_native(global, "str_len".to_owned(), 1, |paren, args| {
+        let s = string(paren, args, 0, "s")?;
+        Ok(Val::Number(s.chars().count() as f64))
+    });
+    let transforms: [(_, fn(&str) -> _); 3] = [
+        ("str_upper", str::to_uppercase),
+        ("str_lower", str::to_lowercase),
+        ("str_trim", |s: &str| s.trim().to_owned()),
+    ];
+    for (name, f) in transforms {
+        define_native(global, name.to_owned(), 1, move |paren, args| {
+            Ok(Val::String(f(string(paren, args, 0, "s")?)))
+        });
+    }
+    define_native(global, "str_substr".to_owned(), 3, |paren, args| {
+        let s = string(paren, args, 0, "s")?;
+        let start = index(paren, args, 1, "start")?;
+        let length = index(paren, args, 2, "length")?;
+        Ok(Val::String(s.chars().skip(start).take(length).collect()))
+    });
+    define_native(global, "str_split".to_owned(), 2, |paren, args| {
+        let s = string(paren, args, 0, "s")?;
+        let delim = string(paren, args, 1, "delim")?;
+        let parts: Vec<_> = if delim.is_empty() {
+            s.chars().map(|c| Val::String(c.to_string())).collect()
+        } else {
+            s.split(delim)
+                .map(|part| Val::String(part.to_owned()))
+                .collect()
+        };
+        Ok(Val::Array(Rc::new(RefCell::new(parts))))
+    });
+
+    define_native(global, "push".to_owned(), 2, |paren, args| {
+        array(paren, args, 0, "array")?
+            .borrow_mut()
+            .push(args[1].clone());
+        Ok(Val::Nil)
+    });
+    define_native(global, "pop".to_owned(), 1, |paren, args| {
+        array(paren, args, 0, "array")?
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| Error::EmptyArray {
+                paren: paren.clone(),
+            })
+    });
+}
+
+pub fn define_native(
+    env: &mut Env,
+    name: String,
+    arity: usize,
+    f: impl Fn(&Token, &[Val]) -> Result<Val> + 'static,
+) {
+    let native = Native {
+        name: name.clone(),
+        arity,
+        f: Rc::new(f),
+    };
+    env.define_var(name, Val::Callable(Callable::Native(native)));
+}
+
+/// Returns the argument at `index`, named `param` in errors, if it's a number.
+fn number(paren: &Token, args: &[Val], index: usize, param: &str) -> Result<f64> {
+    match &args[index] {
+        Val::Number(n) => Ok(*n),
+        arg => Err(type_mismatch(paren, param, "number", arg)),
+    }
+}
+
+/// Returns the argument at `index`, named `param` in errors, if it's a non-negative integer.
+fn index(paren: &Token, args: &[Val], index: usize, param: &str) -> Result<usize> {
+    match &args[index] {
+        Val::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        arg => Err(type_mismatch(paren, param, "a non-negative integer", arg)),
+    }
+}
+
+/// Returns the argument at `index`, named `param` in errors, if it's a string.
+fn string<'a>(paren: &Token, args: &'a [Val], index: usize, param: &str) -> Result<&'a str> {
+    match &args[index] {
+        Val::String(s) => Ok(s),
+        arg => Err(type_mismatch(paren, param, "string", arg)),
+    }
+}
+
+/// Returns the argument at `index`, named `param` in errors, if it's an array.
+fn array<'a>(
+    paren: &Token,
+    args: &'a [Val],
+    index: usize,
+    param: &str,
+) -> Result<&'a RefCell<Vec<Val>>> {
+    match &args[index] {
+        Val::Array(array) => Ok(array),
+        arg => Err(type_mismatch(paren, param, "array", arg)),
+    }
+}
+
+fn type_mismatch(paren: &Token, param: &str, expected: &str, got: &Val) -> Error {
+    Error::TypeMismatch {
+        paren: paren.clone(),
+        param: param.to_owned(),
+        expected: expected.to_owned(),
+        got: got.type_name(),
+    }
+}
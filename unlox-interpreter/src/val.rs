@@ -1,29 +1,221 @@
-use unlox_ast::{Lit, StmtIdx, Token};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+};
+use unlox_ast::{display_number, Lit, Param, StmtIdx, Token};
 
-#[derive(Debug, Default, Clone, PartialEq)]
+use crate::{env::EnvRef, Result};
+
+/// Runtime value.
+///
+/// Arrays and maps are reference types - clones of a value share the same underlying storage.
+/// Equality is structural though, two collections are equal if they have equal contents,
+/// no matter if they share the storage or not. Classes and instances are reference types too, but
+/// they are only equal to themselves.
+///
+/// Collections may contain themselves, e.g. after `push(a, a)`. Such cycles are shown as `[...]`
+/// and `{...}` and don't prevent comparing them.
+#[derive(Debug, Default, Clone)]
 pub enum Val {
     Number(f64),
     String(String),
     Bool(bool),
     #[default]
     Nil,
+    /// Value of a variable declared without an initializer. Reading it is a runtime error, so it
+    /// never shows up in expressions.
+    Uninitialized,
     Callable(Callable),
+    Array(Rc<RefCell<Vec<Val>>>),
+    Map(Rc<RefCell<BTreeMap<String, Val>>>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<RefCell<LoxInstance>>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Callable {
-    Clock,
+    Native(Native),
     Function {
+        /// Empty for lambdas.
         name: String,
-        params: Vec<Token>,
+        params: Vec<Param>,
         body: Vec<StmtIdx>,
+        /// Environment the function was declared in.
+        closure: EnvRef,
+        /// Instance bound to `this`, if the function is a method accessed through one.
+        this: Option<Rc<RefCell<LoxInstance>>>,
     },
 }
 
+/// Function implemented in Rust.
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub f: Rc<NativeFn>,
+}
+
+/// Function called with the closing parenthesis of the call and the arguments.
+pub type NativeFn = dyn Fn(&Token, &[Val]) -> Result<Val>;
+
+/// User-defined class. Calling it creates a new instance.
+#[derive(Debug)]
+pub struct LoxClass {
+    pub name: String,
+    pub superclass: Option<Rc<LoxClass>>,
+    pub methods: HashMap<String, Callable>,
+}
+
+#[derive(Debug)]
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    pub fields: HashMap<String, Val>,
+}
+
+impl LoxClass {
+    /// Returns the number of arguments taken by the `init` method, if there's one.
+    pub fn arity(&self) -> usize {
+        self.find_method("init").map_or(0, Callable::arity)
+    }
+
+    /// Looks up a method of the class, falling back to the superclass chain.
+    pub fn find_method(&self, name: &str) -> Option<&Callable> {
+        match self.methods.get(name) {
+            Some(method) => Some(method),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+}
+
+impl PartialEq for LoxClass {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialEq for LoxInstance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Natives are equal only if they share the same function.
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.f, &other.f)
+    }
+}
+
 impl Val {
     pub fn is_truthy(&self) -> bool {
         !matches!(self, Self::Nil | Self::Bool(false))
     }
+
+    /// Converts the value to a string, the same way `print` shows it.
+    pub fn to_lox_string(&self) -> String {
+        self.to_string()
+    }
+
+    /// Returns the name of the value's runtime type.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Val::Number(_) => "number",
+            Val::String(_) => "string",
+            Val::Bool(_) => "bool",
+            Val::Nil => "nil",
+            Val::Uninitialized => "uninitialized",
+            Val::Callable(_) => "function",
+            Val::Array(_) => "array",
+            Val::Map(_) => "map",
+            Val::Class(_) => "class",
+            Val::Instance(_) => "instance",
+        }
+    }
+
+    /// Calls `visit` with the shared parts of the value, descending into the ones it returns
+    /// `true` for.
+    pub(crate) fn visit_shared(&self, visit: &mut dyn FnMut(Shared<'_>) -> bool) {
+        match self {
+            Val::Callable(callable) => callable.visit_shared(visit),
+            Val::Array(array) => visit_shared(Shared::Array(array), visit),
+            Val::Map(map) => visit_shared(Shared::Map(map), visit),
+            Val::Class(class) => visit_shared(Shared::Class(class), visit),
+            Val::Instance(instance) => visit_shared(Shared::Instance(instance), visit),
+            Val::Number(_) | Val::String(_) | Val::Bool(_) | Val::Nil | Val::Uninitialized => (),
+        }
+    }
+
+    /// Converts the value to JSON, e.g. to hand a result over to the host.
+    ///
+    /// Numbers that JSON can't represent, i.e. infinities and NaN, become `null`. Callables and
+    /// classes become their string representation, instances become objects of their fields.
+    /// Collections and instances nested in themselves become the string `"[...]"`, `"{...}"` or
+    /// e.g. `"Node instance"` where they repeat.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.to_json_nested(&mut Vec::new())
+    }
+
+    /// Converts the value to JSON, with `enclosing` holding the values it's nested in.
+    fn to_json_nested(&self, enclosing: &mut Vec<CollectionPtr>) -> serde_json::Value {
+        use serde_json::Value as Json;
+        let ptr: Option<CollectionPtr> = match self {
+            Val::Array(v) => Some(Rc::as_ptr(v).cast()),
+            Val::Map(v) => Some(Rc::as_ptr(v).cast()),
+            Val::Instance(v) => Some(Rc::as_ptr(v).cast()),
+            _ => None,
+        };
+        if ptr.is_some_and(|ptr| enclosing.contains(&ptr)) {
+            let placeholder = match self {
+                Val::Array(_) => "[...]".to_owned(),
+                Val::Map(_) => "{...}".to_owned(),
+                _ => self.to_string(),
+            };
+            return Json::String(placeholder);
+        }
+        enclosing.extend(ptr);
+        let json = match self {
+            Val::Number(v) => serde_json::Number::from_f64(*v).map_or(Json::Null, Json::Number),
+            Val::String(v) => Json::String(v.clone()),
+            Val::Bool(v) => Json::Bool(*v),
+            Val::Nil | Val::Uninitialized => Json::Null,
+            Val::Callable(v) => Json::String(v.to_string()),
+            Val::Array(v) => Json::Array(
+                v.borrow()
+                    .iter()
+                    .map(|val| val.to_json_nested(enclosing))
+                    .collect(),
+            ),
+            Val::Map(v) => Json::Object(
+                v.borrow()
+                    .iter()
+                    .map(|(key, val)| (key.clone(), val.to_json_nested(enclosing)))
+                    .collect(),
+            ),
+            Val::Class(v) => Json::String(v.name.clone()),
+            Val::Instance(v) => Json::Object(
+                v.borrow()
+                    .fields
+                    .iter()
+                    .map(|(key, val)| (key.clone(), val.to_json_nested(enclosing)))
+                    .collect(),
+            ),
+        };
+        if ptr.is_some() {
+            enclosing.pop();
+        }
+        json
+    }
 }
 
 impl From<Lit> for Val {
@@ -39,12 +231,173 @@ impl From<Lit> for Val {
 
 impl std::fmt::Display for Val {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_nested(f, &mut Vec::new())
+    }
+}
+
+/// Address of a collection's storage, identifying it while walking nested values.
+pub(crate) type CollectionPtr = *const ();
+
+/// Part of a value shared by its clones.
+#[derive(Clone, Copy)]
+pub(crate) enum Shared<'a> {
+    Closure(&'a EnvRef),
+    Array(&'a Rc<RefCell<Vec<Val>>>),
+    Map(&'a Rc<RefCell<BTreeMap<String, Val>>>),
+    Class(&'a Rc<LoxClass>),
+    Instance(&'a Rc<RefCell<LoxInstance>>),
+}
+
+impl Shared<'_> {
+    pub fn ptr(&self) -> CollectionPtr {
+        match self {
+            Shared::Closure(env) => env.as_ptr(),
+            Shared::Array(array) => Rc::as_ptr(array).cast(),
+            Shared::Map(map) => Rc::as_ptr(map).cast(),
+            Shared::Class(class) => Rc::as_ptr(class).cast(),
+            Shared::Instance(instance) => Rc::as_ptr(instance).cast(),
+        }
+    }
+
+    /// Returns the number of values sharing it, wherever they are.
+    pub fn strong_count(&self) -> usize {
+        match self {
+            Shared::Closure(env) => env.closure_count(),
+            Shared::Array(array) => Rc::strong_count(array),
+            Shared::Map(map) => Rc::strong_count(map),
+            Shared::Class(class) => Rc::strong_count(class),
+            Shared::Instance(instance) => Rc::strong_count(instance),
+        }
+    }
+
+    /// Calls `visit` with the shared parts of the values it holds, as [`Val::visit_shared`] does.
+    /// Closures hold nothing, the values in their environment are left to the caller.
+    pub fn visit_contents(&self, visit: &mut dyn FnMut(Shared<'_>) -> bool) {
         match self {
-            Val::Number(v) => write!(f, "{}", v),
+            Shared::Closure(_) => (),
+            Shared::Array(array) => array.borrow().iter().for_each(|v| v.visit_shared(visit)),
+            Shared::Map(map) => map.borrow().values().for_each(|v| v.visit_shared(visit)),
+            Shared::Class(class) => {
+                if let Some(superclass) = &class.superclass {
+                    visit_shared(Shared::Class(superclass), visit);
+                }
+                class.methods.values().for_each(|m| m.visit_shared(visit));
+            }
+            Shared::Instance(instance) => {
+                let instance = instance.borrow();
+                visit_shared(Shared::Class(&instance.class), visit);
+                instance.fields.values().for_each(|v| v.visit_shared(visit));
+            }
+        }
+    }
+}
+
+/// Calls `visit` with `shared`, then with its contents if it returns `true`.
+fn visit_shared(shared: Shared<'_>, visit: &mut dyn FnMut(Shared<'_>) -> bool) {
+    if visit(shared) {
+        shared.visit_contents(visit);
+    }
+}
+
+impl PartialEq for Val {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_nested(other, &mut Vec::new())
+    }
+}
+
+impl Val {
+    /// Compares values, with `comparing` holding the pairs of collections whose contents are
+    /// already being compared further up.
+    fn eq_nested(&self, other: &Self, comparing: &mut Vec<(CollectionPtr, CollectionPtr)>) -> bool {
+        match (self, other) {
+            (Val::Number(a), Val::Number(b)) => a == b,
+            (Val::String(a), Val::String(b)) => a == b,
+            (Val::Bool(a), Val::Bool(b)) => a == b,
+            (Val::Nil, Val::Nil) | (Val::Uninitialized, Val::Uninitialized) => true,
+            (Val::Callable(a), Val::Callable(b)) => a == b,
+            (Val::Array(a), Val::Array(b)) => {
+                let pair = (Rc::as_ptr(a).cast(), Rc::as_ptr(b).cast());
+                // A pair compared further up is assumed equal, any difference shows up there.
+                if Rc::ptr_eq(a, b) || comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                let eq = a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| a.eq_nested(b, comparing));
+                comparing.pop();
+                eq
+            }
+            (Val::Map(a), Val::Map(b)) => {
+                let pair = (Rc::as_ptr(a).cast(), Rc::as_ptr(b).cast());
+                if Rc::ptr_eq(a, b) || comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                let eq = a.len() == b.len()
+                    && a.iter().zip(b.iter()).all(|((key_a, a), (key_b, b))| {
+                        key_a == key_b && a.eq_nested(b, comparing)
+                    });
+                comparing.pop();
+                eq
+            }
+            (Val::Class(a), Val::Class(b)) => a == b,
+            (Val::Instance(a), Val::Instance(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Writes the value, with `enclosing` holding the collections it's nested in.
+    fn fmt_nested(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        enclosing: &mut Vec<CollectionPtr>,
+    ) -> fmt::Result {
+        match self {
+            Val::Number(v) => write!(f, "{}", display_number(*v)),
             Val::String(v) => write!(f, "{}", v),
             Val::Bool(v) => write!(f, "{}", v),
             Val::Nil => write!(f, "nil"),
+            Val::Uninitialized => write!(f, "uninitialized"),
             Val::Callable(v) => write!(f, "{}", v),
+            Val::Array(v) => {
+                let ptr = Rc::as_ptr(v).cast();
+                if enclosing.contains(&ptr) {
+                    return write!(f, "[...]");
+                }
+                enclosing.push(ptr);
+                write!(f, "[")?;
+                for (i, val) in v.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    val.fmt_nested(f, enclosing)?;
+                }
+                enclosing.pop();
+                write!(f, "]")
+            }
+            Val::Map(v) => {
+                let ptr = Rc::as_ptr(v).cast();
+                if enclosing.contains(&ptr) {
+                    return write!(f, "{{...}}");
+                }
+                enclosing.push(ptr);
+                write!(f, "{{")?;
+                for (i, (key, val)) in v.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key}: ")?;
+                    val.fmt_nested(f, enclosing)?;
+                }
+                enclosing.pop();
+                write!(f, "}}")
+            }
+            Val::Class(v) => write!(f, "{}", v.name),
+            Val::Instance(v) => write!(f, "{} instance", v.borrow().class.name),
         }
     }
 }
@@ -52,7 +405,8 @@ impl std::fmt::Display for Val {
 impl std::fmt::Display for Callable {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Callable::Clock => write!(f, "<native fn>"),
+            Callable::Native(_) => write!(f, "<native fn>"),
+            Callable::Function { name, .. } if name.is_empty() => write!(f, "<fn>"),
             Callable::Function { name, .. } => write!(f, "<fn {name}>"),
         }
     }
@@ -61,8 +415,131 @@ impl std::fmt::Display for Callable {
 impl Callable {
     pub fn arity(&self) -> usize {
         match self {
-            Callable::Clock => 0,
+            Callable::Native(native) => native.arity,
             Callable::Function { params, .. } => params.len(),
         }
     }
+
+    /// Calls `visit` with the shared parts of the callable, like [`Val::visit_shared`].
+    fn visit_shared(&self, visit: &mut dyn FnMut(Shared<'_>) -> bool) {
+        if let Callable::Function { closure, this, .. } = self {
+            visit_shared(Shared::Closure(closure), visit);
+            if let Some(instance) = this {
+                visit_shared(Shared::Instance(instance), visit);
+            }
+        }
+    }
+
+    /// Returns a copy of the method with `this` bound to `instance`.
+    pub fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> Callable {
+        let mut method = self.clone();
+        if let Callable::Function { this, .. } = &mut method {
+            *this = Some(instance);
+        }
+        method
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn array(vals: Vec<Val>) -> Val {
+        Val::Array(Rc::new(RefCell::new(vals)))
+    }
+
+    fn map(entries: Vec<(&str, Val)>) -> Val {
+        let entries = entries.into_iter().map(|(k, v)| (k.to_owned(), v));
+        Val::Map(Rc::new(RefCell::new(entries.collect())))
+    }
+
+    #[test]
+    fn displays_numbers() {
+        for (n, expected) in [
+            (1.0, "1"),
+            (1.5, "1.5"),
+            (-2.25, "-2.25"),
+            (1e6, "1000000"),
+            (1e21, "1000000000000000000000"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (-0.0, "-0"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+            (f64::NAN, "NaN"),
+        ] {
+            assert_eq!(Val::Number(n).to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn displays_collections() {
+        let nested = array(vec![Val::Number(1.0), array(vec![]), Val::Nil]);
+        assert_eq!(nested.to_string(), "[1, [], nil]");
+
+        let map = map(vec![
+            ("b", Val::Bool(true)),
+            ("a", array(vec![Val::String("x".into())])),
+        ]);
+        assert_eq!(map.to_string(), "{a: [x], b: true}");
+    }
+
+    #[test]
+    fn compares_collections_structurally() {
+        let a = array(vec![Val::Number(1.0), Val::Number(2.0)]);
+        let b = array(vec![Val::Number(1.0), Val::Number(2.0)]);
+        assert_eq!(a, b);
+        assert_ne!(a, array(vec![Val::Number(1.0)]));
+        assert_eq!(map(vec![("a", Val::Nil)]), map(vec![("a", Val::Nil)]));
+        assert_ne!(map(vec![("a", Val::Nil)]), map(vec![("b", Val::Nil)]));
+    }
+
+    #[test]
+    fn handles_cyclic_collections() {
+        let a = array(vec![Val::Number(1.0)]);
+        let Val::Array(storage) = &a else {
+            unreachable!()
+        };
+        storage.borrow_mut().push(a.clone());
+        assert_eq!(a.to_string(), "[1, [...]]");
+        assert_eq!(
+            array(vec![a.clone(), a.clone()]).to_string(),
+            "[[1, [...]], [1, [...]]]"
+        );
+        assert_eq!(a, a.clone());
+
+        let b = array(vec![Val::Number(1.0)]);
+        let Val::Array(storage) = &b else {
+            unreachable!()
+        };
+        storage.borrow_mut().push(b.clone());
+        assert_eq!(a, b);
+        storage.borrow_mut()[0] = Val::Number(2.0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn converts_scalars_to_json() {
+        assert_eq!(Val::Number(1.5).to_json(), json!(1.5));
+        assert_eq!(Val::Number(f64::NAN).to_json(), json!(null));
+        assert_eq!(Val::String("lox".into()).to_json(), json!("lox"));
+        assert_eq!(Val::Bool(true).to_json(), json!(true));
+        assert_eq!(Val::Nil.to_json(), json!(null));
+    }
+
+    #[test]
+    fn converts_collections_to_json() {
+        let val = map(vec![
+            ("xs", array(vec![Val::Number(1.0), array(vec![Val::Nil])])),
+            ("name", Val::String("a".into())),
+        ]);
+        assert_eq!(val.to_json(), json!({"xs": [1.0, [null]], "name": "a"}));
+
+        let cycle = array(vec![Val::Nil]);
+        let Val::Array(storage) = &cycle else {
+            unreachable!()
+        };
+        storage.borrow_mut().push(cycle.clone());
+        assert_eq!(cycle.to_json(), json!([null, "[...]"]));
+    }
 }
@@ -0,0 +1,44 @@
+use unlox_ast::ExprIdx;
+
+/// Inline cache of resolutions of variables the resolver left to runtime, keyed by the
+/// expression accessing the variable.
+///
+/// Environments are nested the same way whenever an expression is evaluated, so a variable
+/// found some number of environments up stays there, until a new definition shadows it.
+#[derive(Default)]
+pub struct VarCache {
+    entries: Vec<Option<Entry>>,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    depth: usize,
+    /// Number of definitions at the time of resolution.
+    definitions: u64,
+}
+
+impl VarCache {
+    /// Returns the cached depth of the variable accessed by `expr`, unless there were new
+    /// definitions since it was resolved.
+    pub fn get(&self, expr: ExprIdx, definitions: u64) -> Option<usize> {
+        self.entries
+            .get(expr.as_usize())
+            .copied()
+            .flatten()
+            .filter(|entry| entry.definitions == definitions)
+            .map(|entry| entry.depth)
+    }
+
+    pub fn insert(&mut self, expr: ExprIdx, depth: usize, definitions: u64) {
+        let idx = expr.as_usize();
+        if idx >= self.entries.len() {
+            self.entries.resize(idx + 1, None);
+        }
+        self.entries[idx] = Some(Entry { depth, definitions });
+    }
+
+    /// Forgets all entries, e.g. before interpreting a different tree.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
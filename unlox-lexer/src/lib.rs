@@ -6,6 +6,8 @@ mod selection;
 pub struct Lexer<'src> {
     inner: LexerInner<'src>,
     peeked: Option<Token>,
+    /// Whether `Eof` was yielded by the iterator.
+    exhausted: bool,
 }
 
 impl<'src> Lexer<'src> {
@@ -15,8 +17,27 @@ impl<'src> Lexer<'src> {
                 selection: Selection::new(source),
             },
             peeked: None,
+            exhausted: false,
         }
     }
+
+    /// Creates a lexer for the part of `source` from byte `offset` on, e.g. a line appended to
+    /// the code of the prompt. Spans of the tokens point into all of `source`, while their lines
+    /// and columns are counted from `offset`, which should be at the start of a line.
+    pub fn starting_at(source: &'src str, offset: usize) -> Self {
+        Lexer {
+            inner: LexerInner {
+                selection: Selection::starting_at(source, offset),
+            },
+            peeked: None,
+            exhausted: false,
+        }
+    }
+
+    /// Returns an iterator over tokens of `source`, ending with `Eof`.
+    pub fn tokens(source: &'src str) -> impl Iterator<Item = Token> + 'src {
+        Lexer::new(source)
+    }
 }
 
 impl TokenStream for Lexer<'_> {
@@ -32,6 +53,19 @@ impl TokenStream for Lexer<'_> {
     }
 }
 
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.exhausted {
+            return None;
+        }
+        let token = TokenStream::next(self);
+        self.exhausted = token.kind == TokenKind::Eof;
+        Some(token)
+    }
+}
+
 struct LexerInner<'src> {
     selection: Selection<'src>,
 }
@@ -46,11 +80,28 @@ impl LexerInner<'_> {
                 Some(')') => break self.token(TokenKind::RightParen),
                 Some('{') => break self.token(TokenKind::LeftBrace),
                 Some('}') => break self.token(TokenKind::RightBrace),
+                Some('[') => break self.token(TokenKind::LeftBracket),
+                Some(']') => break self.token(TokenKind::RightBracket),
                 Some(',') => break self.token(TokenKind::Comma),
+                Some(':') => break self.token(TokenKind::Colon),
                 Some('.') => break self.token(TokenKind::Dot),
+                Some('-') if self.selection.match_advance('=').is_some() => {
+                    break self.token(TokenKind::MinusEqual)
+                }
                 Some('-') => break self.token(TokenKind::Minus),
+                Some('%') => break self.token(TokenKind::Percent),
+                Some('+') if self.selection.match_advance('=').is_some() => {
+                    break self.token(TokenKind::PlusEqual)
+                }
                 Some('+') => break self.token(TokenKind::Plus),
+                Some('?') => break self.token(TokenKind::Question),
                 Some(';') => break self.token(TokenKind::Semicolon),
+                Some('*') if self.selection.match_advance('*').is_some() => {
+                    break self.token(TokenKind::StarStar)
+                }
+                Some('*') if self.selection.match_advance('=').is_some() => {
+                    break self.token(TokenKind::StarEqual)
+                }
                 Some('*') => break self.token(TokenKind::Star),
                 Some('!') if self.selection.match_advance('=').is_some() => {
                     break self.token(TokenKind::BangEqual)
@@ -71,12 +122,23 @@ impl LexerInner<'_> {
                 Some('/') if self.selection.match_advance('/').is_some() => {
                     self.selection.advance_while(|c| c != '\n')
                 }
+                Some('/') if self.selection.match_advance('*').is_some() => {
+                    if let Some(token) = self.block_comment() {
+                        break token;
+                    }
+                }
+                Some('/') if self.selection.match_advance('=').is_some() => {
+                    break self.token(TokenKind::SlashEqual)
+                }
                 Some('/') => break self.token(TokenKind::Slash),
                 Some('"') => break self.string_token(),
+                Some('0') if matches!(self.selection.peek(), Some('x' | 'b' | 'o')) => {
+                    break self.radix_number_token()
+                }
                 Some('0'..='9') => break self.number_token(),
                 Some('A'..='Z' | 'a'..='z' | '_') => break self.ident_token(),
+                Some(c) => break self.token(TokenKind::Unknown(c)),
                 None => break self.token(TokenKind::Eof),
-                _ => break self.token(TokenKind::Unknown),
             }
         }
     }
@@ -86,25 +148,54 @@ impl LexerInner<'_> {
             kind,
             lexeme: self.selection.range(),
             line: self.selection.line(),
+            col: self.selection.column(),
         }
     }
 
+    /// Scans a string literal, translating escape sequences.
+    ///
+    /// A string with an invalid escape sequence is still scanned up to its closing quote, so that
+    /// lexing can carry on after it.
     fn string_token(&mut self) -> Token {
-        self.selection.advance_while(|c| c != '"');
-        let is_terminated = !self.selection.eof();
-        let kind = if is_terminated {
-            self.selection.advance();
-            let str = self.selection.str();
-            let str = &str[1..str.len() - 1];
-            TokenKind::String(str.to_owned())
-        } else {
-            let str = self.selection.str();
-            let str = &str[1..];
-            TokenKind::StringUnterminated(str.to_owned())
+        let mut value = String::new();
+        let mut invalid_escape = None;
+        let kind = loop {
+            match self.selection.advance() {
+                Some('"') => match invalid_escape {
+                    Some(c) => break TokenKind::StringInvalidEscape(c),
+                    None => break TokenKind::String(value),
+                },
+                Some('\\') => match self.selection.advance() {
+                    Some('\\') => value.push('\\'),
+                    Some('"') => value.push('"'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('0') => value.push('\0'),
+                    Some(c) => {
+                        invalid_escape.get_or_insert(c);
+                    }
+                    None => break TokenKind::StringUnterminated(value),
+                },
+                Some(c) => value.push(c),
+                None => break TokenKind::StringUnterminated(value),
+            }
         };
         self.token(kind)
     }
 
+    /// Skips a block comment following its opening `/*`. Returns a token only if the comment is
+    /// unterminated.
+    fn block_comment(&mut self) -> Option<Token> {
+        loop {
+            match self.selection.advance() {
+                Some('*') if self.selection.match_advance('/').is_some() => return None,
+                Some(_) => (),
+                None => return Some(self.token(TokenKind::UnterminatedComment)),
+            }
+        }
+    }
+
     fn number_token(&mut self) -> Token {
         self.selection.advance_while(|c| c.is_ascii_digit());
 
@@ -113,22 +204,65 @@ impl LexerInner<'_> {
             self.selection.advance_while(|c| c.is_ascii_digit());
         };
 
+        // An exponent without digits isn't part of the number, e.g. `1e` is `1` followed by `e`.
+        let exponent_digit = match self.selection.peek_second() {
+            Some('+' | '-') => self.selection.peek_nth(2),
+            c => c,
+        };
+        if let (Some('e' | 'E'), Some('0'..='9')) = (self.selection.peek(), exponent_digit) {
+            self.selection.advance();
+            if let Some('+' | '-') = self.selection.peek() {
+                self.selection.advance();
+            }
+            self.selection.advance_while(|c| c.is_ascii_digit());
+        }
+
         let value: f64 = self.selection.str().parse().unwrap();
         self.token(TokenKind::Number(value))
     }
 
+    /// Scans an integer literal prefixed with `0x`, `0b` or `0o`, following its leading `0`.
+    ///
+    /// Letters and digits following the prefix all belong to the literal, so a digit invalid in
+    /// the base makes the whole literal an `Unknown` token, carrying the first such digit.
+    fn radix_number_token(&mut self) -> Token {
+        let (prefix, radix) = match self.selection.advance() {
+            Some('x') => ('x', 16),
+            Some('b') => ('b', 2),
+            _ => ('o', 8),
+        };
+        self.selection
+            .advance_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        let digits = &self.selection.str()[2..];
+        let kind = match digits.chars().find(|c| !c.is_digit(radix)) {
+            Some(c) => TokenKind::Unknown(c),
+            None if digits.is_empty() => TokenKind::Unknown(prefix),
+            // Folding into a float rather than parsing an integer, so that large literals lose
+            // precision like decimal ones do instead of overflowing.
+            None => TokenKind::Number(digits.chars().fold(0.0, |value, c| {
+                value * f64::from(radix) + f64::from(c.to_digit(radix).unwrap())
+            })),
+        };
+        self.token(kind)
+    }
+
     fn ident_token(&mut self) -> Token {
         self.selection
-            .advance_while(|c| matches!(c, 'A'..='Z' | 'a'..='z' | '_'));
+            .advance_while(|c| matches!(c, 'A'..='Z' | 'a'..='z' | '_' | '0'..='9'));
         let text = self.selection.str();
         let kind = match text {
             "and" => TokenKind::And,
+            "break" => TokenKind::Break,
             "class" => TokenKind::Class,
+            "continue" => TokenKind::Continue,
+            "do" => TokenKind::Do,
             "else" => TokenKind::Else,
+            "extends" => TokenKind::Extends,
             "false" => TokenKind::False,
             "for" => TokenKind::For,
             "fun" => TokenKind::Fun,
             "if" => TokenKind::If,
+            "in" => TokenKind::In,
             "nil" => TokenKind::Nil,
             "or" => TokenKind::Or,
             "print" => TokenKind::Print,
@@ -136,6 +270,7 @@ impl LexerInner<'_> {
             "super" => TokenKind::Super,
             "this" => TokenKind::This,
             "true" => TokenKind::True,
+            "typeof" => TokenKind::Typeof,
             "var" => TokenKind::Var,
             "while" => TokenKind::While,
             _ => TokenKind::Identifier,
@@ -148,36 +283,76 @@ impl LexerInner<'_> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn iterates_until_eof() {
+        let kinds: Vec<_> = Lexer::tokens("a = 1;").map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Identifier,
+                TokenKind::Equal,
+                TokenKind::Number(1.0),
+                TokenKind::Semicolon,
+                TokenKind::Eof,
+            ]
+        );
+
+        let mut lexer = Lexer::new("");
+        assert_eq!(lexer.peek().kind, TokenKind::Eof);
+        assert_eq!(Iterator::next(&mut lexer).unwrap().kind, TokenKind::Eof);
+        assert_eq!(Iterator::next(&mut lexer), None);
+    }
+
     #[test]
     fn scans_parens() {
         let mut lexer = Lexer::new("()");
         assert_eq!(
-            lexer.next(),
+            TokenStream::next(&mut lexer),
             Token {
                 kind: TokenKind::LeftParen,
                 lexeme: 0..1,
-                line: 1
+                line: 1,
+                col: 1
             }
         );
         assert_eq!(
-            lexer.next(),
+            TokenStream::next(&mut lexer),
             Token {
                 kind: TokenKind::RightParen,
                 lexeme: 1..2,
-                line: 1
+                line: 1,
+                col: 2
             }
         )
     }
 
+    #[test]
+    fn scans_brackets() {
+        let kinds: Vec<_> = Lexer::tokens("a[[0]]").map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Identifier,
+                TokenKind::LeftBracket,
+                TokenKind::LeftBracket,
+                TokenKind::Number(0.0),
+                TokenKind::RightBracket,
+                TokenKind::RightBracket,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
     #[test]
     fn scans_float() {
         let mut lexer = Lexer::new("12.345");
         assert_eq!(
-            lexer.next(),
+            TokenStream::next(&mut lexer),
             Token {
                 kind: TokenKind::Number(12.345),
                 lexeme: 0..6,
-                line: 1
+                line: 1,
+                col: 1
             }
         )
     }
@@ -186,12 +361,235 @@ mod tests {
     fn scans_string() {
         let mut lexer = Lexer::new(r#""string""#);
         assert_eq!(
-            lexer.next(),
+            TokenStream::next(&mut lexer),
             Token {
                 kind: TokenKind::String("string".into()),
                 lexeme: 0..8,
-                line: 1
+                line: 1,
+                col: 1
             }
         )
     }
+
+    #[test]
+    fn scans_escape_sequences() {
+        let mut lexer = Lexer::new(r#""a\\b\"c\nd\te\rf\0""#);
+        assert_eq!(
+            TokenStream::next(&mut lexer).kind,
+            TokenKind::String("a\\b\"c\nd\te\rf\0".into())
+        );
+        assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn rejects_invalid_escape_sequence() {
+        let mut lexer = Lexer::new(r#""a\qb" 1"#);
+        assert_eq!(
+            TokenStream::next(&mut lexer),
+            Token {
+                kind: TokenKind::StringInvalidEscape('q'),
+                lexeme: 0..6,
+                line: 1,
+                col: 1
+            }
+        );
+        assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Number(1.0));
+    }
+
+    #[test]
+    fn skips_block_comments() {
+        let mut lexer = Lexer::new("1 /* a\n * b */ 2 /**/ 3");
+        let numbers: Vec<_> = std::iter::from_fn(|| {
+            let token = TokenStream::next(&mut lexer);
+            (token.kind != TokenKind::Eof).then_some((token.kind, token.line))
+        })
+        .collect();
+        assert_eq!(
+            numbers,
+            [
+                (TokenKind::Number(1.0), 1),
+                (TokenKind::Number(2.0), 2),
+                (TokenKind::Number(3.0), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_block_comment() {
+        let mut lexer = Lexer::new("1\n/* a\nb");
+        assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Number(1.0));
+        assert_eq!(
+            TokenStream::next(&mut lexer),
+            Token {
+                kind: TokenKind::UnterminatedComment,
+                lexeme: 2..8,
+                line: 2,
+                col: 1
+            }
+        );
+        assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn tracks_columns() {
+        let mut lexer = Lexer::new("var a;\n  print\n\"x\ny\" a;");
+        let positions: Vec<_> = std::iter::from_fn(|| {
+            let token = TokenStream::next(&mut lexer);
+            (token.kind != TokenKind::Eof).then_some((token.line, token.col))
+        })
+        .collect();
+        assert_eq!(
+            positions,
+            [(1, 1), (1, 5), (1, 6), (2, 3), (3, 1), (4, 4), (4, 5)]
+        );
+    }
+
+    #[test]
+    fn starts_at_offset() {
+        let src = "var a;\nprint é;";
+        let mut lexer = Lexer::starting_at(src, 7);
+        let print = TokenStream::next(&mut lexer);
+        assert_eq!((print.lexeme, print.line, print.col), (7..12, 1, 1));
+        let e = TokenStream::next(&mut lexer);
+        assert_eq!(e.source_text(src), "é");
+        assert_eq!((e.line, e.col), (1, 7));
+    }
+
+    #[test]
+    fn recovers_from_unknown_character() {
+        let mut lexer = Lexer::new("1 @ é 2");
+        assert_eq!(
+            TokenStream::next(&mut lexer),
+            Token {
+                kind: TokenKind::Number(1.0),
+                lexeme: 0..1,
+                line: 1,
+                col: 1
+            }
+        );
+        assert_eq!(
+            TokenStream::next(&mut lexer),
+            Token {
+                kind: TokenKind::Unknown('@'),
+                lexeme: 2..3,
+                line: 1,
+                col: 3
+            }
+        );
+        assert_eq!(
+            TokenStream::next(&mut lexer),
+            Token {
+                kind: TokenKind::Unknown('é'),
+                lexeme: 4..6,
+                line: 1,
+                col: 5
+            }
+        );
+        assert_eq!(
+            TokenStream::next(&mut lexer),
+            Token {
+                kind: TokenKind::Number(2.0),
+                lexeme: 7..8,
+                line: 1,
+                col: 7
+            }
+        );
+        assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn scans_radix_integers() {
+        for (src, value) in [
+            ("0xFF", 255.0),
+            ("0x1a", 26.0),
+            ("0b1010", 10.0),
+            ("0o17", 15.0),
+            ("0x0", 0.0),
+        ] {
+            let mut lexer = Lexer::new(src);
+            assert_eq!(
+                TokenStream::next(&mut lexer),
+                Token {
+                    kind: TokenKind::Number(value),
+                    lexeme: 0..src.len(),
+                    line: 1,
+                    col: 1
+                }
+            );
+            assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Eof);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_radix_digits() {
+        for (src, c) in [("0b102", '2'), ("0o8", '8'), ("0xFG", 'G'), ("0x", 'x')] {
+            let kinds: Vec<_> = Lexer::tokens(src).map(|token| token.kind).collect();
+            assert_eq!(kinds, [TokenKind::Unknown(c), TokenKind::Eof]);
+        }
+    }
+
+    #[test]
+    fn scans_scientific_notation() {
+        for (src, value) in [
+            ("1e5", 1e5),
+            ("1.5E-3", 1.5e-3),
+            ("0e0", 0.0),
+            ("2e-3", 2e-3),
+            ("2.5e+2", 250.0),
+            ("1e400", f64::INFINITY),
+        ] {
+            let mut lexer = Lexer::new(src);
+            assert_eq!(
+                TokenStream::next(&mut lexer),
+                Token {
+                    kind: TokenKind::Number(value),
+                    lexeme: 0..src.len(),
+                    line: 1,
+                    col: 1
+                }
+            );
+            assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Eof);
+        }
+    }
+
+    #[test]
+    fn leaves_exponent_without_digits() {
+        for src in ["1e", "1e+", "1E-x"] {
+            let mut lexer = Lexer::new(src);
+            assert_eq!(TokenStream::next(&mut lexer).kind, TokenKind::Number(1.0));
+            assert_eq!(
+                TokenStream::next(&mut lexer),
+                Token {
+                    kind: TokenKind::Identifier,
+                    lexeme: 1..2,
+                    line: 1,
+                    col: 2
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn scans_identifiers_with_digits() {
+        for src in ["a1", "_x2y3", "abc123"] {
+            let tokens: Vec<_> = Lexer::tokens(src).collect();
+            assert_eq!(
+                tokens,
+                [
+                    Token {
+                        kind: TokenKind::Identifier,
+                        lexeme: 0..src.len(),
+                        line: 1,
+                        col: 1
+                    },
+                    Token {
+                        kind: TokenKind::Eof,
+                        lexeme: src.len()..src.len(),
+                        line: 1,
+                        col: src.len() as u32 + 1
+                    }
+                ]
+            );
+        }
+    }
 }
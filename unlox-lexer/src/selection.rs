@@ -5,7 +5,12 @@ pub struct Selection<'a> {
     source: &'a str,
     start: usize,
     end: usize,
-    line: u32,
+    /// Line and column of the end position.
+    end_line: u32,
+    end_column: u32,
+    /// Line and column of the beginning position.
+    start_line: u32,
+    start_column: u32,
 }
 
 impl<'a> Selection<'a> {
@@ -15,16 +20,32 @@ impl<'a> Selection<'a> {
             source,
             start: 0,
             end: 0,
-            line: 1,
+            end_line: 1,
+            end_column: 1,
+            start_line: 1,
+            start_column: 1,
+        }
+    }
+
+    /// Creates an empty selection at byte `offset` of `source` text, counting lines and columns
+    /// from there.
+    pub fn starting_at(source: &'a str, offset: usize) -> Self {
+        Selection {
+            start: offset,
+            end: offset,
+            ..Selection::new(source)
         }
     }
 
     /// Advances the end of the selection by one character.
     pub fn advance(&mut self) -> Option<char> {
         let c = self.peek()?;
-        self.end += 1;
+        self.end += c.len_utf8();
         if c == '\n' {
-            self.line += 1;
+            self.end_line += 1;
+            self.end_column = 1;
+        } else {
+            self.end_column += 1;
         }
         Some(c)
     }
@@ -42,10 +63,7 @@ impl<'a> Selection<'a> {
     /// Advances the end of the selection if the next character matches the `expected` character.
     pub fn match_advance(&mut self, expected: char) -> Option<char> {
         match self.peek() {
-            Some(c) if c == expected => {
-                self.end += 1;
-                Some(c)
-            }
+            Some(c) if c == expected => self.advance(),
             _ => None,
         }
     }
@@ -57,12 +75,19 @@ impl<'a> Selection<'a> {
 
     /// Peek at the character after the next character without advancing the selection.
     pub fn peek_second(&self) -> Option<char> {
-        self.source.get((self.end + 1)..)?.chars().next()
+        self.source[self.end..].chars().nth(1)
+    }
+
+    /// Peek at the `n`-th character after the next one without advancing the selection.
+    pub fn peek_nth(&self, n: usize) -> Option<char> {
+        self.source[self.end..].chars().nth(n)
     }
 
     /// Clears the selection by moving it's beginning to it's end.
     pub fn clear(&mut self) {
-        self.start = self.end
+        self.start = self.end;
+        self.start_line = self.end_line;
+        self.start_column = self.end_column;
     }
 
     pub fn range(&self) -> Range<usize> {
@@ -74,12 +99,13 @@ impl<'a> Selection<'a> {
         &self.source[self.start..self.end]
     }
 
-    /// Returns the line number of the selection's end position.
+    /// Returns the line number of the selection's beginning position.
     pub fn line(&self) -> u32 {
-        self.line
+        self.start_line
     }
 
-    pub fn eof(&self) -> bool {
-        self.end >= self.source.len()
+    /// Returns the column number of the selection's beginning position.
+    pub fn column(&self) -> u32 {
+        self.start_column
     }
 }
@@ -0,0 +1,156 @@
+use unlox_ast::{Ast, Expr, ExprIdx, Stmt, StmtIdx, Token, TokenKind};
+
+/// Code that can't affect the program, reported by an optimization pass.
+#[derive(Debug, thiserror::Error)]
+#[error("[Line {}:{}]: {message}", token.line, token.col)]
+pub struct Warning {
+    pub token: Token,
+    pub message: String,
+}
+
+/// Removes the statements of blocks, function and lambda bodies following a `return`, `break` or
+/// `continue`, which can't be reached. Statements always jumping away, e.g. an `if` returning from
+/// both branches, count as well.
+///
+/// Returns a warning for every statement list that was cut short, reported at the jump. The
+/// removed statements are left unreachable, [`Ast::compact`] removes them.
+pub fn eliminate_dead_code(ast: &mut Ast) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut lists: Vec<_> = ast
+        .walk_stmts()
+        .filter(|(_, stmt)| matches!(stmt, Stmt::Block(_) | Stmt::Function { .. }))
+        .map(|(idx, _)| List::Stmt(idx))
+        .collect();
+    lists.extend(
+        ast.walk_exprs()
+            .filter(|(_, expr)| matches!(expr, Expr::Lambda { .. }))
+            .map(|(idx, _)| List::Lambda(idx)),
+    );
+    for list in lists {
+        let stmts = list.stmts(ast);
+        let Some((len, jump)) = stmts
+            .iter()
+            .enumerate()
+            .find_map(|(i, stmt)| jump(ast, *stmt).map(|jump| (i + 1, jump)))
+        else {
+            continue;
+        };
+        if len == stmts.len() {
+            continue;
+        }
+        let keyword = match jump.kind {
+            TokenKind::Return => "return",
+            TokenKind::Break => "break",
+            _ => "continue",
+        };
+        warnings.push(Warning {
+            message: format!("Code after '{keyword}' is unreachable."),
+            token: jump,
+        });
+        list.stmts_mut(ast).truncate(len);
+    }
+    warnings
+}
+
+/// Node holding a list of statements run one after another.
+#[derive(Clone, Copy)]
+enum List {
+    /// A block or a function.
+    Stmt(StmtIdx),
+    /// A lambda.
+    Lambda(ExprIdx),
+}
+
+impl List {
+    fn stmts(self, ast: &Ast) -> &Vec<StmtIdx> {
+        match self {
+            List::Stmt(idx) => match ast.stmt(idx) {
+                Stmt::Block(stmts) | Stmt::Function { body: stmts, .. } => stmts,
+                _ => unreachable!(),
+            },
+            List::Lambda(idx) => match ast.expr(idx) {
+                Expr::Lambda { body, .. } => body,
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    fn stmts_mut(self, ast: &mut Ast) -> &mut Vec<StmtIdx> {
+        match self {
+            List::Stmt(idx) => match ast.stmt_mut(idx) {
+                Stmt::Block(stmts) | Stmt::Function { body: stmts, .. } => stmts,
+                _ => unreachable!(),
+            },
+            List::Lambda(idx) => match ast.expr_mut(idx) {
+                Expr::Lambda { body, .. } => body,
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+/// Returns the `return`, `break` or `continue` keyword `stmt` always ends with, if it does.
+fn jump(ast: &Ast, stmt: StmtIdx) -> Option<Token> {
+    match ast.stmt(stmt) {
+        Stmt::Return(keyword, _) | Stmt::Break(keyword) | Stmt::Continue(keyword) => {
+            Some(keyword.clone())
+        }
+        Stmt::Block(stmts) => stmts.iter().find_map(|stmt| jump(ast, *stmt)),
+        Stmt::If {
+            then_branch,
+            else_branch: Some(else_branch),
+            ..
+        } => {
+            let then_jump = jump(ast, *then_branch)?;
+            jump(ast, *else_branch)?;
+            Some(then_jump)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_ast::PrettyPrinter;
+    use unlox_lexer::Lexer;
+
+    fn eliminate(src: &str) -> (String, Vec<String>) {
+        let mut ast = unlox_parse::parse(Lexer::new(src)).ast;
+        let warnings = eliminate_dead_code(&mut ast);
+        let warnings = warnings.iter().map(ToString::to_string).collect();
+        (PrettyPrinter::new(src).print(&ast), warnings)
+    }
+
+    #[test]
+    fn removes_code_after_jumps() {
+        let src = r#"
+            fun f() { return 1; print "dead"; }
+            while (true) { { break; } print "dead"; }
+            fun g(x) { if (x) return 1; else return 2; print "dead"; }
+            var h = fun () { return; print "dead"; };
+        "#;
+        let (printed, warnings) = eliminate(src);
+        assert!(!printed.contains("dead"), "{printed}");
+        assert_eq!(
+            warnings,
+            [
+                "[Line 2:23]: Code after 'return' is unreachable.",
+                "[Line 3:30]: Code after 'break' is unreachable.",
+                "[Line 4:31]: Code after 'return' is unreachable.",
+                "[Line 5:30]: Code after 'return' is unreachable.",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_reachable_code() {
+        let src = r#"
+            fun f(x) { if (x) return 1; print "live"; }
+            while (true) { if (false) break; print "live"; }
+        "#;
+        let (printed, warnings) = eliminate(src);
+        assert_eq!(printed.matches("live").count(), 2, "{printed}");
+        assert!(warnings.is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use unlox_ast::{display_number, Ast, Expr, ExprIdx, Lit, TokenKind};
+
+/// Replaces unary and binary expressions on literals with the literal they evaluate to, e.g.
+/// `2 * 3 + 1` becomes `7`.
+///
+/// Expressions raising a runtime error, e.g. `1 / 0` or `-"a"`, are left for the interpreter to
+/// report, as are the ones resulting in NaN. The operands of folded expressions are left
+/// unreachable, [`Ast::compact`] removes them. Running the pass again changes nothing.
+pub fn fold_constants(ast: &mut Ast) {
+    // The parser pushes operands before the expressions using them, so a single pass in order
+    // usually folds everything. Trees built differently may need more.
+    loop {
+        let mut folded = false;
+        let indices: Vec<_> = ast.walk_exprs().map(|(idx, _)| idx).collect();
+        for idx in indices {
+            if let Some(lit) = fold(ast, idx) {
+                *ast.expr_mut(idx) = Expr::Literal(lit);
+                folded = true;
+            }
+        }
+        if !folded {
+            break;
+        }
+    }
+}
+
+/// Evaluates `expr` if it's an operation on literals.
+fn fold(ast: &Ast, expr: ExprIdx) -> Option<Lit> {
+    let lit = match ast.expr(expr) {
+        Expr::Unary(operator, right) => match (&operator.kind, literal(ast, *right)?) {
+            (TokenKind::Bang, right) => Lit::Bool(!right.is_truthy()),
+            (TokenKind::Minus, Lit::Number(n)) => Lit::Number(-n),
+            _ => return None,
+        },
+        Expr::Binary(operator, left, right) => {
+            match (&operator.kind, literal(ast, *left)?, literal(ast, *right)?) {
+                (TokenKind::EqualEqual, l, r) => Lit::Bool(l == r),
+                (TokenKind::BangEqual, l, r) => Lit::Bool(l != r),
+                (TokenKind::Plus, Lit::String(l), Lit::String(r)) => Lit::String(l + &r),
+                // Numbers are converted when concatenated with strings.
+                (TokenKind::Plus, Lit::String(l), Lit::Number(r)) => {
+                    Lit::String(l + &display_number(r))
+                }
+                (TokenKind::Plus, Lit::Number(l), Lit::String(r)) => {
+                    Lit::String(display_number(l) + &r)
+                }
+                (kind, Lit::Number(l), Lit::Number(r)) => match kind {
+                    TokenKind::Plus => Lit::Number(l + r),
+                    TokenKind::Minus => Lit::Number(l - r),
+                    TokenKind::Star => Lit::Number(l * r),
+                    TokenKind::Slash if r != 0.0 => Lit::Number(l / r),
+                    TokenKind::Percent if r != 0.0 => Lit::Number(l % r),
+                    TokenKind::StarStar => Lit::Number(l.powf(r)),
+                    TokenKind::Greater => Lit::Bool(l > r),
+                    TokenKind::GreaterEqual => Lit::Bool(l >= r),
+                    TokenKind::Less => Lit::Bool(l < r),
+                    TokenKind::LessEqual => Lit::Bool(l <= r),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    match lit {
+        Lit::Number(n) if n.is_nan() => None,
+        lit => Some(lit),
+    }
+}
+
+/// Returns the value of `expr` if it's a literal, possibly in parentheses.
+fn literal(ast: &Ast, expr: ExprIdx) -> Option<Lit> {
+    match ast.expr(expr) {
+        Expr::Literal(lit) => Some(lit.clone()),
+        Expr::Grouping(inner) => literal(ast, *inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_lexer::Lexer;
+
+    fn folded(src: &str) -> Expr {
+        let mut ast = Ast::new();
+        let expr = unlox_parse::parse_expr(Lexer::new(src), &mut ast).unwrap();
+        fold_constants(&mut ast);
+        ast.expr(expr).clone()
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        assert!(matches!(
+            folded("2 * 3 + 1"),
+            Expr::Literal(Lit::Number(7.0))
+        ));
+        assert!(matches!(
+            folded("-(2 ** 3) < 0 == !nil"),
+            Expr::Literal(Lit::Bool(true))
+        ));
+        assert!(matches!(
+            folded(r#""a" + 1 + "b""#),
+            Expr::Literal(Lit::String(s)) if s == "a1b"
+        ));
+    }
+
+    #[test]
+    fn leaves_runtime_errors() {
+        for src in [
+            "1 / 0",
+            "1 % (1 - 1)",
+            "-\"a\"",
+            "1 + nil",
+            "(-8) ** 0.5",
+            "x + 1",
+        ] {
+            assert!(
+                matches!(folded(src), Expr::Binary(..) | Expr::Unary(..)),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn is_idempotent() {
+        let mut ast = Ast::new();
+        let expr = unlox_parse::parse_expr(Lexer::new("(1 + 2) * x - 3 * 4"), &mut ast).unwrap();
+        fold_constants(&mut ast);
+        let once = format!("{:?}", ast.expr(expr));
+        fold_constants(&mut ast);
+        assert_eq!(format!("{:?}", ast.expr(expr)), once);
+    }
+}
@@ -0,0 +1,7 @@
+//! Optimization passes over the syntax tree, run between parsing and execution.
+
+pub use dead_code::{eliminate_dead_code, Warning};
+pub use fold::fold_constants;
+
+mod dead_code;
+mod fold;
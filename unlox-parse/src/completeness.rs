@@ -0,0 +1,88 @@
+use unlox_ast::TokenKind;
+use unlox_lexer::Lexer;
+
+/// Result of checking whether a source text can be handed to the parser as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completeness {
+    /// All delimiters are balanced and the input doesn't stop in the middle of a construct.
+    Complete,
+    /// The input is a prefix of a possibly valid program, more text is needed.
+    Incomplete,
+    /// No amount of additional text can make the input valid.
+    Invalid,
+}
+
+/// Checks whether `src` is ready to be parsed.
+///
+/// This is a lexical check only - it looks at delimiter balance, unterminated strings and
+/// trailing operators, so a `Complete` input can still fail to parse.
+pub fn is_complete(src: &str) -> Completeness {
+    let mut open = vec![];
+    let mut last = TokenKind::Eof;
+    for token in Lexer::tokens(src) {
+        match token.kind {
+            TokenKind::LeftParen | TokenKind::LeftBrace | TokenKind::LeftBracket => {
+                open.push(token.kind.clone())
+            }
+            TokenKind::RightParen if open.pop() != Some(TokenKind::LeftParen) => {
+                return Completeness::Invalid
+            }
+            TokenKind::RightBrace if open.pop() != Some(TokenKind::LeftBrace) => {
+                return Completeness::Invalid
+            }
+            TokenKind::RightBracket if open.pop() != Some(TokenKind::LeftBracket) => {
+                return Completeness::Invalid
+            }
+            TokenKind::StringUnterminated(_) | TokenKind::UnterminatedComment => {
+                return Completeness::Incomplete
+            }
+            TokenKind::StringInvalidEscape(_) | TokenKind::Unknown(_) => {
+                return Completeness::Invalid
+            }
+            TokenKind::Eof => break,
+            _ => (),
+        }
+        last = token.kind;
+    }
+
+    if !open.is_empty() || last.is_operator() || matches!(last, TokenKind::Comma | TokenKind::Dot) {
+        Completeness::Incomplete
+    } else {
+        Completeness::Complete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_statement() {
+        assert_eq!(is_complete("print 1 + 2;"), Completeness::Complete);
+        assert_eq!(is_complete("fun f() { return 1; }"), Completeness::Complete);
+        assert_eq!(is_complete(""), Completeness::Complete);
+    }
+
+    #[test]
+    fn unbalanced_brace() {
+        assert_eq!(is_complete("fun f() {"), Completeness::Incomplete);
+        assert_eq!(
+            is_complete("if (true) { print (1"),
+            Completeness::Incomplete
+        );
+    }
+
+    #[test]
+    fn unfinished_expression() {
+        assert_eq!(is_complete("var a = 1 +"), Completeness::Incomplete);
+        assert_eq!(is_complete(r#"print "abc"#), Completeness::Incomplete);
+        assert_eq!(is_complete("print 1; /* note"), Completeness::Incomplete);
+    }
+
+    #[test]
+    fn invalid_token() {
+        assert_eq!(is_complete("print @;"), Completeness::Invalid);
+        assert_eq!(is_complete("print (1};"), Completeness::Invalid);
+        assert_eq!(is_complete("}"), Completeness::Invalid);
+    }
+}
@@ -2,44 +2,66 @@
 //! ```text
 //! program        → declaration* EOF ;
 //!
-//! declaration    → fun_decl | var_decl | statement ;
+//! declaration    → class_decl | fun_decl | var_decl | statement ;
 //!
-//! statement      → expr_stmt | for_stmt | if_stmt | print_stmt | return_stmt | while_stmt | block ;
+//! A statement starting with "fun" is always a function declaration. Lambdas are expressions, so
+//! they need to be wrapped in parentheses to start a statement.
+//!
+//! statement      → expr_stmt | for_stmt | for_in_stmt | if_stmt | print_stmt | return_stmt
+//!                | break_stmt | continue_stmt | while_stmt | do_while_stmt | block ;
 //!
 //! expr_stmt      → expression ";" ;
 //! for_stmt       → "for" "(" (var_decl | expr_stmt | ";" ) expression? ";" expression? ")" statement;
+//! for_in_stmt    → "for" "(" IDENTIFIER "in" expression ")" statement ;
 //! if_stmt        → "if" "(" epxression ")" statement ( "else" statement)? ;
 //! print_stmt     → "print" expression ";" ;
 //! return_stmt    → "return" expression? ";" ;
+//! break_stmt     → "break" ";" ;
+//! continue_stmt  → "continue" ";" ;
 //! while_stmt     → "while" "(" expression ")" statement ;
+//! do_while_stmt  → "do" block "while" "(" expression ")" ";" ;
 //! block          → "{" declaration* "}" ;
 //!
-//! fun_decl       → "fun" IDENTIFIER "(" parameters? ")" block ;
-//! parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
+//! class_decl     → "class" IDENTIFIER ( "extends" IDENTIFIER )? "{" function* "}" ;
+//! fun_decl       → "fun" function ;
+//! function       → IDENTIFIER "(" parameters? ")" block ;
+//! parameters     → parameter ( "," parameter )* ;
+//! parameter      → IDENTIFIER ( ":" IDENTIFIER )? ;
 //! var_decl       → "var" IDENTIFIER ( "=" expression )? ";" ;
 //! expression     → assignment ;
-//! assignment     → IDENTIFIER "=" assignment | logic_or ;
+//! assignment     → IDENTIFIER ( "=" | "+=" | "-=" | "*=" | "/=" ) assignment
+//!                | call "." IDENTIFIER "=" assignment | call "[" expression "]" "=" assignment
+//!                | ternary ;
+//! ternary        → logic_or ( "?" expression ":" ternary )? ;
 //! logic_or       → logic_and ( "or" logic_and )* ;
 //! logic_and      → equality ( "and" equality )* ;
 //! equality       → comparison ( ( "!=" | "==" ) comparison )* ;
 //! comparison     → term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
 //! term           → factor ( ( "-" | "+" ) factor )* ;
-//! factor         → unary ( ( "/" | "*" ) unary )* ;
-//! unary          → ( "!" | "-" ) unary | primary ;
-//! call           → primary ( "(" arguments? ")" )*  ;
+//! factor         → power ( ( "/" | "*" | "%" ) power )* ;
+//! power          → unary ( "**" power )? ;
+//! unary          → ( "!" | "-" | "typeof" ) unary | primary ;
+//! call           → primary ( "(" arguments? ")" | "." IDENTIFIER | "[" expression "]" )*  ;
 //! arguments      → expression ( "," expression )* ;
-//! primary        → NUMBER | STRING | "true" | "false" | "nil" | "(" expression ")" | IDENTIFIER;
+//! primary        → NUMBER | STRING | "true" | "false" | "nil" | "this" | "(" expression ")"
+//!                | IDENTIFIER | "super" "." IDENTIFIER | lambda | array ;
+//! lambda         → "fun" "(" parameters? ")" block ;
+//! array          → "[" arguments? "]" ;
 //! ```
 
-use std::{fmt::Display, io};
+use std::fmt::Display;
 
 use unlox_ast::{
     tokens::{matcher, TokenStream, TokenStreamExt},
-    Ast, Expr, Lit, Stmt, Token, TokenKind,
+    Ast, Expr, ExprIdx, Lit, Param, Stmt, StmtIdx, Token, TokenKind,
 };
 
+pub use completeness::{is_complete, Completeness};
+
+mod completeness;
+
 #[derive(Debug, thiserror::Error)]
-#[error("{message}")]
+#[error("[Line {}:{}]: {message}", token.line, token.col)]
 pub struct Error {
     pub token: Token,
     pub message: String,
@@ -56,126 +78,209 @@ impl Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
-pub fn parse(mut stream: impl TokenStream, err: &mut impl io::Write) -> Ast {
+trait ParseStreamExt {
+    /// Takes the next token if it's of `kind`, otherwise returns an error with `message` at it.
+    fn expect_next(&mut self, kind: TokenKind, message: impl Display) -> Result<Token>;
+}
+
+impl<T: TokenStream> ParseStreamExt for T {
+    fn expect_next(&mut self, kind: TokenKind, message: impl Display) -> Result<Token> {
+        self.match_next(matcher::eq(kind))
+            .map_err(|t| Error::new(t, message))
+    }
+}
+
+/// State shared by the grammar rules while parsing.
+struct Ctx {
+    /// Errors reported so far, parsing continues after each of them.
+    errors: Vec<Error>,
+    /// Number of loops enclosing the statement being parsed, within the current function.
+    loop_depth: usize,
+    /// Whether the statement being parsed is within a function body.
+    in_function: bool,
+    /// Innermost class enclosing the expression being parsed.
+    class: Option<ClassKind>,
+}
+
+impl Ctx {
+    fn new() -> Self {
+        Self {
+            errors: vec![],
+            loop_depth: 0,
+            in_function: false,
+            class: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClassKind {
+    Class,
+    /// Class with a superclass.
+    Subclass,
+}
+
+/// Tree parsed from a token stream, along with the errors encountered on the way.
+#[derive(Debug)]
+pub struct ParseResult {
+    pub ast: Ast,
+    pub errors: Vec<Error>,
+}
+
+/// Parses the token stream into an [`Ast`].
+///
+/// Parsing recovers from errors at statement boundaries, so all of them are collected.
+/// Statements that fail to parse are kept in the tree as [`Stmt::ParseErr`].
+pub fn parse(mut stream: impl TokenStream) -> ParseResult {
     let mut ast = Ast::new();
+    let mut ctx = Ctx::new();
     while !stream.eof() {
-        let stmt = declaration(&mut stream, err, &mut ast);
+        let stmt = declaration(&mut stream, &mut ctx, &mut ast);
         ast.push_root_stmt(stmt);
     }
-    ast
+    ParseResult {
+        ast,
+        errors: ctx.errors,
+    }
 }
 
-fn declaration(stream: &mut impl TokenStream, err: &mut impl io::Write, ast: &mut Ast) -> Stmt {
+/// Parses a single expression spanning the whole token stream into `ast`.
+///
+/// The expression isn't added to the roots of `ast`.
+pub fn parse_expr(
+    mut stream: impl TokenStream,
+    ast: &mut Ast,
+) -> std::result::Result<ExprIdx, Error> {
+    let mut ctx = Ctx::new();
+    let expr = expression(&mut stream, &mut ctx, ast)?;
+    // Errors in the bodies of lambdas are recovered from, so they only show up here.
+    if let Some(error) = ctx.errors.into_iter().next() {
+        return Err(error);
+    }
+    if !stream.eof() {
+        return Err(Error::new(stream.next(), "Expected end of expression."));
+    }
+    Ok(ast.push_expr(expr))
+}
+
+fn declaration(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Stmt {
     let token = stream.peek();
+    let start = token.lexeme.start;
     let result = match &token.kind {
         TokenKind::Var => {
             stream.next();
-            var_decl(stream, ast)
+            var_decl(stream, ctx, ast)
+        }
+        TokenKind::Class => {
+            stream.next();
+            class_decl(stream, ctx, ast)
         }
         TokenKind::Fun => {
             stream.next();
-            fun_decl(stream, err, ast, "function")
+            fun_decl(stream, ctx, ast, "function")
         }
-        _ => statement(stream, err, ast),
+        _ => statement(stream, ctx, ast),
     };
-    result.unwrap_or_else(|err| {
-        synchronize(stream);
-        Stmt::ParseErr(err.token.clone(), err.message)
+    result.unwrap_or_else(|error| {
+        let stmt = Stmt::ParseErr(error.token.clone(), error.message.clone());
+        ctx.errors.push(error);
+        synchronize(stream, start);
+        stmt
     })
 }
 
-fn statement(
-    stream: &mut impl TokenStream,
-    err: &mut impl io::Write,
-    ast: &mut Ast,
-) -> Result<Stmt> {
+fn statement(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
     let token = stream.peek();
     let stmt = match &token.kind {
         TokenKind::For => {
             stream.next();
-            for_statement(stream, err, ast)
+            for_statement(stream, ctx, ast)
         }
         TokenKind::If => {
             stream.next();
-            if_statement(stream, err, ast)
+            if_statement(stream, ctx, ast)
         }
         TokenKind::Print => {
             stream.next();
-            print_statement(stream, ast)
+            print_statement(stream, ctx, ast)
         }
         TokenKind::Return => {
             let keyword = stream.next();
-            return_statement(stream, ast, keyword)
+            return_statement(stream, ctx, ast, keyword)
+        }
+        TokenKind::Break | TokenKind::Continue => {
+            let keyword = stream.next();
+            loop_jump_statement(stream, ctx, keyword)
         }
         TokenKind::While => {
             stream.next();
-            while_statement(stream, err, ast)
+            while_statement(stream, ctx, ast)
+        }
+        TokenKind::Do => {
+            stream.next();
+            do_while_statement(stream, ctx, ast)
         }
         TokenKind::LeftBrace => {
             stream.next();
-            let stmt_indices = block(stream, err, ast)?
+            let stmt_indices = block(stream, ctx, ast)?
                 .into_iter()
                 .map(|stmt| ast.push_stmt(stmt))
                 .collect();
             Ok(Stmt::Block(stmt_indices))
         }
-        _ => expression_statement(stream, ast),
+        _ => expression_statement(stream, ctx, ast),
     }?;
     Ok(stmt)
 }
 
-fn for_statement(
-    stream: &mut impl TokenStream,
-    err: &mut impl io::Write,
-    ast: &mut Ast,
-) -> Result<Stmt> {
-    stream
-        .match_next(matcher::eq(TokenKind::LeftParen))
-        .map_err(|t| Error::new(t, "Expected '(' after 'for'."))?;
-    let init = match stream.peek().kind {
+fn for_statement(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    stream.expect_next(TokenKind::LeftParen, "Expected '(' after 'for'.")?;
+    let init = match stream.peek_kind() {
         TokenKind::Semicolon => {
             stream.next();
             None
         }
         TokenKind::Var => {
             stream.next();
-            Some(var_decl(stream, ast)?)
+            Some(var_decl(stream, ctx, ast)?)
+        }
+        _ => {
+            let expr = expression(stream, ctx, ast)?;
+            // Only a lone variable followed by `in` makes a for-in loop.
+            if let Expr::Variable(name) = &expr {
+                if stream.match_next(matcher::eq(TokenKind::In)).is_ok() {
+                    return for_in_statement(stream, ctx, ast, name.clone());
+                }
+            }
+            stream.expect_next(TokenKind::Semicolon, "Expected ';' after expression.")?;
+            Some(Stmt::Expression(ast.push_expr(expr)))
         }
-        _ => Some(expression_statement(stream, ast)?),
     };
 
-    let cond = if stream.peek().kind != TokenKind::Semicolon {
-        Some(expression(stream, ast)?)
+    let cond = if *stream.peek_kind() != TokenKind::Semicolon {
+        Some(expression(stream, ctx, ast)?)
     } else {
         None
     };
 
-    stream
-        .match_next(matcher::eq(TokenKind::Semicolon))
-        .map_err(|t| Error::new(t, "Expected ';' after loop condition."))?;
+    stream.expect_next(TokenKind::Semicolon, "Expected ';' after loop condition.")?;
 
-    let inc = if stream.peek().kind != TokenKind::RightParen {
-        Some(expression(stream, ast)?)
+    let inc = if *stream.peek_kind() != TokenKind::RightParen {
+        Some(expression(stream, ctx, ast)?)
     } else {
         None
     };
 
-    stream
-        .match_next(matcher::eq(TokenKind::RightParen))
-        .map_err(|t| Error::new(t, "Expected ')' after for clauses."))?;
+    stream.expect_next(TokenKind::RightParen, "Expected ')' after for clauses.")?;
 
-    let mut body = statement(stream, err, ast)?;
-    if let Some(inc) = inc {
-        let inc = ast.push_expr(inc);
-        body = Stmt::Block(vec![
-            ast.push_stmt(body),
-            ast.push_stmt(Stmt::Expression(inc)),
-        ]);
-    }
+    ctx.loop_depth += 1;
+    let body = statement(stream, ctx, ast);
+    ctx.loop_depth -= 1;
     let cond = cond.unwrap_or(Expr::Literal(Lit::Bool(true)));
     let while_stmt = Stmt::While {
         cond: ast.push_expr(cond),
-        body: ast.push_stmt(body),
+        body: ast.push_stmt(body?),
+        inc: inc.map(|inc| ast.push_expr(inc)),
     };
     let for_stmt = if let Some(init) = init {
         Stmt::Block(vec![ast.push_stmt(init), ast.push_stmt(while_stmt)])
@@ -185,23 +290,33 @@ fn for_statement(
     Ok(for_stmt)
 }
 
-fn if_statement(
+fn for_in_statement(
     stream: &mut impl TokenStream,
-    err: &mut impl io::Write,
+    ctx: &mut Ctx,
     ast: &mut Ast,
+    name: Token,
 ) -> Result<Stmt> {
-    stream
-        .match_next(matcher::eq(TokenKind::LeftParen))
-        .map_err(|t| Error::new(t, "Expected '(' after 'if'."))?;
-    let cond = expression(stream, ast)?;
-    stream
-        .match_next(matcher::eq(TokenKind::RightParen))
-        .map_err(|t| Error::new(t, "Expected ')' after if condition."))?;
-    let then_branch = statement(stream, err, ast)?;
+    let iterable = expression(stream, ctx, ast)?;
+    stream.expect_next(TokenKind::RightParen, "Expected ')' after for-in iterable.")?;
+    ctx.loop_depth += 1;
+    let body = statement(stream, ctx, ast);
+    ctx.loop_depth -= 1;
+    Ok(Stmt::ForIn {
+        name,
+        iterable: ast.push_expr(iterable),
+        body: ast.push_stmt(body?),
+    })
+}
+
+fn if_statement(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    stream.expect_next(TokenKind::LeftParen, "Expected '(' after 'if'.")?;
+    let cond = expression(stream, ctx, ast)?;
+    stream.expect_next(TokenKind::RightParen, "Expected ')' after if condition.")?;
+    let then_branch = statement(stream, ctx, ast)?;
     let else_branch = stream
         .match_next(matcher::eq(TokenKind::Else))
         .ok()
-        .map(|_| statement(stream, err, ast))
+        .map(|_| statement(stream, ctx, ast))
         .transpose()?;
     Ok(Stmt::If {
         cond: ast.push_expr(cond),
@@ -210,84 +325,165 @@ fn if_statement(
     })
 }
 
-fn while_statement(
-    stream: &mut impl TokenStream,
-    err: &mut impl io::Write,
-    ast: &mut Ast,
-) -> Result<Stmt> {
-    stream
-        .match_next(matcher::eq(TokenKind::LeftParen))
-        .map_err(|t| Error::new(t, "Expected '(' after 'while'."))?;
-    let cond = expression(stream, ast)?;
-    stream
-        .match_next(matcher::eq(TokenKind::RightParen))
-        .map_err(|t| Error::new(t, "Expected ')' after condition."))?;
-    let body = statement(stream, err, ast)?;
+fn while_statement(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    stream.expect_next(TokenKind::LeftParen, "Expected '(' after 'while'.")?;
+    let cond = expression(stream, ctx, ast)?;
+    stream.expect_next(TokenKind::RightParen, "Expected ')' after condition.")?;
+    ctx.loop_depth += 1;
+    let body = statement(stream, ctx, ast);
+    ctx.loop_depth -= 1;
     Ok(Stmt::While {
         cond: ast.push_expr(cond),
-        body: ast.push_stmt(body),
+        body: ast.push_stmt(body?),
+        inc: None,
+    })
+}
+
+fn do_while_statement(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    stream.expect_next(TokenKind::LeftBrace, "Expected '{' after 'do'.")?;
+    ctx.loop_depth += 1;
+    let body = block(stream, ctx, ast);
+    ctx.loop_depth -= 1;
+    let body = body?.into_iter().map(|stmt| ast.push_stmt(stmt)).collect();
+    stream.expect_next(TokenKind::While, "Expected 'while' after do-while body.")?;
+    stream.expect_next(TokenKind::LeftParen, "Expected '(' after 'while'.")?;
+    let cond = expression(stream, ctx, ast)?;
+    stream.expect_next(TokenKind::RightParen, "Expected ')' after condition.")?;
+    stream.expect_next(TokenKind::Semicolon, "Expected ';' after do-while loop.")?;
+    Ok(Stmt::DoWhile {
+        body: ast.push_stmt(Stmt::Block(body)),
+        cond: ast.push_expr(cond),
     })
 }
 
-fn print_statement(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Stmt> {
-    let expr = expression(stream, ast)?;
-    stream
-        .match_next(matcher::eq(TokenKind::Semicolon))
-        .map_err(|t| Error::new(t, "Expected ';' after value."))?;
+fn print_statement(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    let expr = expression(stream, ctx, ast)?;
+    stream.expect_next(TokenKind::Semicolon, "Expected ';' after value.")?;
     Ok(Stmt::Print(ast.push_expr(expr)))
 }
 
-fn return_statement(stream: &mut impl TokenStream, ast: &mut Ast, keyword: Token) -> Result<Stmt> {
-    let val = if stream.peek().kind != TokenKind::Semicolon {
-        Some(expression(stream, ast)?)
+fn return_statement(
+    stream: &mut impl TokenStream,
+    ctx: &mut Ctx,
+    ast: &mut Ast,
+    keyword: Token,
+) -> Result<Stmt> {
+    if !ctx.in_function {
+        return Err(Error::new(keyword, "Can't return from top-level code."));
+    }
+    let val = if *stream.peek_kind() != TokenKind::Semicolon {
+        Some(expression(stream, ctx, ast)?)
     } else {
         None
     };
-    stream
-        .match_next(matcher::eq(TokenKind::Semicolon))
-        .map_err(|t| Error::new(t, "Expected ';' after return value."))?;
+    stream.expect_next(TokenKind::Semicolon, "Expected ';' after return value.")?;
     Ok(Stmt::Return(keyword, val.map(|v| ast.push_expr(v))))
 }
 
-fn expression_statement(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Stmt> {
-    let expr = expression(stream, ast)?;
-    stream
-        .match_next(matcher::eq(TokenKind::Semicolon))
-        .map_err(|t| Error::new(t, "Expected ';' after expression."))?;
-    Ok(Stmt::Expression(ast.push_expr(expr)))
+/// Parses `break` or `continue` following `keyword`.
+fn loop_jump_statement(
+    stream: &mut impl TokenStream,
+    ctx: &mut Ctx,
+    keyword: Token,
+) -> Result<Stmt> {
+    let name = match keyword.kind {
+        TokenKind::Break => "break",
+        _ => "continue",
+    };
+    if ctx.loop_depth == 0 {
+        return Err(Error::new(
+            keyword,
+            format!("Can't use '{name}' outside of a loop."),
+        ));
+    }
+    stream.expect_next(
+        TokenKind::Semicolon,
+        format!("Expected ';' after '{name}'."),
+    )?;
+    Ok(match keyword.kind {
+        TokenKind::Break => Stmt::Break(keyword),
+        _ => Stmt::Continue(keyword),
+    })
 }
 
-fn block(
+fn expression_statement(
     stream: &mut impl TokenStream,
-    err: &mut impl io::Write,
+    ctx: &mut Ctx,
     ast: &mut Ast,
-) -> Result<Vec<Stmt>> {
+) -> Result<Stmt> {
+    let expr = expression(stream, ctx, ast)?;
+    stream.expect_next(TokenKind::Semicolon, "Expected ';' after expression.")?;
+    Ok(Stmt::Expression(ast.push_expr(expr)))
+}
+
+fn block(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Vec<Stmt>> {
     let mut stmts = vec![];
 
-    while stream.peek().kind != TokenKind::RightBrace && !stream.eof() {
-        stmts.push(declaration(stream, err, ast));
+    while *stream.peek_kind() != TokenKind::RightBrace && !stream.eof() {
+        stmts.push(declaration(stream, ctx, ast));
     }
 
-    stream
-        .match_next(matcher::eq(TokenKind::RightBrace))
-        .map_err(|t| Error::new(t, "Expected '}' after block."))?;
+    stream.expect_next(TokenKind::RightBrace, "Expected '}' after block.")?;
     Ok(stmts)
 }
 
+fn class_decl(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    let name = stream.expect_next(TokenKind::Identifier, "Expected class name.")?;
+    let superclass = match stream.match_next(matcher::eq(TokenKind::Extends)) {
+        Ok(_) => Some(stream.expect_next(TokenKind::Identifier, "Expected superclass name.")?),
+        Err(_) => None,
+    };
+    stream.expect_next(TokenKind::LeftBrace, "Expected '{' before class body.")?;
+    let kind = match superclass {
+        Some(_) => ClassKind::Subclass,
+        None => ClassKind::Class,
+    };
+    let enclosing = ctx.class.replace(kind);
+    let methods = methods(stream, ctx, ast);
+    ctx.class = enclosing;
+    let methods = methods?;
+    stream.expect_next(TokenKind::RightBrace, "Expected '}' after class body.")?;
+    Ok(Stmt::Class {
+        name,
+        superclass,
+        methods,
+    })
+}
+
+/// Parses method declarations up to the closing brace of a class body.
+fn methods(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Vec<StmtIdx>> {
+    let mut methods = vec![];
+    while *stream.peek_kind() != TokenKind::RightBrace && !stream.eof() {
+        let method = fun_decl(stream, ctx, ast, "method")?;
+        methods.push(ast.push_stmt(method));
+    }
+    Ok(methods)
+}
+
 fn fun_decl(
     stream: &mut impl TokenStream,
-    err: &mut impl io::Write,
+    ctx: &mut Ctx,
     ast: &mut Ast,
     kind: &str,
 ) -> Result<Stmt> {
-    let name = stream
-        .match_next(matcher::eq(TokenKind::Identifier))
-        .map_err(|t| Error::new(t, format!("Expected {kind} name.")))?;
-    stream
-        .match_next(matcher::eq(TokenKind::LeftParen))
-        .map_err(|t| Error::new(t, format!("Expected '(' after {kind} name.")))?;
+    let name = stream.expect_next(TokenKind::Identifier, format!("Expected {kind} name."))?;
+    stream.expect_next(
+        TokenKind::LeftParen,
+        format!("Expected '(' after {kind} name."),
+    )?;
+    let (params, body) = function(stream, ctx, ast, kind)?;
+    Ok(Stmt::Function { name, params, body })
+}
+
+/// Parses parameters and body of a function, following the opening parenthesis.
+fn function(
+    stream: &mut impl TokenStream,
+    ctx: &mut Ctx,
+    ast: &mut Ast,
+    kind: &str,
+) -> Result<(Vec<Param>, Vec<StmtIdx>)> {
     let mut params = vec![];
-    if stream.peek().kind != TokenKind::RightParen {
+    if *stream.peek_kind() != TokenKind::RightParen {
         loop {
             if params.len() >= 255 {
                 return Err(Error::new(
@@ -296,161 +492,261 @@ fn fun_decl(
                 ));
             }
 
-            params.push(
-                stream
-                    .match_next(matcher::eq(TokenKind::Identifier))
-                    .map_err(|t| Error::new(t, "Expected parameter name."))?,
-            );
+            params.push(parameter(stream)?);
 
             if stream.match_next(matcher::eq(TokenKind::Comma)).is_err() {
                 break;
             }
         }
     }
-    stream
-        .match_next(matcher::eq(TokenKind::RightParen))
-        .map_err(|t| Error::new(t, "Expected ')' after parameters."))?;
-    stream
-        .match_next(matcher::eq(TokenKind::LeftBrace))
-        .map_err(|t| Error::new(t, "Expected '{' before {kind} body."))?;
-    let body = block(stream, err, ast)?;
-    Ok(Stmt::Function {
-        name,
-        params,
-        body: body.into_iter().map(|stmt| ast.push_stmt(stmt)).collect(),
-    })
+    stream.expect_next(TokenKind::RightParen, "Expected ')' after parameters.")?;
+    stream.expect_next(
+        TokenKind::LeftBrace,
+        format!("Expected '{{' before {kind} body."),
+    )?;
+    // Loops enclosing the declaration can't be broken out of from the function body.
+    let loop_depth = std::mem::replace(&mut ctx.loop_depth, 0);
+    let in_function = std::mem::replace(&mut ctx.in_function, true);
+    let body = block(stream, ctx, ast);
+    ctx.loop_depth = loop_depth;
+    ctx.in_function = in_function;
+    let body = body?.into_iter().map(|stmt| ast.push_stmt(stmt)).collect();
+    Ok((params, body))
+}
+
+fn parameter(stream: &mut impl TokenStream) -> Result<Param> {
+    let name = stream.expect_next(TokenKind::Identifier, "Expected parameter name.")?;
+    let ty = stream
+        .match_next(matcher::eq(TokenKind::Colon))
+        .ok()
+        .map(|_| stream.expect_next(TokenKind::Identifier, "Expected parameter type after ':'."))
+        .transpose()?;
+    Ok(Param { name, ty })
 }
 
-fn var_decl(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Stmt> {
-    let name = stream
-        .match_next(matcher::eq(TokenKind::Identifier))
-        .map_err(|t| Error::new(t, "Expected variable name."))?;
+fn var_decl(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Stmt> {
+    let name = stream.expect_next(TokenKind::Identifier, "Expected variable name.")?;
     let token = stream.peek();
     let init = if token.kind == TokenKind::Equal {
         stream.next();
-        Some(expression(stream, ast)?)
+        Some(expression(stream, ctx, ast)?)
     } else {
         None
     };
-    stream
-        .match_next(matcher::eq(TokenKind::Semicolon))
-        .map_err(|t| Error::new(t, "Expected ';' after variable declaration."))?;
+    stream.expect_next(
+        TokenKind::Semicolon,
+        "Expected ';' after variable declaration.",
+    )?;
     Ok(Stmt::VarDecl {
         name,
         init: init.map(|init| ast.push_expr(init)),
     })
 }
 
-fn expression(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    assignment(stream, ast)
+fn expression(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    assignment(stream, ctx, ast)
 }
 
-fn assignment(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = or(stream, ast)?;
+fn assignment(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let expr = ternary(stream, ctx, ast)?;
 
-    if let Ok(equals) = stream.match_next(matcher::eq(TokenKind::Equal)) {
-        let value = assignment(stream, ast)?;
-        if let Expr::Variable(name) = expr {
-            expr = Expr::Assign {
-                var: name,
+    let operator = match stream.peek_kind() {
+        TokenKind::Equal => None,
+        TokenKind::PlusEqual => Some(TokenKind::Plus),
+        TokenKind::MinusEqual => Some(TokenKind::Minus),
+        TokenKind::StarEqual => Some(TokenKind::Star),
+        TokenKind::SlashEqual => Some(TokenKind::Slash),
+        _ => return Ok(expr),
+    };
+    let equals = stream.next();
+    let mut value = assignment(stream, ctx, ast)?;
+    let name = match expr {
+        Expr::Variable(name) => name,
+        Expr::Get { object, name } if operator.is_none() => {
+            return Ok(Expr::Set {
+                object,
+                name,
                 value: ast.push_expr(value),
-            };
-            Ok(expr)
-        } else {
-            Err(Error::new(equals, "Invalid assignment target."))
+            });
         }
-    } else {
-        Ok(expr)
+        Expr::Index {
+            object,
+            bracket,
+            index,
+        } if operator.is_none() => {
+            return Ok(Expr::IndexAssign {
+                object,
+                bracket,
+                index,
+                value: ast.push_expr(value),
+            });
+        }
+        _ => return Err(Error::new(equals, "Invalid assignment target.")),
+    };
+    if let Some(kind) = operator {
+        // Desugar `a += b` into `a = a + b`.
+        let operator = Token { kind, ..equals };
+        let var = ast.push_expr(Expr::Variable(name.clone()));
+        value = Expr::Binary(operator, var, ast.push_expr(value));
+    }
+    Ok(Expr::Assign {
+        var: name,
+        value: ast.push_expr(value),
+    })
+}
+
+fn ternary(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let cond = or(stream, ctx, ast)?;
+    if stream.match_next(matcher::eq(TokenKind::Question)).is_err() {
+        return Ok(cond);
     }
+    let then_expr = expression(stream, ctx, ast)?;
+    stream.expect_next(
+        TokenKind::Colon,
+        "Expected ':' after then branch of conditional expression.",
+    )?;
+    let else_expr = ternary(stream, ctx, ast)?;
+    Ok(Expr::Ternary {
+        cond: ast.push_expr(cond),
+        then_expr: ast.push_expr(then_expr),
+        else_expr: ast.push_expr(else_expr),
+    })
 }
 
-fn or(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = and(stream, ast)?;
+fn or(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = and(stream, ctx, ast)?;
 
-    while let TokenKind::Or = stream.peek().kind {
+    while let TokenKind::Or = stream.peek_kind() {
         let operator = stream.next();
-        let right = and(stream, ast)?;
+        let right = and(stream, ctx, ast)?;
         expr = Expr::Logical(operator, ast.push_expr(expr), ast.push_expr(right));
     }
 
     Ok(expr)
 }
 
-fn and(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = equality(stream, ast)?;
+fn and(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = equality(stream, ctx, ast)?;
 
-    while let TokenKind::And = stream.peek().kind {
+    while let TokenKind::And = stream.peek_kind() {
         let operator = stream.next();
-        let right = equality(stream, ast)?;
+        let right = equality(stream, ctx, ast)?;
         expr = Expr::Logical(operator, ast.push_expr(expr), ast.push_expr(right));
     }
 
     Ok(expr)
 }
 
-fn equality(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = comparison(stream, ast)?;
-    while let TokenKind::BangEqual | TokenKind::EqualEqual = stream.peek().kind {
+fn equality(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = comparison(stream, ctx, ast)?;
+    while let TokenKind::BangEqual | TokenKind::EqualEqual = stream.peek_kind() {
         let token = stream.next();
-        let right = comparison(stream, ast)?;
+        let right = comparison(stream, ctx, ast)?;
         expr = Expr::Binary(token, ast.push_expr(expr), ast.push_expr(right));
     }
     Ok(expr)
 }
 
-fn comparison(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = term(stream, ast)?;
+fn comparison(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = term(stream, ctx, ast)?;
     while let TokenKind::Less
     | TokenKind::LessEqual
     | TokenKind::Greater
-    | TokenKind::GreaterEqual = stream.peek().kind
+    | TokenKind::GreaterEqual = stream.peek_kind()
     {
         let token = stream.next();
-        let right = term(stream, ast)?;
+        let right = term(stream, ctx, ast)?;
         expr = Expr::Binary(token, ast.push_expr(expr), ast.push_expr(right));
     }
     Ok(expr)
 }
 
-fn term(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = factor(stream, ast)?;
-    while let TokenKind::Minus | TokenKind::Plus = stream.peek().kind {
+fn term(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = factor(stream, ctx, ast)?;
+    while let TokenKind::Minus | TokenKind::Plus = stream.peek_kind() {
         let token = stream.next();
-        let right = factor(stream, ast)?;
+        let right = factor(stream, ctx, ast)?;
         expr = Expr::Binary(token, ast.push_expr(expr), ast.push_expr(right));
     }
     Ok(expr)
 }
 
-fn factor(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = unary(stream, ast)?;
-    while let TokenKind::Slash | TokenKind::Star = stream.peek().kind {
+fn factor(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = power(stream, ctx, ast)?;
+    while let TokenKind::Slash | TokenKind::Star | TokenKind::Percent = stream.peek_kind() {
         let token = stream.next();
-        let right = unary(stream, ast)?;
+        let right = power(stream, ctx, ast)?;
         expr = Expr::Binary(token, ast.push_expr(expr), ast.push_expr(right));
     }
     Ok(expr)
 }
 
-fn unary(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    match stream.peek().kind {
+fn power(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let expr = unary(stream, ctx, ast)?;
+    if let Ok(token) = stream.match_next(matcher::eq(TokenKind::StarStar)) {
+        // Recursing on the right operand makes the operator right-associative.
+        let right = power(stream, ctx, ast)?;
+        return Ok(Expr::Binary(
+            token,
+            ast.push_expr(expr),
+            ast.push_expr(right),
+        ));
+    }
+    Ok(expr)
+}
+
+fn unary(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    match stream.peek_kind() {
         TokenKind::Bang | TokenKind::Minus => {
             let token = stream.next();
-            let expr = unary(stream, ast)?;
+            let expr = unary(stream, ctx, ast)?;
             let expr = Expr::Unary(token, ast.push_expr(expr));
             Ok(expr)
         }
-        _ => call(stream, ast),
+        TokenKind::Typeof => {
+            stream.next();
+            let expr = unary(stream, ctx, ast)?;
+            Ok(Expr::Typeof(ast.push_expr(expr)))
+        }
+        _ => call(stream, ctx, ast),
     }
 }
 
-fn call(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
-    let mut expr = primary(stream, ast)?;
-    while let TokenKind::LeftParen = stream.peek().kind {
-        stream.next();
+fn call(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    let mut expr = primary(stream, ctx, ast)?;
+    loop {
+        if stream.match_next(matcher::eq(TokenKind::Dot)).is_ok() {
+            let name =
+                stream.expect_next(TokenKind::Identifier, "Expected property name after '.'.")?;
+            expr = Expr::Get {
+                object: ast.push_expr(expr),
+                name,
+            };
+            continue;
+        }
+        if stream
+            .match_next(matcher::eq(TokenKind::LeftBracket))
+            .is_ok()
+        {
+            let index = expression(stream, ctx, ast)?;
+            let bracket =
+                stream.expect_next(TokenKind::RightBracket, "Expected ']' after index.")?;
+            expr = Expr::Index {
+                object: ast.push_expr(expr),
+                bracket,
+                index: ast.push_expr(index),
+            };
+            continue;
+        }
+        if stream
+            .match_next(matcher::eq(TokenKind::LeftParen))
+            .is_err()
+        {
+            break;
+        }
 
         let mut args = vec![];
-        if stream.peek().kind != TokenKind::RightParen {
+        if *stream.peek_kind() != TokenKind::RightParen {
             loop {
                 if args.len() >= 255 {
                     return Err(Error::new(
@@ -458,7 +754,7 @@ fn call(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
                         "Can't have more than 255 arguments",
                     ));
                 }
-                let arg = expression(stream, ast)?;
+                let arg = expression(stream, ctx, ast)?;
                 args.push(arg);
                 if stream.match_next(matcher::eq(TokenKind::Comma)).is_err() {
                     break;
@@ -466,9 +762,7 @@ fn call(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
             }
         }
 
-        let paren = stream
-            .match_next(matcher::eq(TokenKind::RightParen))
-            .map_err(|t| Error::new(t, "Expect ')' after arguments."))?;
+        let paren = stream.expect_next(TokenKind::RightParen, "Expect ')' after arguments.")?;
         expr = Expr::Call {
             callee: ast.push_expr(expr),
             paren,
@@ -478,7 +772,36 @@ fn call(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
     Ok(expr)
 }
 
-fn primary(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
+fn primary(stream: &mut impl TokenStream, ctx: &mut Ctx, ast: &mut Ast) -> Result<Expr> {
+    if let Ok(keyword) = stream.match_next(matcher::eq(TokenKind::Fun)) {
+        stream.expect_next(TokenKind::LeftParen, "Expected '(' after 'fun'.")?;
+        let (params, body) = function(stream, ctx, ast, "function")?;
+        return Ok(Expr::Lambda {
+            keyword,
+            params,
+            body,
+        });
+    }
+    if stream
+        .match_next(matcher::eq(TokenKind::LeftBracket))
+        .is_ok()
+    {
+        let mut elements = vec![];
+        if *stream.peek_kind() != TokenKind::RightBracket {
+            loop {
+                let element = expression(stream, ctx, ast)?;
+                elements.push(ast.push_expr(element));
+                if stream.match_next(matcher::eq(TokenKind::Comma)).is_err() {
+                    break;
+                }
+            }
+        }
+        stream.expect_next(
+            TokenKind::RightBracket,
+            "Expected ']' after array elements.",
+        )?;
+        return Ok(Expr::Array(elements));
+    }
     let token = stream.peek();
     let expr = match &token.kind {
         TokenKind::False => Expr::Literal(Lit::Bool(false)),
@@ -489,9 +812,18 @@ fn primary(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
         TokenKind::StringUnterminated(_) => {
             return Err(Error::new(token.clone(), "Unterminated string."));
         }
+        TokenKind::UnterminatedComment => {
+            return Err(Error::new(token.clone(), "Unterminated block comment."));
+        }
+        TokenKind::StringInvalidEscape(c) => {
+            return Err(Error::new(
+                token.clone(),
+                format!("Invalid escape sequence '\\{c}'."),
+            ));
+        }
         TokenKind::LeftParen => {
             stream.next();
-            let expr = expression(stream, ast)?;
+            let expr = expression(stream, ctx, ast)?;
             let token = stream.peek();
             if token.kind != TokenKind::RightParen {
                 return Err(Error::new(
@@ -502,6 +834,38 @@ fn primary(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
             Expr::Grouping(ast.push_expr(expr))
         }
         TokenKind::Identifier => Expr::Variable(token.clone()),
+        TokenKind::This if ctx.class.is_none() => {
+            return Err(Error::new(
+                token.clone(),
+                "Can't use 'this' outside of a class.",
+            ));
+        }
+        TokenKind::This => Expr::Variable(token.clone()),
+        TokenKind::Super => {
+            let keyword = stream.next();
+            match ctx.class {
+                None => {
+                    return Err(Error::new(keyword, "Can't use 'super' outside of a class."));
+                }
+                Some(ClassKind::Class) => {
+                    return Err(Error::new(
+                        keyword,
+                        "Can't use 'super' in a class with no superclass.",
+                    ));
+                }
+                Some(ClassKind::Subclass) => (),
+            }
+            stream.expect_next(TokenKind::Dot, "Expected '.' after 'super'.")?;
+            let method =
+                stream.expect_next(TokenKind::Identifier, "Expected superclass method name.")?;
+            return Ok(Expr::Super { keyword, method });
+        }
+        TokenKind::Unknown(c) => {
+            return Err(Error::new(
+                token.clone(),
+                format!("Unexpected character '{c}'."),
+            ));
+        }
         TokenKind::Eof => {
             return Err(Error::new(
                 token.clone(),
@@ -516,30 +880,257 @@ fn primary(stream: &mut impl TokenStream, ast: &mut Ast) -> Result<Expr> {
     Ok(expr)
 }
 
-fn synchronize(stream: &mut impl TokenStream) {
-    let mut current = stream.next();
+/// Skips the rest of a statement that failed to parse, starting at byte `start`.
+///
+/// Braces opened by the statement are skipped up to their closing brace, so the body of a broken
+/// statement doesn't produce errors of its own. A closing brace of the enclosing block is left for
+/// the block to consume.
+fn synchronize(stream: &mut impl TokenStream, start: usize) {
+    let mut depth = 0;
+    let mut first = true;
     loop {
-        if current.kind == TokenKind::Semicolon {
+        let next = stream.peek();
+        let boundary = match next.kind {
+            TokenKind::Eof => true,
+            // Unless it's where the statement starts, in which case nothing would be consumed.
+            TokenKind::RightBrace => depth == 0 && next.lexeme.start != start,
+            TokenKind::Class
+            | TokenKind::Fun
+            | TokenKind::Var
+            | TokenKind::For
+            | TokenKind::If
+            | TokenKind::While
+            | TokenKind::Do
+            | TokenKind::Print
+            | TokenKind::Return
+            | TokenKind::Break
+            | TokenKind::Continue => depth == 0 && !first,
+            _ => false,
+        };
+        if boundary {
             break;
         }
+        first = false;
+        match stream.next().kind {
+            TokenKind::LeftBrace => depth += 1,
+            TokenKind::RightBrace if depth <= 1 => break,
+            TokenKind::RightBrace => depth -= 1,
+            TokenKind::Semicolon if depth == 0 => break,
+            _ => (),
+        }
+    }
+}
 
-        let next = stream.peek();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_lexer::Lexer;
 
-        if matches!(
-            next.kind,
-            TokenKind::Eof
-                | TokenKind::Class
-                | TokenKind::Fun
-                | TokenKind::Var
-                | TokenKind::For
-                | TokenKind::If
-                | TokenKind::While
-                | TokenKind::Print
-                | TokenKind::Return
-        ) {
-            break;
+    fn parse_str(src: &str) -> Ast {
+        let ParseResult { ast, errors } = parse(Lexer::new(src));
+        assert!(errors.is_empty(), "{errors:?}");
+        ast
+    }
+
+    fn parse_errors(src: &str) -> Vec<String> {
+        let ParseResult { ast, errors } = parse(Lexer::new(src));
+        assert_eq!(ast.has_parse_errors(), !errors.is_empty());
+        errors.iter().map(|error| error.to_string()).collect()
+    }
+
+    fn param_names<'a>(src: &'a str, ast: &Ast) -> Vec<(&'a str, Option<&'a str>)> {
+        let Stmt::Function { params, .. } = ast.stmt(ast.roots()[0]) else {
+            panic!("Expected function declaration");
+        };
+        params
+            .iter()
+            .map(|p| {
+                let ty = p.ty.as_ref().map(|ty| ty.source_text(src));
+                (p.name.source_text(src), ty)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_unannotated_params() {
+        let src = "fun f(a, b) {}";
+        let ast = parse_str(src);
+        assert_eq!(param_names(src, &ast), [("a", None), ("b", None)]);
+    }
+
+    #[test]
+    fn parses_annotated_params() {
+        let src = "fun f(a: number, b, c: string) {}";
+        let ast = parse_str(src);
+        assert_eq!(
+            param_names(src, &ast),
+            [("a", Some("number")), ("b", None), ("c", Some("string"))]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_annotation() {
+        assert_eq!(
+            parse_errors("fun f(a:) {}"),
+            ["[Line 1:9]: Expected parameter type after ':'."]
+        );
+    }
+
+    #[test]
+    fn rejects_this_outside_of_class() {
+        assert_eq!(
+            parse_errors("fun f() { print this; }"),
+            ["[Line 1:17]: Can't use 'this' outside of a class."]
+        );
+    }
+
+    #[test]
+    fn rejects_super_outside_of_subclass() {
+        for (src, message) in [
+            (
+                "super.m();",
+                "[Line 1:1]: Can't use 'super' outside of a class.",
+            ),
+            (
+                "class A { m() { super.m(); } }",
+                "[Line 1:17]: Can't use 'super' in a class with no superclass.",
+            ),
+            (
+                "class A extends B { m() { class C { n() { super.n(); } } } }",
+                "[Line 1:43]: Can't use 'super' in a class with no superclass.",
+            ),
+        ] {
+            assert_eq!(parse_errors(src), [message]);
+        }
+    }
+
+    #[test]
+    fn rejects_return_outside_of_function() {
+        assert_eq!(
+            parse_errors("if (true) { return 1; }"),
+            ["[Line 1:13]: Can't return from top-level code."]
+        );
+    }
+
+    #[test]
+    fn parses_do_while() {
+        let src = "do { print 1; } while (false);";
+        let ast = parse_str(src);
+        assert_eq!(
+            unlox_ast::PrettyPrinter::new(src).print(&ast),
+            "(do-while (block (print 1)) false)"
+        );
+        for (src, message) in [
+            ("do print 1;", "[Line 1:4]: Expected '{' after 'do'."),
+            (
+                "do {} (true);",
+                "[Line 1:7]: Expected 'while' after do-while body.",
+            ),
+            (
+                "do {} while (true)",
+                "[Line 1:19]: Expected ';' after do-while loop.",
+            ),
+        ] {
+            assert_eq!(parse_errors(src), [message]);
         }
+    }
 
-        current = stream.next();
+    #[test]
+    fn parses_for_in() {
+        let src = "for (x in [1, 2]) print x; for (i = 0; i < 2;) i = i + 1;";
+        let ast = parse_str(src);
+        assert_eq!(
+            unlox_ast::PrettyPrinter::new(src).print(&ast),
+            "(for-in x (array 1 2) (print x))\n\
+             (block (; (= i 0)) (while (< i 2) (; (= i (+ i 1)))))"
+        );
+        for (src, message) in [
+            (
+                "for (a.b in c) {}",
+                "[Line 1:10]: Expected ';' after expression.",
+            ),
+            (
+                "for (x in c {}",
+                "[Line 1:13]: Expected ')' after for-in iterable.",
+            ),
+        ] {
+            assert_eq!(parse_errors(src), [message]);
+        }
+    }
+
+    #[test]
+    fn reports_unexpected_character() {
+        let ParseResult { ast, errors } = parse(Lexer::new("print @;\nprint 1;"));
+        assert_eq!(ast.roots().len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "[Line 1:7]: Unexpected character '@'."
+        );
+    }
+
+    #[test]
+    fn collects_all_errors() {
+        assert_eq!(
+            parse_errors("var = 1;\nprint 2;\nprint (3;\nreturn;"),
+            [
+                "[Line 1:5]: Expected variable name.",
+                r#"[Line 3:9]: Expected ")" after expression."#,
+                "[Line 4:1]: Can't return from top-level code.",
+            ]
+        );
+    }
+
+    #[test]
+    fn recovers_at_braces() {
+        let src = "if (true { print 1; }\nprint 2;\n{ print 3 }\nprint 4;\n}\nprint 5;";
+        let ParseResult { ast, errors } = parse(Lexer::new(src));
+        let errors: Vec<_> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "[Line 1:10]: Expected ')' after if condition.",
+                "[Line 3:11]: Expected ';' after value.",
+                "[Line 5:1]: Expected expression.",
+            ]
+        );
+        let kinds: Vec<_> = ast
+            .roots()
+            .iter()
+            .map(|stmt| matches!(ast.stmt(*stmt), Stmt::ParseErr(..)))
+            .collect();
+        assert_eq!(kinds, [true, false, false, false, true, false]);
+    }
+
+    #[test]
+    fn rejects_unclosed_brackets() {
+        assert_eq!(
+            parse_errors("print [1, 2;\nprint xs[0;\n[1] += 2;"),
+            [
+                "[Line 1:12]: Expected ']' after array elements.",
+                "[Line 2:11]: Expected ']' after index.",
+                "[Line 3:5]: Invalid assignment target.",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_single_expression() {
+        let mut ast = Ast::new();
+        let expr = parse_expr(Lexer::new("1 + 2 * 3"), &mut ast).unwrap();
+        assert!(matches!(ast.expr(expr), Expr::Binary(..)));
+        assert!(ast.roots().is_empty());
+
+        for (src, message) in [
+            ("1 + 2;", "[Line 1:6]: Expected end of expression."),
+            ("print 1", "[Line 1:1]: Expected expression."),
+            (
+                "fun () { return 1 }",
+                "[Line 1:19]: Expected ';' after return value.",
+            ),
+        ] {
+            let error = parse_expr(Lexer::new(src), &mut Ast::new()).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
     }
 }
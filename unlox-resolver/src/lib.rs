@@ -0,0 +1,339 @@
+//! Static analysis of variable scopes.
+//!
+//! The resolver walks the tree before it's executed, mirroring the environments the interpreter
+//! creates at runtime. Local variables are resolved to the number of scopes between their use
+//! and declaration, while anything not found in local scopes is assumed to be global.
+//!
+//! Misplaced `return`, `break`, `continue`, `this` and `super` are already rejected by the parser.
+
+use std::{collections::HashMap, io};
+
+use unlox_ast::{Ast, Expr, ExprIdx, Param, Stmt, StmtIdx, Token};
+
+#[derive(Debug, thiserror::Error)]
+#[error("[Line {}:{}]: {message}", token.line, token.col)]
+pub struct Error {
+    pub token: Token,
+    pub message: String,
+}
+
+/// Depths of local variables accessed by `Expr::Variable`, `Expr::Assign` and `Expr::Super`,
+/// i.e. how many environments up from the accessing one they are defined. Globals are absent.
+pub type Locals = HashMap<ExprIdx, usize>;
+
+/// Resolves variables of the `ast` parsed from `src`.
+///
+/// Errors are reported to `err`, in which case `None` is returned.
+pub fn resolve(src: &str, ast: &Ast, err: &mut impl io::Write) -> Option<Locals> {
+    match try_resolve(src, ast) {
+        Ok(locals) => Some(locals),
+        Err(errors) => {
+            for error in errors {
+                writeln!(err, "{error}").unwrap();
+            }
+            None
+        }
+    }
+}
+
+/// Like [`resolve`], but returns the errors instead of reporting them.
+pub fn try_resolve(src: &str, ast: &Ast) -> Result<Locals, Vec<Error>> {
+    let mut resolver = Resolver::new(src, ast);
+    resolver.run();
+    if resolver.errors.is_empty() {
+        Ok(resolver.locals)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+struct Resolver<'a> {
+    src: &'a str,
+    ast: &'a Ast,
+    /// Pending nodes, handled from an explicit stack like in the interpreter, so deeply nested
+    /// trees don't overflow the native one.
+    tasks: Vec<Task<'a>>,
+    /// Local scopes, innermost last. Variables map to whether their initializer was resolved.
+    scopes: Vec<HashMap<&'a str, bool>>,
+    locals: Locals,
+    errors: Vec<Error>,
+}
+
+enum Task<'a> {
+    Stmt(StmtIdx),
+    Expr(ExprIdx),
+    /// Enters the scope of a function body, which it shares with the parameters.
+    Function(&'a [Param], &'a [StmtIdx]),
+    /// Enters the scope of a `for-in` loop body, which declares the loop variable.
+    LoopVar(&'a Token, StmtIdx),
+    /// Marks a variable declared in the innermost scope as initialized.
+    Define(&'a Token),
+    /// Leaves the innermost scope.
+    PopScope,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(src: &'a str, ast: &'a Ast) -> Self {
+        Self {
+            src,
+            ast,
+            tasks: ast
+                .roots()
+                .iter()
+                .rev()
+                .map(|stmt| Task::Stmt(*stmt))
+                .collect(),
+            scopes: vec![],
+            locals: HashMap::new(),
+            errors: vec![],
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(task) = self.tasks.pop() {
+            match task {
+                Task::Stmt(stmt) => self.stmt(stmt),
+                Task::Expr(expr) => self.expr(expr),
+                Task::Function(params, body) => {
+                    self.scopes.push(HashMap::new());
+                    for param in params {
+                        self.declare(&param.name, true);
+                    }
+                    self.tasks.push(Task::PopScope);
+                    self.schedule_block(body);
+                }
+                Task::LoopVar(name, body) => {
+                    self.scopes.push(HashMap::new());
+                    self.declare(name, true);
+                    self.tasks.push(Task::PopScope);
+                    self.tasks.push(Task::Stmt(body));
+                }
+                Task::Define(name) => self.declare(name, true),
+                Task::PopScope => {
+                    self.scopes.pop();
+                }
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: StmtIdx) {
+        match self.ast.stmt(stmt) {
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                if let Some(else_branch) = else_branch {
+                    self.tasks.push(Task::Stmt(*else_branch));
+                }
+                self.tasks.push(Task::Stmt(*then_branch));
+                self.tasks.push(Task::Expr(*cond));
+            }
+            Stmt::While { cond, body, inc } => {
+                if let Some(inc) = inc {
+                    self.tasks.push(Task::Expr(*inc));
+                }
+                self.tasks.push(Task::Stmt(*body));
+                self.tasks.push(Task::Expr(*cond));
+            }
+            Stmt::DoWhile { body, cond } => {
+                self.tasks.push(Task::Expr(*cond));
+                self.tasks.push(Task::Stmt(*body));
+            }
+            Stmt::ForIn {
+                name,
+                iterable,
+                body,
+            } => {
+                self.tasks.push(Task::LoopVar(name, *body));
+                self.tasks.push(Task::Expr(*iterable));
+            }
+            Stmt::Print(expr) | Stmt::Expression(expr) | Stmt::Return(_, Some(expr)) => {
+                self.tasks.push(Task::Expr(*expr))
+            }
+            Stmt::Return(_, None) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::ParseErr(..) => (),
+            Stmt::VarDecl { name, init } => {
+                self.declare(name, false);
+                self.tasks.push(Task::Define(name));
+                if let Some(init) = init {
+                    self.tasks.push(Task::Expr(*init));
+                }
+            }
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                self.tasks.push(Task::PopScope);
+                self.schedule_block(stmts);
+            }
+            Stmt::Function { name, params, body } => {
+                self.declare(name, true);
+                self.tasks.push(Task::Function(params, body));
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                self.declare(name, true);
+                if superclass.is_some() {
+                    self.scopes.push(HashMap::from([("super", true)]));
+                    self.tasks.push(Task::PopScope);
+                }
+                self.scopes.push(HashMap::from([("this", true)]));
+                self.tasks.push(Task::PopScope);
+                for method in methods.iter().rev() {
+                    let Stmt::Function { params, body, .. } = self.ast.stmt(*method) else {
+                        unreachable!("Methods are function declarations")
+                    };
+                    self.tasks.push(Task::Function(params, body));
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: ExprIdx) {
+        match self.ast.expr(expr) {
+            Expr::Variable(var) => {
+                let src = self.src;
+                let name = var.source_text(src);
+                // Globals aren't tracked, so `var a = a;` at the top level reads the previous
+                // definition of `a`, as redefining globals is allowed.
+                if let Some(false) = self.scopes.last().and_then(|scope| scope.get(name)) {
+                    self.errors.push(Error {
+                        token: var.clone(),
+                        message: "Can't read local variable in its own initializer.".to_owned(),
+                    });
+                }
+                self.local(expr, name);
+            }
+            Expr::Assign { var, value } => {
+                let src = self.src;
+                self.local(expr, var.source_text(src));
+                self.tasks.push(Task::Expr(*value));
+            }
+            Expr::Super { .. } => self.local(expr, "super"),
+            Expr::Literal(_) => (),
+            Expr::Grouping(expr)
+            | Expr::Unary(_, expr)
+            | Expr::Typeof(expr)
+            | Expr::Get { object: expr, .. } => self.tasks.push(Task::Expr(*expr)),
+            Expr::Binary(_, left, right)
+            | Expr::Logical(_, left, right)
+            | Expr::Set {
+                object: left,
+                value: right,
+                ..
+            }
+            | Expr::Index {
+                object: left,
+                index: right,
+                ..
+            } => {
+                self.tasks.push(Task::Expr(*right));
+                self.tasks.push(Task::Expr(*left));
+            }
+            Expr::Ternary {
+                cond: first,
+                then_expr: second,
+                else_expr: third,
+            }
+            | Expr::IndexAssign {
+                object: first,
+                index: second,
+                value: third,
+                ..
+            } => {
+                self.tasks.push(Task::Expr(*third));
+                self.tasks.push(Task::Expr(*second));
+                self.tasks.push(Task::Expr(*first));
+            }
+            Expr::Call { callee, args, .. } => {
+                self.tasks
+                    .extend(args.iter().rev().map(|arg| Task::Expr(*arg)));
+                self.tasks.push(Task::Expr(*callee));
+            }
+            Expr::Array(elements) => self
+                .tasks
+                .extend(elements.iter().rev().map(|element| Task::Expr(*element))),
+            Expr::Lambda { params, body, .. } => self.tasks.push(Task::Function(params, body)),
+        }
+    }
+
+    /// Schedules statements of a block to be resolved in order.
+    fn schedule_block(&mut self, stmts: &[StmtIdx]) {
+        self.tasks
+            .extend(stmts.iter().rev().map(|stmt| Task::Stmt(*stmt)));
+    }
+
+    /// Declares a variable in the innermost scope, unless it's a global one.
+    fn declare(&mut self, name: &Token, initialized: bool) {
+        let src = self.src;
+        let name = name.source_text(src);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name, initialized);
+        }
+    }
+
+    /// Records the depth of a local variable accessed by `expr`.
+    fn local(&mut self, expr: ExprIdx, name: &str) {
+        let depth = self
+            .scopes
+            .iter()
+            .rev()
+            .position(|scope| scope.contains_key(name));
+        if let Some(depth) = depth {
+            self.locals.insert(expr, depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unlox_lexer::Lexer;
+
+    fn resolve_str(src: &str) -> (Option<Locals>, String) {
+        let parsed = unlox_parse::parse(Lexer::new(src));
+        assert!(parsed.errors.is_empty());
+        let ast = parsed.ast;
+        let mut err = Vec::new();
+        let locals = resolve(src, &ast, &mut err);
+        (locals, String::from_utf8(err).unwrap())
+    }
+
+    fn depths(src: &str) -> Vec<usize> {
+        let mut depths: Vec<_> = resolve_str(src).0.unwrap().into_iter().collect();
+        depths.sort_by_key(|(expr, _)| expr.as_usize());
+        depths.into_iter().map(|(_, depth)| depth).collect()
+    }
+
+    #[test]
+    fn leaves_globals_unresolved() {
+        assert!(depths("var a = 1; print a; a = 2;").is_empty());
+    }
+
+    #[test]
+    fn resolves_local_depths() {
+        assert_eq!(
+            depths("{ var a = 1; { print a; a = 2; } print a; }"),
+            [1, 1, 0]
+        );
+        assert_eq!(depths("fun f(a) { return fun() { return a; }; }"), [1]);
+    }
+
+    #[test]
+    fn resolves_this_and_super() {
+        let src = "class A {} class B extends A { m() { super.m(); return this; } }";
+        assert_eq!(depths(src), [2, 1]);
+    }
+
+    #[test]
+    fn rejects_variable_read_in_own_initializer() {
+        let (locals, err) = resolve_str("var a = 1; { var a = a + 1; }");
+        assert!(locals.is_none());
+        assert_eq!(
+            err,
+            "[Line 1:22]: Can't read local variable in its own initializer.\n"
+        );
+        assert!(resolve_str("var a = a + 1;").0.is_some());
+    }
+}
@@ -1,23 +1,45 @@
 use std::ops::Range;
 
 #[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token {
     pub kind: TokenKind,
     pub lexeme: Range<usize>,
+    /// Line and column of the first character of the token, counted from 1. Columns are
+    /// counted in characters.
     pub line: u32,
+    pub col: u32,
+}
+
+impl Token {
+    /// Byte range of the token in the source it was lexed from.
+    pub fn span(&self) -> Range<usize> {
+        self.lexeme.clone()
+    }
+
+    /// Returns the token as spelled in `src`.
+    pub fn source_text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.span()]
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenKind {
     // single character
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
+    Percent,
     Plus,
+    Question,
     Semicolon,
     Slash,
     Star,
@@ -31,21 +53,33 @@ pub enum TokenKind {
     GreaterEqual,
     Less,
     LessEqual,
+    StarStar,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
 
     // literals
     Identifier,
     String(String),
     StringUnterminated(String),
+    /// String containing an unsupported escape sequence, holding the character after `\`.
+    StringInvalidEscape(char),
     Number(f64),
 
     // keywords
     And,
+    Break,
     Class,
+    Continue,
+    Do,
     Else,
+    Extends,
     False,
     Fun,
     For,
     If,
+    In,
     Nil,
     Or,
     Print,
@@ -53,17 +87,114 @@ pub enum TokenKind {
     Super,
     This,
     True,
+    Typeof,
     Var,
     While,
 
     // Unexpected character
-    Unknown,
+    Unknown(char),
+
+    /// Block comment missing its closing `*/`, spanning from its opening `/*`.
+    UnterminatedComment,
 
     // end of input
     #[default]
     Eof,
 }
 
+impl TokenKind {
+    /// Returns `true` for reserved words, including the `true`, `false` and `nil` literals.
+    pub fn is_keyword(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::And
+                | TokenKind::Break
+                | TokenKind::Class
+                | TokenKind::Continue
+                | TokenKind::Do
+                | TokenKind::Else
+                | TokenKind::Extends
+                | TokenKind::False
+                | TokenKind::Fun
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::In
+                | TokenKind::Nil
+                | TokenKind::Or
+                | TokenKind::Print
+                | TokenKind::Return
+                | TokenKind::Super
+                | TokenKind::This
+                | TokenKind::True
+                | TokenKind::Typeof
+                | TokenKind::Var
+                | TokenKind::While
+        )
+    }
+
+    /// Returns `true` for tokens denoting a literal value.
+    pub fn is_literal(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::String(_)
+                | TokenKind::StringUnterminated(_)
+                | TokenKind::StringInvalidEscape(_)
+                | TokenKind::Number(_)
+                | TokenKind::True
+                | TokenKind::False
+                | TokenKind::Nil
+        )
+    }
+
+    /// Returns `true` for unary and binary operators, including assignment.
+    pub fn is_operator(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::Minus
+                | TokenKind::Percent
+                | TokenKind::Plus
+                | TokenKind::Question
+                | TokenKind::Slash
+                | TokenKind::Star
+                | TokenKind::Bang
+                | TokenKind::BangEqual
+                | TokenKind::Equal
+                | TokenKind::EqualEqual
+                | TokenKind::Greater
+                | TokenKind::GreaterEqual
+                | TokenKind::Less
+                | TokenKind::LessEqual
+                | TokenKind::StarStar
+                | TokenKind::PlusEqual
+                | TokenKind::MinusEqual
+                | TokenKind::StarEqual
+                | TokenKind::SlashEqual
+        )
+    }
+
+    /// Returns `true` for names of variables, functions, classes and properties.
+    pub fn is_identifier(&self) -> bool {
+        matches!(self, TokenKind::Identifier)
+    }
+
+    /// Returns `true` for punctuation separating or grouping other tokens.
+    pub fn is_delimiter(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::LeftParen
+                | TokenKind::RightParen
+                | TokenKind::LeftBrace
+                | TokenKind::RightBrace
+                | TokenKind::LeftBracket
+                | TokenKind::RightBracket
+                | TokenKind::Comma
+                | TokenKind::Colon
+                | TokenKind::Dot
+                | TokenKind::Semicolon
+        )
+    }
+}
+
 pub trait TokenStream {
     fn next(&mut self) -> Token;
     fn peek(&mut self) -> &Token;
@@ -72,6 +203,9 @@ pub trait TokenStream {
 pub trait TokenStreamExt {
     fn match_next(&mut self, matcher: impl FnOnce(&TokenKind) -> bool) -> Result<Token, Token>;
     fn eof(&mut self) -> bool;
+    fn peek_kind(&mut self) -> &TokenKind;
+    /// Advances past the tokens matching `pred`, stopping at the end of input.
+    fn skip_while(&mut self, pred: impl Fn(&TokenKind) -> bool);
 }
 
 impl<T: TokenStream> TokenStreamExt for T {
@@ -87,6 +221,16 @@ impl<T: TokenStream> TokenStreamExt for T {
     fn eof(&mut self) -> bool {
         self.peek().kind == TokenKind::Eof
     }
+
+    fn peek_kind(&mut self) -> &TokenKind {
+        &self.peek().kind
+    }
+
+    fn skip_while(&mut self, pred: impl Fn(&TokenKind) -> bool) {
+        while !self.eof() && pred(self.peek_kind()) {
+            self.next();
+        }
+    }
 }
 
 pub mod matcher {
@@ -95,4 +239,130 @@ pub mod matcher {
     pub fn eq(kind: TokenKind) -> impl FnOnce(&TokenKind) -> bool {
         move |k| *k == kind
     }
+
+    pub fn one_of(kinds: impl IntoIterator<Item = TokenKind>) -> impl FnOnce(&TokenKind) -> bool {
+        move |k| kinds.into_iter().any(|kind| kind == *k)
+    }
+
+    pub fn none_of(kinds: impl IntoIterator<Item = TokenKind>) -> impl FnOnce(&TokenKind) -> bool {
+        let one_of = one_of(kinds);
+        move |k| !one_of(k)
+    }
+
+    pub fn any() -> impl FnOnce(&TokenKind) -> bool {
+        |_| true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_source_text() {
+        let token = Token {
+            kind: TokenKind::Identifier,
+            lexeme: 4..7,
+            line: 1,
+            col: 5,
+        };
+        assert_eq!(token.span(), 4..7);
+        assert_eq!(token.source_text("var foo = 1;"), "foo");
+    }
+
+    /// Stream of tokens, the next one last. Ends with the `Eof` token, which is never taken.
+    struct Tokens(Vec<Token>);
+
+    impl Tokens {
+        fn new(kinds: impl IntoIterator<Item = TokenKind>) -> Self {
+            let mut tokens: Vec<_> = kinds
+                .into_iter()
+                .chain([TokenKind::Eof])
+                .map(|kind| Token {
+                    kind,
+                    ..Default::default()
+                })
+                .collect();
+            tokens.reverse();
+            Self(tokens)
+        }
+    }
+
+    impl TokenStream for Tokens {
+        fn next(&mut self) -> Token {
+            if self.0.len() > 1 {
+                self.0.pop().unwrap()
+            } else {
+                self.0[0].clone()
+            }
+        }
+
+        fn peek(&mut self) -> &Token {
+            self.0.last().unwrap()
+        }
+    }
+
+    #[test]
+    fn skips_tokens() {
+        let mut stream = Tokens::new([TokenKind::Semicolon, TokenKind::Semicolon, TokenKind::Dot]);
+        stream.skip_while(|kind| *kind == TokenKind::Semicolon);
+        assert_eq!(stream.peek_kind(), &TokenKind::Dot);
+        stream.skip_while(|_| true);
+        assert!(stream.eof());
+    }
+
+    #[test]
+    fn combines_matchers() {
+        let kinds = || [TokenKind::Plus, TokenKind::Minus];
+        assert!(matcher::one_of(kinds())(&TokenKind::Minus));
+        assert!(!matcher::one_of(kinds())(&TokenKind::Star));
+        assert!(!matcher::none_of(kinds())(&TokenKind::Plus));
+        assert!(matcher::none_of(kinds())(&TokenKind::Star));
+        assert!(matcher::any()(&TokenKind::Eof));
+
+        let mut stream = Tokens::new([TokenKind::Minus, TokenKind::Star]);
+        assert!(stream.match_next(matcher::one_of(kinds())).is_ok());
+        assert!(stream.match_next(matcher::one_of(kinds())).is_err());
+        assert!(stream.match_next(matcher::any()).is_ok());
+    }
+
+    #[test]
+    fn categorizes_keywords() {
+        assert!(TokenKind::While.is_keyword());
+        assert!(TokenKind::Nil.is_keyword());
+        assert!(!TokenKind::Identifier.is_keyword());
+        assert!(!TokenKind::Plus.is_keyword());
+    }
+
+    #[test]
+    fn categorizes_literals() {
+        assert!(TokenKind::Number(1.0).is_literal());
+        assert!(TokenKind::String("str".into()).is_literal());
+        assert!(TokenKind::True.is_literal());
+        assert!(!TokenKind::Identifier.is_literal());
+    }
+
+    #[test]
+    fn categorizes_operators() {
+        assert!(TokenKind::Plus.is_operator());
+        assert!(TokenKind::BangEqual.is_operator());
+        assert!(!TokenKind::Semicolon.is_operator());
+        assert!(!TokenKind::And.is_operator());
+    }
+
+    #[test]
+    fn categorizes_identifiers() {
+        assert!(TokenKind::Identifier.is_identifier());
+        assert!(!TokenKind::This.is_identifier());
+        assert!(!TokenKind::String("name".into()).is_identifier());
+    }
+
+    #[test]
+    fn categorizes_delimiters() {
+        assert!(TokenKind::LeftBrace.is_delimiter());
+        assert!(TokenKind::RightBracket.is_delimiter());
+        assert!(TokenKind::Comma.is_delimiter());
+        assert!(!TokenKind::Minus.is_delimiter());
+        assert!(!TokenKind::Eof.is_delimiter());
+    }
 }
@@ -1,4 +1,5 @@
 use slab::Slab;
+use std::collections::VecDeque;
 
 /// Generic tree data structure implementation.
 pub struct Tree<T> {
@@ -72,10 +73,30 @@ impl<T> Tree<T> {
         assert!(
             self.nodes
                 .get(idx.0)
-                .map_or(true, |n| n.first_child.is_none()),
+                .is_none_or(|n| n.first_child.is_none()),
             "Node is not a leaf"
         );
         let node = self.nodes.try_remove(idx.as_usize())?;
+        self.unlink(idx, &node);
+        Some(node.data)
+    }
+
+    /// Removes the node at `idx` along with all of its descendants, returning their data in
+    /// depth-first pre-order.
+    pub fn remove_subtree(&mut self, idx: Index) -> Option<Vec<T>> {
+        if !self.nodes.contains(idx.0) {
+            return None;
+        }
+        let indices: Vec<_> = self.dfs(idx).collect();
+        let nodes: Vec<_> = indices.iter().map(|i| self.nodes.remove(i.0)).collect();
+        // Descendants are removed along with their parents and siblings, only the links to the
+        // subtree's root need fixing.
+        self.unlink(idx, &nodes[0]);
+        Some(nodes.into_iter().map(|node| node.data).collect())
+    }
+
+    /// Detaches the removed `node`, previously at `idx`, from its parent and siblings.
+    fn unlink(&mut self, idx: Index, node: &Node<T>) {
         if let Some(parent) = node.parent {
             let parent = &mut self.nodes[parent.0];
 
@@ -93,7 +114,6 @@ impl<T> Tree<T> {
                 self.nodes[next_sibling.0].prev_sibling = node.prev_sibling;
             }
         }
-        Some(node.data)
     }
 
     pub fn parent(&self, idx: Index) -> Option<Index> {
@@ -107,6 +127,40 @@ impl<T> Tree<T> {
     pub fn node_data_mut(&mut self, idx: Index) -> Option<&mut T> {
         self.nodes.get_mut(idx.0).map(|n| &mut n.data)
     }
+
+    /// Iterates over the children of `parent`, in the order they were added.
+    pub fn children(&self, parent: Index) -> impl Iterator<Item = Index> + '_ {
+        std::iter::successors(self.nodes[parent.0].first_child, |idx| {
+            self.nodes[idx.0].next_sibling
+        })
+    }
+
+    /// Iterates over `root` and its descendants in depth-first pre-order.
+    ///
+    /// Uses an explicit stack, so deep trees don't overflow the native one.
+    pub fn dfs(&self, root: Index) -> impl Iterator<Item = Index> + '_ {
+        let mut stack = vec![root];
+        std::iter::from_fn(move || {
+            let idx = stack.pop()?;
+            // Pushed last to first, so that the first child is visited next.
+            let mut child = self.nodes[idx.0].last_child;
+            while let Some(next) = child {
+                stack.push(next);
+                child = self.nodes[next.0].prev_sibling;
+            }
+            Some(idx)
+        })
+    }
+
+    /// Iterates over `root` and its descendants in breadth-first order.
+    pub fn bfs(&self, root: Index) -> impl Iterator<Item = Index> + '_ {
+        let mut queue = VecDeque::from([root]);
+        std::iter::from_fn(move || {
+            let idx = queue.pop_front()?;
+            queue.extend(self.children(idx));
+            Some(idx)
+        })
+    }
 }
 
 impl Index {
@@ -114,3 +168,50 @@ impl Index {
         self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds `0 -> (1 -> (3, 4), 2 -> (5))`, returning the tree with indices by label.
+    fn sample() -> (Tree<u32>, Vec<Index>) {
+        let mut tree = Tree::new();
+        let root = tree.add_root(0);
+        let a = tree.add_leaf(root, 1);
+        let b = tree.add_leaf(root, 2);
+        let c = tree.add_leaf(a, 3);
+        let d = tree.add_leaf(a, 4);
+        let e = tree.add_leaf(b, 5);
+        (tree, vec![root, a, b, c, d, e])
+    }
+
+    fn labels(tree: &Tree<u32>, indices: impl Iterator<Item = Index>) -> Vec<u32> {
+        indices.map(|idx| *tree.node_data(idx).unwrap()).collect()
+    }
+
+    #[test]
+    fn traverses() {
+        let (tree, indices) = sample();
+        let root = indices[0];
+        assert_eq!(labels(&tree, tree.children(root)), [1, 2]);
+        assert_eq!(labels(&tree, tree.children(indices[5])), []);
+        assert_eq!(labels(&tree, tree.dfs(root)), [0, 1, 3, 4, 2, 5]);
+        assert_eq!(labels(&tree, tree.bfs(root)), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(labels(&tree, tree.dfs(indices[1])), [1, 3, 4]);
+    }
+
+    #[test]
+    fn removes_subtrees() {
+        let (mut tree, indices) = sample();
+        let root = indices[0];
+        assert_eq!(tree.remove_subtree(indices[1]), Some(vec![1, 3, 4]));
+        assert_eq!(tree.remove_subtree(indices[1]), None);
+        assert!(tree.node_data(indices[3]).is_none());
+        assert_eq!(labels(&tree, tree.dfs(root)), [0, 2, 5]);
+        let f = tree.add_leaf(root, 6);
+        assert_eq!(labels(&tree, tree.children(root)), [2, 6]);
+        assert_eq!(tree.remove_subtree(root), Some(vec![0, 2, 5, 6]));
+        assert!(tree.is_empty());
+        assert!(tree.node_data(f).is_none());
+    }
+}
@@ -0,0 +1,74 @@
+use std::rc::Rc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use unlox_bytecode::{Chunk, Function, OpCode, Value};
+use unlox_vm::Vm;
+
+/// Assembles `fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }` followed by
+/// `return fib(n);`.
+fn fibonacci_chunk(n: f64) -> Chunk {
+    let mut function = Chunk::new();
+    let fib = function.add_constant(Value::String("fib".into()));
+    let one = function.add_constant(Value::Number(1.0));
+    let two = function.add_constant(Value::Number(2.0));
+    // Slot 0 holds the function itself and slot 1 holds `n`.
+    for byte in [OpCode::GetLocal as u8, 1, OpCode::Constant as u8, two] {
+        function.write(byte, 2);
+    }
+    function.write(OpCode::Less as u8, 2);
+    let jump = function.write_jump(OpCode::JumpIfFalse, 2);
+    for byte in [OpCode::Pop as u8, OpCode::GetLocal as u8, 1] {
+        function.write(byte, 2);
+    }
+    function.write(OpCode::Return as u8, 2);
+    function.patch_jump(jump, function.code.len());
+    function.write(OpCode::Pop as u8, 3);
+    for constant in [one, two] {
+        for byte in [OpCode::GetGlobal as u8, fib, OpCode::GetLocal as u8, 1] {
+            function.write(byte, 3);
+        }
+        for byte in [OpCode::Constant as u8, constant, OpCode::Subtract as u8] {
+            function.write(byte, 3);
+        }
+        for byte in [OpCode::Call as u8, 1] {
+            function.write(byte, 3);
+        }
+    }
+    function.write(OpCode::Add as u8, 3);
+    function.write(OpCode::Return as u8, 3);
+    let function = Value::Function(Rc::new(Function {
+        name: "fib".into(),
+        arity: 1,
+        chunk: function,
+    }));
+
+    let mut chunk = Chunk::new();
+    let function = chunk.add_constant(function);
+    let fib = chunk.add_constant(Value::String("fib".into()));
+    let n = chunk.add_constant(Value::Number(n));
+    for byte in [
+        OpCode::Constant as u8,
+        function,
+        OpCode::DefineGlobal as u8,
+        fib,
+    ] {
+        chunk.write(byte, 5);
+    }
+    for byte in [OpCode::GetGlobal as u8, fib, OpCode::Constant as u8, n] {
+        chunk.write(byte, 5);
+    }
+    for byte in [OpCode::Call as u8, 1, OpCode::Return as u8] {
+        chunk.write(byte, 5);
+    }
+    chunk
+}
+
+fn fibonacci(c: &mut Criterion) {
+    let chunk = fibonacci_chunk(20.0);
+    c.bench_function("fibonacci", |b| {
+        b.iter(|| assert_eq!(Vm::new().interpret(&chunk).unwrap(), Value::Number(6765.0)))
+    });
+}
+
+criterion_group!(benches, fibonacci);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+use std::fmt;
+
+use unlox_bytecode::ObjectId;
+
+/// Value allocated on the [`Heap`], referenced by [`unlox_bytecode::Value::Object`].
+#[derive(Debug, PartialEq)]
+pub enum Object {
+    String(Box<str>),
+}
+
+impl Object {
+    /// Pushes the objects referenced by this one.
+    fn trace(&self, _gray: &mut Vec<ObjectId>) {
+        match self {
+            Object::String(_) => {}
+        }
+    }
+}
+
+impl From<String> for Object {
+    fn from(s: String) -> Self {
+        Object::String(s.into())
+    }
+}
+
+impl From<&str> for Object {
+    fn from(s: &str) -> Self {
+        Object::String(s.into())
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Object::String(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+struct Entry {
+    marked: bool,
+    object: Object,
+}
+
+/// Garbage collected storage for objects, freed by [`Heap::collect`] once unreachable.
+///
+/// Slots of freed objects get reused, so an [`ObjectId`] must not outlive its object.
+#[derive(Default)]
+pub struct Heap {
+    entries: Vec<Option<Entry>>,
+    /// Indices of the freed entries.
+    free: Vec<usize>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allocate<T: Into<Object>>(&mut self, data: T) -> ObjectId {
+        let entry = Some(Entry {
+            marked: false,
+            object: data.into(),
+        });
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.entries[idx] = entry;
+                idx
+            }
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        ObjectId(idx)
+    }
+
+    /// # Panics if the object was freed
+    pub fn get(&self, id: ObjectId) -> &Object {
+        match &self.entries[id.0] {
+            Some(entry) => &entry.object,
+            None => panic!("Object {} was already freed", id.0),
+        }
+    }
+
+    /// Number of live objects.
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frees the objects that can't be reached from `roots`.
+    pub fn collect(&mut self, roots: impl Iterator<Item = ObjectId>) {
+        self.mark(roots);
+        self.sweep();
+    }
+
+    fn mark(&mut self, roots: impl Iterator<Item = ObjectId>) {
+        let mut gray: Vec<_> = roots.collect();
+        while let Some(id) = gray.pop() {
+            let entry = self.entries[id.0]
+                .as_mut()
+                .expect("Roots should only reference live objects");
+            if !entry.marked {
+                entry.marked = true;
+                entry.object.trace(&mut gray);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        for (idx, slot) in self.entries.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => entry.marked = false,
+                Some(_) => {
+                    *slot = None;
+                    self.free.push(idx);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let a = heap.allocate("a");
+        let b = heap.allocate(String::from("b"));
+        let c = heap.allocate("c");
+        heap.collect([a, c].into_iter());
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.get(a), &Object::from("a"));
+        assert_eq!(heap.get(c), &Object::from("c"));
+
+        // The freed slot gets reused.
+        assert_eq!(heap.allocate("d"), b);
+        heap.collect(std::iter::empty());
+        assert!(heap.is_empty());
+    }
+}
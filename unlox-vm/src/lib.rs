@@ -1,20 +1,62 @@
-use unlox_bytecode::{Chunk, OpCode, Value};
+pub use heap::{Heap, Object};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::Arc,
+};
+use unlox_bytecode::{
+    Chunk, Closure, Function, Interner, ObjectId, OpCode, Upvalue, Value, VerifyError, FRAME_SIZE,
+};
 
-const STACK_SIZE: usize = 256;
+mod heap;
+
+/// Maximum number of nested calls.
+const FRAMES_MAX: usize = 64;
+/// Verified chunks don't use more than [`FRAME_SIZE`] slots, so the stack can't overflow with
+/// the script and all the frames on it.
+const STACK_SIZE: usize = FRAME_SIZE * (FRAMES_MAX + 1);
+/// Number of live objects above which the first garbage collection happens.
+const GC_INITIAL_THRESHOLD: usize = 1024;
 
 pub struct Vm {
     stack: Stack,
+    /// Functions waiting for the one being executed to return.
+    frames: Vec<CallFrame>,
+    /// Global variables, kept between chunks.
+    globals: HashMap<Arc<str>, Value>,
+    /// Upvalues still pointing into the stack, ordered by slot.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    heap: Heap,
+    /// String constants. Strings made at runtime, e.g. by concatenation, aren't interned.
+    strings: Interner,
+    /// Number of live objects above which the next allocation collects garbage.
+    next_gc: usize,
+}
+
+/// Function being executed.
+struct CallFrame {
+    /// `None` for the chunk passed to [`Vm::interpret`].
+    function: Option<Rc<Function>>,
+    /// Set when a closure, rather than a bare function, is being executed.
+    closure: Option<Rc<Closure>>,
+    ip: usize,
+    /// Stack slot of the first local variable of the frame. For called functions it holds the
+    /// function itself, followed by the arguments.
+    slots_base: usize,
 }
 
 struct Stack {
-    stack: [Value; STACK_SIZE],
+    stack: Box<[Value]>,
     top: usize,
 }
 
 impl Stack {
     fn new() -> Self {
         Self {
-            stack: [0.0; STACK_SIZE],
+            stack: std::iter::repeat_with(|| Value::Nil)
+                .take(STACK_SIZE)
+                .collect(),
             top: 0,
         }
     }
@@ -26,14 +68,29 @@ impl Stack {
 
     fn pop(&mut self) -> Value {
         self.top -= 1;
-        self.stack[self.top]
+        std::mem::take(&mut self.stack[self.top])
+    }
+
+    /// Drops the values above `top`.
+    fn truncate(&mut self, top: usize) {
+        while self.top > top {
+            self.pop();
+        }
+    }
+
+    fn peek(&self) -> &Value {
+        &self.stack[self.top - 1]
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("Compile error.")]
     Compile,
-    Runtime,
+    #[error("Invalid bytecode: {0}")]
+    Verify(#[from] VerifyError),
+    #[error("[Line {line}]: {message}")]
+    Runtime { line: usize, message: String },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -42,55 +99,341 @@ impl Vm {
     pub fn new() -> Self {
         Self {
             stack: Stack::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
+            strings: Interner::new(),
+            next_gc: GC_INITIAL_THRESHOLD,
         }
     }
 
-    pub fn interpret(&mut self, chunk: &Chunk) -> Result<()> {
-        let mut ip = 0;
-        let mut read_byte = || {
-            let byte = chunk.code[ip];
-            ip += 1;
-            byte
+    /// Objects referenced by values returned from [`Vm::interpret`]. They're only kept until
+    /// the next call.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Returns a string constant sharing its allocation with the equal constants made by this VM.
+    pub fn intern(&mut self, s: &str) -> Value {
+        Value::String(self.strings.intern(s))
+    }
+
+    /// Executes `chunk`, returning the value left by its `OpCode::Return`.
+    pub fn interpret(&mut self, chunk: &Chunk) -> Result<Value> {
+        chunk.verify()?;
+        let slots_base = self.stack.top;
+        let result = self.run(chunk);
+        if result.is_err() {
+            self.stack.truncate(slots_base);
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    fn run(&mut self, script: &Chunk) -> Result<Value> {
+        let mut frame = CallFrame {
+            function: None,
+            closure: None,
+            ip: 0,
+            slots_base: self.stack.top,
         };
         loop {
-            let byte = read_byte();
-            let opcode = OpCode::parse(byte).unwrap();
+            let chunk = frame
+                .function
+                .as_deref()
+                .map_or(script, |function| &function.chunk);
+            let offset = frame.ip;
+            let opcode = OpCode::parse(chunk.code[offset]).unwrap();
+            frame.ip += 1;
+            let error = |message: &str| Error::Runtime {
+                line: chunk.line_at(offset),
+                message: message.to_owned(),
+            };
             match opcode {
                 OpCode::Constant => {
-                    let constant = chunk.constants[usize::from(read_byte())];
+                    let constant = chunk.constants[usize::from(chunk.code[frame.ip])].clone();
+                    frame.ip += 1;
                     self.stack.push(constant);
                 }
-                OpCode::Add => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    self.stack.push(a + b);
-                }
-                OpCode::Subtract => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    self.stack.push(a - b);
+                OpCode::ConstantLong => {
+                    let constant = chunk.constants[chunk.read_u24(offset)].clone();
+                    frame.ip += 3;
+                    self.stack.push(constant);
                 }
-                OpCode::Multiply => {
+                OpCode::Add => {
                     let b = self.stack.pop();
                     let a = self.stack.pop();
-                    self.stack.push(a * b);
+                    let val = if let (Value::Number(a), Value::Number(b)) = (&a, &b) {
+                        Value::Number(a + b)
+                    } else {
+                        let (Some(a), Some(b)) = (self.as_str(&a), self.as_str(&b)) else {
+                            return Err(error("Operands must be two numbers or two strings."));
+                        };
+                        let concatenated = format!("{a}{b}");
+                        Value::Object(self.allocate(concatenated))
+                    };
+                    self.stack.push(val);
                 }
-                OpCode::Divide => {
+                OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Greater
+                | OpCode::Less => {
                     let b = self.stack.pop();
                     let a = self.stack.pop();
-                    self.stack.push(a / b);
+                    let (Value::Number(a), Value::Number(b)) = (a, b) else {
+                        return Err(error("Operands must be numbers."));
+                    };
+                    let val = match opcode {
+                        OpCode::Subtract => Value::Number(a - b),
+                        OpCode::Multiply => Value::Number(a * b),
+                        OpCode::Divide => Value::Number(a / b),
+                        OpCode::Greater => Value::Bool(a > b),
+                        _ => Value::Bool(a < b),
+                    };
+                    self.stack.push(val);
                 }
                 OpCode::Negate => {
+                    let Value::Number(v) = self.stack.pop() else {
+                        return Err(error("Operand must be a number."));
+                    };
+                    self.stack.push(Value::Number(-v));
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Bool(true)),
+                OpCode::False => self.stack.push(Value::Bool(false)),
+                OpCode::Not => {
                     let v = self.stack.pop();
-                    self.stack.push(-v);
+                    self.stack.push(Value::Bool(!v.is_truthy()));
+                }
+                OpCode::GetLocal => {
+                    let slot = frame.slots_base + usize::from(chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    self.stack.push(self.stack.stack[slot].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = frame.slots_base + usize::from(chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    self.stack.stack[slot] = self.stack.peek().clone();
+                }
+                OpCode::DefineGlobal => {
+                    let name = global_name(chunk, chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    let val = self.stack.pop();
+                    self.globals.insert(name, val);
+                }
+                OpCode::GetGlobal => {
+                    let name = global_name(chunk, chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    let Some(val) = self.globals.get(&name) else {
+                        return Err(error(&format!("Undefined variable '{name}'.")));
+                    };
+                    self.stack.push(val.clone());
+                }
+                OpCode::SetGlobal => {
+                    let name = global_name(chunk, chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    let Some(slot) = self.globals.get_mut(&name) else {
+                        return Err(error(&format!("Undefined variable '{name}'.")));
+                    };
+                    *slot = self.stack.peek().clone();
+                }
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::Jump => frame.ip = offset + 3 + usize::from(chunk.read_u16(offset)),
+                OpCode::JumpIfFalse => {
+                    frame.ip = offset + 3;
+                    if !self.stack.peek().is_truthy() {
+                        frame.ip += usize::from(chunk.read_u16(offset));
+                    }
+                }
+                OpCode::Loop => frame.ip = offset + 3 - usize::from(chunk.read_u16(offset)),
+                OpCode::Call => {
+                    let arg_count = usize::from(chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    let callee_slot = self.stack.top - arg_count - 1;
+                    let (function, closure) = match &self.stack.stack[callee_slot] {
+                        Value::Function(function) => (function, None),
+                        Value::Closure(closure) => (&closure.function, Some(closure.clone())),
+                        _ => return Err(error("Can only call functions and closures.")),
+                    };
+                    if usize::from(function.arity) != arg_count {
+                        return Err(error(&format!(
+                            "Expected {} arguments but got {arg_count}.",
+                            function.arity
+                        )));
+                    }
+                    if self.frames.len() >= FRAMES_MAX {
+                        return Err(error("Stack overflow."));
+                    }
+                    let callee = CallFrame {
+                        function: Some(function.clone()),
+                        closure,
+                        ip: 0,
+                        slots_base: callee_slot,
+                    };
+                    self.frames.push(std::mem::replace(&mut frame, callee));
+                }
+                OpCode::Closure => {
+                    let Value::Function(function) =
+                        &chunk.constants[usize::from(chunk.code[frame.ip])]
+                    else {
+                        panic!("Closures should be made of function constants");
+                    };
+                    let upvalue_count = usize::from(chunk.code[frame.ip + 1]);
+                    frame.ip += 2;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = chunk.code[frame.ip] == 1;
+                        let idx = usize::from(chunk.code[frame.ip + 1]);
+                        frame.ip += 2;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(frame.slots_base + idx)
+                        } else {
+                            current_closure(&frame).upvalues[idx].clone()
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = Closure {
+                        function: function.clone(),
+                        upvalues,
+                    };
+                    self.stack.push(Value::Closure(Rc::new(closure)));
+                }
+                OpCode::GetUpvalue => {
+                    let idx = usize::from(chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    let val = match &*current_closure(&frame).upvalues[idx].borrow() {
+                        Upvalue::Open(slot) => self.stack.stack[*slot].clone(),
+                        Upvalue::Closed(val) => val.clone(),
+                    };
+                    self.stack.push(val);
+                }
+                OpCode::SetUpvalue => {
+                    let idx = usize::from(chunk.code[frame.ip]);
+                    frame.ip += 1;
+                    let val = self.stack.peek().clone();
+                    match &mut *current_closure(&frame).upvalues[idx].borrow_mut() {
+                        Upvalue::Open(slot) => self.stack.stack[*slot] = val,
+                        Upvalue::Closed(closed) => *closed = val,
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.top - 1);
+                    self.stack.pop();
                 }
                 OpCode::Return => {
-                    println!("{}", self.stack.pop());
-                    return Ok(());
+                    let result = self.stack.pop();
+                    self.close_upvalues(frame.slots_base);
+                    self.stack.truncate(frame.slots_base);
+                    let Some(caller) = self.frames.pop() else {
+                        return Ok(result);
+                    };
+                    frame = caller;
+                    self.stack.push(result);
                 }
             }
         }
     }
+
+    fn as_str<'a>(&'a self, value: &'a Value) -> Option<&'a str> {
+        match value {
+            Value::String(s) => Some(s),
+            Value::Object(id) => match self.heap.get(*id) {
+                Object::String(s) => Some(s),
+            },
+            _ => None,
+        }
+    }
+
+    /// Allocates `object` on the heap, collecting garbage first if there are too many objects.
+    fn allocate(&mut self, object: impl Into<Object>) -> ObjectId {
+        if self.heap.len() >= self.next_gc {
+            self.collect_garbage();
+        }
+        self.heap.allocate(object)
+    }
+
+    fn collect_garbage(&mut self) {
+        let mut roots = Vec::new();
+        let mut seen = HashSet::new();
+        let values = self.stack.stack[..self.stack.top]
+            .iter()
+            .chain(self.globals.values());
+        for value in values {
+            value_roots(value, &mut roots, &mut seen);
+        }
+        self.heap.collect(roots.into_iter());
+        self.next_gc = (self.heap.len() * 2).max(GC_INITIAL_THRESHOLD);
+    }
+
+    /// Returns the upvalue pointing to `slot`, shared with the closures that already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let pos = self
+            .open_upvalues
+            .partition_point(|upvalue| open_slot(upvalue) < slot);
+        if let Some(upvalue) = self.open_upvalues.get(pos) {
+            if open_slot(upvalue) == slot {
+                return upvalue.clone();
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(pos, upvalue.clone());
+        upvalue
+    }
+
+    /// Moves the variables in `first_slot` and above off the stack, into their upvalues.
+    fn close_upvalues(&mut self, first_slot: usize) {
+        let pos = self
+            .open_upvalues
+            .partition_point(|upvalue| open_slot(upvalue) < first_slot);
+        for upvalue in self.open_upvalues.drain(pos..) {
+            let slot = open_slot(&upvalue);
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack.stack[slot].clone());
+        }
+    }
+}
+
+/// Pushes the objects referenced by `value`, including through variables captured by closures.
+/// `seen` holds the closures already visited, as they can capture themselves.
+fn value_roots(value: &Value, roots: &mut Vec<ObjectId>, seen: &mut HashSet<*const Closure>) {
+    match value {
+        Value::Object(id) => roots.push(*id),
+        Value::Closure(closure) if seen.insert(Rc::as_ptr(closure)) => {
+            for upvalue in &closure.upvalues {
+                if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                    value_roots(value, roots, seen);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn open_slot(upvalue: &RefCell<Upvalue>) -> usize {
+    match *upvalue.borrow() {
+        Upvalue::Open(slot) => slot,
+        Upvalue::Closed(_) => panic!("Closed upvalues shouldn't be tracked"),
+    }
+}
+
+/// # Panics if the frame isn't executing a closure
+fn current_closure(frame: &CallFrame) -> &Closure {
+    frame
+        .closure
+        .as_deref()
+        .expect("Upvalues should only be accessed from closures")
+}
+
+/// Returns the name of a global variable, stored in the constant at `idx`.
+fn global_name(chunk: &Chunk, idx: u8) -> Arc<str> {
+    match &chunk.constants[usize::from(idx)] {
+        Value::String(name) => name.clone(),
+        _ => panic!("Global variable names should be string constants"),
+    }
 }
 
 impl Default for Vm {
@@ -98,3 +441,504 @@ impl Default for Vm {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a chunk applying `opcode` to constants `a` and `b`.
+    fn binary(a: Value, b: Value, opcode: OpCode) -> Chunk {
+        let mut chunk = Chunk::new();
+        for value in [a, b] {
+            let constant = chunk.add_constant(value);
+            chunk.write(OpCode::Constant as u8, 1);
+            chunk.write(constant, 1);
+        }
+        chunk.write(opcode as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        chunk
+    }
+
+    /// Builds a chunk executing `opcodes` and returning the result.
+    fn simple(opcodes: &[OpCode]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for opcode in opcodes {
+            chunk.write(*opcode as u8, 1);
+        }
+        chunk.write(OpCode::Return as u8, 1);
+        chunk
+    }
+
+    #[test]
+    fn executes_arithmetic() {
+        for (opcode, expected) in [
+            (OpCode::Add, 8.0),
+            (OpCode::Subtract, 4.0),
+            (OpCode::Multiply, 12.0),
+            (OpCode::Divide, 3.0),
+        ] {
+            let chunk = binary(Value::Number(6.0), Value::Number(2.0), opcode);
+            let result = Vm::new().interpret(&chunk).unwrap();
+            assert_eq!(result, Value::Number(expected));
+        }
+        let chunk = binary(
+            Value::String("a".into()),
+            Value::String("b".into()),
+            OpCode::Add,
+        );
+        let mut vm = Vm::new();
+        let Value::Object(id) = vm.interpret(&chunk).unwrap() else {
+            panic!("Concatenation should allocate a string");
+        };
+        assert_eq!(vm.heap().get(id), &Object::from("ab"));
+    }
+
+    #[test]
+    fn loads_long_constants() {
+        let mut chunk = Chunk::new();
+        for n in 0..300 {
+            chunk.write_constant_instruction(Value::Number(f64::from(n)), 1);
+            if n < 298 {
+                chunk.write(OpCode::Pop as u8, 1);
+            }
+        }
+        chunk.write(OpCode::Add as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        let result = Vm::new().interpret(&chunk).unwrap();
+        assert_eq!(result, Value::Number(298.0 + 299.0));
+    }
+
+    #[test]
+    fn compares_numbers() {
+        for (a, b, opcode, expected) in [
+            (1.0, 2.0, OpCode::Less, true),
+            (2.0, 2.0, OpCode::Less, false),
+            (3.0, 2.0, OpCode::Greater, true),
+            (2.0, 2.0, OpCode::Greater, false),
+        ] {
+            let chunk = binary(Value::Number(a), Value::Number(b), opcode);
+            let result = Vm::new().interpret(&chunk).unwrap();
+            assert_eq!(result, Value::Bool(expected));
+        }
+        let chunk = binary(Value::Nil, Value::Number(1.0), OpCode::Less);
+        let error = Vm::new().interpret(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "[Line 1]: Operands must be numbers.");
+    }
+
+    #[test]
+    fn verifies_chunks() {
+        let chunk = simple(&[OpCode::Add]);
+        let error = Vm::new().interpret(&chunk).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid bytecode: Instruction at offset 0 pops from an empty stack."
+        );
+    }
+
+    #[test]
+    fn accesses_locals() {
+        let mut chunk = Chunk::new();
+        for value in [1.0, 2.0] {
+            let constant = chunk.add_constant(Value::Number(value));
+            chunk.write(OpCode::Constant as u8, 1);
+            chunk.write(constant, 1);
+        }
+        // Stack: [1, 2] -> [1, 2, 1] -> [1, 1, 1] -> [1, 2]
+        for (opcode, slot) in [(OpCode::GetLocal, 0), (OpCode::SetLocal, 1)] {
+            chunk.write(opcode as u8, 1);
+            chunk.write(slot, 1);
+        }
+        chunk.write(OpCode::Add as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        assert_eq!(Vm::new().interpret(&chunk).unwrap(), Value::Number(2.0));
+    }
+
+    #[test]
+    fn accesses_globals() {
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(Value::String("x".into()));
+        let one = chunk.add_constant(Value::Number(1.0));
+        let two = chunk.add_constant(Value::Number(2.0));
+        // var x = 1; x = 2; return x + x;
+        for (opcode, operand) in [
+            (OpCode::Constant, one),
+            (OpCode::DefineGlobal, name),
+            (OpCode::Constant, two),
+            (OpCode::SetGlobal, name),
+            (OpCode::GetGlobal, name),
+        ] {
+            chunk.write(opcode as u8, 1);
+            chunk.write(operand, 1);
+        }
+        chunk.write(OpCode::Add as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&chunk).unwrap(), Value::Number(4.0));
+
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(Value::String("y".into()));
+        chunk.write(OpCode::Nil as u8, 2);
+        chunk.write(OpCode::SetGlobal as u8, 2);
+        chunk.write(name, 2);
+        chunk.write(OpCode::Return as u8, 2);
+        let error = vm.interpret(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "[Line 2]: Undefined variable 'y'.");
+    }
+
+    #[test]
+    fn branches() {
+        for (cond, expected) in [(OpCode::True, 1.0), (OpCode::False, 2.0)] {
+            // if (cond) 1 else 2
+            let mut chunk = Chunk::new();
+            chunk.write(cond as u8, 1);
+            let else_jump = chunk.write_jump(OpCode::JumpIfFalse, 1);
+            chunk.write(OpCode::Pop as u8, 1);
+            let constant = chunk.add_constant(Value::Number(1.0));
+            chunk.write(OpCode::Constant as u8, 1);
+            chunk.write(constant, 1);
+            let end_jump = chunk.write_jump(OpCode::Jump, 1);
+            chunk.patch_jump(else_jump, chunk.code.len());
+            chunk.write(OpCode::Pop as u8, 1);
+            let constant = chunk.add_constant(Value::Number(2.0));
+            chunk.write(OpCode::Constant as u8, 1);
+            chunk.write(constant, 1);
+            chunk.patch_jump(end_jump, chunk.code.len());
+            chunk.write(OpCode::Return as u8, 1);
+            assert_eq!(
+                Vm::new().interpret(&chunk).unwrap(),
+                Value::Number(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn loops() {
+        // var x = true; var n = 0; while (x) { x = !x; n = n + 1; } return n;
+        let mut chunk = Chunk::new();
+        let x = chunk.add_constant(Value::String("x".into()));
+        let n = chunk.add_constant(Value::String("n".into()));
+        let zero = chunk.add_constant(Value::Number(0.0));
+        let one = chunk.add_constant(Value::Number(1.0));
+        chunk.write(OpCode::True as u8, 1);
+        chunk.write(OpCode::DefineGlobal as u8, 1);
+        chunk.write(x, 1);
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(zero, 1);
+        chunk.write(OpCode::DefineGlobal as u8, 1);
+        chunk.write(n, 1);
+        let loop_start = chunk.code.len();
+        chunk.write(OpCode::GetGlobal as u8, 2);
+        chunk.write(x, 2);
+        let exit_jump = chunk.write_jump(OpCode::JumpIfFalse, 2);
+        chunk.write(OpCode::Pop as u8, 2);
+        for byte in [OpCode::GetGlobal as u8, x, OpCode::Not as u8] {
+            chunk.write(byte, 3);
+        }
+        for byte in [OpCode::SetGlobal as u8, x, OpCode::Pop as u8] {
+            chunk.write(byte, 3);
+        }
+        for byte in [OpCode::GetGlobal as u8, n, OpCode::Constant as u8, one] {
+            chunk.write(byte, 4);
+        }
+        for byte in [
+            OpCode::Add as u8,
+            OpCode::SetGlobal as u8,
+            n,
+            OpCode::Pop as u8,
+        ] {
+            chunk.write(byte, 4);
+        }
+        chunk.write_loop(loop_start, 5);
+        chunk.patch_jump(exit_jump, chunk.code.len());
+        chunk.write(OpCode::Pop as u8, 6);
+        chunk.write(OpCode::GetGlobal as u8, 6);
+        chunk.write(n, 6);
+        chunk.write(OpCode::Return as u8, 6);
+        assert_eq!(Vm::new().interpret(&chunk).unwrap(), Value::Number(1.0));
+    }
+
+    /// Builds `fun add(a, b) { return a + b; }`.
+    fn add_function() -> Value {
+        let mut chunk = Chunk::new();
+        // Slot 0 holds the function itself.
+        for byte in [OpCode::GetLocal as u8, 1, OpCode::GetLocal as u8, 2] {
+            chunk.write(byte, 1);
+        }
+        chunk.write(OpCode::Add as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        Value::Function(Rc::new(Function {
+            name: "add".into(),
+            arity: 2,
+            chunk,
+        }))
+    }
+
+    #[test]
+    fn calls_functions() {
+        // return add(1, add(2, 3)) + 4;
+        let mut chunk = Chunk::new();
+        let add = chunk.add_constant(add_function());
+        let [one, two, three, four] =
+            [1.0, 2.0, 3.0, 4.0].map(|n| chunk.add_constant(Value::Number(n)));
+        for byte in [OpCode::Constant as u8, add, OpCode::Constant as u8, one] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Constant as u8, add, OpCode::Constant as u8, two] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Constant as u8, three, OpCode::Call as u8, 2] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Call as u8, 2, OpCode::Constant as u8, four] {
+            chunk.write(byte, 2);
+        }
+        chunk.write(OpCode::Add as u8, 2);
+        chunk.write(OpCode::Return as u8, 2);
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&chunk).unwrap(), Value::Number(10.0));
+        assert_eq!(vm.stack.top, 0);
+    }
+
+    #[test]
+    fn rejects_bad_calls() {
+        let mut chunk = Chunk::new();
+        let add = chunk.add_constant(add_function());
+        for byte in [OpCode::Constant as u8, add, OpCode::Call as u8, 0] {
+            chunk.write(byte, 3);
+        }
+        chunk.write(OpCode::Return as u8, 3);
+        let error = Vm::new().interpret(&chunk).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[Line 3]: Expected 2 arguments but got 0."
+        );
+
+        let mut chunk = Chunk::new();
+        for byte in [OpCode::True as u8, OpCode::Call as u8, 0] {
+            chunk.write(byte, 4);
+        }
+        chunk.write(OpCode::Return as u8, 4);
+        let mut vm = Vm::new();
+        let error = vm.interpret(&chunk).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[Line 4]: Can only call functions and closures."
+        );
+        assert_eq!(vm.stack.top, 0);
+    }
+
+    #[test]
+    fn overflows_frames_before_the_stack() {
+        // fun f() { var a1; ... var a200; f(); } f();
+        let mut function = Chunk::new();
+        let f = function.add_constant(Value::String("f".into()));
+        for _ in 0..200 {
+            function.write(OpCode::Nil as u8, 1);
+        }
+        for byte in [OpCode::GetGlobal as u8, f, OpCode::Call as u8, 0] {
+            function.write(byte, 1);
+        }
+        function.write(OpCode::Return as u8, 1);
+        let function = Value::Function(Rc::new(Function {
+            name: "f".into(),
+            arity: 0,
+            chunk: function,
+        }));
+
+        let mut chunk = Chunk::new();
+        let function = chunk.add_constant(function);
+        let f = chunk.add_constant(Value::String("f".into()));
+        for byte in [
+            OpCode::Constant as u8,
+            function,
+            OpCode::DefineGlobal as u8,
+            f,
+        ] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::GetGlobal as u8, f, OpCode::Call as u8, 0] {
+            chunk.write(byte, 2);
+        }
+        chunk.write(OpCode::Return as u8, 2);
+        let mut vm = Vm::new();
+        let error = vm.interpret(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "[Line 1]: Stack overflow.");
+        assert_eq!(vm.stack.top, 0);
+    }
+
+    #[test]
+    fn rejects_mismatched_operands() {
+        let chunk = binary(Value::Number(1.0), Value::Bool(true), OpCode::Multiply);
+        let error = Vm::new().interpret(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "[Line 1]: Operands must be numbers.");
+        let chunk = binary(Value::Number(1.0), Value::String("a".into()), OpCode::Add);
+        let error = Vm::new().interpret(&chunk).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[Line 1]: Operands must be two numbers or two strings."
+        );
+    }
+
+    #[test]
+    fn executes_literals_and_not() {
+        for (opcodes, expected) in [
+            (&[OpCode::Nil][..], Value::Nil),
+            (&[OpCode::True], Value::Bool(true)),
+            (&[OpCode::False, OpCode::Not], Value::Bool(true)),
+            (&[OpCode::Nil, OpCode::Not], Value::Bool(true)),
+            (&[OpCode::True, OpCode::Not], Value::Bool(false)),
+        ] {
+            assert_eq!(Vm::new().interpret(&simple(opcodes)).unwrap(), expected);
+        }
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(0.0));
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(constant, 1);
+        chunk.write(OpCode::Not as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        assert_eq!(Vm::new().interpret(&chunk).unwrap(), Value::Bool(false));
+    }
+
+    /// Builds `fun increment() { x = x + 1; return x; }`, where `x` is its only upvalue.
+    fn increment_function() -> Value {
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Value::Number(1.0));
+        for byte in [OpCode::GetUpvalue as u8, 0, OpCode::Constant as u8, one] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::Add as u8, OpCode::SetUpvalue as u8, 0] {
+            chunk.write(byte, 1);
+        }
+        chunk.write(OpCode::Return as u8, 1);
+        Value::Function(Rc::new(Function {
+            name: "increment".into(),
+            arity: 0,
+            chunk,
+        }))
+    }
+
+    #[test]
+    fn closes_over_locals() {
+        // fun counter() { var x = 1; fun increment() {...} increment(); return increment; }
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Value::Number(1.0));
+        let increment = chunk.add_constant(increment_function());
+        // Slot 1 holds `x`, slot 2 holds `increment`.
+        for byte in [OpCode::Constant as u8, one] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::Closure as u8, increment, 1, 1, 1] {
+            chunk.write(byte, 1);
+        }
+        // Updates `x` while it's still on the stack.
+        for byte in [OpCode::GetLocal as u8, 2, OpCode::Call as u8, 0] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::Pop as u8, OpCode::GetLocal as u8, 2] {
+            chunk.write(byte, 1);
+        }
+        chunk.write(OpCode::Return as u8, 1);
+        let counter = Value::Function(Rc::new(Function {
+            name: "counter".into(),
+            arity: 0,
+            chunk,
+        }));
+
+        // var increment = counter(); increment(); return increment();
+        let mut chunk = Chunk::new();
+        let counter = chunk.add_constant(counter);
+        for byte in [OpCode::Constant as u8, counter, OpCode::Call as u8, 0] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::GetLocal as u8, 0, OpCode::Call as u8, 0] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Pop as u8, OpCode::GetLocal as u8, 0] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Call as u8, 0, OpCode::Return as u8] {
+            chunk.write(byte, 2);
+        }
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&chunk).unwrap(), Value::Number(4.0));
+        assert_eq!(vm.stack.top, 0);
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn closes_upvalues_at_scope_end() {
+        // { var x = 1; fun increment() {...} f = increment; } f(); return f();
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Value::Number(1.0));
+        let increment = chunk.add_constant(increment_function());
+        let f = chunk.add_constant(Value::String("f".into()));
+        for byte in [OpCode::Constant as u8, one] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::Closure as u8, increment, 1, 1, 0] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::DefineGlobal as u8, f, OpCode::CloseUpvalue as u8] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::GetGlobal as u8, f, OpCode::Call as u8, 0] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Pop as u8, OpCode::GetGlobal as u8, f] {
+            chunk.write(byte, 2);
+        }
+        for byte in [OpCode::Call as u8, 0, OpCode::Return as u8] {
+            chunk.write(byte, 2);
+        }
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&chunk).unwrap(), Value::Number(3.0));
+        assert_eq!(vm.stack.top, 0);
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn collects_garbage() {
+        let mut vm = Vm::new();
+        // var g = "a" + "b";
+        let mut chunk = Chunk::new();
+        let [a, b, g] = ["a", "b", "g"].map(|s| chunk.add_constant(vm.intern(s)));
+        for byte in [OpCode::Constant as u8, a, OpCode::Constant as u8, b] {
+            chunk.write(byte, 1);
+        }
+        for byte in [OpCode::Add as u8, OpCode::DefineGlobal as u8, g] {
+            chunk.write(byte, 1);
+        }
+        chunk.write(OpCode::Nil as u8, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        vm.interpret(&chunk).unwrap();
+
+        let garbage = binary(
+            Value::String("c".into()),
+            Value::String("d".into()),
+            OpCode::Add,
+        );
+        for _ in 0..GC_INITIAL_THRESHOLD * 3 {
+            vm.interpret(&garbage).unwrap();
+        }
+        assert!(vm.heap().len() <= GC_INITIAL_THRESHOLD);
+
+        let mut chunk = Chunk::new();
+        let g = chunk.add_constant(vm.intern("g"));
+        for byte in [OpCode::GetGlobal as u8, g, OpCode::Return as u8] {
+            chunk.write(byte, 2);
+        }
+        let Value::Object(id) = vm.interpret(&chunk).unwrap() else {
+            panic!("Global should hold a heap string");
+        };
+        assert_eq!(vm.heap().get(id), &Object::from("ab"));
+    }
+
+    #[test]
+    fn interns_constants() {
+        let mut vm = Vm::new();
+        let (Value::String(a), Value::String(b)) = (vm.intern("x"), vm.intern("x")) else {
+            panic!("Interning should make string values");
+        };
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+}
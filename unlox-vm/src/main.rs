@@ -1,20 +1,20 @@
-use unlox_bytecode::{Chunk, OpCode};
+use unlox_bytecode::{Chunk, OpCode, Value};
 use unlox_vm::Vm;
 
 fn main() {
     let mut chunk = Chunk::new();
 
-    let constant = chunk.add_constant(1.2);
+    let constant = chunk.add_constant(Value::Number(1.2));
     chunk.write(OpCode::Constant as u8, 123);
     chunk.write(constant, 123);
 
-    let constant = chunk.add_constant(3.4);
+    let constant = chunk.add_constant(Value::Number(3.4));
     chunk.write(OpCode::Constant as u8, 123);
     chunk.write(constant, 123);
 
     chunk.write(OpCode::Add as u8, 123);
 
-    let constant = chunk.add_constant(5.6);
+    let constant = chunk.add_constant(Value::Number(5.6));
     chunk.write(OpCode::Constant as u8, 123);
     chunk.write(constant, 123);
 
@@ -25,5 +25,5 @@ fn main() {
     chunk.write(OpCode::Return as u8, 123);
 
     let mut vm = Vm::new();
-    vm.interpret(&chunk).unwrap();
+    println!("{}", vm.interpret(&chunk).unwrap());
 }
@@ -1,10 +1,41 @@
+use std::{io::Write, ops::Range, rc::Rc};
+
 use js_sys::Reflect;
-use unlox_interpreter::output::SingleOutput;
+use unlox_ast::Ast;
+use unlox_interpreter::{output::SingleOutput, Callable, Val};
+use unlox_parse::ParseResult;
+use unlox_resolver::Locals;
 use wasm_bindgen::prelude::*;
 
+/// Maximum number of steps of a single program.
+const STEP_LIMIT: u64 = 10_000_000;
+
 #[wasm_bindgen]
 pub struct Interpreter {
     interpreter: unlox_interpreter::Interpreter,
+    /// Programs compiled so far. Functions defined by earlier programs keep pointing into their
+    /// code and tree, so each program is added to them rather than compiled on its own.
+    code: String,
+    ast: Ast,
+    locals: Locals,
+    /// Program being run by [`Interpreter::interpret_step`].
+    program: Option<Program>,
+}
+
+struct Program {
+    src: String,
+    /// Positions of the statements of the program in the roots of the tree.
+    roots: Range<usize>,
+    /// Position of the next statement to execute in the roots of the tree.
+    current_stmt: usize,
+}
+
+/// Outcome of [`Interpreter::interpret_step`].
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Continue,
+    Done,
 }
 
 #[wasm_bindgen]
@@ -12,22 +43,250 @@ impl Interpreter {
     #[allow(clippy::new_without_default)]
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
+        let mut interpreter = unlox_interpreter::Interpreter::new();
+        // A runaway loop would hang the worker running the interpreter, and the playground would
+        // never get a result.
+        interpreter.set_step_limit(STEP_LIMIT);
         Self {
-            interpreter: unlox_interpreter::Interpreter::new(),
+            interpreter,
+            code: String::new(),
+            ast: Ast::new(),
+            locals: Locals::new(),
+            program: None,
         }
     }
 
+    /// Runs `src`, writing its output and errors to `writer`.
+    ///
+    /// Throws a [`LoxError`] with the first error the program failed with.
     #[wasm_bindgen]
-    pub fn interpret(&mut self, src: &str, writer: JsValue) -> Result<(), JsError> {
-        let mut writer = JsWriter::new(writer)?;
-        let lexer = unlox_lexer::Lexer::new(src);
-        let ast = unlox_parse::parse(lexer, &mut writer);
+    pub fn interpret(&mut self, src: &str, writer: JsValue) -> Result<(), LoxError> {
+        let mut writer = JsWriter::new(writer).map_err(LoxError::host)?;
+        self.program = None;
+        let roots = self.compile(src, &mut writer)?;
+        self.interpreter.start(&self.locals);
         let mut ctx = unlox_interpreter::Ctx {
-            src,
+            src: &self.code,
             out: SingleOutput::new(&mut writer),
         };
-        self.interpreter.interpret(&mut ctx, &ast);
-        Ok(())
+        let result = self.ast.roots()[roots]
+            .iter()
+            .try_for_each(|&stmt| self.interpreter.try_execute_root(&mut ctx, &self.ast, stmt));
+        runtime_error(result, &mut writer)
+    }
+
+    /// Runs the next top-level statement of `src`, so that JS can yield between statements of a
+    /// long-running program. Passing a different `src` than the last call starts it over.
+    ///
+    /// Errors are reported like by [`Interpreter::interpret`], after which the program is done.
+    #[wasm_bindgen]
+    pub fn interpret_step(&mut self, src: &str, writer: JsValue) -> Result<StepResult, LoxError> {
+        let mut writer = JsWriter::new(writer).map_err(LoxError::host)?;
+        if self
+            .program
+            .as_ref()
+            .is_none_or(|program| program.src != src)
+        {
+            self.program = None;
+            let roots = self.compile(src, &mut writer)?;
+            self.interpreter.start(&self.locals);
+            self.program = Some(Program {
+                src: src.to_owned(),
+                current_stmt: roots.start,
+                roots,
+            });
+        }
+        let program = self.program.as_mut().unwrap();
+        if program.current_stmt >= program.roots.end {
+            return Ok(StepResult::Done);
+        }
+        let stmt = self.ast.roots()[program.current_stmt];
+        let mut ctx = unlox_interpreter::Ctx {
+            src: &self.code,
+            out: SingleOutput::new(&mut writer),
+        };
+        let result = self.interpreter.try_execute_root(&mut ctx, &self.ast, stmt);
+        program.current_stmt = match result {
+            Ok(()) => program.current_stmt + 1,
+            Err(_) => program.roots.end,
+        };
+        runtime_error(result, &mut writer)?;
+        Ok(if self.is_done() {
+            StepResult::Done
+        } else {
+            StepResult::Continue
+        })
+    }
+
+    /// Returns whether the program run by [`Interpreter::interpret_step`] has no statements
+    /// left, or there's no such program.
+    #[wasm_bindgen]
+    pub fn is_done(&self) -> bool {
+        self.program
+            .as_ref()
+            .is_none_or(|program| program.current_stmt >= program.roots.end)
+    }
+
+    /// Makes the next [`Interpreter::interpret_step`] start the program over. Variables defined
+    /// by it so far are kept.
+    #[wasm_bindgen]
+    pub fn reset_cursor(&mut self) {
+        if let Some(program) = &mut self.program {
+            self.interpreter.start(&self.locals);
+            program.current_stmt = program.roots.start;
+        }
+    }
+
+    /// Returns the functions that were being executed when the last runtime error was raised,
+    /// outermost first, as an array of `{ name, line }` objects. `line` is the line of the call.
+    #[wasm_bindgen]
+    pub fn call_stack(&self) -> JsValue {
+        let frames = js_sys::Array::new();
+        for frame in self.interpreter.error_call_stack() {
+            let object = js_sys::Object::new();
+            Reflect::set(&object, &"name".into(), &frame.name.as_str().into()).unwrap();
+            Reflect::set(&object, &"line".into(), &frame.call_site_line.into()).unwrap();
+            frames.push(&object);
+        }
+        frames.into()
+    }
+
+    /// Returns the global variables defined by the programs run so far, as an object mapping
+    /// their names to their values. Native functions are left out.
+    #[wasm_bindgen]
+    pub fn globals(&self) -> JsValue {
+        vars_to_js(self.interpreter.global_vars())
+    }
+
+    /// Like [`Interpreter::globals`], but for the environment `depth` environments up from the
+    /// current one. Returns `undefined` if there are fewer environments.
+    #[wasm_bindgen]
+    pub fn locals_at_depth(&self, depth: usize) -> JsValue {
+        self.interpreter
+            .vars_at_depth(depth)
+            .map_or(JsValue::UNDEFINED, vars_to_js)
+    }
+}
+
+impl Interpreter {
+    /// Parses and resolves `src`, adding it to the programs compiled so far and returning the
+    /// positions of its statements in the roots of the tree. Errors are written to `writer`.
+    fn compile(&mut self, src: &str, writer: &mut JsWriter) -> Result<Range<usize>, LoxError> {
+        let start = self.code.len();
+        self.code.push_str(src);
+        // The next program has to start on a line of its own.
+        self.code.push('\n');
+        let lexer = unlox_lexer::Lexer::starting_at(&self.code, start);
+        let ParseResult { ast, errors } = unlox_parse::parse(lexer);
+        if let Some(first) = errors.first() {
+            for error in &errors {
+                writeln!(writer, "{error}").map_err(LoxError::host)?;
+            }
+            let token = &first.token;
+            return Err(LoxError::new("parse", first, token.line, token.col));
+        }
+        let locals = match unlox_resolver::try_resolve(&self.code, &ast) {
+            Ok(locals) => locals,
+            Err(errors) => {
+                for error in &errors {
+                    writeln!(writer, "{error}").map_err(LoxError::host)?;
+                }
+                // Resolution errors are found before running the program, like syntax errors.
+                let first = &errors[0];
+                let token = &first.token;
+                return Err(LoxError::new("parse", first, token.line, token.col));
+            }
+        };
+        let mapping = self.ast.merge(ast);
+        self.locals.extend(
+            locals
+                .into_iter()
+                .map(|(expr, depth)| (mapping.expr(expr), depth)),
+        );
+        Ok(mapping.root_range())
+    }
+}
+
+/// Writes the error of running a program to `writer`, converting it to a [`LoxError`].
+fn runtime_error(
+    result: unlox_interpreter::Result<()>,
+    writer: &mut JsWriter,
+) -> Result<(), LoxError> {
+    let Err(error) = result else {
+        return Ok(());
+    };
+    writeln!(writer, "{error}").map_err(LoxError::host)?;
+    let (line, col) = error
+        .token()
+        .map_or((0, 0), |token| (token.line, token.col));
+    Err(LoxError::new("runtime", &error, line, col))
+}
+
+fn vars_to_js<'a>(vars: impl Iterator<Item = (&'a str, &'a Val)>) -> JsValue {
+    let object = js_sys::Object::new();
+    for (name, val) in vars {
+        if !matches!(val, Val::Callable(Callable::Native(_))) {
+            Reflect::set(&object, &name.into(), &val_to_js(val, &mut Vec::new())).unwrap();
+        }
+    }
+    object.into()
+}
+
+/// Converts `val` to the matching JS type. Values without one, e.g. functions, become their
+/// string representation.
+///
+/// `enclosing` holds the arrays being converted, an array nested in itself becomes `"[...]"`,
+/// like when it's printed.
+fn val_to_js(val: &Val, enclosing: &mut Vec<*const ()>) -> JsValue {
+    match val {
+        Val::Number(n) => JsValue::from_f64(*n),
+        Val::String(s) => JsValue::from_str(s),
+        Val::Bool(b) => JsValue::from_bool(*b),
+        Val::Nil | Val::Uninitialized => JsValue::NULL,
+        Val::Array(vals) => {
+            let ptr = Rc::as_ptr(vals).cast();
+            if enclosing.contains(&ptr) {
+                return JsValue::from_str("[...]");
+            }
+            enclosing.push(ptr);
+            let array: js_sys::Array = vals
+                .borrow()
+                .iter()
+                .map(|val| val_to_js(val, enclosing))
+                .collect();
+            enclosing.pop();
+            array.into()
+        }
+        _ => JsValue::from_str(&val.to_string()),
+    }
+}
+
+/// Error thrown by [`Interpreter::interpret`].
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone)]
+pub struct LoxError {
+    /// `"parse"` for errors found before running the program, `"runtime"` for errors raised while
+    /// running it and `"host"` for misuse of the binding itself.
+    pub kind: String,
+    /// The error as written to the output.
+    pub message: String,
+    /// Position the error is reported at, counted from 1, or 0 if it has none.
+    pub line: u32,
+    pub column: u32,
+}
+
+impl LoxError {
+    fn new(kind: &str, error: &impl std::fmt::Display, line: u32, column: u32) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            message: error.to_string(),
+            line,
+            column,
+        }
+    }
+
+    fn host(error: impl std::fmt::Display) -> Self {
+        Self::new("host", &error, 0, 0)
     }
 }
 
@@ -83,28 +342,20 @@ impl std::io::Write for JsWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let buf = std::str::from_utf8(buf).unwrap();
         let buf = JsValue::from_str(buf);
-        let nwritten = self.write.call1(&self.writer, &buf).map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unexpected exception caught from JsWriter",
-            )
-        })?;
+        let nwritten = self
+            .write
+            .call1(&self.writer, &buf)
+            .map_err(|_| std::io::Error::other("Unexpected exception caught from JsWriter"))?;
         let nwritten = nwritten.as_f64().ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Expected JsWriter.write to return number of bytes written",
-            )
+            std::io::Error::other("Expected JsWriter.write to return number of bytes written")
         })?;
         Ok(nwritten as usize)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.flush.call0(&self.writer).map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unexpected exception caught from JsWriter",
-            )
-        })?;
+        self.flush
+            .call0(&self.writer)
+            .map_err(|_| std::io::Error::other("Unexpected exception caught from JsWriter"))?;
         Ok(())
     }
 }
@@ -33,12 +33,17 @@ monaco.languages.register({ id: "lox" });
 monaco.languages.setMonarchTokensProvider('lox', {
     keywords: [
         "and",
+        "break",
         "class",
+        "continue",
+        "do",
         "else",
+        "extends",
         "false",
         "fun",
         "for",
         "if",
+        "in",
         "nil",
         "or",
         "print",
@@ -46,19 +51,47 @@ monaco.languages.setMonarchTokensProvider('lox', {
         "super",
         "this",
         "true",
+        "typeof",
         "var",
         "while"
     ],
+    // Mirrors the rules of `unlox-lexer`, so that tokens it rejects are shown as invalid.
     tokenizer: {
         root: [
-            [/@?[a-zA-Z][\w$]*/, {
+            [/[a-zA-Z_]\w*/, {
                 cases: {
                     '@keywords': 'keyword',
                     '@default': 'variable',
                 }
             }],
-            [/".*?"/, 'string'],
-            [/\/\//, 'comment']
+            [/0x[0-9a-fA-F]+(?!\w)/, 'number.hex'],
+            [/0b[01]+(?!\w)/, 'number.binary'],
+            [/0o[0-7]+(?!\w)/, 'number.octal'],
+            [/0[xbo]\w*/, 'invalid'],
+            [/\d+(\.\d+)?([eE][+-]?\d+)?/, 'number'],
+            [/\/\/.*$/, 'comment'],
+            [/\/\*/, 'comment', '@comment'],
+            // Monaco tokenizes a line at a time, so a string left open at the end of the line it
+            // starts on is marked invalid, as it may never be closed.
+            [/"([^\\"]|\\.)*$/, { token: 'invalid', next: '@string' }],
+            [/"/, 'string', '@string'],
+            [/[{}()\[\]]/, '@brackets'],
+            [/[;,.:?]/, 'delimiter'],
+            [/\*\*|[-+*\/!=<>]=?|%/, 'operator'],
+            [/\s+/, 'white'],
+            [/./, 'invalid']
+        ],
+        // Strings and block comments can span multiple lines.
+        string: [
+            [/[^\\"]+/, 'string'],
+            [/\\[\\"ntr0]/, 'string.escape'],
+            [/\\./, 'string.escape.invalid'],
+            [/"/, 'string', '@pop']
+        ],
+        comment: [
+            [/[^*]+/, 'comment'],
+            [/\*\//, 'comment', '@pop'],
+            [/\*/, 'comment']
         ]
     }
 });
@@ -83,6 +116,7 @@ let indicatorInterval: number | null;
 document.getElementById("run")?.addEventListener("click", () => {
     const start = Date.now();
     output.textContent = "";
+    monaco.editor.setModelMarkers(editor.getModel()!, "unlox", []);
 
     if (worker) {
         if (indicatorInterval) {
@@ -107,6 +141,23 @@ document.getElementById("run")?.addEventListener("click", () => {
             case "output":
                 output.textContent += event.data.output;
                 break;
+            case "error":
+                const { message, line, column } = event.data.error;
+                for (const frame of [...event.data.callStack].reverse()) {
+                    output.textContent += `    in ${frame.name || "lambda"}, called at line ${frame.line}\n`;
+                }
+                // Errors reported by the host rather than the program have no position.
+                if (line > 0) {
+                    monaco.editor.setModelMarkers(editor.getModel()!, "unlox", [{
+                        severity: monaco.MarkerSeverity.Error,
+                        message,
+                        startLineNumber: line,
+                        startColumn: column,
+                        endLineNumber: line,
+                        endColumn: column + 1,
+                    }]);
+                }
+                break;
             case "end":
                 const end = Date.now();
                 if (indicatorInterval) {
@@ -1,4 +1,4 @@
-import init, { Interpreter } from "unlox-wasm";
+import init, { Interpreter, LoxError } from "unlox-wasm";
 
 class Writer {
     write(output: string) {
@@ -15,6 +15,16 @@ self.onmessage = async (event: any) => {
     await init({});
     const writer = new Writer();
     const interpreter = new Interpreter();
-    interpreter.interpret(event.data, writer);
+    try {
+        interpreter.interpret(event.data, writer);
+    } catch (error) {
+        if (error instanceof LoxError) {
+            const { kind, message, line, column } = error;
+            const callStack = interpreter.call_stack();
+            postMessage({ type: "error", error: { kind, message, line, column }, callStack });
+        } else {
+            throw error;
+        }
+    }
     postMessage({ type: "end" })
 }
\ No newline at end of file